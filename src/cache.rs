@@ -0,0 +1,215 @@
+//! A cache of the search results for each file, so that files that haven't changed since an
+//! earlier run with the same search don't need to be searched again.
+//!
+//! Each search has its own cache file in the cache directory, named by a hash of the search. Each
+//! line of the file has the format "MODIFIED SIZE HASH LINES PATH", where "MODIFIED" is the
+//! modification time in nanoseconds since the unix epoch, "HASH" is a hash of the file contents,
+//! and "LINES" is a comma-separated list of the (0-indexed) matching lines, or "-" if there are no
+//! matching lines.
+
+use std::collections::BTreeMap;
+use std::fs::Metadata;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::Context;
+
+use crate::util::fnv1a;
+
+/// The cache directory, relative to the current directory.
+pub const DIR: &str = ".repatch-cache";
+
+#[derive(Debug, PartialEq, Eq)]
+struct Entry {
+    modified: u128,
+    size: u64,
+    hash: u64,
+    lines: Vec<u64>,
+}
+
+pub struct MatchCache {
+    path: PathBuf,
+    /// The entries from the cache file.
+    old: BTreeMap<PathBuf, Entry>,
+    /// The entries of the files that were searched (or found in the cache) during this run. Only
+    /// these are written back to the cache file.
+    new: BTreeMap<PathBuf, Entry>,
+}
+
+impl MatchCache {
+    /// Load the cache for the search `key`. A missing or invalid cache file is treated as empty.
+    pub fn load(key: &str) -> Self {
+        // the results may change between versions
+        let key = format!("{}\0{key}", env!("CARGO_PKG_VERSION"));
+        let path = Path::new(DIR).join(format!("{:016x}", fnv1a(key.as_bytes())));
+
+        let old = std::fs::read(&path)
+            .ok()
+            .and_then(|x| parse(&x))
+            .unwrap_or_default();
+
+        Self {
+            path,
+            old,
+            new: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the cached matching lines of the file, or `None` if the file isn't in the cache or
+    /// has changed.
+    pub fn get(&mut self, path: &Path, meta: &Metadata) -> Option<Vec<u64>> {
+        let entry = self.old.get(path)?;
+        if entry.size != meta.len() {
+            return None;
+        }
+
+        let modified = nanos(meta.modified().ok()?);
+        if entry.modified != modified {
+            // the file may have been touched without being changed (for example by `git checkout`)
+            let hash = fnv1a(&std::fs::read(path).ok()?);
+            if entry.hash != hash {
+                return None;
+            }
+        }
+
+        let mut entry = self.old.remove(path)?;
+        entry.modified = modified;
+        let lines = entry.lines.clone();
+        self.new.insert(path.to_path_buf(), entry);
+        Some(lines)
+    }
+
+    /// Add the matching lines of a file that was searched.
+    pub fn insert(&mut self, path: &Path, meta: &Metadata, lines: Vec<u64>) {
+        let Ok(modified) = meta.modified() else {
+            return;
+        };
+        let Ok(contents) = std::fs::read(path) else {
+            return;
+        };
+
+        let entry = Entry {
+            modified: nanos(modified),
+            size: meta.len(),
+            hash: fnv1a(&contents),
+            lines,
+        };
+        self.new.insert(path.to_path_buf(), entry);
+    }
+
+    /// Write the entries of this run to the cache file.
+    pub fn write(&self) -> anyhow::Result<()> {
+        std::fs::create_dir_all(DIR)
+            .with_context(|| format!("could not create the cache directory '{DIR}'"))?;
+
+        let mut out = Vec::new();
+        for (path, entry) in &self.new {
+            let path = path.as_os_str().as_bytes();
+            if path.contains(&b'\n') {
+                continue;
+            }
+
+            let lines = match entry.lines.is_empty() {
+                true => "-".to_string(),
+                false => {
+                    let lines: Vec<_> = entry.lines.iter().map(|x| x.to_string()).collect();
+                    lines.join(",")
+                }
+            };
+
+            out.extend_from_slice(
+                format!(
+                    "{} {} {:016x} {lines} ",
+                    entry.modified, entry.size, entry.hash,
+                )
+                .as_bytes(),
+            );
+            out.extend_from_slice(path);
+            out.push(b'\n');
+        }
+
+        // write to a temporary file first so that other runs never see a partially written file
+        let mut tmp = tempfile::NamedTempFile::new_in(DIR)?;
+        std::io::Write::write_all(&mut tmp, &out)?;
+        tmp.persist(&self.path)
+            .with_context(|| format!("could not write '{}'", self.path.display()))?;
+
+        Ok(())
+    }
+}
+
+fn parse(s: &[u8]) -> Option<BTreeMap<PathBuf, Entry>> {
+    let mut entries = BTreeMap::new();
+
+    for line in s.split(|x| *x == b'\n').filter(|x| !x.is_empty()) {
+        let mut fields = line.splitn(5, |x| *x == b' ');
+        let mut field = || std::str::from_utf8(fields.next()?).ok();
+
+        let modified = field()?.parse().ok()?;
+        let size = field()?.parse().ok()?;
+        let hash = u64::from_str_radix(field()?, 16).ok()?;
+        let lines = match field()? {
+            "-" => Vec::new(),
+            x => x
+                .split(',')
+                .map(|x| x.parse().ok())
+                .collect::<Option<_>>()?,
+        };
+        let path = std::ffi::OsStr::from_bytes(fields.next()?);
+
+        let entry = Entry {
+            modified,
+            size,
+            hash,
+            lines,
+        };
+        entries.insert(PathBuf::from(path), entry);
+    }
+
+    Some(entries)
+}
+
+fn nanos(time: SystemTime) -> u128 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|x| x.as_nanos())
+        .unwrap_or(0)
+}
+
+/// The key of a search from the name and value of each of its options, which must include every
+/// option that can change which lines of a file are recorded as matching.
+pub fn key(options: &[(&str, String)]) -> String {
+    let mut key = String::new();
+    for (name, value) in options {
+        key.push_str(name);
+        key.push('=');
+        key.push_str(value);
+        key.push('\0');
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let entries = parse(b"10 20 00000000000000ff 1,5 a b.txt\n3 0 0000000000000001 - c\n");
+        let entries = entries.unwrap();
+        assert_eq!(
+            entries[Path::new("a b.txt")],
+            Entry {
+                modified: 10,
+                size: 20,
+                hash: 0xff,
+                lines: vec![1, 5],
+            },
+        );
+        assert_eq!(entries[Path::new("c")].lines, []);
+
+        assert!(parse(b"10 20 ff 1,x a\n").is_none());
+        assert!(parse(b"10 20 ff 1\n").is_none());
+        assert_eq!(parse(b"").unwrap().len(), 0);
+    }
+}
@@ -6,37 +6,1208 @@ const VERSION_STR: &str = concat!("re:patch ", env!("CARGO_PKG_VERSION"));
 
 /// re:patch is a line-oriented find-and-replace tool with a `git add --patch`-like interface.
 /// Directories are searched recursively. Hidden files/directories and binary files are ignored, as
-/// well as files/directories specified in gitignore rules. Regular expressions with capture groups
-/// are supported.
+/// well as files/directories specified in gitignore rules, ".ignore", ".rgignore", and
+/// ".repatchignore" files, and the global ignore file from the config. Regular expressions with
+/// capture groups are supported.
+const SUBCOMMANDS_HELP: &str = "\
+Subcommands:
+  run          Find and replace interactively (the default if no subcommand is given)
+  apply        Find and replace without prompting, like 'run --apply'
+  scan         Only search and print the matching lines
+  plan         Choose hunks like 'run', but save them to a plan file instead of the files
+  execute      Apply the hunks of a plan file to the files that haven't changed
+  template     Run a built-in or configured template, or list them if no name is given
+  undo         Restore the files modified by the last run or another recent run
+  eol          Convert line endings, like 'run --convert-eol <EOL>' with '--to <EOL>'
+  serve-preview
+               Serve a read-only HTML preview of the changes, like 'run --serve-preview' with
+               '--port <PORT>' and '--host <HOST>'
+  completions  Print a shell completion script";
+
+/// The subcommands, which must be the first argument. Running without a subcommand is the same as
+/// `run`, so a `<FIND>` argument that is also the name of a subcommand must follow an explicit
+/// `run`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Subcommand {
+    Run,
+    Apply,
+    Scan,
+    Plan,
+    Execute,
+    Template,
+    Undo,
+    Eol,
+    ServePreview,
+    Completions,
+}
+
+impl Subcommand {
+    pub fn from_arg(arg: &std::ffi::OsStr) -> Option<Self> {
+        Some(match arg.to_str()? {
+            "run" => Self::Run,
+            "apply" => Self::Apply,
+            "scan" => Self::Scan,
+            "plan" => Self::Plan,
+            "execute" => Self::Execute,
+            "template" => Self::Template,
+            "undo" => Self::Undo,
+            "eol" => Self::Eol,
+            "serve-preview" => Self::ServePreview,
+            "completions" => Self::Completions,
+            _ => return None,
+        })
+    }
+
+    pub const fn list() -> &'static [Self] {
+        &[
+            Self::Run,
+            Self::Apply,
+            Self::Scan,
+            Self::Plan,
+            Self::Execute,
+            Self::Template,
+            Self::Undo,
+            Self::Eol,
+            Self::ServePreview,
+            Self::Completions,
+        ]
+    }
+
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Run => "run",
+            Self::Apply => "apply",
+            Self::Scan => "scan",
+            Self::Plan => "plan",
+            Self::Execute => "execute",
+            Self::Template => "template",
+            Self::Undo => "undo",
+            Self::Eol => "eol",
+            Self::ServePreview => "serve-preview",
+            Self::Completions => "completions",
+        }
+    }
+}
+
 #[derive(Debug, Parser)]
 #[command(version, name = "re:patch", max_term_width = 120, help_expected = true)]
-#[command(before_help(VERSION_STR))]
+#[command(before_help(VERSION_STR), after_help(SUBCOMMANDS_HELP))]
 pub struct Args {
-    /// Regex to search for, optionally with capture groups.
+    /// Regex to search for, optionally with capture groups. It can't be a pattern that matches the
+    /// empty string everywhere, such as "" or "x*".
     pub find: String,
-    /// Text to replace `<FIND>` with. Capture group indices and names are supported.
-    pub replace: String,
-    /// Paths (files and/or directories) to search recursively.
-    #[clap(required = true)]
+    /// Text to replace `<FIND>` with. Capture groups are referenced by index or name as `$1`,
+    /// `${1}`, `$name`, or `${name}`, and `$0` is the whole match. An unbraced name continues for
+    /// as long as there are letters, digits, and underscores, so group 1 followed by "a" is
+    /// `${1}a`. `$$` is a "$". The variables `${FILE}` (the file path), `${LINE}` (the line
+    /// number), and `${MATCH}` (the matched text) are also supported.
+    #[clap(required_unless_present_any([
+        "replace_file", "ensure_header", "rules", "convert_eol", "search_only"
+    ]))]
+    pub replace: Option<String>,
+    /// Paths (files and/or directories) to search recursively. Without any paths (or with the
+    /// path "-"), stdin is read and the result is written to stdout, and the hunks are prompted
+    /// for on the terminal.
     pub paths: Vec<PathBuf>,
+    /// Use the find, replace, glob, and flag arguments from the preset `<NAME>` in the config file.
+    /// The preset's find and replace arguments should be omitted from the command line.
+    #[clap(long, value_name = "NAME")]
+    pub preset: Option<String>,
+    /// Compute the replacement for each match by running the script in `<FILE>`, rather than using
+    /// `<REPLACE>` directly. The script uses a subset of the Rhai language, where `m` is the
+    /// matched text, `caps` is an array of the capture groups, `cap("name")` is a named capture
+    /// group, and `replace` is `<REPLACE>`. The value of the last expression is the replacement.
+    #[clap(long, value_name = "FILE")]
+    pub replace_script: Option<PathBuf>,
+    /// Use the entire content of `<FILE>` as the replacement, including any trailing newline. The
+    /// `<REPLACE>` argument should be omitted. This makes it easier to replace a match with many
+    /// lines of text.
+    #[clap(long, value_name = "FILE")]
+    pub replace_file: Option<PathBuf>,
+    /// Make sure that each file starts with the content of `<FILE>`, such as a license header. The
+    /// `<REPLACE>` argument should be omitted. The lines at the start of a file that match
+    /// `<FIND>` (for example "^// ") are an outdated header and are replaced, otherwise the header
+    /// is inserted at the start of the file. A "#!" line always stays first. Files that already
+    /// start with the header are skipped.
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all([
+            "replace_file", "replace_script", "replace_one_of", "line_terminator", "normalize",
+            "matches_from", "backend", "cache", "columns", "byte_range", "between", "first_only",
+            "last_only", "identifier", "explain_no_matches",
+        ])
+    )]
+    pub ensure_header: Option<PathBuf>,
+    /// Replace the matches of each rule in the rules file `<FILE>` instead of `<FIND>`, where
+    /// each rule is a `[rule.NAME]` table with a "find" and a "replace" key. The `<FIND>` and
+    /// `<REPLACE>` arguments should be omitted. The rules are applied in the order of the file,
+    /// each to the text as changed by the rules before it, and the changes of all rules to the
+    /// same lines are shown together in one hunk.
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all([
+            "replace_file", "replace_script", "replace_one_of", "ensure_header", "normalize",
+            "backend",
+        ])
+    )]
+    pub rules: Option<PathBuf>,
+    /// Convert the line endings of every line to `<EOL>`, showing the lines that change as hunks.
+    /// The `<FIND>` and `<REPLACE>` arguments should be omitted. A last line without a line
+    /// ending is left as it is.
+    #[clap(
+        long,
+        value_name = "EOL",
+        conflicts_with_all([
+            "replace_file", "replace_script", "replace_one_of", "ensure_header", "rules",
+            "normalize", "line_terminator", "multiline", "fix_eol", "backend", "matches_from",
+            "cache", "columns", "byte_range", "between", "identifier", "explain_no_matches",
+            "idempotent_check",
+        ])
+    )]
+    pub convert_eol: Option<LineEnding>,
+    /// Only search, and list the hunks of matching lines with their context without replacing
+    /// anything. The `<REPLACE>` argument should be omitted. All of the search filters still
+    /// apply.
+    #[clap(
+        long,
+        conflicts_with_all([
+            "replace_file", "replace_script", "replace_one_of", "ensure_header", "rules",
+            "convert_eol", "apply", "show", "check", "serve", "hook", "write_plan",
+            "idempotent_check",
+        ])
+    )]
+    pub search_only: bool,
+    /// Other replacements that can be chosen instead of `<REPLACE>` at each hunk, where the prompt
+    /// numbers `<REPLACE>` as 1 and these replacements from 2. Takes one or more values, so the
+    /// other arguments should come first or be separated from it by "--".
+    #[clap(long, value_name = "REPLACE", num_args = 1.., conflicts_with_all(["replace_script"]))]
+    pub replace_one_of: Vec<String>,
+    /// Only search files matching `<GLOB>`, or exclude them if the glob starts with "!". Can be
+    /// given multiple times.
+    #[clap(long, short, value_name = "GLOB")]
+    pub glob: Vec<String>,
+    /// Only search files of the type `<TYPE>`, such as "rust" or "py". The types are the same as
+    /// ripgrep's, and are listed by '--type-list'. Can be given multiple times.
+    #[clap(long = "type", short = 't', value_name = "TYPE", conflicts_with_all(["backend"]))]
+    pub file_type: Vec<String>,
+    /// List the file types that can be used with '--type' and the globs of each type, and exit.
+    #[clap(long)]
+    pub type_list: bool,
+    /// Don't search paths matching `<GLOB>`, even if they're given in `<PATHS>`. Globs without a
+    /// "/" also match the file name. Can be given multiple times.
+    #[clap(long, value_name = "GLOB", conflicts_with_all(["backend", "matches_from"]))]
+    pub exclude: Vec<String>,
+    /// Search hidden files and directories, whose names start with ".". The `.git` directory of
+    /// a repository is never searched.
+    #[clap(long)]
+    pub hidden: bool,
+    /// Don't respect any ignore files, such as `.gitignore`, `.ignore`, `.rgignore`, and
+    /// `.repatchignore` files, and the ignore file of the config file. Hidden files are still
+    /// skipped unless '--hidden' is also given.
+    #[clap(long)]
+    pub no_ignore: bool,
+    /// Don't respect git's ignore files (`.gitignore`, `.git/info/exclude`, and the global ignore
+    /// file), but still respect the other ignore files.
+    #[clap(long)]
+    pub no_ignore_vcs: bool,
+    /// Don't respect git's global ignore file, which is set by `core.excludesFile` in the git
+    /// config (by default "$XDG_CONFIG_HOME/git/ignore").
+    #[clap(long)]
+    pub no_ignore_global: bool,
+    /// Don't respect the `.git/info/exclude` file of each repository.
+    #[clap(long)]
+    pub no_ignore_exclude: bool,
+    /// Run again with the same arguments as the last run in the history. Any other arguments are
+    /// added to the end.
+    #[clap(long)]
+    pub repeat_last: bool,
+    /// List the previous runs in the history and exit. The history is only recorded if it's
+    /// enabled in the config file.
+    #[clap(long)]
+    pub history: bool,
     /// Case-insensitive search.
     #[clap(long, short)]
     pub ignore_case: bool,
+    /// Treat `<FIND>` as a literal string instead of a regex, and use `<REPLACE>` as it is, without
+    /// expanding capture groups or variables such as `$1` and `${FILE}`.
+    #[clap(long, short = 'F', conflicts_with_all(["rules", "convert_eol"]))]
+    pub fixed_strings: bool,
+    /// The regex engine that `<FIND>` is matched with. The fancy engine (PCRE2) also supports
+    /// look-around such as "foo(?!_bar)" and backreferences such as "(\w+) \1", but is usually
+    /// slower. With "auto", the fancy engine is only used if the default engine can't compile
+    /// `<FIND>`.
+    #[clap(
+        long,
+        value_name = "ENGINE",
+        default_value = "default",
+        conflicts_with_all(["rules", "normalize", "ensure_header", "convert_eol"])
+    )]
+    pub engine: Engine,
     /// Ignore filesystem-related errors while searching ("no such file", "permission denied", etc).
     #[clap(long)]
     pub ignore_errors: bool,
     /// Generate diffs with `<N>` lines of context; also accepts "infinite".
     #[clap(long, default_value_t, value_name = "N")]
     pub context: Context,
+    /// Write the patches of `--output-patch` and `--sandbox` with `<N>` lines of context, instead
+    /// of the lines of context that are shown; also accepts "infinite". More context lets a patch
+    /// be applied more reliably to files that have changed since it was written.
+    #[clap(long, default_value = "3", value_name = "N")]
+    pub export_context: Context,
+    /// When the hunks of nearby matches are combined. With "never", each matching line has its own
+    /// hunk, and context lines that are shared with another hunk are shown in both.
+    #[clap(long, default_value = "exact", value_name = "MODE")]
+    pub hunk_merge: HunkMerge,
+    /// Lines wider than `<N>` columns are shortened when shown, but are still patched in full. Use
+    /// 0 to never shorten lines.
+    #[clap(long, default_value_t = 1000, value_name = "N")]
+    pub max_line_len: usize,
+    /// Unchanged lines more than `<N>` lines away from a change are folded when shown, but are
+    /// still patched. Use 0 to never fold lines.
+    #[clap(long, default_value_t = 10, value_name = "N")]
+    pub fold_context: usize,
+    /// Show each hunk by piping it as a unified diff to the shell command `<CMD>`, such as "delta"
+    /// or "difft", instead of showing the diff ourselves. The prompt is still shown below the
+    /// command's output. If the command fails, the hunk is shown as usual.
+    #[clap(long, value_name = "CMD", conflicts_with_all(["check", "serve"]))]
+    pub diff_viewer: Option<String>,
+    /// Show the author, commit, and age of the most recent change to each hunk's lines in the hunk
+    /// header, as reported by `git blame`.
+    #[clap(long)]
+    pub blame: bool,
     /// Show the changes without modifying any files.
     ///
     /// This does not generate valid patch files and is meant only for terminal output. ANSI escape
     /// sequences are replaced in the generated patches.
     #[clap(long, conflicts_with_all(["apply"]))]
     pub show: bool,
+    /// Print the location of each match without modifying any files, and exit with a non-zero
+    /// exit code if there were any matches.
+    #[clap(long, conflicts_with_all(["show", "apply", "confirm_files"]))]
+    pub check: bool,
+    /// Run as a pre-commit hook. The paths are read from stdin if none are given, and the changes
+    /// are shown without colors (unless "FORCE_COLOR" is set). Exits with a non-zero exit code if
+    /// any file would be changed. With '--apply', the files are fixed and the modified files are
+    /// listed.
+    #[clap(long, conflicts_with_all(["check", "menu", "serve", "confirm_files", "sandbox"]))]
+    pub hook: bool,
+    /// With '--show', exit with a non-zero exit code if any file would be changed, so that it can
+    /// be used as a pre-commit hook. '--check' always does this.
+    #[clap(long)]
+    pub dry_run_exit_code: bool,
+    /// The format of the output.
+    ///
+    /// With "github", GitHub Actions annotations are printed for each match when using `--check`,
+    /// and for each applied change otherwise. With "sarif", a SARIF log describing each match and
+    /// its replacement is printed; this can only be used with `--check`. With "json", a summary of
+    /// the files and hunks that would be changed is printed; this can only be used with `--show`.
+    /// With "word-diff", each hunk is shown with its removed and added words marked as "[-old-]"
+    /// and "{+new+}"; this can only be used with `--show`.
+    #[clap(long, default_value = "human", value_name = "FORMAT")]
+    pub format: OutputFormat,
+    /// Print one JSON object per line to stdout instead of the human-readable output, similar to
+    /// ripgrep's `--json`. This can only be used with `--show` or `--apply`.
+    ///
+    /// Each object has a "type". A "hunk" object is printed for each hunk with its "path", "id",
+    /// "original" and "replaced" lines, the ids of the "matches" that it replaces, and whether it
+    /// was "applied". A match's id stays the same across runs as long as the file's path and the
+    /// matched text and its replacement don't change. The lines have a 1-indexed
+    /// "start" and an "end" (which isn't included), and either the "text" of the lines or, if
+    /// they aren't valid UTF-8, their base64-encoded "bytes". A "file" object is printed after
+    /// each file's hunks, and a "summary" object is printed at the end.
+    #[clap(
+        long,
+        conflicts_with_all([
+            "format", "check", "serve", "serve_preview", "menu", "confirm_files", "pick", "hook",
+            "paths_from_stdin", "search_only", "batch_size", "write_plan", "sandbox",
+        ])
+    )]
+    pub json: bool,
+    /// Write only `<WHAT>` to stdout, and write everything else, such as prompts, counts, and
+    /// summaries, to stderr. With "diff", the diff of the chosen hunks is written like
+    /// `--output-patch -` without modifying any files, so that it can be piped to `git apply`.
+    /// With "json", the objects of `--json` are written. With "none", nothing is written to
+    /// stdout.
+    #[clap(
+        long,
+        value_name = "WHAT",
+        conflicts_with_all([
+            "json", "output_patch", "format", "serve", "serve_preview", "menu", "write_plan",
+            "sandbox",
+        ])
+    )]
+    pub stdout: Option<StdoutContent>,
+
+    /// How to print errors and warnings.
+    ///
+    /// Errors and warnings are always printed to stderr. With "json", each is printed as a json
+    /// object on its own line, with a "level" of "error" or "warning" and a "message".
+    #[clap(long, default_value = "human", value_name = "FORMAT")]
+    pub errors: ErrorFormat,
+    /// After searching, print each distinct matched text and its number of occurrences.
+    #[clap(long)]
+    pub summary: bool,
+    /// After searching, print an estimate of the size of the changes (the hunks, the lines added
+    /// and removed, and the file with the most changed lines) before prompting. The estimate
+    /// assumes that each matching line is replaced by one line.
+    #[clap(long)]
+    pub estimate: bool,
+    /// Print more details in the summary at the end of a run, which counts the files examined and
+    /// modified, the hunks applied, skipped, and edited, and the matches replaced. The details
+    /// are how long the search and the patching took and how many bytes were written.
+    #[clap(long)]
+    pub stats: bool,
+    /// How file paths are displayed. By default paths are displayed as they were found, which
+    /// depends on how the `<PATHS>` arguments were written.
+    #[clap(long, value_name = "MODE")]
+    pub path_display: Option<PathDisplay>,
+    /// Search the whole repository that contains the current directory instead of `<PATHS>`,
+    /// where the repository's root is the closest parent directory with a ".git" entry. Paths are
+    /// displayed relative to the root unless `--path-display` is given.
+    #[clap(
+        long,
+        conflicts_with_all(["paths", "matches_from", "paths_from_stdin", "retry_failed", "hook"])
+    )]
+    pub repo_root: bool,
     /// Apply and write all changes automatically without any user input or confirmation.
     #[clap(long)]
     pub apply: bool,
+    /// With `--apply`, append the changes to `<FILE>` as a patch as each file is written, so that
+    /// there's a record of exactly what was changed. The patch can be reverted with `patch -R -p1`.
+    #[clap(long, value_name = "FILE", requires = "apply")]
+    pub apply_log: Option<PathBuf>,
+    /// The lines of context around each change in the `--apply-log` patch; also accepts
+    /// "infinite".
+    #[clap(long, default_value_t, value_name = "N", requires = "apply_log")]
+    pub apply_log_context: Context,
+    /// Write the paths in patches relative to `<DIR>`, instead of relative to the root of each
+    /// file's git repository (or the current directory if not in a repository). All files must be
+    /// within `<DIR>`. Useful when searching several repositories, where files in different
+    /// repositories could otherwise have the same path.
+    #[clap(long, value_name = "DIR", conflicts_with_all(["check", "serve"]))]
+    pub patch_root: Option<PathBuf>,
+    /// Apply the patches in files matching `<GLOB>` without asking. Can be given multiple times.
+    #[clap(
+        long,
+        value_name = "GLOB",
+        value_parser = parse_glob,
+        conflicts_with_all(["apply", "show", "check"])
+    )]
+    pub auto_yes: Vec<globset::Glob>,
+    /// Skip the patches in files matching `<GLOB>` without asking. If a file matches both
+    /// `--auto-yes` and `--auto-no`, the patches are skipped. Can be given multiple times.
+    #[clap(
+        long,
+        value_name = "GLOB",
+        value_parser = parse_glob,
+        conflicts_with_all(["apply", "show", "check"])
+    )]
+    pub auto_no: Vec<globset::Glob>,
+    /// Apply the hunks with the IDs `<IDS>`, such as "1,4,9-12", and skip all other hunks without
+    /// asking. Each hunk's ID is shown after its "@@" header, and is the same between runs if the
+    /// files haven't changed.
+    #[clap(
+        long,
+        value_name = "IDS",
+        value_delimiter = ',',
+        value_parser = parse_hunk_ids,
+        conflicts_with_all(["apply", "show", "check", "auto_yes", "auto_no"])
+    )]
+    pub apply_hunks: Vec<std::ops::RangeInclusive<u64>>,
+    /// With '--show', only show the hunks with the IDs `<IDS>`, such as "1,4,9-12". Hunks are
+    /// numbered across all files, so a large dry run can be shown in parts.
+    #[clap(
+        long,
+        value_name = "IDS",
+        value_delimiter = ',',
+        value_parser = parse_hunk_ids,
+        requires = "show"
+    )]
+    pub only: Vec<std::ops::RangeInclusive<u64>>,
+    /// Pause after every `<N>` files and ask whether to continue, stop, or apply the patches in
+    /// the remaining files without asking.
+    #[clap(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with_all(["apply", "show", "check"])
+    )]
+    pub batch_size: Option<u64>,
+    /// Whether to prompt for each hunk, or once for each file with all of the file's changes.
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = InteractiveMode::Hunk,
+        value_name = "MODE",
+        conflicts_with_all(["apply", "show", "check"])
+    )]
+    pub interactive: InteractiveMode,
+    /// Add the answers "g" and "G" to the patch prompt, which apply or skip the hunk and every
+    /// later hunk that is similar to it. Hunks are similar if they replace the same matched text
+    /// with the same replacement, and their lines only differ by whitespace and numbers.
+    #[clap(long, conflicts_with_all(["apply", "show", "check"]))]
+    pub group_similar: bool,
+    /// If a patch prompt isn't answered within `<DURATION>` (for example "30s" or "5m"), take the
+    /// answer from `--prompt-default`. The number of prompts that timed out is printed at the end.
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub prompt_timeout: Option<std::time::Duration>,
+    /// The answer taken when a patch prompt times out.
+    #[clap(
+        long,
+        default_value = "no",
+        value_name = "ANSWER",
+        requires = "prompt_timeout"
+    )]
+    pub prompt_default: PromptDefault,
+    /// Only replace matches that start within the columns `<RANGE>`, such as "1-4" or "5". Columns
+    /// are counted in bytes, starting from 1.
+    #[clap(long, value_name = "RANGE", value_parser = parse_columns)]
+    pub columns: Option<std::ops::RangeInclusive<u64>>,
+    /// Only replace matches that aren't part of a longer identifier, which is stricter than "\b"
+    /// for languages where characters like "-" or "$" can be part of an identifier. The identifier
+    /// characters depend on the file's extension, and can be set in the `[identifiers]` table of
+    /// the config file.
+    #[clap(long)]
+    pub identifier: bool,
+    /// Only replace matches that start within the bytes `<START>..<END>` of the file, where the
+    /// offsets start from 0 and the end is exclusive. Can be given multiple times, and can only be
+    /// used when searching a single file.
+    #[clap(
+        long,
+        value_name = "START..END",
+        value_parser = parse_byte_range,
+        conflicts_with_all(["matches_from", "paths_from_stdin", "cache"])
+    )]
+    pub byte_range: Vec<std::ops::Range<u64>>,
+    /// Only replace matches on the lines between a line containing `<BEGIN>` and the next line
+    /// containing `<END>`, such as "BEGIN GENERATED" and "END GENERATED". The marker lines
+    /// themselves aren't replaced, and a block without an end line is skipped.
+    #[clap(long, num_args = 2, value_names = ["BEGIN", "END"])]
+    pub between: Vec<String>,
+    /// Skip the matching lines that also match `<REGEX>`, such as lines containing "#\[allow\(".
+    /// They're left out of the search, so they aren't counted, shown, or replaced.
+    #[clap(
+        long,
+        value_name = "REGEX",
+        conflicts_with_all(["matches_from", "ensure_header"])
+    )]
+    pub exclude_lines: Option<String>,
+    /// Only replace the first match in each file.
+    #[clap(long, conflicts_with_all(["last_only"]))]
+    pub first_only: bool,
+    /// Only replace the last match in each file.
+    #[clap(long)]
+    pub last_only: bool,
+    /// Stop replacing after the first `<N>` matches in each file. The other matches are skipped.
+    #[clap(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with_all([
+            "first_only", "last_only", "multiline", "ensure_header", "convert_eol",
+            "paths_from_stdin",
+        ])
+    )]
+    pub max_count: Option<u64>,
+    /// Only replace the `<K>`th match (starting from 1) on each line, or in each file with
+    /// `--nth-per-file`. The other matches are skipped.
+    #[clap(
+        long,
+        value_name = "K",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with_all([
+            "first_only", "last_only", "multiline", "ensure_header", "convert_eol",
+            "paths_from_stdin",
+        ])
+    )]
+    pub nth: Option<u64>,
+    /// Count the matches for `--nth` across the whole file instead of on each line.
+    #[clap(long, requires = "nth")]
+    pub nth_per_file: bool,
+    /// Match text regardless of whether it's in a composed or decomposed Unicode form. Each
+    /// replacement is written in the form of the text that it replaces (or of the rest of its line
+    /// if the matched text doesn't have any composable characters), and otherwise in the
+    /// normalization form `<FORM>`.
+    #[clap(long, value_name = "FORM")]
+    pub normalize: Option<NormalizationForm>,
+    /// Allow the pattern to match across lines, like ripgrep's `--multiline`. "\n" in the pattern
+    /// matches a line ending, "^" and "$" match at the start and end of each line, and "(?s)" lets
+    /// "." match line endings. All of the lines of a match are replaced together in one hunk.
+    #[clap(
+        long,
+        conflicts_with_all([
+            "line_terminator", "backend", "matches_from", "cache", "columns", "byte_range",
+            "identifier", "first_only", "last_only", "between", "ensure_header",
+        ])
+    )]
+    pub multiline: bool,
+    /// Use `<BYTE>` as the line terminator instead of "\n". Escape sequences such as "\r" and
+    /// "\x1e" are supported, but the byte must be ASCII.
+    #[clap(long, value_name = "BYTE", value_parser = parse_line_terminator)]
+    pub line_terminator: Option<u8>,
+    /// Remove trailing spaces and tabs from the lines that a replacement changes. Other lines are
+    /// left as they are, and the removed whitespace is shown in each hunk.
+    #[clap(long)]
+    pub fix_whitespace: bool,
+    /// With `--fix-whitespace`, also convert the line endings of the changed lines to `<EOL>`,
+    /// overriding the "eol" attribute of a file.
+    #[clap(
+        long,
+        value_name = "EOL",
+        requires = "fix_whitespace",
+        conflicts_with_all(["line_terminator"])
+    )]
+    pub fix_eol: Option<LineEnding>,
+    /// Stop searching and exit with an error if there are more than `<N>` matching lines in total.
+    #[clap(long, value_name = "N")]
+    pub max_total_matches: Option<u64>,
+    /// Stop searching once `<N>` files with matches have been found, without searching the rest of
+    /// the files. Useful for quickly trying a pattern on a large tree.
+    #[clap(
+        long,
+        value_name = "N",
+        conflicts_with_all(["matches_from", "paths_from_stdin", "backend", "ensure_header"])
+    )]
+    pub limit_files: Option<u64>,
+    /// Don't check files for a UTF-16 byte-order mark. By default, UTF-16 files with a byte-order
+    /// mark are searched as if they were UTF-8.
+    #[clap(long)]
+    pub no_bom_sniffing: bool,
+    /// What to do with files that contain NUL bytes. Like git, a file's `.gitattributes` can mark
+    /// it as text or binary regardless of its contents, and its `eol` attribute sets the line
+    /// endings of replaced lines. Also accepts ripgrep's `--binary-detection` names "none",
+    /// "quit", and "convert".
+    #[clap(
+        long,
+        visible_alias = "binary-detection",
+        default_value = "search",
+        value_name = "MODE"
+    )]
+    pub binary: BinaryMode,
+    /// Search files with binary data as if they were text, which is the default ('--binary
+    /// search'). To patch the text of a file with a few NUL bytes without touching the binary
+    /// data, use '--binary convert' instead.
+    #[clap(long, short = 'a', conflicts_with_all(["binary"]))]
+    pub text: bool,
+    /// Skip files that look like generated or vendored code, since automated rewrites of them are
+    /// usually wrong. A file is skipped if one of its first lines contains "@generated" or "DO NOT
+    /// EDIT", or if its `.gitattributes` set `linguist-generated` or `linguist-vendored`.
+    #[clap(long)]
+    pub no_generated: bool,
+    /// The largest buffer to use when searching a file, such as "64M". Searching a file with a
+    /// line longer than this fails.
+    #[clap(long, value_name = "SIZE", value_parser = parse_byte_size)]
+    pub max_buffer_size: Option<u64>,
+    /// Memory-map files that are at least `<SIZE>` large, such as "64M", rather than reading them
+    /// in chunks.
+    #[clap(
+        long,
+        default_value = "64M",
+        value_name = "SIZE",
+        value_parser = parse_byte_size
+    )]
+    pub mmap_threshold: u64,
+    /// Print a warning for each file that's at least `<SIZE>` large, such as "256M", since it may
+    /// be slow to search and patch. Use "0" to never warn.
+    #[clap(
+        long,
+        default_value = "256M",
+        value_name = "SIZE",
+        value_parser = parse_byte_size
+    )]
+    pub warn_file_size: u64,
+    /// Skip files that are at least `<SIZE>` large, such as "1G", without searching them. A
+    /// warning is printed for each skipped file.
+    #[clap(long, value_name = "SIZE", value_parser = parse_byte_size)]
+    pub skip_file_size: Option<u64>,
+    /// Skip a file and report it as an error if searching it takes longer than `<DURATION>`, such
+    /// as "10s". A warning is shown while a search is taking longer than this, so that a slow
+    /// pattern on a huge file doesn't look like a hang.
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub file_timeout: Option<std::time::Duration>,
+    /// The number of threads to search files with. By default, this is chosen based on the number
+    /// of CPUs. With '--limit-files', files are always searched with one thread so that the same
+    /// files are found each time.
+    #[clap(long, value_name = "N")]
+    pub threads: Option<usize>,
+    /// Only search the files that couldn't be searched in the last run, such as files that
+    /// couldn't be read or that took longer than '--file-timeout'. Combine with '--ignore-errors'
+    /// to patch what can be read and retry the rest later.
+    #[clap(long, conflicts_with_all(["paths", "matches_from", "paths_from_stdin"]))]
+    pub retry_failed: bool,
+    /// Sync each new file and its directory to disk before and after it replaces the original
+    /// file, so that a crash can't leave a file truncated or missing. This is slower.
+    #[clap(long)]
+    pub fsync: bool,
+    /// Keep the new files in memory and only write them once every hunk has been answered, so
+    /// that the files are either all changed or not changed at all. Nothing is written if you quit,
+    /// if there's an error, or if any of the files were modified by another program in the
+    /// meantime.
+    #[clap(
+        long,
+        conflicts_with_all([
+            "show",
+            "check",
+            "serve",
+            "serve_preview",
+            "menu",
+            "paths_from_stdin",
+            "write_plan",
+            "output_patch",
+            "sandbox",
+            "verify_cmd",
+            "post_file_cmd",
+            "apply_log"
+        ])
+    )]
+    pub atomic: bool,
+    /// Write each new file in `<DIR>` before it replaces the original file, instead of in the
+    /// original file's directory. If `<DIR>` is on a different filesystem, the new file is copied
+    /// into place.
+    #[clap(long, value_name = "DIR")]
+    pub temp_dir: Option<PathBuf>,
+    /// Set the permissions of each patched file to `<MODE>` instead of keeping the original
+    /// file's permissions. `<MODE>` is an octal mode such as "644", or symbolic changes such as
+    /// "go-w,u+x" that are applied to the original permissions. The umask isn't applied, and
+    /// files written with '--unwritable sudo' keep their permissions.
+    #[clap(long, value_name = "MODE", value_parser = parse_mode_change)]
+    pub chmod: Option<crate::util::ModeChange>,
+    /// What to do with files that the current user can't write, such as files owned by root.
+    /// Files written through `sudo` can't be restored by `repatch undo`.
+    #[clap(long, default_value = "fail", value_name = "MODE")]
+    pub unwritable: UnwritableMode,
+    /// Print the time spent walking the directory tree, searching, diffing, waiting at prompts,
+    /// and writing files to stderr at the end of the run.
+    #[clap(long)]
+    pub profile: bool,
+    /// Don't warn when replacing the matches again would change the replaced text again (for
+    /// example "foo" -> "foofoo"), which means that re-running the same command would compound the
+    /// changes.
+    #[clap(long)]
+    pub no_rescan_guard: bool,
+    /// Run even if another repatch process that may modify files is running on an overlapping
+    /// path. By default this is an error, since the two runs could overwrite each other's changes.
+    #[clap(long)]
+    pub force: bool,
+    /// Replace the matches twice in memory and warn about each file where the second replacement
+    /// would change the file again (for example "a" -> "aa"), without modifying any files. Exits
+    /// with a non-zero exit code if any file would change again.
+    #[clap(
+        long,
+        conflicts_with_all([
+            "show", "check", "apply", "serve", "menu", "ensure_header", "first_only", "last_only",
+        ])
+    )]
+    pub idempotent_check: bool,
+    /// If nothing matches, search again with some restrictions removed and show the near-misses,
+    /// such as case-only differences, matches in ignored or binary files, and matches that the
+    /// replacement wouldn't change.
+    #[clap(long, conflicts_with_all(["matches_from", "backend"]))]
+    pub explain_no_matches: bool,
+    /// Print how `<REPLACE>` is parsed, as literal text, capture group references, and variables,
+    /// and exit without searching.
+    #[clap(
+        long,
+        conflicts_with_all([
+            "fixed_strings", "replace_script", "rules", "ensure_header", "convert_eol",
+            "search_only",
+        ])
+    )]
+    pub explain_replacement: bool,
+    /// Don't page the output of `--show` and `--check`, or the hunks that are taller than the
+    /// terminal when prompting. By default the output is paged with `$PAGER` (or a built-in pager
+    /// if it isn't set) when stdout is a terminal, and hunks are paged with `$REPATCH_PAGER` (or
+    /// `$PAGER`, or "less -R" if neither is set).
+    #[clap(long)]
+    pub no_pager: bool,
+    /// Only search files that have uncommitted changes, as reported by `git status`.
+    #[clap(long, conflicts_with_all(["only_clean"]))]
+    pub only_dirty: bool,
+    /// Only search files that don't have uncommitted changes, as reported by `git status`.
+    #[clap(long)]
+    pub only_clean: bool,
+    /// Only replace matches in a file with the extension `<A>` if the file with the same name and
+    /// the extension `<B>` also has matches, and vice versa, such as "h:c" for C headers and
+    /// sources. Both files of a pair are then applied or skipped together with one prompt, or
+    /// reviewed hunk by hunk. Can be given multiple times.
+    #[clap(long, value_name = "A:B", value_parser = parse_extension_pair)]
+    pub paired: Vec<(String, String)>,
+    /// List the matched files before showing any hunks and choose which files to include. Files
+    /// can be chosen by number or glob, or by deleting them from the list in your editor.
+    #[clap(long)]
+    pub confirm_files: bool,
+    /// Group the matched files by directory: show the number of matches in each directory, go
+    /// through the files one directory at a time, and allow selecting whole directories with
+    /// '--confirm-files'.
+    #[clap(long)]
+    pub group_by_dir: bool,
+    /// Go through the matched files in natural order, where numbers are compared by their value
+    /// (so "file2" comes before "file10") and letters are compared case-insensitively.
+    #[clap(long)]
+    pub natural_sort: bool,
+    /// Choose which of the matched files to include using a fuzzy finder before showing any hunks.
+    #[clap(long, conflicts_with_all(["check", "confirm_files", "serve", "plain"]))]
+    pub pick: bool,
+    /// Don't use colors or other styling, show "ADDED:" and "REMOVED:" instead of "+" and "-" in
+    /// patches, and don't use full-screen prompts. This is useful with screen readers and dumb
+    /// terminals.
+    #[clap(long)]
+    pub plain: bool,
+    /// Read the answers to prompts as plain lines from stdin, without the built-in line editor.
+    /// The line editor is only used when stdin and stdout are terminals, and never with '--plain'.
+    #[clap(long)]
+    pub no_readline: bool,
+    /// Make the interactive output deterministic for end-to-end tests: the answers to prompts are
+    /// read as plain lines from stdin and echoed, the terminal is 80x24, nothing is styled or
+    /// paged, and times that depend on the clock aren't shown.
+    #[clap(long, hide = true)]
+    pub test_mode: bool,
+    /// Pipe the replacement text of each hunk to the shell command `<CMD>` before it's shown. If
+    /// the command exits with 0, its output is used as the new replacement text. If it exits with
+    /// 1, the hunk is skipped. The environment variables REPATCH_PATH, REPATCH_LINE, and
+    /// REPATCH_ORIGINAL are set to the file path, the hunk's line number, and the path of a file
+    /// containing the original text of the hunk.
+    #[clap(long, value_name = "CMD", conflicts_with_all(["check", "serve"]))]
+    pub hunk_filter: Option<String>,
+    /// Run the shell command `<CMD>` before each file is patched, where "{}" is replaced with the
+    /// file path (or the path is added to the end if there's no "{}"). The file is searched again
+    /// if the command modifies it.
+    #[clap(long, value_name = "CMD", conflicts_with_all(["show", "check", "serve"]))]
+    pub pre_file_cmd: Option<String>,
+    /// Run the shell command `<CMD>` after each file is modified, where "{}" is replaced with the
+    /// file path (or the path is added to the end if there's no "{}").
+    #[clap(long, value_name = "CMD", conflicts_with_all(["show", "check", "serve"]))]
+    pub post_file_cmd: Option<String>,
+    /// Run the shell command `<CMD>` after each file is modified. If the command fails, the
+    /// original contents of the file are restored and re:patch exits.
+    #[clap(long, value_name = "CMD", conflicts_with_all(["show", "check", "serve"]))]
+    pub verify_cmd: Option<String>,
+    /// Run the `--verify-cmd` command only once after all files are modified, and restore all of
+    /// the modified files if it fails.
+    #[clap(long, requires = "verify_cmd")]
+    pub verify_once: bool,
+    /// Start with a menu for showing the status, patching, showing the diff, and selecting files,
+    /// rather than patching each file in order.
+    #[clap(long, conflicts_with_all(["show", "check", "confirm_files", "serve"]))]
+    pub menu: bool,
+    /// Write the answer to each hunk to `<FILE>`, so that it can be used with
+    /// `--replay-decisions`. Hunks that were edited are not recorded. Each answer is preceded by a
+    /// comment with the ids of the hunk's matches, which are the same as in the `--json` output.
+    #[clap(long, value_name = "FILE", conflicts_with_all(["show", "check", "serve"]))]
+    pub export_decisions: Option<PathBuf>,
+    /// Write the hunks that you choose to apply to the plan file `<FILE>` instead of modifying any
+    /// files, so that they can be applied later with `repatch execute <FILE>`. This is what
+    /// `repatch plan <FILE> ...` does.
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all(["show", "check", "serve", "paths_from_stdin", "verify_cmd"])
+    )]
+    pub write_plan: Option<PathBuf>,
+    /// Write the diff of the hunks that you choose to apply to `<FILE>` instead of modifying any
+    /// files, or to stdout if `<FILE>` is "-". The diff can be applied with `git apply` or
+    /// `patch -p1`.
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all([
+            "show",
+            "check",
+            "serve",
+            "write_plan",
+            "sandbox",
+            "verify_cmd",
+            "post_file_cmd"
+        ])
+    )]
+    pub output_patch: Option<PathBuf>,
+    /// Write a report of the changes to `<FILE>`, where `<FORMAT>` is "md" (Markdown) or "html".
+    ///
+    /// The report has a collapsible section for each file with its changed hunks, so that it can
+    /// be attached to a ticket or a document describing the change. With `--show` it has the
+    /// changes that would be made, and otherwise the changes that were applied.
+    #[clap(
+        long,
+        num_args = 2,
+        value_names = ["FORMAT", "FILE"],
+        conflicts_with_all(["check", "serve", "serve_preview", "search_only"])
+    )]
+    pub report: Vec<String>,
+    /// Copy the files with matches into `<DIR>` and apply all changes to the copies rather than the
+    /// original files, so that the complete effect can be inspected. The copies keep their paths
+    /// relative to the repository root, and the diff of all changes is written to
+    /// `<DIR>/repatch.diff`. Implies `--apply`.
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all(["show", "check", "serve", "menu", "paths_from_stdin", "write_plan"])
+    )]
+    pub sandbox: Option<PathBuf>,
+    /// Answer hunks using the decisions in `<FILE>`, which was written by `--export-decisions`.
+    /// Decisions are matched by the file path and the hunk contents, and any other hunks are
+    /// prompted for as usual.
+    #[clap(long, value_name = "FILE", conflicts_with_all(["show", "check", "serve"]))]
+    pub replay_decisions: Option<PathBuf>,
+    /// Remember the hunks that you answer 'no' to, so that later runs can skip them with
+    /// '--skip-previously-rejected'. They're saved in "$XDG_STATE_HOME/repatch/rejected".
+    #[clap(long, conflicts_with_all(["show", "check", "serve"]))]
+    pub remember_rejected: bool,
+    /// Answer 'no' to hunks that were rejected in an earlier run with '--remember-rejected'.
+    /// Hunks are matched by the file's absolute path and the hunk contents, so hunks that only
+    /// moved within the file are still skipped.
+    #[clap(long, conflicts_with_all(["show", "check", "serve"]))]
+    pub skip_previously_rejected: bool,
+    /// Record each answered hunk and finished file in the session file `<FILE>` as you go, so that
+    /// you can quit and later run the same command again to continue from the first hunk that
+    /// wasn't answered. Finished files are skipped unless they changed since. The file is kept
+    /// after the run, so it's also a record of what was decided.
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all(["show", "check", "serve", "serve_preview", "apply", "menu"])
+    )]
+    pub session: Option<PathBuf>,
+    /// Cache the search results in the directory ".repatch-cache", so that later runs with the same
+    /// `<FIND>` don't need to search files that haven't changed.
+    #[clap(long, conflicts_with_all(["backend", "matches_from"]))]
+    pub cache: bool,
+    /// The search backend. The git-grep backend only searches files tracked by git, and `<FIND>`
+    /// is interpreted as a Perl-compatible regex when searching.
+    #[clap(
+        long,
+        value_name = "BACKEND",
+        default_value = "builtin",
+        conflicts_with_all(["glob", "line_terminator"])
+    )]
+    pub backend: Backend,
+    /// Read the matching lines from stdin in the format `<FORMAT>` instead of searching `<PATHS>`.
+    /// `<FIND>` and `<REPLACE>` are still used to replace text, but only within those lines. If
+    /// stdin isn't a terminal, prompts are read from the terminal instead.
+    #[clap(
+        long,
+        value_name = "FORMAT",
+        conflicts_with_all(["paths", "glob", "backend", "serve"])
+    )]
+    pub matches_from: Option<MatchSource>,
+    /// Read paths from stdin, one per line, and process each file as soon as its path is read
+    /// instead of searching `<PATHS>`. This keeps running until stdin is closed, so it can be used
+    /// with programs that print paths as they change, such as `fd --watch` or a build system.
+    /// Requires '--apply' or '--show'.
+    #[clap(
+        long,
+        conflicts_with_all([
+            "paths", "matches_from", "backend", "serve", "menu", "confirm_files", "pick", "check",
+            "cache",
+        ])
+    )]
+    pub paths_from_stdin: bool,
+    /// Instead of prompting, read requests from stdin and write responses to stdout using a
+    /// line-delimited JSON protocol. This is meant for editor extensions and other programs.
+    #[clap(long, conflicts_with_all(["show", "apply", "check", "confirm_files"]))]
+    pub serve: bool,
+    /// Instead of prompting, serve a read-only HTML page of the changes over HTTP, so that they
+    /// can be reviewed in a browser before they're applied. Nothing is modified. This runs until
+    /// it's interrupted.
+    #[clap(
+        long,
+        conflicts_with_all([
+            "show", "apply", "check", "serve", "menu", "confirm_files", "pick", "hook",
+            "paths_from_stdin", "search_only", "write_plan", "output_patch", "sandbox",
+        ])
+    )]
+    pub serve_preview: bool,
+    /// The port to serve the preview on, for '--serve-preview'.
+    #[clap(long, value_name = "PORT", default_value_t = 8000)]
+    pub preview_port: u16,
+    /// The address to serve the preview on, for '--serve-preview'. Use "0.0.0.0" to allow other
+    /// machines to connect.
+    #[clap(long, value_name = "HOST", default_value = "127.0.0.1")]
+    pub preview_host: String,
+}
+
+/// Search for a regex and print the matching lines, without replacing anything.
+#[derive(Debug, Parser)]
+#[command(name = "re:patch scan", max_term_width = 120, help_expected = true)]
+pub struct ScanArgs {
+    /// Regex to search for.
+    pub find: String,
+    /// Paths (files and/or directories) to search recursively.
+    #[clap(required = true)]
+    pub paths: Vec<PathBuf>,
+    /// Only search files matching `<GLOB>`, or exclude them if the glob starts with "!". Can be
+    /// given multiple times.
+    #[clap(long, short, value_name = "GLOB")]
+    pub glob: Vec<String>,
+    /// Only search files of the type `<TYPE>`, such as "rust" or "py". Can be given multiple
+    /// times.
+    #[clap(long = "type", short = 't', value_name = "TYPE")]
+    pub file_type: Vec<String>,
+    /// Don't search paths matching `<GLOB>`, even if they're given in `<PATHS>`. Can be given
+    /// multiple times.
+    #[clap(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+    /// Case-insensitive search.
+    #[clap(long, short)]
+    pub ignore_case: bool,
+    /// Ignore filesystem-related errors while searching ("no such file", "permission denied", etc).
+    #[clap(long)]
+    pub ignore_errors: bool,
+    /// Print each distinct matched text and its number of occurrences after the matching lines.
+    #[clap(long)]
+    pub summary: bool,
+    /// Print only the text of each match, one per line, rather than the matching lines. With
+    /// `--summary`, only the distinct texts and their numbers of occurrences are printed.
+    #[clap(long, short = 'o')]
+    pub only_matching: bool,
+    /// Print each distinct matched text only once, in the order that they're first found.
+    #[clap(long, requires = "only_matching", conflicts_with_all(["summary"]))]
+    pub unique: bool,
+    /// Stop searching and exit with an error if there are more than `<N>` matching lines in total.
+    #[clap(long, value_name = "N")]
+    pub max_total_matches: Option<u64>,
+    /// Don't page the output. By default the output is paged with `$PAGER` (or a built-in pager if
+    /// it isn't set) when stdout is a terminal.
+    #[clap(long)]
+    pub no_pager: bool,
+}
+
+/// Restore the files that were modified by a recent run to their original contents.
+#[derive(Debug, Parser)]
+#[command(name = "re:patch undo", max_term_width = 120, help_expected = true)]
+pub struct UndoArgs {
+    /// The ID of the run to undo, as shown by '--list'. By default the last run is undone.
+    pub id: Option<String>,
+    /// List the runs that can be undone and exit.
+    #[clap(long, conflicts_with_all(["id", "force"]))]
+    pub list: bool,
+    /// Restore the files even if they were modified after the run.
+    #[clap(long)]
+    pub force: bool,
+}
+
+/// Apply the hunks of a plan file, which was written by `repatch plan`. Files that have changed
+/// since the plan was made are skipped.
+#[derive(Debug, Parser)]
+#[command(name = "re:patch execute", max_term_width = 120, help_expected = true)]
+pub struct ExecuteArgs {
+    /// The plan file.
+    pub plan: PathBuf,
+}
+
+/// Print a shell completion script.
+#[derive(Debug, Parser)]
+#[command(
+    name = "re:patch completions",
+    max_term_width = 120,
+    help_expected = true
+)]
+pub struct CompletionsArgs {
+    /// The shell to generate the script for.
+    pub shell: Shell,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+fn parse_line_terminator(s: &str) -> Result<u8, &'static str> {
+    match crate::parse::escaped_byte(s) {
+        Some(x) if x.is_ascii() => Ok(x),
+        _ => Err("must be a single ASCII byte"),
+    }
+}
+
+fn parse_columns(s: &str) -> Result<std::ops::RangeInclusive<u64>, &'static str> {
+    crate::parse::index_range(s).ok_or("must be a column or a range of columns such as \"1-4\"")
+}
+
+fn parse_byte_range(s: &str) -> Result<std::ops::Range<u64>, &'static str> {
+    crate::parse::byte_range(s).ok_or("must be a range of byte offsets such as \"10..20\"")
+}
+
+fn parse_hunk_ids(s: &str) -> Result<std::ops::RangeInclusive<u64>, &'static str> {
+    crate::parse::index_range(s).ok_or("must be a hunk ID or a range of IDs such as \"9-12\"")
+}
+
+fn parse_glob(s: &str) -> Result<globset::Glob, globset::Error> {
+    globset::Glob::new(s)
+}
+
+fn parse_extension_pair(s: &str) -> Result<(String, String), &'static str> {
+    crate::parse::extension_pair(s).ok_or("must be two different file extensions such as \"h:c\"")
+}
+
+fn parse_byte_size(s: &str) -> Result<u64, &'static str> {
+    crate::parse::byte_size(s).ok_or("must be a size such as \"512K\" or \"64M\"")
+}
+
+fn parse_mode_change(s: &str) -> Result<crate::util::ModeChange, &'static str> {
+    crate::parse::mode_change(s)
+        .ok_or("must be an octal mode such as \"644\", or symbolic changes such as \"go-w,u+x\"")
+}
+
+fn parse_duration(s: &str) -> Result<std::time::Duration, &'static str> {
+    crate::parse::duration(s).ok_or("must be a number with an optional unit (ms, s, m, or h)")
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum InteractiveMode {
+    /// Prompt for each hunk.
+    Hunk,
+    /// Show the combined diff of each file and prompt once for the whole file.
+    Once,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum HunkMerge {
+    /// Combine hunks whose context lines overlap or are next to each other.
+    Aggressive,
+    /// Combine hunks whose context lines overlap.
+    Exact,
+    /// Never combine hunks.
+    Never,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LineEnding {
+    /// "\n" line endings.
+    Lf,
+    /// "\r\n" line endings.
+    Crlf,
+}
+
+impl From<LineEnding> for crate::git::Eol {
+    fn from(x: LineEnding) -> Self {
+        match x {
+            LineEnding::Lf => Self::Lf,
+            LineEnding::Crlf => Self::Crlf,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PromptDefault {
+    /// Apply the patch.
+    Yes,
+    /// Don't apply the patch.
+    No,
+    /// Don't apply the patch or any later patches.
+    Quit,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum UnwritableMode {
+    /// Replace them like any other file, which fails if their directory isn't writable.
+    Fail,
+    /// Write them through `sudo` after their hunks have been reviewed.
+    Sudo,
+    /// Save their changes to a patch file, and print a command to apply it.
+    Patch,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum BinaryMode {
+    /// Search them like any other file.
+    #[value(alias = "none")]
+    Search,
+    /// Skip them, even if they have matches before the first NUL byte. Files that git considers
+    /// binary are also skipped.
+    #[value(alias = "quit")]
+    Skip,
+    /// Search them, but treat the lines that contain NUL bytes as binary data that is never
+    /// matched or replaced, so the text around the binary data can still be patched.
+    Convert,
+}
+
+impl Args {
+    /// The `<REPLACE>` argument, or the content of `--replace-file` once it has been read. This is
+    /// empty with `--ensure-header`.
+    pub fn replacement(&self) -> &str {
+        self.replace.as_deref().unwrap_or_default()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable output.
+    Human,
+    /// GitHub Actions workflow commands.
+    Github,
+    /// SARIF 2.1.0.
+    Sarif,
+    /// A JSON summary of the changes that would be made.
+    Json,
+    /// The changes that would be made as word diffs, like `git diff --word-diff`.
+    WordDiff,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum StdoutContent {
+    /// The diff of the chosen hunks.
+    Diff,
+    /// The objects of `--json`.
+    Json,
+    /// Nothing.
+    None,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorFormat {
+    /// Human-readable messages.
+    Human,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum PathDisplay {
+    /// Relative to the current directory.
+    Relative,
+    /// Absolute paths.
+    Absolute,
+    /// Relative to the root of the git repository, or to the current directory if not in a git
+    /// repository.
+    FromRoot,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// The built-in search, which respects gitignore rules.
+    Builtin,
+    /// `git grep`, which can be faster in large repositories.
+    GitGrep,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Engine {
+    /// The regex engine of ripgrep, which guarantees linear time searches.
+    Default,
+    /// PCRE2, which supports look-around and backreferences.
+    Fancy,
+    /// The default engine, or the fancy engine if the default engine can't compile `<FIND>`.
+    Auto,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum MatchSource {
+    /// The output of `rg --json`.
+    RgJson,
+    /// Lines starting with "PATH:LINE" (or "PATH:LINE:COLUMN"), like the output of `git grep -n`
+    /// or compiler diagnostics. Other lines are ignored.
+    Lines,
+}
+
+#[derive(Copy, Clone, Debug, clap::ValueEnum)]
+pub enum NormalizationForm {
+    /// Canonical composition.
+    Nfc,
+    /// Canonical decomposition.
+    Nfd,
 }
 
 #[derive(Copy, Clone, Debug)]
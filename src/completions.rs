@@ -0,0 +1,300 @@
+//! Shell completion scripts, generated from the command line definitions.
+
+use clap::CommandFactory;
+
+use crate::cli::{Args, CompletionsArgs, ExecuteArgs, ScanArgs, Shell, Subcommand, UndoArgs};
+
+/// An option that can be completed.
+struct Flag {
+    long: String,
+    short: Option<char>,
+    help: String,
+    takes_value: bool,
+    /// The possible values, if the value is from a fixed set.
+    values: Vec<String>,
+}
+
+/// The flags of the subcommand (or of `run`, which also applies without a subcommand).
+fn flags(subcommand: Subcommand) -> Vec<Flag> {
+    let cmd = match subcommand {
+        Subcommand::Run
+        | Subcommand::Apply
+        | Subcommand::Plan
+        | Subcommand::Template
+        | Subcommand::Eol
+        | Subcommand::ServePreview => Args::command(),
+        Subcommand::Execute => ExecuteArgs::command(),
+        Subcommand::Scan => ScanArgs::command(),
+        Subcommand::Undo => UndoArgs::command(),
+        Subcommand::Completions => CompletionsArgs::command(),
+    };
+
+    let mut flags: Vec<_> = cmd
+        .get_arguments()
+        .filter_map(|arg| {
+            let help = arg.get_help().map(|x| x.to_string()).unwrap_or_default();
+            Some(Flag {
+                long: arg.get_long()?.to_string(),
+                short: arg.get_short(),
+                // only the first sentence
+                help: help
+                    .split(". ")
+                    .next()
+                    .unwrap()
+                    .trim_end_matches('.')
+                    .to_string(),
+                takes_value: arg.get_action().takes_values(),
+                values: arg
+                    .get_possible_values()
+                    .iter()
+                    .map(|x| x.get_name().to_string())
+                    .collect(),
+            })
+        })
+        .collect();
+
+    // `eol --to <EOL>` is rewritten to `--convert-eol <EOL>`
+    if subcommand == Subcommand::Eol {
+        flags.push(Flag {
+            long: "to".to_string(),
+            short: None,
+            help: "The line ending to convert to".to_string(),
+            takes_value: true,
+            values: ["lf", "crlf"].map(String::from).to_vec(),
+        });
+    }
+
+    // `serve-preview --port <PORT> --host <HOST>` is rewritten to `--serve-preview` with
+    // `--preview-port` and `--preview-host`
+    if subcommand == Subcommand::ServePreview {
+        for (long, help) in [
+            ("port", "The port to serve the preview on"),
+            ("host", "The address to serve the preview on"),
+        ] {
+            flags.push(Flag {
+                long: long.to_string(),
+                short: None,
+                help: help.to_string(),
+                takes_value: true,
+                values: Vec::new(),
+            });
+        }
+    }
+
+    flags.push(Flag {
+        long: "help".to_string(),
+        short: Some('h'),
+        help: "Print help".to_string(),
+        takes_value: false,
+        values: Vec::new(),
+    });
+
+    flags
+}
+
+/// The positional values of the subcommand that can be completed, if they're from a fixed set.
+fn positional_values(subcommand: Subcommand) -> Vec<String> {
+    match subcommand {
+        Subcommand::Completions => ["bash", "zsh", "fish"].map(String::from).to_vec(),
+        Subcommand::Template => crate::template::builtin_names(),
+        _ => Vec::new(),
+    }
+}
+
+pub fn script(shell: Shell) -> String {
+    match shell {
+        Shell::Bash => bash(),
+        Shell::Zsh => zsh(),
+        Shell::Fish => fish(),
+    }
+}
+
+fn subcommand_names() -> Vec<&'static str> {
+    Subcommand::list().iter().map(|x| x.as_str()).collect()
+}
+
+fn bash() -> String {
+    let mut cases = String::new();
+    // `run` matches any word, so it must be last
+    for subcommand in Subcommand::list().iter().rev() {
+        let flags = flags(*subcommand);
+
+        let words: Vec<_> = flags
+            .iter()
+            .flat_map(|x| {
+                let short = x.short.map(|c| format!("-{c}"));
+                std::iter::once(format!("--{}", x.long)).chain(short)
+            })
+            .collect();
+
+        let mut values = String::new();
+        for flag in flags.iter().filter(|x| !x.values.is_empty()) {
+            values.push_str(&format!(
+                "            --{}) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return ;;\n",
+                flag.long,
+                flag.values.join(" "),
+            ));
+        }
+
+        let positional = match positional_values(*subcommand).join(" ") {
+            x if x.is_empty() => "COMPREPLY=($(compgen -f -- \"$cur\"))".to_string(),
+            x => format!("COMPREPLY=($(compgen -W \"{x}\" -- \"$cur\"))"),
+        };
+
+        let pattern = match subcommand {
+            Subcommand::Run => "*".to_string(),
+            x => x.as_str().to_string(),
+        };
+
+        cases.push_str(&format!(
+            "    {pattern})\n        case \"$prev\" in\n{values}        esac\n        \
+            if [[ $cur == -* ]]; then\n            \
+            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n        \
+            else\n            {positional}\n        fi\n        ;;\n",
+            words.join(" "),
+        ));
+    }
+
+    format!(
+        "\
+_repatch() {{
+    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"
+    local prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"
+
+    if [[ $COMP_CWORD -eq 1 && $cur != -* ]]; then
+        COMPREPLY=($(compgen -W \"{}\" -- \"$cur\") $(compgen -f -- \"$cur\"))
+        return
+    fi
+
+    case \"${{COMP_WORDS[1]}}\" in
+{cases}    esac
+}}
+
+complete -o filenames -F _repatch repatch
+",
+        subcommand_names().join(" "),
+    )
+}
+
+/// Quote a string for zsh's `_arguments` descriptions.
+fn zsh_escape(s: &str) -> String {
+    s.replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+fn zsh() -> String {
+    let mut functions = String::new();
+    for subcommand in Subcommand::list() {
+        let mut specs = Vec::new();
+        for flag in flags(*subcommand) {
+            let value = match (flag.takes_value, flag.values.is_empty()) {
+                (false, _) => String::new(),
+                (true, true) => ":value:_files".to_string(),
+                (true, false) => format!(":value:({})", flag.values.join(" ")),
+            };
+            let help = zsh_escape(&flag.help);
+            // options that take multiple values can be given multiple times
+            specs.push(format!("'*--{}[{help}]{value}'", flag.long));
+            if let Some(short) = flag.short {
+                specs.push(format!("'*-{short}[{help}]{value}'"));
+            }
+        }
+
+        match positional_values(*subcommand).join(" ") {
+            x if x.is_empty() => specs.push("'*:file:_files'".to_string()),
+            x => specs.push(format!("':value:({x})'")),
+        }
+
+        functions.push_str(&format!(
+            "_repatch_{}() {{\n    _arguments -s \\\n        {}\n}}\n\n",
+            subcommand.as_str(),
+            specs.join(" \\\n        "),
+        ));
+    }
+
+    format!(
+        "\
+#compdef repatch
+
+{functions}_repatch() {{
+    if (( CURRENT > 2 )); then
+        case $words[2] in
+            {}) local cmd=$words[2]; shift words; (( CURRENT-- )); _repatch_$cmd; return ;;
+        esac
+    elif [[ $words[CURRENT] != -* ]]; then
+        _alternative 'subcommands:subcommand:({})' 'files:file:_files'
+        return
+    fi
+    _repatch_run
+}}
+
+_repatch \"$@\"
+",
+        subcommand_names().join("|"),
+        subcommand_names().join(" "),
+    )
+}
+
+/// Quote a string for fish.
+fn fish_escape(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn fish() -> String {
+    let mut out = String::from("complete -c repatch -f\n");
+
+    for subcommand in Subcommand::list() {
+        let condition = match subcommand {
+            Subcommand::Run => format!(
+                "not __fish_seen_subcommand_from {}",
+                subcommand_names()[1..].join(" "),
+            ),
+            x => format!("__fish_seen_subcommand_from {}", x.as_str()),
+        };
+
+        if *subcommand != Subcommand::Completions {
+            out.push_str(&format!(
+                "complete -c repatch -n {} -F\n",
+                fish_escape(&condition),
+            ));
+        }
+
+        for value in positional_values(*subcommand) {
+            out.push_str(&format!(
+                "complete -c repatch -n {} -a {value}\n",
+                fish_escape(&condition),
+            ));
+        }
+
+        for flag in flags(*subcommand) {
+            out.push_str(&format!(
+                "complete -c repatch -n {} -l {}",
+                fish_escape(&condition),
+                flag.long,
+            ));
+            if let Some(short) = flag.short {
+                out.push_str(&format!(" -s {short}"));
+            }
+            if flag.takes_value {
+                out.push_str(" -r");
+                if flag.values.is_empty() {
+                    out.push_str(" -F");
+                } else {
+                    out.push_str(&format!(" -a {}", fish_escape(&flag.values.join(" "))));
+                }
+            }
+            out.push_str(&format!(" -d {}\n", fish_escape(&flag.help)));
+        }
+    }
+
+    for subcommand in Subcommand::list() {
+        out.push_str(&format!(
+            "complete -c repatch -n __fish_use_subcommand -a {}\n",
+            subcommand.as_str(),
+        ));
+    }
+
+    out
+}
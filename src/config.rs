@@ -0,0 +1,567 @@
+//! The user's config file.
+//!
+//! The config file uses a small subset of TOML: `[table]` headers (with dotted names), and
+//! `key = value` pairs where the value is a string, a boolean, or an array of strings. For example:
+//!
+//! ```toml
+//! [preset.fix-logging]
+//! find = 'log\.warn\((.*)\)'
+//! replace = 'log.warning($1)'
+//! globs = ["*.py"]
+//! flags = ["--ignore-case"]
+//!
+//! [keys]
+//! yes = ["y", "j"]
+//! no = "k"
+//! ```
+//!
+//! Templates (`[template.NAME]`) and identifier characters (`[identifiers]`) are described in the
+//! `template` and `identifier` modules.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use crate::identifier::Identifiers;
+use crate::template::Template;
+use crate::ui::MenuOption;
+
+/// The path of the config file. The `REPATCH_CONFIG` environment variable takes precedence over
+/// the default of "$XDG_CONFIG_HOME/repatch/config.toml".
+pub fn path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("REPATCH_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(x) if !x.is_empty() => PathBuf::from(x),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+
+    Some(config_dir.join("repatch").join("config.toml"))
+}
+
+#[derive(Debug, Default)]
+pub struct Config {
+    pub presets: BTreeMap<String, Preset>,
+    pub templates: BTreeMap<String, Template>,
+    /// Whether runs should be recorded in the history.
+    pub history: bool,
+    /// A gitignore-style file with rules that apply to every search.
+    pub ignore_file: Option<PathBuf>,
+    /// Custom keys for answering the patch prompt.
+    pub keys: Keys,
+    /// The extra identifier characters of file extensions, for `--identifier`.
+    pub identifiers: Identifiers,
+}
+
+/// Custom keys for answering the patch prompt. If an answer has any custom keys, they replace its
+/// default key. A key can be several characters long.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Keys {
+    pub yes: Vec<String>,
+    pub no: Vec<String>,
+    pub quit: Vec<String>,
+    pub edit: Vec<String>,
+    pub note: Vec<String>,
+    pub bytes: Vec<String>,
+    pub unfold: Vec<String>,
+    pub captures: Vec<String>,
+    pub matches: Vec<String>,
+    pub search: Vec<String>,
+    pub apply_file: Vec<String>,
+    pub skip_file: Vec<String>,
+    pub apply_all: Vec<String>,
+    pub apply_similar: Vec<String>,
+    pub skip_similar: Vec<String>,
+}
+
+impl Keys {
+    /// The keys of each answer, including the default keys of answers without custom keys.
+    pub fn get(&self, option: MenuOption) -> Vec<&str> {
+        let custom = match option {
+            MenuOption::Yes => &self.yes,
+            MenuOption::No => &self.no,
+            MenuOption::Quit => &self.quit,
+            MenuOption::Edit => &self.edit,
+            MenuOption::Note => &self.note,
+            MenuOption::Bytes => &self.bytes,
+            MenuOption::Unfold => &self.unfold,
+            MenuOption::Captures => &self.captures,
+            MenuOption::Matches => &self.matches,
+            MenuOption::Search => &self.search,
+            MenuOption::ApplyFile => &self.apply_file,
+            MenuOption::SkipFile => &self.skip_file,
+            MenuOption::ApplyAll => &self.apply_all,
+            MenuOption::ApplySimilar => &self.apply_similar,
+            MenuOption::SkipSimilar => &self.skip_similar,
+        };
+
+        match custom.is_empty() {
+            true => vec![option.as_char()],
+            false => custom.iter().map(String::as_str).collect(),
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut seen = Vec::new();
+        for option in MenuOption::list() {
+            for key in self.get(*option) {
+                if key.is_empty() || key == "?" || key.contains(char::is_whitespace) {
+                    anyhow::bail!("[keys]: invalid key '{key}'");
+                }
+                if seen.contains(&key) {
+                    anyhow::bail!("[keys]: the key '{key}' is used more than once");
+                }
+                seen.push(key);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A named bundle of arguments.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Preset {
+    pub find: Option<String>,
+    pub replace: Option<String>,
+    pub globs: Vec<String>,
+    pub flags: Vec<String>,
+}
+
+impl Config {
+    /// Load the config file. A missing config file is treated as an empty config.
+    pub fn load() -> anyhow::Result<Self> {
+        let Some(path) = path() else {
+            return Ok(Self::default());
+        };
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("could not read config file '{}'", path.display()))
+            }
+        };
+
+        Self::parse(&contents).with_context(|| format!("invalid config file '{}'", path.display()))
+    }
+
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut config = Self::default();
+
+        for (table, entries) in parse_tables(s)? {
+            let (kind, name) = table.split_once('.').unwrap_or((&table, ""));
+            match kind {
+                "preset" if !name.is_empty() => {
+                    let mut preset = Preset::default();
+                    for (key, value) in entries {
+                        match (key.as_str(), value) {
+                            ("find", Value::String(x)) => preset.find = Some(x),
+                            ("replace", Value::String(x)) => preset.replace = Some(x),
+                            ("globs", Value::Array(x)) => preset.globs = x,
+                            ("flags", Value::Array(x)) => preset.flags = x,
+                            (key, _) => anyhow::bail!("[{table}]: invalid key '{key}'"),
+                        }
+                    }
+                    config.presets.insert(name.to_string(), preset);
+                }
+                "template" if !name.is_empty() => {
+                    let mut template = Template::default();
+                    for (key, value) in entries {
+                        match (key.as_str(), value) {
+                            ("description", Value::String(x)) => template.description = x,
+                            ("find", Value::String(x)) => template.find = x,
+                            ("replace", Value::String(x)) => template.replace = x,
+                            ("globs", Value::Array(x)) => template.globs = x,
+                            ("flags", Value::Array(x)) => template.flags = x,
+                            ("params", Value::Array(x)) => template.params = x,
+                            (key, _) => anyhow::bail!("[{table}]: invalid key '{key}'"),
+                        }
+                    }
+                    template.validate().with_context(|| format!("[{table}]"))?;
+                    config.templates.insert(name.to_string(), template);
+                }
+                "history" if name.is_empty() => {
+                    for (key, value) in entries {
+                        match (key.as_str(), value) {
+                            ("enabled", Value::Bool(x)) => config.history = x,
+                            (key, _) => anyhow::bail!("[{table}]: invalid key '{key}'"),
+                        }
+                    }
+                }
+                "ignore" if name.is_empty() => {
+                    for (key, value) in entries {
+                        match (key.as_str(), value) {
+                            ("file", Value::String(x)) => {
+                                config.ignore_file = Some(expand_home(&x))
+                            }
+                            (key, _) => anyhow::bail!("[{table}]: invalid key '{key}'"),
+                        }
+                    }
+                }
+                "identifiers" if name.is_empty() => {
+                    for (key, value) in entries {
+                        match value {
+                            Value::String(x) => config.identifiers.extra.insert(key, x),
+                            _ => anyhow::bail!("[{table}]: invalid value for '{key}'"),
+                        };
+                    }
+                }
+                "keys" if name.is_empty() => {
+                    for (key, value) in entries {
+                        let keys = match value {
+                            Value::String(x) => vec![x],
+                            Value::Array(x) => x,
+                            Value::Bool(_) => anyhow::bail!("[{table}]: invalid value for '{key}'"),
+                        };
+                        match key.as_str() {
+                            "yes" => config.keys.yes = keys,
+                            "no" => config.keys.no = keys,
+                            "quit" => config.keys.quit = keys,
+                            "edit" => config.keys.edit = keys,
+                            "note" => config.keys.note = keys,
+                            "bytes" => config.keys.bytes = keys,
+                            "unfold" => config.keys.unfold = keys,
+                            "captures" => config.keys.captures = keys,
+                            "matches" => config.keys.matches = keys,
+                            "search" => config.keys.search = keys,
+                            "apply-file" => config.keys.apply_file = keys,
+                            "skip-file" => config.keys.skip_file = keys,
+                            "apply-all" => config.keys.apply_all = keys,
+                            "apply-similar" => config.keys.apply_similar = keys,
+                            "skip-similar" => config.keys.skip_similar = keys,
+                            key => anyhow::bail!("[{table}]: invalid key '{key}'"),
+                        }
+                    }
+                    config.keys.validate()?;
+                }
+                // the top-level table
+                "" if entries.is_empty() => {}
+                "" => anyhow::bail!("unknown key '{}'", entries[0].0),
+                _ => anyhow::bail!("unknown table [{table}]"),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Expand a leading "~/" to the user's home directory.
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Expand a `--preset NAME` argument by inserting the preset's arguments before the remaining
+/// command line arguments. The preset's find and replace arguments are inserted as the first
+/// positional arguments. Arguments after a "--" are left unchanged.
+pub fn expand_preset(args: Vec<OsString>, config: &Config) -> anyhow::Result<Vec<OsString>> {
+    let mut name = None;
+    let mut rest = Vec::new();
+
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            rest.push(arg);
+            rest.extend(iter.by_ref());
+            break;
+        }
+
+        if arg == "--preset" {
+            let Some(x) = iter.next() else {
+                anyhow::bail!("'--preset' requires a value");
+            };
+            name = Some(x);
+            continue;
+        }
+
+        match arg.to_str().and_then(|x| x.strip_prefix("--preset=")) {
+            Some(x) => name = Some(x.into()),
+            None => rest.push(arg),
+        }
+    }
+
+    let Some(name) = name else {
+        return Ok(rest);
+    };
+    let name = name
+        .into_string()
+        .map_err(|_| anyhow::anyhow!("the preset name is not valid UTF-8"))?;
+
+    let Some(preset) = config.presets.get(&name) else {
+        anyhow::bail!("no preset named '{name}' in the config file");
+    };
+
+    let mut rest = rest.into_iter();
+    let mut args: Vec<OsString> = rest.next().into_iter().collect();
+
+    args.extend(preset.flags.iter().map(OsString::from));
+    args.extend(preset.globs.iter().map(|x| format!("--glob={x}").into()));
+    args.push(format!("--preset={name}").into());
+    args.extend(preset.find.iter().map(OsString::from));
+    args.extend(preset.replace.iter().map(OsString::from));
+    args.extend(rest);
+
+    Ok(args)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Bool(bool),
+    Array(Vec<String>),
+}
+
+/// A table name and its key/value pairs.
+pub type Table = (String, Vec<(String, Value)>);
+
+/// Parse the tables and their key/value pairs. Entries before the first table header are returned
+/// under the table name "".
+pub fn parse_tables(s: &str) -> anyhow::Result<Vec<Table>> {
+    let mut tables = vec![(String::new(), Vec::new())];
+
+    for (line_num, line) in s.lines().enumerate() {
+        let line_num = line_num + 1;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let Some((name, remaining)) = header.split_once(']') else {
+                anyhow::bail!("line {line_num}: invalid table header");
+            };
+            if !strip_comment(remaining).is_empty() {
+                anyhow::bail!("line {line_num}: unexpected characters after the table header");
+            }
+            let name = name.trim();
+            if name.is_empty() || tables.iter().any(|(x, _)| x == name) {
+                anyhow::bail!("line {line_num}: invalid or duplicate table [{name}]");
+            }
+            tables.push((name.to_string(), Vec::new()));
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            anyhow::bail!("line {line_num}: expected a table header or \"key = value\"");
+        };
+
+        let key = key.trim();
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_".contains(c))
+        {
+            anyhow::bail!("line {line_num}: invalid key '{key}'");
+        }
+
+        let (value, remaining) = parse_value(value.trim_start())
+            .ok_or_else(|| anyhow::anyhow!("line {line_num}: invalid value for '{key}'"))?;
+        if !strip_comment(remaining).is_empty() {
+            anyhow::bail!("line {line_num}: unexpected characters after the value");
+        }
+
+        let entries = &mut tables.last_mut().unwrap().1;
+        if entries.iter().any(|(x, _)| x == key) {
+            anyhow::bail!("line {line_num}: duplicate key '{key}'");
+        }
+        entries.push((key.to_string(), value));
+    }
+
+    Ok(tables)
+}
+
+/// Strip whitespace and a trailing comment.
+fn strip_comment(s: &str) -> &str {
+    let s = s.trim();
+    if s.starts_with('#') {
+        return "";
+    }
+    s
+}
+
+/// Parse a value at the start of `s`, returning the value and the remaining string.
+fn parse_value(s: &str) -> Option<(Value, &str)> {
+    if let Some(s) = s.strip_prefix("true") {
+        return Some((Value::Bool(true), s));
+    }
+    if let Some(s) = s.strip_prefix("false") {
+        return Some((Value::Bool(false), s));
+    }
+
+    if let Some(mut s) = s.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            s = s.trim_start();
+            if let Some(s) = s.strip_prefix(']') {
+                return Some((Value::Array(values), s));
+            }
+            let (value, remaining) = parse_string(s)?;
+            values.push(value);
+            s = remaining.trim_start();
+            match s.strip_prefix(',') {
+                Some(x) => s = x,
+                None if s.starts_with(']') => {}
+                None => return None,
+            }
+        }
+    }
+
+    let (value, s) = parse_string(s)?;
+    Some((Value::String(value), s))
+}
+
+/// Parse a basic ("...") or literal ('...') string at the start of `s`, returning the string and
+/// the remaining string.
+fn parse_string(s: &str) -> Option<(String, &str)> {
+    if let Some(s) = s.strip_prefix('\'') {
+        let (value, s) = s.split_once('\'')?;
+        return Some((value.to_string(), s));
+    }
+
+    let s = s.strip_prefix('"')?;
+    let mut value = String::new();
+    let mut chars = s.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &s[i + 1..])),
+            '\\' => match chars.next()?.1 {
+                '"' => value.push('"'),
+                '\\' => value.push('\\'),
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                    value.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                _ => return None,
+            },
+            c => value.push(c),
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tables() {
+        let s = r#"
+            # comment
+            [preset.a] # comment
+            find = 'a\.b' # comment
+            replace = "x\"\tyé"
+            globs = ["*.rs", '!target/*',]
+            flag = true
+            [preset.b]
+        "#;
+        assert_eq!(
+            parse_tables(s).unwrap(),
+            [
+                (String::new(), vec![]),
+                (
+                    "preset.a".to_string(),
+                    vec![
+                        ("find".to_string(), Value::String(r"a\.b".to_string())),
+                        ("replace".to_string(), Value::String("x\"\tyé".to_string())),
+                        (
+                            "globs".to_string(),
+                            Value::Array(vec!["*.rs".to_string(), "!target/*".to_string()]),
+                        ),
+                        ("flag".to_string(), Value::Bool(true)),
+                    ],
+                ),
+                ("preset.b".to_string(), vec![]),
+            ],
+        );
+
+        assert!(parse_tables("[a").is_err());
+        assert!(parse_tables("[a]\n[a]").is_err());
+        assert!(parse_tables("a = 'b").is_err());
+        assert!(parse_tables("a = 'b' c").is_err());
+        assert!(parse_tables("a = 'b'\na = 'c'").is_err());
+        assert!(parse_tables("a = [1]").is_err());
+        assert!(parse_tables("a b = 'c'").is_err());
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let s = "[preset.fix]\nfind = 'a'\nflags = ['-i']\n";
+        let config = Config::parse(s).unwrap();
+        assert_eq!(
+            config.presets["fix"],
+            Preset {
+                find: Some("a".to_string()),
+                replace: None,
+                globs: vec![],
+                flags: vec!["-i".to_string()],
+            },
+        );
+
+        assert!(!config.history);
+
+        let config =
+            Config::parse("[template.t]\nfind = '{{a}}'\nreplace = 'b'\nparams = ['a']\n").unwrap();
+        assert_eq!(config.templates["t"].params, ["a"]);
+        assert!(Config::parse("[template.t]\nfind = '{{a}}'\n").is_err());
+
+        let config = Config::parse("[history]\nenabled = true\n").unwrap();
+        assert!(config.history);
+
+        let config = Config::parse("[ignore]\nfile = '/a/b'\n").unwrap();
+        assert_eq!(config.ignore_file, Some(PathBuf::from("/a/b")));
+
+        let config = Config::parse("[identifiers]\ncss = '-'\n").unwrap();
+        assert_eq!(config.identifiers.extra["css"], "-");
+        assert!(Config::parse("[identifiers]\ncss = true\n").is_err());
+
+        let config = Config::parse("[keys]\nyes = ['y', 'ok']\nno = 'k'\n").unwrap();
+        assert_eq!(config.keys.get(MenuOption::Yes), ["y", "ok"]);
+        assert_eq!(config.keys.get(MenuOption::No), ["k"]);
+        assert_eq!(config.keys.get(MenuOption::Quit), ["q"]);
+
+        assert!(Config::parse("[keys]\nyes = 'n'\n").is_err());
+        assert!(Config::parse("[keys]\nyes = ['y', 'y']\n").is_err());
+        assert!(Config::parse("[keys]\nyes = '?'\n").is_err());
+        assert!(Config::parse("[keys]\nyes = ''\n").is_err());
+        assert!(Config::parse("[keys]\nmaybe = 'm'\n").is_err());
+        assert!(Config::parse("[preset]").is_err());
+        assert!(Config::parse("[foo]").is_err());
+        assert!(Config::parse("[preset.a]\nfind = true").is_err());
+    }
+
+    #[test]
+    fn test_expand_preset() {
+        let config = Config::parse("[preset.fix]\nfind = 'a'\nflags = ['-i']\n").unwrap();
+        let expand = |args: &[&str]| {
+            let args = args.iter().map(OsString::from).collect();
+            expand_preset(args, &config).map(|x| x.into_iter().collect::<Vec<_>>())
+        };
+
+        assert_eq!(
+            expand(&["repatch", "--preset", "fix", "b"]).unwrap(),
+            ["repatch", "-i", "--preset=fix", "a", "b"],
+        );
+        assert_eq!(
+            expand(&["repatch", "--preset=fix", "--", "--preset"]).unwrap(),
+            ["repatch", "-i", "--preset=fix", "a", "--", "--preset"],
+        );
+        assert_eq!(expand(&["repatch", "x"]).unwrap(), ["repatch", "x"]);
+
+        let err = expand(&["repatch", "x", "--preset"]).unwrap_err();
+        assert_eq!(err.to_string(), "'--preset' requires a value");
+        assert!(expand(&["repatch", "--preset", "other"]).is_err());
+    }
+}
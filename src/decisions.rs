@@ -0,0 +1,297 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+/// A yes/no answer for a hunk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Yes,
+    No,
+}
+
+impl Decision {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Yes => "yes",
+            Self::No => "no",
+        }
+    }
+}
+
+/// Decisions for hunks, keyed by the file path and a hash of the hunk's contents.
+///
+/// The file format has one decision per line: "<HASH> <yes|no> <PATH>". Empty lines and lines
+/// starting with "#" are ignored, except that a "# note: <TEXT>" line is the note of the hunk on
+/// the next line. The note of a hunk without a decision (an edited hunk) is followed by a commented
+/// "# <HASH> edited <PATH>" line. Similarly, a "# matches: <ID>..." line has the [`MatchIds`] of
+/// the matches that the hunk on the next line replaces.
+#[derive(Debug, Default)]
+pub struct Decisions {
+    map: BTreeMap<(PathBuf, u64), Decision>,
+    /// Notes that the user added to hunks.
+    notes: BTreeMap<(PathBuf, u64), String>,
+    /// The ids of the matches that each hunk replaces.
+    match_ids: BTreeMap<(PathBuf, u64), Vec<String>>,
+}
+
+impl Decisions {
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read decisions file '{}'", path.display()))?;
+        Self::parse(&contents)
+            .with_context(|| format!("invalid decisions file '{}'", path.display()))
+    }
+
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut map = BTreeMap::new();
+        let mut notes = BTreeMap::new();
+        let mut match_ids = BTreeMap::new();
+
+        // the note and match ids for the next decision
+        let mut note = None;
+        let mut ids = None;
+
+        for (line_num, line) in s.lines().enumerate() {
+            if let Some(x) = line.strip_prefix("# note: ") {
+                note = Some(x.to_string());
+                continue;
+            }
+            if let Some(x) = line.strip_prefix("# matches: ") {
+                ids = Some(x.split_whitespace().map(str::to_string).collect());
+                continue;
+            }
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.splitn(3, ' ');
+            let (Some(hash), Some(decision), Some(path)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                anyhow::bail!("line {}: expected \"<HASH> <yes|no> <PATH>\"", line_num + 1);
+            };
+
+            let hash = u64::from_str_radix(hash, 16)
+                .with_context(|| format!("line {}: invalid hash", line_num + 1))?;
+            let decision = match decision {
+                "yes" => Decision::Yes,
+                "no" => Decision::No,
+                x => anyhow::bail!("line {}: unknown decision '{x}'", line_num + 1),
+            };
+
+            if let Some(note) = note.take() {
+                notes.insert((PathBuf::from(path), hash), note);
+            }
+            if let Some(ids) = ids.take() {
+                match_ids.insert((PathBuf::from(path), hash), ids);
+            }
+            map.insert((PathBuf::from(path), hash), decision);
+        }
+
+        Ok(Self {
+            map,
+            notes,
+            match_ids,
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+
+        let mut keys: Vec<_> = self.map.keys().chain(self.notes.keys()).collect();
+        keys.sort();
+        keys.dedup();
+
+        for key @ (path, hash) in keys {
+            if let Some(note) = self.notes.get(key) {
+                writeln!(file, "# note: {note}")?;
+            }
+            let decided = self.map.contains_key(key);
+            // edited hunks aren't read back, so their matches aren't written
+            if let (true, Some(ids)) = (decided, self.match_ids.get(key)) {
+                if !ids.is_empty() {
+                    writeln!(file, "# matches: {}", ids.join(" "))?;
+                }
+            }
+            let decision = self.map.get(key).map_or("edited", Decision::as_str);
+            let comment = if decided { "" } else { "# " };
+            writeln!(file, "{comment}{hash:016x} {decision} {}", path.display())?;
+        }
+
+        file.flush()
+    }
+
+    /// Read the hunks that were rejected in earlier runs with `--remember-rejected`.
+    pub fn read_rejected() -> anyhow::Result<Self> {
+        let path = rejected_path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Self::read(&path)
+    }
+
+    /// Add these decisions to the hunks that were rejected in earlier runs.
+    pub fn save_rejected(self) -> anyhow::Result<()> {
+        if self.map.is_empty() {
+            return Ok(());
+        }
+
+        let path = rejected_path()?;
+        let mut rejected = Self::read_rejected()?;
+        rejected.map.extend(self.map);
+
+        let parent = path.parent().unwrap();
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("could not create '{}'", parent.display()))?;
+        rejected
+            .write(&path)
+            .with_context(|| format!("could not write '{}'", path.display()))
+    }
+
+    pub fn get(&self, path: &Path, hash: u64) -> Option<Decision> {
+        self.map.get(&(path.to_path_buf(), hash)).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// The decisions, ordered by path.
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, u64, Decision)> {
+        self.map
+            .iter()
+            .map(|((path, hash), decision)| (path.as_path(), *hash, *decision))
+    }
+
+    pub fn insert(&mut self, path: &Path, hash: u64, decision: Decision) {
+        self.map.insert((path.to_path_buf(), hash), decision);
+    }
+
+    /// Add a note to the hunk. Notes must be a single line.
+    pub fn insert_note(&mut self, path: &Path, hash: u64, note: String) {
+        self.notes.insert((path.to_path_buf(), hash), note);
+    }
+
+    /// Record the ids of the matches that the hunk replaces.
+    pub fn insert_match_ids(&mut self, path: &Path, hash: u64, ids: Vec<String>) {
+        self.match_ids.insert((path.to_path_buf(), hash), ids);
+    }
+}
+
+/// Stable ids for the matches of a file, so that other programs can refer to a match across runs.
+///
+/// An id is "<PATH HASH>-<MATCH HASH>-<N>", where the path hash is of the file's path, the match
+/// hash is of the matched text and its replacement, and `<N>` counts the earlier matches in the
+/// file with the same match hash. An id doesn't change when other lines of the file are changed.
+#[derive(Debug)]
+pub struct MatchIds {
+    path_hash: u64,
+    /// The number of matches so far with each match hash.
+    counts: HashMap<u64, u64>,
+}
+
+impl MatchIds {
+    pub fn new(path: &Path) -> Self {
+        // "./a" and "a" are the same path
+        let path: PathBuf = path
+            .components()
+            .filter(|x| *x != std::path::Component::CurDir)
+            .collect();
+        Self {
+            path_hash: crate::util::fnv1a(path.as_os_str().as_encoded_bytes()),
+            counts: HashMap::new(),
+        }
+    }
+
+    /// The id of the next match in the file.
+    pub fn next(&mut self, matched: &[u8], replacement: &[u8]) -> String {
+        let hash = hunk_hash(matched, replacement);
+        let count = self.counts.entry(hash).or_default();
+        let id = format!("{:016x}-{hash:016x}-{count}", self.path_hash);
+        *count += 1;
+        id
+    }
+}
+
+/// The file with the hunks rejected with `--remember-rejected`, "$XDG_STATE_HOME/repatch/rejected".
+/// Its format is the same as a decisions file, and its paths are absolute.
+fn rejected_path() -> anyhow::Result<PathBuf> {
+    let dir = crate::history::state_dir()
+        .ok_or_else(|| anyhow::anyhow!("could not find the state directory"))?;
+    Ok(dir.join("rejected"))
+}
+
+/// A hash of the hunk that is stable across platforms and program versions (64-bit FNV-1a).
+pub fn hunk_hash(original: &[u8], replaced: &[u8]) -> u64 {
+    let len = u64::try_from(original.len()).unwrap();
+    crate::util::fnv1a_chunks([&len.to_le_bytes()[..], original, replaced])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hunk_hash() {
+        assert_eq!(hunk_hash(b"", b""), hunk_hash(b"", b""));
+        assert_ne!(hunk_hash(b"ab", b"c"), hunk_hash(b"a", b"bc"));
+        assert_ne!(hunk_hash(b"foo\n", b"bar\n"), hunk_hash(b"foo\n", b"baz\n"));
+        // the hash of the original's length and then both texts, which is saved in decisions files
+        let data = b"\x03\0\0\0\0\0\0\0foobar";
+        assert_eq!(hunk_hash(b"foo", b"bar"), crate::util::fnv1a(data));
+        assert_eq!(hunk_hash(b"foo", b"bar"), 0x4dd5838b8d29dfcf);
+    }
+
+    #[test]
+    fn test_match_ids() {
+        let mut ids = MatchIds::new(Path::new("./src/a.rs"));
+        let first = ids.next(b"foo", b"bar");
+        let other = ids.next(b"foo", b"baz");
+        let second = ids.next(b"foo", b"bar");
+        assert!(first.ends_with("-0"));
+        assert!(other.ends_with("-0"));
+        assert!(second.ends_with("-1"));
+        assert_eq!(first[..first.len() - 1], second[..second.len() - 1]);
+
+        // the ids only depend on the path and the earlier matches with the same text
+        let mut ids = MatchIds::new(Path::new("src/a.rs"));
+        assert_eq!(ids.next(b"foo", b"bar"), first);
+        let mut ids = MatchIds::new(Path::new("src/b.rs"));
+        assert_ne!(ids.next(b"foo", b"bar"), first);
+    }
+
+    #[test]
+    fn test_parse() {
+        let s = "# comment\n\n00000000000000ff yes ./a b.rs\n1 no src/c.rs\n";
+        let decisions = Decisions::parse(s).unwrap();
+        assert_eq!(
+            decisions.get(Path::new("./a b.rs"), 0xff),
+            Some(Decision::Yes)
+        );
+        assert_eq!(decisions.get(Path::new("src/c.rs"), 1), Some(Decision::No));
+        assert_eq!(decisions.get(Path::new("src/c.rs"), 2), None);
+
+        let s = "# note: keep the old name\n1 no a.rs\n2 yes a.rs\n# note: x\n# 3 edited a.rs\n";
+        let decisions = Decisions::parse(s).unwrap();
+        let note = |hash| decisions.notes.get(&(PathBuf::from("a.rs"), hash));
+        assert_eq!(note(1).map(String::as_str), Some("keep the old name"));
+        assert_eq!(note(2), None);
+        assert_eq!(note(3), None);
+        assert_eq!(decisions.get(Path::new("a.rs"), 3), None);
+
+        let s = "# matches: a-b-0 a-c-1\n1 yes a.rs\n2 no a.rs\n";
+        let decisions = Decisions::parse(s).unwrap();
+        let ids = |hash| decisions.match_ids.get(&(PathBuf::from("a.rs"), hash));
+        assert_eq!(
+            ids(1),
+            Some(&vec!["a-b-0".to_string(), "a-c-1".to_string()])
+        );
+        assert_eq!(ids(2), None);
+
+        assert!(Decisions::parse("ff yes").is_err());
+        assert!(Decisions::parse("ff maybe a.rs").is_err());
+        assert!(Decisions::parse("xyz yes a.rs").is_err());
+    }
+}
@@ -0,0 +1,274 @@
+//! The regex engines that `<FIND>` can be matched with, for `--engine`. The default engine is
+//! grep-regex, and the fancy engine is PCRE2, which also supports look-around and backreferences
+//! but is usually slower. Both implement [`Matcher`], so searching and replacing don't depend on
+//! which one is used.
+
+use grep_matcher::{ByteSet, Captures, LineMatchKind, LineTerminator, Match, Matcher, NoError};
+
+use crate::cli::Engine;
+
+/// The options that the pattern is compiled with.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MatcherOptions {
+    pub case_insensitive: bool,
+    pub line_terminator: Option<u8>,
+    /// A match can span several lines, for `--multiline`.
+    pub multi_line: bool,
+}
+
+/// Compile `pattern` with the engine `engine`. With [`Engine::Auto`], the fancy engine is only
+/// used if the default engine can't compile the pattern, and the default engine's error is
+/// returned if neither can.
+pub fn build(
+    pattern: &str,
+    engine: Engine,
+    options: MatcherOptions,
+) -> anyhow::Result<PatternMatcher> {
+    let default = || {
+        grep_regex::RegexMatcherBuilder::new()
+            .case_insensitive(options.case_insensitive)
+            .line_terminator(options.line_terminator)
+            .multi_line(options.multi_line)
+            .build(pattern)
+            .map(PatternMatcher::Default)
+    };
+    let fancy = || {
+        grep_pcre2::RegexMatcherBuilder::new()
+            .caseless(options.case_insensitive)
+            .multi_line(options.multi_line)
+            .utf(true)
+            .ucp(true)
+            .jit_if_available(true)
+            .build(pattern)
+            .map(PatternMatcher::Fancy)
+    };
+
+    Ok(match engine {
+        Engine::Default => match default() {
+            Ok(x) => x,
+            Err(e) if fancy().is_ok() => {
+                return Err(anyhow::Error::from(e).context(
+                    "the default engine can't compile '<FIND>' (use '--engine fancy' to compile it \
+                    with PCRE2)",
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        },
+        Engine::Fancy => fancy()?,
+        Engine::Auto => match default() {
+            Ok(x) => x,
+            Err(e) => fancy().map_err(|_| e)?,
+        },
+    })
+}
+
+/// A compiled `<FIND>` pattern.
+#[derive(Clone, Debug)]
+pub enum PatternMatcher {
+    Default(grep_regex::RegexMatcher),
+    Fancy(grep_pcre2::RegexMatcher),
+}
+
+impl PatternMatcher {
+    /// Compile `pattern` with the default engine and options.
+    pub fn new(pattern: &str) -> anyhow::Result<Self> {
+        build(pattern, Engine::Default, MatcherOptions::default())
+    }
+
+    /// Whether the pattern is matched by the fancy engine.
+    pub fn is_fancy(&self) -> bool {
+        matches!(self, Self::Fancy(_))
+    }
+}
+
+impl From<grep_regex::RegexMatcher> for PatternMatcher {
+    fn from(matcher: grep_regex::RegexMatcher) -> Self {
+        Self::Default(matcher)
+    }
+}
+
+/// The capture groups of a match of a [`PatternMatcher`].
+#[derive(Clone, Debug)]
+pub enum PatternCaptures {
+    Default(grep_regex::RegexCaptures),
+    Fancy(grep_pcre2::RegexCaptures),
+}
+
+impl Captures for PatternCaptures {
+    fn len(&self) -> usize {
+        match self {
+            Self::Default(x) => x.len(),
+            Self::Fancy(x) => x.len(),
+        }
+    }
+
+    fn get(&self, i: usize) -> Option<Match> {
+        match self {
+            Self::Default(x) => x.get(i),
+            Self::Fancy(x) => x.get(i),
+        }
+    }
+}
+
+/// An error while matching, such as PCRE2's backtracking limit being reached. The default engine
+/// never fails.
+#[derive(Debug)]
+pub struct MatchError(grep_pcre2::Error);
+
+impl std::fmt::Display for MatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for MatchError {}
+
+fn no_error(_: NoError) -> MatchError {
+    unreachable!("the default engine never fails")
+}
+
+/// The captures of the wrong engine were given, which is a bug.
+fn wrong_captures() -> ! {
+    panic!("the captures are from a different engine than the matcher")
+}
+
+impl Matcher for PatternMatcher {
+    type Captures = PatternCaptures;
+    type Error = MatchError;
+
+    fn find_at(&self, haystack: &[u8], at: usize) -> Result<Option<Match>, MatchError> {
+        match self {
+            Self::Default(x) => x.find_at(haystack, at).map_err(no_error),
+            Self::Fancy(x) => x.find_at(haystack, at).map_err(MatchError),
+        }
+    }
+
+    fn new_captures(&self) -> Result<PatternCaptures, MatchError> {
+        match self {
+            Self::Default(x) => x
+                .new_captures()
+                .map(PatternCaptures::Default)
+                .map_err(no_error),
+            Self::Fancy(x) => x
+                .new_captures()
+                .map(PatternCaptures::Fancy)
+                .map_err(MatchError),
+        }
+    }
+
+    fn capture_count(&self) -> usize {
+        match self {
+            Self::Default(x) => x.capture_count(),
+            Self::Fancy(x) => x.capture_count(),
+        }
+    }
+
+    fn capture_index(&self, name: &str) -> Option<usize> {
+        match self {
+            Self::Default(x) => x.capture_index(name),
+            Self::Fancy(x) => x.capture_index(name),
+        }
+    }
+
+    fn try_find_iter<F, E>(&self, haystack: &[u8], matched: F) -> Result<Result<(), E>, MatchError>
+    where
+        F: FnMut(Match) -> Result<bool, E>,
+    {
+        match self {
+            Self::Default(x) => x.try_find_iter(haystack, matched).map_err(no_error),
+            Self::Fancy(x) => x.try_find_iter(haystack, matched).map_err(MatchError),
+        }
+    }
+
+    fn captures_at(
+        &self,
+        haystack: &[u8],
+        at: usize,
+        caps: &mut PatternCaptures,
+    ) -> Result<bool, MatchError> {
+        match (self, caps) {
+            (Self::Default(x), PatternCaptures::Default(caps)) => {
+                x.captures_at(haystack, at, caps).map_err(no_error)
+            }
+            (Self::Fancy(x), PatternCaptures::Fancy(caps)) => {
+                x.captures_at(haystack, at, caps).map_err(MatchError)
+            }
+            _ => wrong_captures(),
+        }
+    }
+
+    fn is_match_at(&self, haystack: &[u8], at: usize) -> Result<bool, MatchError> {
+        match self {
+            Self::Default(x) => x.is_match_at(haystack, at).map_err(no_error),
+            Self::Fancy(x) => x.is_match_at(haystack, at).map_err(MatchError),
+        }
+    }
+
+    fn shortest_match_at(&self, haystack: &[u8], at: usize) -> Result<Option<usize>, MatchError> {
+        match self {
+            Self::Default(x) => x.shortest_match_at(haystack, at).map_err(no_error),
+            Self::Fancy(x) => x.shortest_match_at(haystack, at).map_err(MatchError),
+        }
+    }
+
+    fn non_matching_bytes(&self) -> Option<&ByteSet> {
+        match self {
+            Self::Default(x) => x.non_matching_bytes(),
+            Self::Fancy(x) => x.non_matching_bytes(),
+        }
+    }
+
+    fn line_terminator(&self) -> Option<LineTerminator> {
+        match self {
+            Self::Default(x) => x.line_terminator(),
+            Self::Fancy(x) => x.line_terminator(),
+        }
+    }
+
+    fn find_candidate_line(&self, haystack: &[u8]) -> Result<Option<LineMatchKind>, MatchError> {
+        match self {
+            Self::Default(x) => x.find_candidate_line(haystack).map_err(no_error),
+            Self::Fancy(x) => x.find_candidate_line(haystack).map_err(MatchError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let options = MatcherOptions::default();
+
+        // look-around and backreferences need the fancy engine
+        assert!(build("foo(?!_bar)", Engine::Default, options).is_err());
+        let matcher = build("foo(?!_bar)", Engine::Auto, options).unwrap();
+        assert!(matcher.is_fancy());
+        assert_eq!(
+            matcher.find(b"foo_bar foo_baz").unwrap(),
+            Some(Match::new(8, 11))
+        );
+
+        let matcher = build(r"(\w+) \1", Engine::Fancy, options).unwrap();
+        let mut caps = matcher.new_captures().unwrap();
+        assert!(matcher.captures(b"a b b", &mut caps).unwrap());
+        assert_eq!(caps.get(0), Some(Match::new(2, 5)));
+        assert_eq!(caps.get(1), Some(Match::new(2, 3)));
+
+        // patterns that the default engine can compile still use it
+        assert!(!build("fo+", Engine::Auto, options).unwrap().is_fancy());
+
+        let options = MatcherOptions {
+            case_insensitive: true,
+            ..options
+        };
+        let matcher = build("(?<=a)B", Engine::Fancy, options).unwrap();
+        assert!(matcher.is_match(b"ab").unwrap());
+        assert!(!matcher.is_match(b"cb").unwrap());
+
+        // invalid utf-8 doesn't prevent matching
+        assert!(matcher.is_match(b"\xffab").unwrap());
+        assert!(build("(", Engine::Auto, options).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+//! Fuzzy matching of a query against text, similar to fzf and skim.
+
+/// The score for each matched char.
+const MATCH: i64 = 16;
+/// The bonus for a matched char that directly follows the previous matched char.
+const CONSECUTIVE: i64 = 8;
+/// The penalty for each unmatched char between two matched chars.
+const GAP: i64 = 1;
+
+/// Returns a score if all of the chars of `query` appear in order in `text`, along with the
+/// indices of the matched chars of `text`. Higher scores are better matches. Matching is
+/// case-insensitive unless the query contains an uppercase char.
+pub fn score(query: &str, text: &str) -> Option<(i64, Vec<usize>)> {
+    let case_sensitive = query.chars().any(char::is_uppercase);
+    let fold = |c: char| match case_sensitive {
+        true => c,
+        false => c.to_lowercase().next().unwrap_or(c),
+    };
+
+    let query: Vec<char> = query.chars().map(fold).collect();
+    let text: Vec<char> = text.chars().collect();
+
+    if query.is_empty() {
+        return Some((0, Vec::new()));
+    }
+    if query.len() > text.len() {
+        return None;
+    }
+
+    // `scores[i][j]` is the best score where `query[i]` is matched with `text[j]`
+    let mut scores = vec![vec![None; text.len()]; query.len()];
+
+    for (i, q) in query.iter().enumerate() {
+        // the best score of the previous query char at or before `j - 1`, minus the gap penalty
+        let mut best_prev: Option<i64> = None;
+
+        for (j, t) in text.iter().enumerate() {
+            if i > 0 && j > 0 {
+                best_prev = match (best_prev, scores[i - 1][j - 1]) {
+                    (Some(x), Some(y)) => Some((x - GAP).max(y)),
+                    (x, y) => x.map(|x| x - GAP).or(y),
+                };
+            }
+
+            if fold(*t) != *q {
+                continue;
+            }
+
+            let prev = if i == 0 {
+                Some(0)
+            } else {
+                let consecutive = j
+                    .checked_sub(1)
+                    .and_then(|j| scores[i - 1][j])
+                    .map(|x| x + CONSECUTIVE);
+                consecutive.max(best_prev)
+            };
+
+            scores[i][j] = prev.map(|x| x + MATCH + bonus(&text, j));
+        }
+    }
+
+    // find the best end position, and then trace back the positions of the earlier chars
+    let last = query.len() - 1;
+    let (mut j, best) = (0..text.len())
+        .filter_map(|j| Some((j, scores[last][j]?)))
+        .max_by_key(|(j, score)| (*score, std::cmp::Reverse(*j)))?;
+
+    let mut positions = vec![j];
+    for i in (0..last).rev() {
+        let current = scores[i + 1][j].unwrap() - MATCH - bonus(&text, j);
+        j = (0..j)
+            .rev()
+            .find(|k| {
+                let Some(x) = scores[i][*k] else {
+                    return false;
+                };
+                let gap = i64::try_from(j - k - 1).unwrap();
+                x - GAP * gap == current || (*k + 1 == j && x + CONSECUTIVE == current)
+            })
+            .unwrap();
+        positions.push(j);
+    }
+    positions.reverse();
+
+    Some((best, positions))
+}
+
+/// The bonus for matching the char at `idx`, which is higher at the start of words and path
+/// components.
+fn bonus(text: &[char], idx: usize) -> i64 {
+    let Some(prev) = idx.checked_sub(1).map(|x| text[x]) else {
+        return 8;
+    };
+
+    match prev {
+        '/' => 10,
+        '_' | '-' | '.' | ' ' => 8,
+        _ if prev.is_lowercase() && text[idx].is_uppercase() => 7,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score() {
+        assert_eq!(score("", "abc"), Some((0, vec![])));
+        assert_eq!(score("abc", "ab"), None);
+        assert_eq!(score("ba", "abc"), None);
+        assert_eq!(score("abc", "xaxbxc").unwrap().1, [1, 3, 5]);
+
+        // case-insensitive unless the query has an uppercase char
+        assert!(score("abc", "ABC").is_some());
+        assert!(score("Abc", "abc").is_none());
+
+        // consecutive chars are preferred
+        assert_eq!(score("foo", "f_o_o_foo").unwrap().1, [6, 7, 8]);
+
+        // the starts of path components are preferred
+        assert_eq!(score("m", "src/am/main.rs").unwrap().1, [7]);
+
+        // better matches have higher scores
+        let score = |text| score("main", text).unwrap().0;
+        assert!(score("src/main.rs") > score("src/domain.rs"));
+        assert!(score("src/main.rs") > score("src/m_a_i_n.rs"));
+        assert!(score("src/domain.rs") > score("src/mxaxixn.rs"));
+    }
+}
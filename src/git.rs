@@ -0,0 +1,440 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::Context;
+
+/// Run a git command in the directory `dir` and return its stdout.
+fn git_output(dir: &Path, args: &[&str]) -> anyhow::Result<Vec<u8>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .context("could not run git")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("git {}: {}", args.join(" "), stderr.trim());
+    }
+
+    Ok(output.stdout)
+}
+
+/// Search the tracked files in `paths` using `git grep`, where `pattern` is a Perl-compatible
+/// regex. Returns the path and (0-indexed) line number of each matching line.
+pub fn grep(
+    pattern: &str,
+    case_insensitive: bool,
+    paths: &[PathBuf],
+) -> anyhow::Result<Vec<(PathBuf, u64)>> {
+    let mut cmd = Command::new("git");
+    cmd.args(["grep", "-n", "-z", "-I", "--no-color", "-P"]);
+    if case_insensitive {
+        cmd.arg("-i");
+    }
+    cmd.arg("-e").arg(pattern).arg("--").args(paths);
+
+    let output = cmd.output().context("could not run git")?;
+
+    // git grep exits with 1 if there were no matches
+    if !output.status.success() && output.status.code() != Some(1) {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("git grep: {}", stderr.trim());
+    }
+
+    let mut lines = Vec::new();
+
+    // each line has the format "PATH\0LINE\0TEXT"
+    for entry in output
+        .stdout
+        .split(|x| *x == b'\n')
+        .filter(|x| !x.is_empty())
+    {
+        let mut fields = entry.splitn(3, |x| *x == 0);
+        let (Some(path), Some(line_num)) = (fields.next(), fields.next()) else {
+            anyhow::bail!("unexpected 'git grep' output");
+        };
+        let line_num: u64 = std::str::from_utf8(line_num)
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .filter(|x| *x > 0)
+            .context("unexpected 'git grep' output")?;
+
+        // line numbers are given starting from 1
+        lines.push((
+            PathBuf::from(OsString::from_vec(path.to_vec())),
+            line_num - 1,
+        ));
+    }
+
+    Ok(lines)
+}
+
+/// Returns the top-level directory of the git repository containing the directory `dir`.
+pub fn toplevel(dir: &Path) -> anyhow::Result<PathBuf> {
+    let mut stdout = git_output(dir, &["rev-parse", "--show-toplevel"])?;
+
+    // remove the trailing newline
+    if stdout.last() == Some(&b'\n') {
+        stdout.pop();
+    }
+
+    let path = PathBuf::from(OsString::from_vec(stdout));
+
+    // git may not resolve symlinks the same way that we do
+    Ok(std::fs::canonicalize(path)?)
+}
+
+/// Returns the closest directory that contains `dir` (or is `dir`) and has a ".git" entry, which
+/// is a directory in most repositories but a file in worktrees and submodules. Unlike
+/// [`toplevel`], this doesn't run git.
+pub fn find_root(dir: &Path) -> Option<&Path> {
+    dir.ancestors()
+        .find(|x| std::fs::symlink_metadata(x.join(".git")).is_ok())
+}
+
+/// Returns the paths of all files with uncommitted changes (including untracked files) in the
+/// repository with top-level directory `toplevel`.
+fn dirty_files(toplevel: &Path) -> anyhow::Result<HashSet<PathBuf>> {
+    let stdout = git_output(
+        toplevel,
+        &["status", "--porcelain=v1", "-z", "--untracked-files=all"],
+    )?;
+
+    let mut files = HashSet::new();
+    let mut entries = stdout.split(|x| *x == 0).filter(|x| !x.is_empty());
+
+    // each entry has the format "XY PATH"
+    while let Some(entry) = entries.next() {
+        let Some((status, path)) = entry.split_at_checked(3) else {
+            anyhow::bail!("unexpected 'git status' output");
+        };
+
+        files.insert(toplevel.join(OsString::from_vec(path.to_vec())));
+
+        // renames and copies are followed by an additional entry with the original path
+        if status.contains(&b'R') || status.contains(&b'C') {
+            entries.next();
+        }
+    }
+
+    Ok(files)
+}
+
+/// Answers whether files have uncommitted changes, as reported by `git status`. Results are cached
+/// per repository.
+#[derive(Default)]
+pub struct StatusCache {
+    /// The repository top-level directory for each directory we've looked up.
+    toplevels: HashMap<PathBuf, PathBuf>,
+    /// The dirty files for each repository top-level directory.
+    dirty: HashMap<PathBuf, HashSet<PathBuf>>,
+}
+
+impl StatusCache {
+    pub fn is_dirty(&mut self, path: &Path) -> anyhow::Result<bool> {
+        let path = std::fs::canonicalize(path)?;
+        let toplevel = self.toplevel(&path)?;
+
+        if !self.dirty.contains_key(&toplevel) {
+            self.dirty.insert(toplevel.clone(), dirty_files(&toplevel)?);
+        }
+
+        Ok(self.dirty[&toplevel].contains(&path))
+    }
+
+    /// Returns the top-level directory of the git repository containing the file with the
+    /// canonical path `path`.
+    pub fn toplevel(&mut self, path: &Path) -> anyhow::Result<PathBuf> {
+        let dir = path.parent().unwrap();
+
+        if let Some(x) = self.toplevels.get(dir) {
+            return Ok(x.clone());
+        }
+
+        let toplevel = toplevel(dir)?;
+        self.toplevels.insert(dir.to_path_buf(), toplevel.clone());
+        Ok(toplevel)
+    }
+}
+
+/// The most recent commit that changed any of a range of lines, from `git blame`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlameSummary {
+    /// The abbreviated commit hash, or `None` if some lines haven't been committed yet.
+    pub commit: Option<String>,
+    pub author: String,
+    /// The author time in seconds since the Unix epoch.
+    pub time: u64,
+}
+
+/// Returns the most recent commit that changed any of the (0-indexed) `lines` of the file at
+/// `path`. Returns `None` if the file isn't tracked by git.
+pub fn blame(path: &Path, lines: std::ops::Range<u64>) -> Option<BlameSummary> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return None;
+    };
+    let dir = match dir.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => dir,
+    };
+    if lines.is_empty() {
+        return None;
+    }
+
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["blame", "--line-porcelain", "-L"])
+        .arg(format!("{},{}", lines.start + 1, lines.end))
+        .arg("--")
+        .arg(name)
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    parse_blame(&output.stdout)
+}
+
+/// Parse the output of `git blame --line-porcelain`, returning the most recent commit of any line.
+/// Uncommitted lines are considered more recent than any commit.
+fn parse_blame(stdout: &[u8]) -> Option<BlameSummary> {
+    let mut latest: Option<BlameSummary> = None;
+    let mut current: Option<BlameSummary> = None;
+
+    let is_newer = |x: &BlameSummary, than: &Option<BlameSummary>| match than {
+        None => true,
+        Some(than) => match (&x.commit, &than.commit) {
+            (None, Some(_)) => true,
+            (Some(_), None) => false,
+            _ => x.time > than.time,
+        },
+    };
+
+    for line in stdout.split(|x| *x == b'\n').filter(|x| !x.is_empty()) {
+        let line = String::from_utf8_lossy(line);
+
+        // the contents of each line are prefixed with a tab and end its entry
+        if line.starts_with('\t') {
+            if let Some(entry) = current.take() {
+                if is_newer(&entry, &latest) {
+                    latest = Some(entry);
+                }
+            }
+            continue;
+        }
+
+        let Some(entry) = &mut current else {
+            // each entry starts with "HASH ORIG_LINE FINAL_LINE [COUNT]"
+            let hash = line.split(' ').next()?;
+            if hash.len() < 40 || !hash.bytes().all(|x| x.is_ascii_hexdigit()) {
+                return None;
+            }
+            current = Some(BlameSummary {
+                commit: (!hash.bytes().all(|x| x == b'0')).then(|| hash[..7].to_string()),
+                author: String::new(),
+                time: 0,
+            });
+            continue;
+        };
+
+        if let Some(author) = line.strip_prefix("author ") {
+            entry.author = author.to_string();
+        } else if let Some(time) = line.strip_prefix("author-time ") {
+            entry.time = time.parse().ok()?;
+        }
+    }
+
+    latest
+}
+
+/// The line endings that a file should have in the working tree, from its `eol` attribute.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Eol {
+    Lf,
+    Crlf,
+}
+
+/// The `.gitattributes` settings of a file that affect how we read and write it.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    /// Whether the file is text (`text`) or binary (`-text` or `binary`), or `None` if git would
+    /// detect it from the file contents.
+    pub text: Option<bool>,
+    pub eol: Option<Eol>,
+    /// Whether the file is marked as generated or vendored code (`linguist-generated` or
+    /// `linguist-vendored`).
+    pub generated: bool,
+}
+
+/// Returns the attributes of each of the files in `paths`, as reported by `git check-attr`. Files
+/// that aren't in a git repository have no attributes.
+pub fn attributes<'a>(
+    paths: impl IntoIterator<Item = &'a Path>,
+) -> HashMap<PathBuf, FileAttributes> {
+    // run git once for each directory so that it knows which repository the files are in
+    let mut dirs: BTreeMap<&Path, Vec<&Path>> = BTreeMap::new();
+    for path in paths {
+        let (Some(dir), Some(_)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+        let dir = match dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => dir,
+        };
+        dirs.entry(dir).or_default().push(path);
+    }
+
+    let mut attributes = HashMap::new();
+
+    for (dir, paths) in dirs {
+        let mut args = vec![
+            OsString::from("check-attr"),
+            OsString::from("-z"),
+            OsString::from("text"),
+            OsString::from("eol"),
+            OsString::from("linguist-generated"),
+            OsString::from("linguist-vendored"),
+            OsString::from("--"),
+        ];
+        args.extend(paths.iter().map(|x| x.file_name().unwrap().to_owned()));
+
+        let output = Command::new("git").arg("-C").arg(dir).args(&args).output();
+
+        // git fails if the directory isn't in a repository
+        let stdout = match output {
+            Ok(x) if x.status.success() => x.stdout,
+            _ => continue,
+        };
+
+        let by_name = parse_check_attr(&stdout);
+        for path in paths {
+            let name = path.file_name().unwrap().as_bytes();
+            if let Some(attrs) = by_name.get(name) {
+                attributes.insert(path.to_path_buf(), *attrs);
+            }
+        }
+    }
+
+    attributes
+}
+
+/// Parse the output of `git check-attr -z`, where each entry has the format
+/// "PATH\0ATTRIBUTE\0VALUE\0".
+fn parse_check_attr(stdout: &[u8]) -> HashMap<&[u8], FileAttributes> {
+    let mut attributes: HashMap<&[u8], FileAttributes> = HashMap::new();
+
+    let fields: Vec<&[u8]> = stdout.split(|x| *x == 0).collect();
+    for entry in fields.chunks_exact(3) {
+        let [path, attr, value] = entry else {
+            unreachable!();
+        };
+        let attrs = attributes.entry(path).or_default();
+
+        // the "binary" macro attribute unsets "text"
+        match (*attr, *value) {
+            (b"text", b"set") => attrs.text = Some(true),
+            (b"text", b"unset") => attrs.text = Some(false),
+            (b"eol", b"lf") => attrs.eol = Some(Eol::Lf),
+            (b"eol", b"crlf") => attrs.eol = Some(Eol::Crlf),
+            (b"linguist-generated" | b"linguist-vendored", b"set" | b"true") => {
+                attrs.generated = true
+            }
+            _ => {}
+        }
+    }
+
+    attributes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_check_attr() {
+        let stdout = b"a.png\0text\0unset\0a.png\0eol\0unspecified\0\
+                       b.bat\0text\0set\0b.bat\0eol\0crlf\0\
+                       c.rs\0text\0auto\0c.rs\0eol\0lf\0\
+                       d.pb.go\0linguist-generated\0true\0d.pb.go\0linguist-vendored\0unspecified\0";
+        let attributes = parse_check_attr(stdout);
+
+        assert_eq!(
+            attributes[&b"a.png"[..]],
+            FileAttributes {
+                text: Some(false),
+                eol: None,
+                generated: false,
+            },
+        );
+        assert_eq!(
+            attributes[&b"b.bat"[..]],
+            FileAttributes {
+                text: Some(true),
+                eol: Some(Eol::Crlf),
+                generated: false,
+            },
+        );
+        assert_eq!(
+            attributes[&b"c.rs"[..]],
+            FileAttributes {
+                text: None,
+                eol: Some(Eol::Lf),
+                generated: false,
+            },
+        );
+        assert!(attributes[&b"d.pb.go"[..]].generated);
+        assert!(parse_check_attr(b"").is_empty());
+    }
+
+    #[test]
+    fn test_find_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub = dir.path().join("a/b");
+        std::fs::create_dir_all(&sub).unwrap();
+        assert_ne!(find_root(&sub), Some(dir.path()));
+
+        std::fs::create_dir(dir.path().join(".git")).unwrap();
+        assert_eq!(find_root(&sub), Some(dir.path()));
+        assert_eq!(find_root(dir.path()), Some(dir.path()));
+
+        // a worktree has a ".git" file
+        std::fs::write(sub.join(".git"), "gitdir: ../../.git").unwrap();
+        assert_eq!(find_root(&sub), Some(sub.as_path()));
+    }
+
+    #[test]
+    fn test_parse_blame() {
+        let entry = |hash: &str, author: &str, time: u64| {
+            format!(
+                "{hash} 1 1 1\nauthor {author}\nauthor-mail <a@b>\nauthor-time {time}\n\
+                 summary x\nfilename a.rs\n\tline\n"
+            )
+        };
+        let old = "1234567890abcdef1234567890abcdef12345678";
+        let new = "abcdef1234567890abcdef1234567890abcdef12";
+        let uncommitted = "0".repeat(40);
+
+        let stdout = entry(old, "A", 100) + &entry(new, "B", 200) + &entry(old, "A", 100);
+        assert_eq!(
+            parse_blame(stdout.as_bytes()),
+            Some(BlameSummary {
+                commit: Some("abcdef1".to_string()),
+                author: "B".to_string(),
+                time: 200,
+            }),
+        );
+
+        let stdout = entry(&uncommitted, "Not Committed Yet", 50) + &entry(new, "B", 200);
+        assert_eq!(parse_blame(stdout.as_bytes()).unwrap().commit, None);
+
+        assert_eq!(parse_blame(b""), None);
+        assert_eq!(parse_blame(b"fatal: no such path\n"), None);
+    }
+}
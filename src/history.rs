@@ -0,0 +1,189 @@
+//! A history of previous runs, which is only recorded if enabled in the config file:
+//!
+//! ```toml
+//! [history]
+//! enabled = true
+//! ```
+//!
+//! Each line of the history file is a JSON object with the time, working directory, and command
+//! line arguments of a run.
+
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::Context;
+
+use crate::json::Value;
+
+/// The maximum number of runs to keep in the history.
+const MAX_ENTRIES: usize = 1000;
+
+/// The directory for files that persist between runs, "$XDG_STATE_HOME/repatch".
+pub fn state_dir() -> Option<PathBuf> {
+    let state_dir = match std::env::var_os("XDG_STATE_HOME") {
+        Some(x) if !x.is_empty() => PathBuf::from(x),
+        _ => PathBuf::from(std::env::var_os("HOME")?)
+            .join(".local")
+            .join("state"),
+    };
+
+    Some(state_dir.join("repatch"))
+}
+
+fn path() -> anyhow::Result<PathBuf> {
+    let dir = state_dir().ok_or_else(|| anyhow::anyhow!("could not find the state directory"))?;
+    Ok(dir.join("history"))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// Seconds since the unix epoch.
+    pub time: u64,
+    pub cwd: String,
+    /// The command line arguments, not including the program name.
+    pub args: Vec<String>,
+}
+
+impl Entry {
+    /// The command line, quoted for a POSIX shell.
+    pub fn command(&self) -> String {
+        let args: Vec<_> = self.args.iter().map(|x| shell_quote(x)).collect();
+        format!("repatch {}", args.join(" "))
+    }
+
+    fn to_json(&self) -> Value {
+        let args: Vec<Value> = self.args.iter().map(|x| x.as_str().into()).collect();
+        Value::object([
+            ("time", self.time.into()),
+            ("cwd", self.cwd.as_str().into()),
+            ("args", args.into()),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let Some(Value::Array(args)) = value.get("args") else {
+            return None;
+        };
+
+        Some(Self {
+            time: value.get("time")?.as_u64()?,
+            cwd: value.get("cwd")?.as_str()?.to_string(),
+            args: args
+                .iter()
+                .map(|x| x.as_str().map(str::to_string))
+                .collect::<Option<_>>()?,
+        })
+    }
+}
+
+/// Read the history, oldest first. Invalid lines are ignored.
+pub fn read() -> anyhow::Result<Vec<Entry>> {
+    let path = path()?;
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("could not read history file '{}'", path.display()))
+        }
+    };
+
+    Ok(parse(&contents))
+}
+
+fn parse(s: &str) -> Vec<Entry> {
+    s.lines()
+        .filter_map(|line| crate::json::parse(line).ok())
+        .filter_map(|value| Entry::from_json(&value))
+        .collect()
+}
+
+/// Add a run to the history. Runs with arguments that aren't valid UTF-8 aren't recorded.
+pub fn record(args: &[OsString]) -> anyhow::Result<()> {
+    let Some(args) = args
+        .iter()
+        .map(|x| x.to_str().map(str::to_string))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return Ok(());
+    };
+
+    let Some(cwd) = std::env::current_dir()?.to_str().map(str::to_string) else {
+        return Ok(());
+    };
+
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+
+    let mut entries = read()?;
+    entries.push(Entry { time, cwd, args });
+    let skip = entries.len().saturating_sub(MAX_ENTRIES);
+
+    let path = path()?;
+    write(&path, &entries[skip..])
+        .with_context(|| format!("could not write history file '{}'", path.display()))
+}
+
+fn write(path: &Path, entries: &[Entry]) -> std::io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path.parent().unwrap())?;
+
+    // the history may contain private information, so only the user should be able to read it
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+
+    let mut file = std::io::BufWriter::new(file);
+    for entry in entries {
+        writeln!(file, "{}", entry.to_json())?;
+    }
+    file.flush()
+}
+
+/// Quote an argument for a POSIX shell, if needed.
+pub fn shell_quote(arg: &str) -> std::borrow::Cow<'_, str> {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%^".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        return arg.into();
+    }
+
+    format!("'{}'", arg.replace('\'', r"'\''")).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let entry = Entry {
+            time: 1700000000,
+            cwd: "/home/user/my project".to_string(),
+            args: vec!["foo\n".to_string(), "bar".to_string(), ".".to_string()],
+        };
+
+        let s = format!("{}\ninvalid\n{{}}\n", entry.to_json());
+        assert_eq!(parse(&s), [entry]);
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("src/a.rs"), "src/a.rs");
+        assert_eq!(shell_quote("--context=5"), "--context=5");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("$1"), "'$1'");
+    }
+}
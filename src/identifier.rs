@@ -0,0 +1,112 @@
+//! The characters that identifiers can contain in each language, for `--identifier`.
+//!
+//! Identifiers can always contain ASCII letters and digits, "_", and non-ASCII characters. Some
+//! languages allow other characters, such as "-" in CSS and Lisps, which are looked up by the
+//! file's extension. The config file can set the extra characters of any extension:
+//!
+//! ```toml
+//! [identifiers]
+//! clj = "-*?!"
+//! rb = "?!"
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The extra identifier characters of some common languages, by file extension.
+const DEFAULT_EXTRA: &[(&[&str], &str)] = &[
+    (
+        &[
+            "css", "scss", "sass", "less", "html", "htm", "xml", "lisp", "el", "scm", "rkt", "clj",
+            "cljs", "cljc", "edn", "cob", "cbl",
+        ],
+        "-",
+    ),
+    (&["js", "jsx", "mjs", "cjs", "ts", "tsx", "php"], "$"),
+    (&["hs", "ml", "mli", "elm"], "'"),
+];
+
+/// A set of identifier characters.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IdentifierChars([bool; 256]);
+
+impl IdentifierChars {
+    fn new(extra: &str) -> Self {
+        let mut chars = [false; 256];
+        for (b, is_ident) in chars.iter_mut().enumerate() {
+            let b = b as u8;
+            *is_ident = b.is_ascii_alphanumeric() || b == b'_' || !b.is_ascii();
+        }
+        for b in extra.bytes() {
+            chars[usize::from(b)] = true;
+        }
+        Self(chars)
+    }
+
+    pub fn contains(&self, b: u8) -> bool {
+        self.0[usize::from(b)]
+    }
+
+    /// Whether the `range` of `haystack` isn't directly preceded or followed by an identifier
+    /// character, so that it isn't part of a longer identifier.
+    pub fn is_complete(&self, haystack: &[u8], range: std::ops::Range<usize>) -> bool {
+        let before = range.start.checked_sub(1).map(|i| haystack[i]);
+        let after = haystack.get(range.end).copied();
+        !before.is_some_and(|x| self.contains(x)) && !after.is_some_and(|x| self.contains(x))
+    }
+}
+
+/// The identifier characters of each file extension.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Identifiers {
+    /// The extra characters of extensions from the config file, which take precedence over the
+    /// defaults.
+    pub extra: BTreeMap<String, String>,
+}
+
+impl Identifiers {
+    /// The identifier characters of the file at `path`.
+    pub fn chars(&self, path: &Path) -> IdentifierChars {
+        let ext = path.extension().and_then(|x| x.to_str()).unwrap_or("");
+
+        let extra = match self.extra.get(ext) {
+            Some(x) => x.as_str(),
+            None => DEFAULT_EXTRA
+                .iter()
+                .find(|(exts, _)| exts.contains(&ext))
+                .map_or("", |(_, extra)| extra),
+        };
+
+        IdentifierChars::new(extra)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_complete() {
+        let identifiers = Identifiers {
+            extra: BTreeMap::from([("rb".to_string(), "?".to_string())]),
+        };
+
+        let rs = identifiers.chars(Path::new("a.rs"));
+        assert!(rs.is_complete(b"a-b", 0..1));
+        assert!(rs.is_complete(b"a-b", 2..3));
+        assert!(!rs.is_complete(b"ab", 0..1));
+        assert!(!rs.is_complete("éa".as_bytes(), 2..3));
+
+        let css = identifiers.chars(Path::new("dir/a.css"));
+        assert!(!css.is_complete(b"a-b", 0..1));
+        assert!(css.is_complete(b"a-b {", 0..3));
+
+        let rb = identifiers.chars(Path::new("a.rb"));
+        assert!(!rb.is_complete(b"empty?", 0..5));
+        assert!(rb.is_complete(b"a-b", 0..1));
+
+        assert!(identifiers
+            .chars(Path::new("Makefile"))
+            .is_complete(b"", 0..0));
+    }
+}
@@ -0,0 +1,362 @@
+use std::fmt::Write;
+
+/// A JSON value. Objects preserve the order of their members.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Build an object from its members.
+    pub fn object<'a>(members: impl IntoIterator<Item = (&'a str, Value)>) -> Self {
+        Self::Object(
+            members
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+
+    /// Returns the member with the given key if this is an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Self::Object(x) => x.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::Number(x) if x.fract() == 0.0 && *x >= 0.0 && *x <= u64::MAX as f64 => {
+                Some(*x as u64)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(x: bool) -> Self {
+        Self::Bool(x)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(x: u64) -> Self {
+        Self::Number(x as f64)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(x: usize) -> Self {
+        Self::Number(x as f64)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(x: &str) -> Self {
+        Self::String(x.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(x: String) -> Self {
+        Self::String(x)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(x: Vec<Value>) -> Self {
+        Self::Array(x)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(x: Option<T>) -> Self {
+        match x {
+            Some(x) => x.into(),
+            None => Self::Null,
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    /// Writes compact JSON.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Null => write!(f, "null"),
+            Self::Bool(x) => write!(f, "{x}"),
+            // JSON doesn't support NaN or infinity
+            Self::Number(x) if !x.is_finite() => write!(f, "null"),
+            Self::Number(x) => write!(f, "{x}"),
+            Self::String(x) => write_string(f, x),
+            Self::Array(x) => {
+                f.write_char('[')?;
+                for (i, value) in x.iter().enumerate() {
+                    if i != 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                f.write_char(']')
+            }
+            Self::Object(x) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in x.iter().enumerate() {
+                    if i != 0 {
+                        f.write_char(',')?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut impl Write, s: &str) -> std::fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", u32::from(c))?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// Parse a JSON document.
+pub fn parse(s: &str) -> Result<Value, ParseError> {
+    let mut parser = Parser { s, pos: 0 };
+    let value = parser.value()?;
+    parser.whitespace();
+    if parser.pos != s.len() {
+        return Err(parser.error("unexpected trailing characters"));
+    }
+    Ok(value)
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    msg: &'static str,
+    pos: usize,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at position {}", self.msg, self.pos)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, msg: &'static str) -> ParseError {
+        ParseError { msg, pos: self.pos }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: &'static str) -> Result<(), ParseError> {
+        if !self.s[self.pos..].starts_with(expected) {
+            return Err(self.error("unexpected character"));
+        }
+        self.pos += expected.len();
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        self.whitespace();
+        match self.peek() {
+            Some('n') => self.expect("null").map(|_| Value::Null),
+            Some('t') => self.expect("true").map(|_| Value::Bool(true)),
+            Some('f') => self.expect("false").map(|_| Value::Bool(false)),
+            Some('"') => self.string().map(Value::String),
+            Some('[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                self.whitespace();
+                if self.peek() == Some(']') {
+                    self.pos += 1;
+                    return Ok(Value::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.whitespace();
+                    match self.next() {
+                        Some(',') => {}
+                        Some(']') => return Ok(Value::Array(values)),
+                        _ => return Err(self.error("expected ',' or ']'")),
+                    }
+                }
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.whitespace();
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                loop {
+                    self.whitespace();
+                    if self.peek() != Some('"') {
+                        return Err(self.error("expected a string key"));
+                    }
+                    let key = self.string()?;
+                    self.whitespace();
+                    self.expect(":")?;
+                    members.push((key, self.value()?));
+                    self.whitespace();
+                    match self.next() {
+                        Some(',') => {}
+                        Some('}') => return Ok(Value::Object(members)),
+                        _ => return Err(self.error("expected ',' or '}'")),
+                    }
+                }
+            }
+            Some('-' | '0'..='9') => {
+                let start = self.pos;
+                while let Some('-' | '+' | '.' | 'e' | 'E' | '0'..='9') = self.peek() {
+                    self.pos += 1;
+                }
+                self.s[start..self.pos]
+                    .parse()
+                    .map(Value::Number)
+                    .map_err(|_| self.error("invalid number"))
+            }
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.expect("\"")?;
+        let mut rv = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(rv),
+                Some('\\') => match self.next() {
+                    Some('"') => rv.push('"'),
+                    Some('\\') => rv.push('\\'),
+                    Some('/') => rv.push('/'),
+                    Some('b') => rv.push('\u{8}'),
+                    Some('f') => rv.push('\u{c}'),
+                    Some('n') => rv.push('\n'),
+                    Some('r') => rv.push('\r'),
+                    Some('t') => rv.push('\t'),
+                    Some('u') => {
+                        let high = self.hex_u16()?;
+                        let c = if (0xD800..0xDC00).contains(&high) {
+                            // a surrogate pair
+                            self.expect("\\u")?;
+                            let low = self.hex_u16()?;
+                            char::decode_utf16([high, low]).next().unwrap().ok()
+                        } else {
+                            char::from_u32(u32::from(high))
+                        };
+                        rv.push(c.ok_or_else(|| self.error("invalid unicode escape"))?);
+                    }
+                    _ => return Err(self.error("invalid escape")),
+                },
+                Some(c) if c.is_control() => return Err(self.error("unescaped control character")),
+                Some(c) => rv.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn hex_u16(&mut self) -> Result<u16, ParseError> {
+        let hex = self
+            .s
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| self.error("invalid unicode escape"))?;
+        let x = u16::from_str_radix(hex, 16).map_err(|_| self.error("invalid unicode escape"))?;
+        self.pos += 4;
+        Ok(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize() {
+        let value = Value::object([
+            ("a", 1u64.into()),
+            ("b", Value::from("x\"\n\u{1b}é")),
+            (
+                "c",
+                vec![Value::Null, true.into(), Value::Number(-1.5)].into(),
+            ),
+            ("d", Value::object([])),
+            ("e", Value::from(None::<u64>)),
+        ]);
+        assert_eq!(
+            value.to_string(),
+            r#"{"a":1,"b":"x\"\n\u001bé","c":[null,true,-1.5],"d":{},"e":null}"#,
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let s = r#" {"a": 1, "b": "x\"\n\u00e9\ud83d\ude00", "c": [null, true, -1.5e1], "d": {}} "#;
+        let value = parse(s).unwrap();
+        assert_eq!(value.get("a").and_then(Value::as_u64), Some(1));
+        assert_eq!(value.get("b").and_then(Value::as_str), Some("x\"\né😀"));
+        assert_eq!(
+            value.get("c"),
+            Some(&vec![Value::Null, true.into(), Value::Number(-15.0)].into()),
+        );
+        assert_eq!(value.get("d"), Some(&Value::object([])));
+        assert_eq!(value.get("e"), None);
+
+        // round trip
+        assert_eq!(parse(&value.to_string()).unwrap(), value);
+
+        assert!(parse("").is_err());
+        assert!(parse("{").is_err());
+        assert!(parse("[1,]").is_err());
+        assert!(parse(r#"{"a" 1}"#).is_err());
+        assert!(parse(r#""\x""#).is_err());
+        assert!(parse("1 2").is_err());
+    }
+}
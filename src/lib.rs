@@ -0,0 +1,2451 @@
+//! re:patch is a line-oriented find-and-replace tool with a `git add --patch`-like interface.
+//!
+//! This library allows re:patch to be embedded in other programs using a [`Session`], where each
+//! hunk is answered by a callback rather than by prompting the user.
+
+#![deny(unsafe_op_in_unsafe_fn)]
+
+mod cache;
+mod cli;
+mod completions;
+mod config;
+mod decisions;
+mod engine;
+mod fuzzy;
+mod git;
+mod history;
+mod identifier;
+mod json;
+mod lock;
+mod match_input;
+mod normalize;
+mod output;
+mod pager;
+mod parse;
+mod patch;
+mod plan;
+mod preview;
+mod profile;
+mod prompter;
+mod replace;
+mod report;
+mod resume;
+mod retry;
+mod rules;
+mod script;
+mod search;
+mod serve;
+mod session;
+mod template;
+mod transaction;
+mod ui;
+mod undo;
+mod util;
+mod workspace;
+
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::SystemTime;
+
+use anyhow::Context as anyhowContext;
+use bstr::ByteSlice;
+use clap::Parser;
+use grep_regex::{RegexMatcher, RegexMatcherBuilder};
+use ignore::types::TypesBuilder;
+
+use crate::cache::MatchCache;
+use crate::cli::{
+    Args, Backend, BinaryMode, CompletionsArgs, Context, ExecuteArgs, HunkMerge, LineEnding,
+    OutputFormat, PathDisplay, PromptDefault, ScanArgs, StdoutContent, Subcommand, UndoArgs,
+    UnwritableMode,
+};
+use crate::decisions::Decisions;
+use crate::engine::{MatcherOptions, PatternMatcher};
+use crate::patch::{patch_files, prompt_hunk, HunkDecisions};
+use crate::profile::{measure, Phase};
+use crate::replace::{replace_matches, OnlyMatch, ReplaceOptions, WriteFile};
+use crate::search::{
+    check_max_total_matches, collect_matches, find_header_matches, find_matches, for_each_line,
+    limit_matches, read_matches, replacement_changes_line, restrict_between, restrict_lines,
+    search_file, window_columns, KeepLine, LineSink, MatchInfo, MatchLimit, PathFilters,
+    SearchConfig, SearchFilter,
+};
+use crate::ui::{
+    error, style, warning, FilePaths, MainMenuOption, MenuOption, COUNT_STYLE, FILENAME_STYLE,
+};
+use crate::util::{MatchFilter, Replacement};
+
+pub use crate::patch::FileOutcome;
+pub use crate::replace::Hunk;
+pub use crate::session::{Answer, Error, Session, SessionBuilder};
+
+/// The entry point of the command line program.
+#[doc(hidden)]
+pub fn main() -> ExitCode {
+    match start(std::env::args_os().collect()) {
+        Ok(code) => code,
+        Err(e) => {
+            error!("{e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Handle the arguments that must be processed before parsing the command line (the history and
+/// presets), and then run.
+fn start(mut args: Vec<OsString>) -> anyhow::Result<ExitCode> {
+    let config = crate::config::Config::load()?;
+    crate::ui::set_keys(config.keys.clone());
+
+    // only look at the arguments before any "--"
+    let end = args.iter().position(|x| x == "--").unwrap_or(args.len());
+
+    if args[..end].iter().any(|x| x == "--history") {
+        for (i, entry) in crate::history::read()?.iter().enumerate() {
+            let num = style!(i + 1, &COUNT_STYLE);
+            println!("{num} ({}) {}", entry.cwd, entry.command());
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if args[..end].iter().any(|x| x == "--type-list") {
+        print_type_list();
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(idx) = args[..end].iter().position(|x| x == "--repeat-last") {
+        args.remove(idx);
+        let Some(last) = crate::history::read()?.pop() else {
+            anyhow::bail!("there are no runs in the history");
+        };
+
+        println!("Repeating: {}", last.command());
+
+        args.splice(1..1, last.args.into_iter().map(OsString::from));
+    }
+
+    match args.get(1).and_then(|x| Subcommand::from_arg(x)) {
+        Some(Subcommand::Run) => {
+            args.remove(1);
+        }
+        Some(Subcommand::Apply) => args[1] = OsString::from("--apply"),
+        Some(Subcommand::Plan) => args[1] = OsString::from("--write-plan"),
+        Some(Subcommand::Eol) => {
+            // `eol --to <EOL>` is the same as `--convert-eol <EOL>`
+            args.remove(1);
+            if !rename_flag(&mut args, "to", "convert-eol") {
+                anyhow::bail!("the 'eol' subcommand requires '--to <EOL>'");
+            }
+        }
+        Some(Subcommand::ServePreview) => {
+            args[1] = OsString::from("--serve-preview");
+            rename_flag(&mut args, "port", "preview-port");
+            rename_flag(&mut args, "host", "preview-host");
+        }
+        Some(Subcommand::Execute) => {
+            let execute_args = ExecuteArgs::parse_from(&args[1..]);
+            return crate::plan::execute(&execute_args.plan);
+        }
+        Some(Subcommand::Template) => {
+            let Some(expanded) = crate::template::expand(std::mem::take(&mut args), &config)?
+            else {
+                return Ok(ExitCode::SUCCESS);
+            };
+            args = expanded;
+        }
+        Some(Subcommand::Scan) => {
+            let scan_args = ScanArgs::parse_from(&args[1..]);
+            let pager = match scan_args.no_pager {
+                true => None,
+                false => crate::pager::start()?,
+            };
+            let result = scan(scan_args, &config);
+            if let Some(pager) = pager {
+                pager.finish();
+            }
+            return result;
+        }
+        Some(Subcommand::Undo) => {
+            let undo_args = UndoArgs::parse_from(&args[1..]);
+            if undo_args.list {
+                let now = SystemTime::now();
+                for run in crate::undo::list()?.iter().rev() {
+                    let age = now
+                        .duration_since(
+                            SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(run.time),
+                        )
+                        .unwrap_or_default();
+                    println!(
+                        "{} ({}) {} file{}",
+                        style!(&run.id, &COUNT_STYLE),
+                        crate::ui::format_age(age),
+                        run.files,
+                        if run.files == 1 { "" } else { "s" },
+                    );
+                }
+                return Ok(ExitCode::SUCCESS);
+            }
+            for path in crate::undo::undo(undo_args.id.as_deref(), undo_args.force)? {
+                println!("Restored '{}'.", path.display());
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Subcommand::Completions) => {
+            let completions_args = CompletionsArgs::parse_from(&args[1..]);
+            print!("{}", crate::completions::script(completions_args.shell));
+            return Ok(ExitCode::SUCCESS);
+        }
+        None => {}
+    }
+
+    let parsed = Args::parse_from(crate::config::expand_preset(args.clone(), &config)?);
+
+    if config.history {
+        crate::history::record(&args[1..])?;
+    }
+
+    if parsed.profile {
+        crate::profile::enable();
+    }
+
+    crate::ui::set_plain(parsed.plain);
+    crate::prompter::init(parsed.plain || parsed.no_readline);
+    crate::ui::set_test_mode(parsed.test_mode);
+    if parsed.test_mode {
+        let stdin = std::io::BufReader::new(std::io::stdin());
+        crate::prompter::set(Box::new(crate::prompter::Echo::new(stdin)));
+    }
+    crate::ui::set_json_errors(parsed.errors == cli::ErrorFormat::Json);
+    if parsed.hook {
+        let force_color =
+            std::env::var_os("FORCE_COLOR").is_some_and(|x| !x.is_empty() && x != "0");
+        crate::ui::set_color(force_color);
+    }
+    if let Some(dir) = &parsed.temp_dir {
+        crate::util::set_temp_dir(dir.clone());
+    }
+    if let Some(change) = &parsed.chmod {
+        crate::util::set_mode_change(change.clone());
+    }
+
+    // the temporary files are written to the system's temporary directory if this fails
+    let _workspace = match crate::workspace::create() {
+        Ok(x) => Some(x),
+        Err(e) => {
+            warning!("{e:#}");
+            None
+        }
+    };
+
+    // hunks are paged one at a time when prompting, but not with `--plain` since the pager's
+    // screen can't be read by a screen reader
+    crate::pager::set_page_hunks(
+        !parsed.no_pager && !parsed.hook && !parsed.plain && !parsed.test_mode,
+    );
+
+    // with `--stdout`, everything else is written to stderr (and paged there)
+    if parsed.stdout.is_some() {
+        crate::ui::divert_to_stderr().context("could not redirect stdout to stderr")?;
+    }
+
+    // only output that doesn't need any user input is paged
+    let pager = match (parsed.show || parsed.check || parsed.search_only)
+        && !parsed.no_pager
+        && !parsed.hook
+        && !parsed.test_mode
+    {
+        true => crate::pager::start()?,
+        false => None,
+    };
+
+    let result = run(parsed, &config);
+    if let Some(pager) = pager {
+        pager.finish();
+    }
+    crate::profile::report();
+    result
+}
+
+/// Rename the flag `--<from>` (before any "--") to `--<to>`, for subcommands that are rewritten
+/// to flags. Returns whether the flag was found.
+fn rename_flag(args: &mut [OsString], from: &str, to: &str) -> bool {
+    let end = args.iter().position(|x| x == "--").unwrap_or(args.len());
+    let mut found = false;
+    for arg in &mut args[1..end] {
+        let Some(rest) = arg.to_str().and_then(|x| x.strip_prefix("--")) else {
+            continue;
+        };
+        if rest == from {
+            *arg = OsString::from(format!("--{to}"));
+            found = true;
+        } else if let Some(value) = rest.strip_prefix(from).and_then(|x| x.strip_prefix('=')) {
+            *arg = OsString::from(format!("--{to}={value}"));
+            found = true;
+        }
+    }
+    found
+}
+
+/// Print each file type that can be used with `--type`, and its globs.
+fn print_type_list() {
+    let mut types = TypesBuilder::new();
+    types.add_defaults();
+    for def in types.definitions() {
+        println!("{}: {}", def.name(), def.globs().join(", "));
+    }
+}
+
+/// Search without replacing anything, and print the matching lines.
+fn scan(args: ScanArgs, config: &crate::config::Config) -> anyhow::Result<ExitCode> {
+    let matcher = PatternMatcher::from(
+        RegexMatcherBuilder::new()
+            .case_insensitive(args.ignore_case)
+            .build(&args.find)?,
+    );
+
+    let filters = PathFilters::new(&args.glob, &args.file_type, &args.exclude, config)?;
+
+    let matches = match find_matches(
+        &matcher,
+        &args.paths,
+        filters,
+        &SearchConfig::new(b'\n'),
+        args.ignore_errors,
+        None,
+        SearchFilter {
+            max_total: args.max_total_matches,
+            ..Default::default()
+        },
+    ) {
+        Ok(x) => x,
+        Err(num_errors) => anyhow::bail!(
+            "found {} error{}",
+            style!(num_errors, &COUNT_STYLE),
+            if num_errors == 1 { "" } else { "s" },
+        ),
+    };
+
+    check_max_total_matches(&matches, args.max_total_matches)?;
+
+    if args.only_matching {
+        if args.summary {
+            print_match_summary(&matcher, &matches, b'\n')?;
+        } else {
+            print_matched_texts(&matcher, &matches, args.unique)?;
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let match_count = matches.values().map(|i| i.lines.len()).sum::<usize>();
+    println!(
+        "Found {} match{} in {} file{}.",
+        style!(match_count, &COUNT_STYLE),
+        if match_count == 1 { "" } else { "es" },
+        style!(matches.len(), &COUNT_STYLE),
+        if matches.len() == 1 { "" } else { "s" },
+    );
+
+    for (path, match_info) in &matches {
+        for_each_line(path, &match_info.lines, b'\n', |line_num, line| {
+            let line = crate::ui::escape_ansi(line);
+            let path = match_info.display_path.display();
+            println!("{path}:{}:{}", line_num + 1, line.as_bstr());
+        })
+        .with_context(|| format!("could not read file '{}'", path.display()))?;
+    }
+
+    if args.summary {
+        print_match_summary(&matcher, &matches, b'\n')?;
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run(mut args: Args, config: &crate::config::Config) -> anyhow::Result<ExitCode> {
+    if args.replace_file.is_some() || args.ensure_header.is_some() || args.search_only {
+        // the `<REPLACE>` argument is omitted, so the first path was parsed as the replacement
+        if let Some(path) = args.replace.take() {
+            args.paths.insert(0, path.into());
+        }
+    }
+    if args.rules.is_some() {
+        // the `<FIND>` and `<REPLACE>` arguments are omitted, so the first paths were parsed as
+        // them
+        if let Some(path) = args.replace.take() {
+            args.paths.insert(0, path.into());
+        }
+        args.paths.insert(0, std::mem::take(&mut args.find).into());
+    }
+    if let Some(eol) = args.convert_eol {
+        // the `<FIND>` and `<REPLACE>` arguments are omitted, so the first paths were parsed as
+        // them
+        if let Some(path) = args.replace.take() {
+            args.paths.insert(0, path.into());
+        }
+        args.paths.insert(0, std::mem::take(&mut args.find).into());
+        // the lines that end with the other line ending
+        args.find = match eol {
+            LineEnding::Lf => r"(?m)\r$".to_string(),
+            LineEnding::Crlf => r"(?m)(?:^|[^\r])$".to_string(),
+        };
+    }
+    // without any paths (or with the path "-"), stdin is filtered to stdout
+    let from_stdin = match args.paths.as_slice() {
+        [] => {
+            args.matches_from.is_none()
+                && !args.paths_from_stdin
+                && !args.retry_failed
+                && !args.hook
+                && !args.repo_root
+                && !args.explain_replacement
+        }
+        [path] => path == Path::new("-"),
+        paths if paths.iter().any(|x| x == Path::new("-")) => {
+            anyhow::bail!("the path '-' (stdin) can't be given with other paths");
+        }
+        _ => false,
+    };
+    let stdin_input = match from_stdin {
+        true => Some(read_stdin_input(&mut args)?),
+        false => None,
+    };
+
+    if let Some(dir) = &args.patch_root {
+        let dir = std::fs::canonicalize(dir)
+            .with_context(|| format!("could not find the patch root '{}'", dir.display()))?;
+        args.patch_root = Some(dir);
+    }
+    if let Some(file) = &args.replace_file {
+        let replace = std::fs::read_to_string(file)
+            .with_context(|| format!("could not read '{}'", file.display()))?;
+        args.replace = Some(replace);
+    }
+
+    let header = match &args.ensure_header {
+        Some(file) => {
+            let mut header = std::fs::read(file)
+                .with_context(|| format!("could not read the header '{}'", file.display()))?;
+            if header.is_empty() {
+                anyhow::bail!("the header '{}' is empty", file.display());
+            }
+            if !header.ends_with(b"\n") {
+                header.push(b'\n');
+            }
+            Some(header)
+        }
+        None => None,
+    };
+
+    if args.format == OutputFormat::Sarif && !args.check {
+        anyhow::bail!("the sarif output format can only be used with '--check'");
+    }
+    if args.format == OutputFormat::Json && !args.show {
+        anyhow::bail!("the json output format can only be used with '--show'");
+    }
+    match args.stdout {
+        Some(StdoutContent::Diff) => args.output_patch = Some(PathBuf::from("-")),
+        Some(StdoutContent::Json) if !args.show && !args.apply => {
+            anyhow::bail!("'--stdout json' can only be used with '--show' or '--apply'");
+        }
+        Some(StdoutContent::Json) => args.json = true,
+        Some(StdoutContent::None) | None => {}
+    }
+    if args.json && !args.show && !args.apply {
+        anyhow::bail!("'--json' can only be used with '--show' or '--apply'");
+    }
+    if args.atomic && args.unwritable != UnwritableMode::Fail {
+        anyhow::bail!("'--atomic' can't be used with '--unwritable'");
+    }
+    let report_format = match args.report.as_slice() {
+        [format, _] => match crate::report::ReportFormat::from_arg(format) {
+            Some(x) => Some(x),
+            None => anyhow::bail!("unknown report format '{format}' (expected 'md' or 'html')"),
+        },
+        _ => None,
+    };
+    if args.format == OutputFormat::WordDiff && !args.show {
+        anyhow::bail!("the word-diff output format can only be used with '--show'");
+    }
+    // a hook shows the changes by default, and fails if anything would be or was changed
+    if args.hook {
+        if args.paths.is_empty() && args.matches_from.is_none() {
+            args.paths_from_stdin = true;
+        }
+        if !args.apply {
+            args.show = true;
+        }
+        args.dry_run_exit_code = true;
+    }
+
+    if args.dry_run_exit_code && !args.show && !args.check && !args.hook {
+        anyhow::bail!("'--dry-run-exit-code' can only be used with '--show' or '--check'");
+    }
+    if args.paths_from_stdin && !args.apply && !args.show {
+        anyhow::bail!("'--paths-from-stdin' can only be used with '--apply' or '--show'");
+    }
+
+    // the copies in the sandbox are always patched without prompting
+    if args.sandbox.is_some() {
+        args.apply = true;
+    }
+
+    if args.retry_failed {
+        args.paths = crate::retry::load()?;
+        if args.paths.is_empty() {
+            println!("No files failed in the last run.");
+            return Ok(ExitCode::SUCCESS);
+        }
+    }
+
+    if args.repo_root {
+        let cwd = std::fs::canonicalize(std::env::current_dir()?)?;
+        let Some(root) = crate::git::find_root(&cwd) else {
+            anyhow::bail!(
+                "'--repo-root' was given, but '{}' isn't in a repository",
+                cwd.display(),
+            );
+        };
+        args.paths = vec![root.to_path_buf()];
+        args.path_display.get_or_insert(PathDisplay::FromRoot);
+    }
+
+    // only runs that may modify the files are locked, and the lock is held until the run ends
+    let read_only = args.show || args.check || args.serve_preview || args.sandbox.is_some();
+    let _lock = match read_only || args.paths.is_empty() || args.explain_replacement {
+        true => None,
+        false => crate::lock::acquire(&args.paths, args.force)?,
+    };
+
+    let mut matcher = RegexMatcherBuilder::new();
+    matcher.case_insensitive(args.ignore_case);
+    matcher.line_terminator(args.line_terminator);
+    // without a line terminator, the matches can span several lines
+    matcher.multi_line(args.multiline);
+    let rules = match &args.rules {
+        Some(path) => crate::rules::load(path, &matcher)?,
+        None => Vec::new(),
+    };
+    // the lines that any of the rules match are searched for
+    if !rules.is_empty() {
+        args.find = crate::rules::pattern(&rules)?;
+    }
+    if args.fixed_strings {
+        args.find = regex_syntax::escape(&args.find);
+    }
+    let pattern = match args.normalize {
+        Some(_) => crate::normalize::pattern(&args.find)?,
+        None => args.find.clone(),
+    };
+    let options = MatcherOptions {
+        case_insensitive: args.ignore_case,
+        line_terminator: args.line_terminator,
+        multi_line: args.multiline,
+    };
+    let matcher = crate::engine::build(&pattern, args.engine, options)?;
+
+    // a match of the empty string would be between every pair of characters
+    if crate::util::matches_empty_anywhere(&pattern) {
+        anyhow::bail!(
+            "'<FIND>' matches the empty string, so it would match between every character; use a \
+            pattern that matches at least one character, or anchor it with '^', '$', or '\\b'"
+        );
+    }
+    if args.multiline {
+        for anchor in crate::util::unmatchable_anchors(&pattern) {
+            let (position, next) = match anchor {
+                '^' => ("start", "preceded"),
+                _ => ("end", "followed"),
+            };
+            warning!(
+                "'<FIND>' has a '{anchor}' that's {next} by something other than a line break, so \
+                it can't match (with '--multiline', '{anchor}' matches at the {position} of each \
+                line)"
+            );
+        }
+    }
+
+    let exclude_lines = match &args.exclude_lines {
+        Some(x) => Some(
+            RegexMatcherBuilder::new()
+                .case_insensitive(args.ignore_case)
+                .line_terminator(args.line_terminator)
+                .build(x)
+                .context("invalid '--exclude-lines' regex")?,
+        ),
+        None => None,
+    };
+    if exclude_lines.is_some() && args.backend == Backend::GitGrep {
+        anyhow::bail!("'--exclude-lines' can't be used with the git-grep backend");
+    }
+
+    // with `--normalize`, each replacement is normalized to the form of its match when it's
+    // replaced
+    let replace_with = args.replacement().to_string();
+    if args.explain_replacement {
+        explain_replacement(&matcher, replace_with.as_bytes());
+        return Ok(ExitCode::SUCCESS);
+    }
+    let alternatives = args.replace_one_of.clone();
+
+    let script = match &args.replace_script {
+        Some(path) => {
+            let source = std::fs::read_to_string(path)
+                .with_context(|| format!("could not read the script '{}'", path.display()))?;
+            let script = crate::script::Script::parse(&source)
+                .with_context(|| format!("could not parse the script '{}'", path.display()))?;
+            Some((source, script))
+        }
+        None => None,
+    };
+    let replacement = match &script {
+        Some((_, script)) => Replacement::Script(script, &replace_with),
+        None if !rules.is_empty() => Replacement::Rules(&rules),
+        None if args.fixed_strings => Replacement::Literal(replace_with.as_bytes()),
+        None => Replacement::Template(replace_with.as_bytes()),
+    };
+
+    let line_terminator = args.line_terminator.unwrap_or(b'\n');
+
+    let identifiers = args.identifier.then_some(&config.identifiers);
+
+    let filters = PathFilters::from_args(&args, config)?;
+    let stream_filters = match args.paths_from_stdin {
+        true => Some(PathFilters::from_args(&args, config)?),
+        false => None,
+    };
+
+    let windows = match args.byte_range.as_slice() {
+        [] => None,
+        ranges => {
+            let path = match args.paths.as_slice() {
+                [path] if path.is_file() => path,
+                _ => anyhow::bail!("'--byte-range' can only be used when searching a single file"),
+            };
+            let bytes = std::fs::read(path)
+                .with_context(|| format!("could not read file '{}'", path.display()))?;
+            let windows = crate::util::line_windows(&bytes, ranges, line_terminator);
+            Some(windows.map_err(|line_num| {
+                anyhow::anyhow!(
+                    "'--byte-range' can't have separate ranges on the same line (line {})",
+                    line_num + 1,
+                )
+            })?)
+        }
+    };
+
+    let between = match args.between.as_slice() {
+        [begin, end] => Some((begin.as_str(), end.as_str())),
+        _ => None,
+    };
+
+    let convert_eol = args.convert_eol.map(crate::git::Eol::from);
+
+    let is_changed = |path: &Path, line_num, line: &[u8]| {
+        let is_match = |matcher: &RegexMatcher| grep_matcher::Matcher::is_match(matcher, line);
+        if exclude_lines.as_ref().is_some_and(|x| is_match(x).unwrap()) {
+            return false;
+        }
+        if let Some(eol) = convert_eol {
+            return crate::util::convert_eol(line, eol) != line;
+        }
+        let filter = MatchFilter {
+            columns: args.columns.clone(),
+            identifier: identifiers.map(|x| x.chars(path)),
+            normalize: args.normalize,
+            line_terminator,
+        };
+        if args.search_only {
+            return !crate::util::match_captures(&matcher, &filter, line)
+                .unwrap()
+                .is_empty();
+        }
+        replacement_changes_line(&matcher, replacement, &filter, path, line_num, line)
+    };
+
+    // the files that couldn't be searched, for `--retry-failed`
+    let failed = std::cell::RefCell::new(Vec::new());
+    let on_failure = |path: &Path| failed.borrow_mut().push(path.to_path_buf());
+
+    let filter = SearchFilter {
+        max_total: args.max_total_matches,
+        max_files: args.limit_files,
+        // with columns, the first matching line might not have a match within the columns
+        first_per_file: args.first_only
+            && args.columns.is_none()
+            && windows.is_none()
+            && between.is_none(),
+        keep_line: Some(&is_changed),
+        on_failure: Some(&on_failure),
+    };
+
+    let search_started = std::time::Instant::now();
+    let matches = match args.matches_from {
+        // the files are searched later as their paths are read, or stdin is searched
+        None if args.paths_from_stdin || stdin_input.is_some() => Ok(BTreeMap::new()),
+        Some(source) => {
+            let matches = read_matches(source, args.ignore_errors)?;
+            // stdin was used for the matches, so the user must be prompted using the terminal
+            let automatic = args.apply || args.show || args.check || !args.apply_hunks.is_empty();
+            if !automatic || args.confirm_files || args.menu {
+                crate::util::reopen_stdin_tty()
+                    .context("could not open the terminal to prompt for input")?;
+            }
+            matches
+        }
+        None if header.is_some() => {
+            let matches = find_header_matches(
+                &matcher,
+                header.as_deref().unwrap(),
+                &args.paths,
+                filters,
+                &SearchConfig::from_args(&args),
+                args.ignore_errors,
+                Some(&on_failure),
+            )?;
+            crate::retry::save(&failed.take())?;
+            matches
+        }
+        None if args.backend == Backend::GitGrep => {
+            let lines = measure(Phase::Search, || {
+                crate::git::grep(&pattern, args.ignore_case, &args.paths)
+            })?;
+            collect_matches(lines, args.ignore_errors)
+        }
+        None => {
+            let mut cache = args.cache.then(|| {
+                // every option that can change which lines of a file are recorded: only the first
+                // match of each file is recorded when searching with `--first-only`, lines that
+                // the replacement wouldn't change (or that the match filters reject) aren't
+                // recorded, and the searcher options can change which lines match
+                let options = [
+                    ("pattern", pattern.clone()),
+                    ("fancy", matcher.is_fancy().to_string()),
+                    ("ignore-case", args.ignore_case.to_string()),
+                    ("line-terminator", line_terminator.to_string()),
+                    ("multiline", args.multiline.to_string()),
+                    ("first-only", filter.first_per_file.to_string()),
+                    ("search-only", args.search_only.to_string()),
+                    ("replace", replace_with.clone()),
+                    ("fixed-strings", args.fixed_strings.to_string()),
+                    (
+                        "script",
+                        script.as_ref().map_or("", |(source, _)| source).to_string(),
+                    ),
+                    (
+                        "rules",
+                        format!("{:?}", rules.iter().map(|x| &x.replace).collect::<Vec<_>>()),
+                    ),
+                    ("columns", format!("{:?}", args.columns)),
+                    ("identifiers", format!("{identifiers:?}")),
+                    ("normalize", format!("{:?}", args.normalize)),
+                    ("exclude-lines", format!("{:?}", args.exclude_lines)),
+                    ("convert-eol", format!("{:?}", args.convert_eol)),
+                    ("no-bom-sniffing", args.no_bom_sniffing.to_string()),
+                    ("binary", format!("{:?}", args.binary)),
+                    ("max-buffer-size", format!("{:?}", args.max_buffer_size)),
+                ];
+                MatchCache::load(&crate::cache::key(&options))
+            });
+            let matches = find_matches(
+                &matcher,
+                &args.paths,
+                filters,
+                &SearchConfig::from_args(&args),
+                args.ignore_errors,
+                cache.as_mut(),
+                filter,
+            );
+            if let Some(cache) = cache {
+                cache.write()?;
+            }
+            crate::retry::save(&failed.take())?;
+            matches
+        }
+    };
+
+    let mut matches = match matches {
+        Ok(x) => x,
+        Err(num_errors) => anyhow::bail!(
+            "found {} error{}",
+            style!(num_errors, &COUNT_STYLE),
+            if num_errors == 1 { "" } else { "s" },
+        ),
+    };
+
+    check_max_total_matches(&matches, args.max_total_matches)?;
+
+    // the search stopped early because of `--limit-files`
+    let matches_found = matches.len();
+    let limited = args
+        .limit_files
+        .is_some_and(|max| matches_found as u64 >= max);
+
+    let only = match (args.first_only, args.last_only) {
+        (true, _) => Some(OnlyMatch::First),
+        (_, true) => Some(OnlyMatch::Last),
+        _ => None,
+    };
+
+    if let Some(markers) = between {
+        for (path, match_info) in matches.iter_mut() {
+            let bytes = std::fs::read(path)
+                .with_context(|| format!("could not read file '{}'", path.display()))?;
+            restrict_between(markers, &bytes, &mut match_info.lines, line_terminator);
+        }
+        matches.retain(|_, match_info| !match_info.lines.is_empty());
+    }
+
+    if args.columns.is_some() || windows.is_some() || only.is_some() {
+        for (path, match_info) in matches.iter_mut() {
+            File::open(path)
+                .and_then(|src| {
+                    restrict_lines(
+                        &matcher,
+                        args.columns.as_ref(),
+                        windows.as_ref(),
+                        only,
+                        src,
+                        &mut match_info.lines,
+                        line_terminator,
+                    )
+                })
+                .with_context(|| format!("could not read file '{}'", path.display()))?;
+        }
+        matches.retain(|_, match_info| !match_info.lines.is_empty());
+    }
+
+    if args.only_dirty || args.only_clean {
+        let mut status = crate::git::StatusCache::default();
+
+        let mut excluded = Vec::new();
+        for path in matches.keys() {
+            let is_dirty = status
+                .is_dirty(path)
+                .with_context(|| format!("could not get the git status of '{}'", path.display()))?;
+            if is_dirty != args.only_dirty {
+                excluded.push(path.clone());
+            }
+        }
+
+        for path in excluded {
+            matches.remove(&path);
+        }
+    }
+
+    if !args.paired.is_empty() {
+        // files whose companion has no matches are left alone
+        let unpaired: Vec<_> = matches
+            .keys()
+            .filter(|path| companion(&args.paired, path).is_some_and(|x| !matches.contains_key(&x)))
+            .cloned()
+            .collect();
+
+        for path in unpaired {
+            matches.remove(&path);
+        }
+    }
+
+    if let Some(path_display) = args.path_display {
+        let mut status = crate::git::StatusCache::default();
+        for (path, info) in matches.iter_mut() {
+            info.display_path = display_path(path, path_display, &mut status)
+                .with_context(|| format!("could not resolve the path '{}'", path.display()))?;
+        }
+    }
+
+    // the columns of the matches that can be replaced on each line of each file (by display
+    // path), and the number of matches that can't
+    let mut limited_windows = None;
+    let mut over_limit = 0;
+    if args.max_count.is_some() || args.nth.is_some() {
+        if stdin_input.is_some() {
+            anyhow::bail!("'--max-count' and '--nth' can't be used when reading from stdin");
+        }
+        let limit = MatchLimit {
+            max_count: args.max_count,
+            nth: args.nth,
+            nth_per_file: args.nth_per_file,
+        };
+
+        let mut by_file = BTreeMap::new();
+        for (path, match_info) in matches.iter_mut() {
+            let filter = |line_num| MatchFilter {
+                columns: window_columns(args.columns.as_ref(), windows.as_ref(), line_num),
+                identifier: identifiers.map(|x| x.chars(path)),
+                normalize: args.normalize,
+                line_terminator,
+            };
+            let (line_windows, skipped) = File::open(path)
+                .and_then(|src| {
+                    limit_matches(
+                        &matcher,
+                        filter,
+                        limit,
+                        src,
+                        &mut match_info.lines,
+                        line_terminator,
+                    )
+                })
+                .with_context(|| format!("could not read file '{}'", path.display()))?;
+            over_limit += skipped;
+            by_file.insert(match_info.display_path.clone(), line_windows);
+        }
+        matches.retain(|_, match_info| !match_info.lines.is_empty());
+        limited_windows = Some(by_file);
+    }
+
+    let search_duration = search_started.elapsed();
+
+    let match_count = matches.values().map(|i| i.lines.len()).sum::<usize>();
+    // the sarif and json outputs and the serve protocol must be valid json
+    let json_output = matches!(args.format, OutputFormat::Sarif | OutputFormat::Json) || args.json;
+    if !json_output && !args.serve && !args.paths_from_stdin && stdin_input.is_none() {
+        println!(
+            "Found {} match{} in {} file{}.",
+            style!(match_count, &COUNT_STYLE),
+            if match_count == 1 { "" } else { "es" },
+            style!(matches.len(), &COUNT_STYLE),
+            if matches.len() == 1 { "" } else { "s" },
+        );
+        if limited {
+            println!(
+                "Stopped searching after {} file{} with matches ('--limit-files'), so the \
+                remaining files were skipped.",
+                style!(matches_found, &COUNT_STYLE),
+                if matches_found == 1 { "" } else { "s" },
+            );
+        }
+        if over_limit > 0 {
+            println!(
+                "Skipped {} match{} over the '--max-count' or '--nth' limit.",
+                style!(over_limit, &COUNT_STYLE),
+                if over_limit == 1 { "" } else { "es" },
+            );
+        }
+    }
+
+    if args.group_by_dir && !json_output && !args.serve {
+        print_dir_summary(&matches, args.natural_sort);
+    }
+
+    if args.summary && !json_output && !args.serve {
+        print_match_summary(&matcher, &matches, line_terminator)?;
+    }
+
+    if args.estimate && !json_output && !args.serve && !matches.is_empty() {
+        let padding = match args.context {
+            Context::Num(x) => x,
+            Context::Infinite => u64::MAX,
+        };
+        print_estimate(&matches, padding, args.hunk_merge);
+    }
+
+    if matches.is_empty() && args.explain_no_matches && stdin_input.is_none() {
+        explain_no_matches(&args, config, &pattern, &is_changed)?;
+    }
+
+    if args.check {
+        let options = ReplaceOptions {
+            matcher: &matcher,
+            replacement,
+            padding: 0,
+            hunk_merge: args.hunk_merge,
+            line_terminator,
+            columns: args.columns.clone(),
+            identifiers,
+            normalize: args.normalize,
+            only,
+            prompt_timeout: None,
+            max_line_len: None,
+            fold_context: None,
+            diff_viewer: None,
+            multiline: args.multiline,
+            windows: windows.clone(),
+            limited: limited_windows.as_ref(),
+            between,
+            exclude_lines: exclude_lines.as_ref(),
+            alternatives: &[],
+            capture_names: &[],
+            details: false,
+            blame: false,
+            header: header.as_deref(),
+            convert_eol,
+            fix_whitespace: false,
+            fix_eol: None,
+        };
+        return check_matches(&args, &options, &matches);
+    }
+
+    if args.confirm_files && !matches.is_empty() {
+        println!();
+
+        let Some(selected) = select_files(&matches, |_| true, args.group_by_dir, args.natural_sort)
+        else {
+            return Ok(ExitCode::SUCCESS);
+        };
+
+        matches.retain(|path, _| selected.contains(path));
+    }
+
+    if args.pick && !matches.is_empty() {
+        let files: Vec<_> = matches
+            .values()
+            .map(|info| (info.display_path.as_path(), info.lines.len()))
+            .collect();
+
+        if !std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+            anyhow::bail!("'--pick' can only be used when stdin is a terminal");
+        }
+
+        let Some(selected) = crate::ui::fuzzy_pick_prompt(&files)? else {
+            return Ok(ExitCode::SUCCESS);
+        };
+
+        let mut selected = selected.into_iter();
+        matches.retain(|_, _| selected.next().unwrap());
+    }
+
+    let capture_names = crate::util::capture_names(&pattern);
+
+    // common options we'll use during the find & replace process across all files
+    let config = ReplaceOptions {
+        matcher: &matcher,
+        replacement,
+        padding: match args.context {
+            Context::Num(x) => x,
+            Context::Infinite => u64::MAX,
+        },
+        hunk_merge: args.hunk_merge,
+        line_terminator,
+        columns: args.columns.clone(),
+        identifiers,
+        normalize: args.normalize,
+        only,
+        max_line_len: (args.max_line_len > 0).then_some(args.max_line_len),
+        fold_context: (args.fold_context > 0).then_some(args.fold_context),
+        diff_viewer: args.diff_viewer.as_deref(),
+        multiline: args.multiline,
+        windows,
+        limited: limited_windows.as_ref(),
+        between,
+        exclude_lines: exclude_lines.as_ref(),
+        alternatives: &alternatives,
+        capture_names: &capture_names,
+        details: !args.hook,
+        blame: args.blame,
+        header: header.as_deref(),
+        convert_eol,
+        fix_whitespace: args.fix_whitespace,
+        fix_eol: args.fix_eol.map(Into::into),
+        prompt_timeout: args
+            .prompt_timeout
+            .map(|duration| crate::ui::PromptTimeout {
+                duration,
+                default: match args.prompt_default {
+                    PromptDefault::Yes => MenuOption::Yes,
+                    PromptDefault::No => MenuOption::No,
+                    PromptDefault::Quit => MenuOption::Quit,
+                },
+                expired: Default::default(),
+            }),
+    };
+
+    if let Some((input, output)) = stdin_input {
+        return filter_stdin(&args, &config, &is_changed, &input, output);
+    }
+
+    if args.idempotent_check {
+        return idempotent_check(&config, &matches);
+    }
+
+    if args.search_only {
+        return print_search_hunks(&config, &matches);
+    }
+
+    // the header and line endings aren't replacements of the matches
+    if !args.no_rescan_guard
+        && matches!(args.format, OutputFormat::Human | OutputFormat::WordDiff)
+        && !args.serve
+        && header.is_none()
+        && convert_eol.is_none()
+    {
+        if let Some(line) = find_compounding_lines(&config, &matches, false)?.first() {
+            warning!(
+                "replacing the matches again would change {}:{} from \"{}\" to \"{}\", so \
+                re-running this command would compound the changes (use '--no-rescan-guard' to \
+                hide this warning)",
+                line.path.display(),
+                line.line_num + 1,
+                crate::ui::escape_ansi(&line.once).as_bstr(),
+                crate::ui::escape_ansi(&line.twice).as_bstr(),
+            );
+        }
+    }
+
+    if args.serve {
+        crate::serve::serve(&config, matches)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if args.serve_preview {
+        crate::preview::serve(&config, &matches, &args.preview_host, args.preview_port)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // an interactive run that's interrupted can be resumed by running the same command again
+    let resumable = !read_only && !args.apply && !args.menu && stream_filters.is_none();
+    if args.session.is_some() && !resumable {
+        anyhow::bail!("'--session' can only be used when prompting for each hunk");
+    }
+    let (resume, resumed) = match (resumable, &args.session) {
+        (true, Some(session)) => resume_session(&args, session, &mut matches)?,
+        (true, None) => resume_run(&args, &mut matches)?,
+        (false, _) => (None, None),
+    };
+
+    let mut decisions = HunkDecisions {
+        replay: match &args.replay_decisions {
+            Some(path) => Some(Decisions::read(path)?),
+            None => None,
+        },
+        export: args.export_decisions.as_ref().map(|_| Decisions::default()),
+        rejected: match args.skip_previously_rejected {
+            true => Some(Decisions::read_rejected()?),
+            false => None,
+        },
+        remember: args.remember_rejected.then(Decisions::default),
+        plan: args
+            .write_plan
+            .as_ref()
+            .map(|_| crate::plan::Plan::new(&args.find, args.replacement(), line_terminator)),
+        output_patch: args.output_patch.as_ref().map(|_| Vec::new()),
+        report: report_format.map(|_| crate::report::Report::default()),
+        resume,
+        resumed,
+        transaction: args.atomic.then(crate::transaction::Transaction::default),
+    };
+
+    check_patch_paths(matches.keys(), args.patch_root.as_deref())?;
+
+    // the originals of the copies in the sandbox
+    let sandboxed = match &args.sandbox {
+        Some(dir) => sandbox_copies(dir, args.patch_root.as_deref(), &mut matches)?,
+        None => Vec::new(),
+    };
+
+    let mut journal = crate::undo::Journal::default();
+
+    // only the lines that the replacement would change are matches
+    let mut would_change = !matches.is_empty();
+
+    // the outcomes of the files that were patched, for the summary at the end of the run
+    let mut outcomes = Vec::new();
+    let patch_started = std::time::Instant::now();
+
+    let result = if let Some(filters) = &stream_filters {
+        stream_paths(
+            &args,
+            &config,
+            filters,
+            &mut decisions,
+            &mut journal,
+            &mut would_change,
+        )
+    } else if args.menu {
+        main_menu(&args, &config, &mut matches, &mut decisions, &mut journal)
+    } else {
+        let show = args.show;
+        patch_files(
+            &args,
+            &config,
+            &mut matches,
+            show,
+            &mut decisions,
+            &mut journal,
+        )
+        .map(|x| outcomes = x)
+    };
+
+    // the files of an `--atomic` run are only written if the run finished
+    let result = match decisions.transaction.take() {
+        Some(transaction) => result.and_then(|()| transaction.commit(args.fsync, &mut journal)),
+        None => result,
+    };
+
+    // the progress is kept if there was an error, so that the run can be resumed, and a session
+    // is always kept
+    if let (Ok(()), Some(resume), None) = (&result, decisions.resume.take(), &args.session) {
+        resume.finish();
+    }
+
+    let modified: Vec<PathBuf> = journal.paths().map(Path::to_path_buf).collect();
+
+    // save the journal even if there was an error so that the files modified before the error can
+    // be restored, unless only the copies in the sandbox were modified
+    if args.sandbox.is_none() {
+        journal.save()?;
+    }
+
+    // the rejected hunks are also saved, since the user has already answered them
+    if let Some(remember) = decisions.remember.take() {
+        remember.save_rejected()?;
+    }
+
+    if let Some(timeout) = &config.prompt_timeout {
+        let expired = timeout.expired.get();
+        if expired > 0 {
+            println!(
+                "\n{} prompt{} timed out and {} answered '{}'.",
+                style!(expired, &COUNT_STYLE),
+                if expired == 1 { "" } else { "s" },
+                if expired == 1 { "was" } else { "were" },
+                timeout.default.keys()[0],
+            );
+        }
+    }
+
+    result?;
+
+    if !args.show && !args.hook && !json_output && !outcomes.is_empty() {
+        let durations = args
+            .stats
+            .then(|| (search_duration, patch_started.elapsed()));
+        print_run_summary(&outcomes, durations);
+    }
+
+    if let (Some(plan), Some(path)) = (decisions.plan, &args.write_plan) {
+        plan.write(path)?;
+        let (files, hunks) = plan.counts();
+        println!(
+            "\nWrote {} hunk{} in {} file{} to the plan '{}'.",
+            style!(hunks, &COUNT_STYLE),
+            if hunks == 1 { "" } else { "s" },
+            style!(files, &COUNT_STYLE),
+            if files == 1 { "" } else { "s" },
+            path.display(),
+        );
+    }
+
+    if let Some(dir) = &args.sandbox {
+        write_sandbox_diff(
+            dir,
+            args.patch_root.as_deref(),
+            &sandboxed,
+            export_context(args.export_context),
+        )?;
+    }
+
+    if let (Some(diffs), Some(path)) = (decisions.output_patch, &args.output_patch) {
+        write_output_patch(path, &diffs)?;
+    }
+
+    if let (Some(report), Some(format), [_, path]) =
+        (decisions.report, report_format, args.report.as_slice())
+    {
+        let applied = !args.show && args.write_plan.is_none() && args.output_patch.is_none();
+        report.write(Path::new(path), format, applied)?;
+    }
+
+    if let (Some(export), Some(path)) = (decisions.export, &args.export_decisions) {
+        export
+            .write(path)
+            .with_context(|| format!("could not write decisions file '{}'", path.display()))?;
+    }
+
+    if args.hook && args.apply {
+        let cwd = std::env::current_dir()?;
+        if !modified.is_empty() {
+            println!();
+        }
+        for path in &modified {
+            println!(
+                "Modified {}",
+                crate::util::relative_path(path, &cwd).display()
+            );
+        }
+        would_change = !modified.is_empty();
+    }
+
+    if args.dry_run_exit_code && would_change {
+        return Ok(ExitCode::FAILURE);
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Print each part of the replacement template `template` and what it's replaced with, for
+/// `--explain-replacement`.
+fn explain_replacement(matcher: &PatternMatcher, template: &[u8]) {
+    use crate::util::{GroupRef, TemplatePart};
+    use grep_matcher::Matcher;
+
+    let parts = crate::util::template_parts(template);
+    if parts.is_empty() {
+        println!("The replacement is empty, so each match is removed.");
+        return;
+    }
+
+    let groups = matcher.capture_count();
+    for (source, part) in &parts {
+        let explained = match part {
+            TemplatePart::Literal(x) => format!("the text {:?}", x.as_bstr()),
+            TemplatePart::Variable("FILE") => "the path of the file".to_string(),
+            TemplatePart::Variable("LINE") => "the line number".to_string(),
+            TemplatePart::Variable(_) => "the whole match".to_string(),
+            TemplatePart::Group(GroupRef::Index(0)) => "the whole match (group 0)".to_string(),
+            TemplatePart::Group(GroupRef::Index(x)) if *x < groups => format!("capture group {x}"),
+            TemplatePart::Group(GroupRef::Name(x)) => match matcher.capture_index(x) {
+                Some(index) => format!("the capture group named {x:?} (group {index})"),
+                None => format!("nothing, since there's no capture group named {x:?}"),
+            },
+            TemplatePart::Group(GroupRef::Index(x)) => {
+                format!("nothing, since there's no capture group {x}")
+            }
+        };
+        println!("{:>16}  {explained}", format!("{:?}", source.as_bstr()));
+
+        // a group index followed by letters or underscores is read as a name
+        if let TemplatePart::Group(GroupRef::Name(name)) = part {
+            let digits = name.bytes().take_while(u8::is_ascii_digit).count();
+            if digits > 0 && matcher.capture_index(name).is_none() {
+                let (index, rest) = name.split_at(digits);
+                println!(
+                    "{:>16}  (use \"${{{index}}}{rest}\" for capture group {index} followed by \
+                    {rest:?})",
+                    "",
+                );
+            }
+        }
+    }
+}
+
+/// Print the summary of a run that patched files, with how long the search and the patching took
+/// if `durations` is set, for `--stats`.
+fn print_run_summary(
+    outcomes: &[FileOutcome],
+    durations: Option<(std::time::Duration, std::time::Duration)>,
+) {
+    let sum = |f: fn(&FileOutcome) -> u64| outcomes.iter().map(f).sum::<u64>();
+    let modified = outcomes.iter().filter(|x| x.written).count();
+    let applied = sum(|x| x.applied_hunks);
+    let edited = sum(|x| x.edited_hunks);
+    let skipped = sum(|x| x.skipped_hunks);
+    let replacements = sum(|x| x.replacements);
+
+    println!(
+        "\nExamined {} file{} and modified {}.",
+        style!(outcomes.len(), &COUNT_STYLE),
+        if outcomes.len() == 1 { "" } else { "s" },
+        style!(modified, &COUNT_STYLE),
+    );
+    println!(
+        "Applied {} hunk{} ({} edited) and skipped {}, replacing {} match{}.",
+        style!(applied, &COUNT_STYLE),
+        if applied == 1 { "" } else { "s" },
+        style!(edited, &COUNT_STYLE),
+        style!(skipped, &COUNT_STYLE),
+        style!(replacements, &COUNT_STYLE),
+        if replacements == 1 { "" } else { "es" },
+    );
+
+    if let Some((searching, patching)) = durations {
+        let bytes = sum(|x| x.bytes_written);
+        println!(
+            "Searched for {:.2}s and patched for {:.2}s, writing {} byte{}.",
+            searching.as_secs_f64(),
+            patching.as_secs_f64(),
+            style!(bytes, &COUNT_STYLE),
+            if bytes == 1 { "" } else { "s" },
+        );
+    }
+}
+
+/// Copy the files with matches into the sandbox directory `dir` for `--sandbox`, and search the
+/// copies instead. Returns the original and copied path of each file.
+fn sandbox_copies(
+    dir: &Path,
+    patch_root: Option<&Path>,
+    matches: &mut BTreeMap<PathBuf, MatchInfo>,
+) -> anyhow::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut status = crate::git::StatusCache::default();
+    let mut copies = Vec::new();
+
+    for (path, mut match_info) in std::mem::take(matches) {
+        // the copy keeps the path relative to the root, but it must stay within the sandbox
+        let relative = patch_path(&path, patch_root, &mut status);
+        if !relative
+            .components()
+            .all(|x| matches!(x, std::path::Component::Normal(_)))
+        {
+            anyhow::bail!(
+                "'{}' is outside of the current directory and repository, so it can't be copied \
+                into the sandbox",
+                path.display(),
+            );
+        }
+        let copy = dir.join(&relative);
+
+        std::fs::create_dir_all(copy.parent().unwrap())
+            .with_context(|| format!("could not create the directory for '{}'", copy.display()))?;
+        crate::util::copy_file(&path, &copy).with_context(|| {
+            format!(
+                "could not copy '{}' to '{}'",
+                path.display(),
+                copy.display()
+            )
+        })?;
+
+        match_info.modified = std::fs::metadata(&copy)?.modified()?;
+        matches.insert(copy.clone(), match_info);
+        copies.push((path, copy));
+    }
+
+    Ok(copies)
+}
+
+/// A patch from `original` to `new` with `context` lines of context, where both files have the path
+/// `path` relative to the root.
+fn file_diff(path: &Path, original: &[u8], new: &[u8], context: usize) -> Vec<u8> {
+    // diffy can't handle an unlimited context, but it doesn't need more context than the file has
+    let max_lines =
+        crate::util::count_lines(original, b'\n').max(crate::util::count_lines(new, b'\n'));
+    let context = context.min(usize::try_from(max_lines).unwrap_or(usize::MAX));
+
+    let patch = diffy::DiffOptions::new()
+        .set_context_len(context)
+        .create_patch_bytes(original, new)
+        .to_bytes();
+
+    // replace the '---' and '+++' lines with the file's path
+    let hunks = patch.splitn(3, |x| *x == b'\n').nth(2).unwrap_or_default();
+    let mut diff = format!("--- a/{}\n+++ b/{}\n", path.display(), path.display()).into_bytes();
+    diff.extend_from_slice(hunks);
+    diff
+}
+
+/// The lines of context of a patch that is written to a file.
+fn export_context(context: Context) -> usize {
+    match context {
+        Context::Num(x) => usize::try_from(x).unwrap_or(usize::MAX),
+        Context::Infinite => usize::MAX,
+    }
+}
+
+/// Write the diff between each original file and its copy in the sandbox to
+/// `<DIR>/repatch.diff`, for `--sandbox`.
+fn write_sandbox_diff(
+    dir: &Path,
+    patch_root: Option<&Path>,
+    copies: &[(PathBuf, PathBuf)],
+    context: usize,
+) -> anyhow::Result<()> {
+    let mut status = crate::git::StatusCache::default();
+    let mut diff = Vec::new();
+    let mut changed = 0;
+
+    for (original, copy) in copies {
+        let original_bytes = std::fs::read(original)?;
+        let copy_bytes = std::fs::read(copy)?;
+        if original_bytes == copy_bytes {
+            continue;
+        }
+        changed += 1;
+
+        let path = patch_path(original, patch_root, &mut status);
+        diff.extend(file_diff(&path, &original_bytes, &copy_bytes, context));
+    }
+
+    let diff_path = dir.join("repatch.diff");
+    std::fs::write(&diff_path, &diff)
+        .with_context(|| format!("could not write '{}'", diff_path.display()))?;
+
+    println!(
+        "\nChanged {} of {} file{} in the sandbox '{}', without modifying the original files. The \
+        diff was written to '{}'.",
+        style!(changed, &COUNT_STYLE),
+        style!(copies.len(), &COUNT_STYLE),
+        if copies.len() == 1 { "" } else { "s" },
+        dir.display(),
+        diff_path.display(),
+    );
+
+    Ok(())
+}
+
+/// Write the diffs of the files to `path`, or to stdout if `path` is "-", for `--output-patch`.
+fn write_output_patch(path: &Path, diffs: &[Vec<u8>]) -> anyhow::Result<()> {
+    if path == Path::new("-") {
+        crate::ui::write_stdout(&diffs.concat())?;
+        return Ok(());
+    }
+
+    std::fs::write(path, diffs.concat())
+        .with_context(|| format!("could not write '{}'", path.display()))?;
+
+    println!(
+        "\nWrote the diff of {} file{} to '{}', without modifying the files.",
+        style!(diffs.len(), &COUNT_STYLE),
+        if diffs.len() == 1 { "" } else { "s" },
+        path.display(),
+    );
+
+    Ok(())
+}
+
+/// If the last run of this command was interrupted, offer to resume it from the first file that it
+/// didn't finish by removing the finished files from `matches`. Returns the recorder of this run's
+/// progress and the answers of the interrupted run, if it's resumed.
+fn resume_run(
+    args: &Args,
+    matches: &mut BTreeMap<PathBuf, MatchInfo>,
+) -> anyhow::Result<(Option<crate::resume::Recorder>, Option<Decisions>)> {
+    let key = crate::resume::key(args)?;
+    let path = crate::resume::state_path(key)?;
+
+    let saved = match crate::resume::load(&path)? {
+        Some(saved) => {
+            // the files of an `--atomic` run aren't finished until the end of the run
+            match saved.done.len() {
+                0 => println!("The last run of this command was interrupted."),
+                done => println!(
+                    "The last run of this command was interrupted after {} file{} {} finished.",
+                    style!(done, &COUNT_STYLE),
+                    if done == 1 { "" } else { "s" },
+                    if done == 1 { "was" } else { "were" },
+                ),
+            }
+            match crate::ui::yes_no_prompt("Resume from the first unfinished file [y/n]?") {
+                true => Some(saved),
+                false => None,
+            }
+        }
+        None => None,
+    };
+
+    if let Some(saved) = &saved {
+        skip_finished(saved, matches);
+    }
+
+    // the run isn't resumable if its progress can't be saved, but it can still continue
+    let recorder = match crate::resume::Recorder::start(&path, key, saved.as_ref()) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            warning!("{e:#}");
+            None
+        }
+    };
+
+    Ok((recorder, saved.map(|x| x.decisions)))
+}
+
+/// Continue the session in the file `session` from its first unanswered hunk, for `--session`.
+/// Returns the recorder of the session's progress and the answers from earlier in the session.
+fn resume_session(
+    args: &Args,
+    session: &Path,
+    matches: &mut BTreeMap<PathBuf, MatchInfo>,
+) -> anyhow::Result<(Option<crate::resume::Recorder>, Option<Decisions>)> {
+    let key = crate::resume::key(args)?;
+
+    let saved = crate::resume::load(session)?;
+    if let Some(saved) = &saved {
+        if saved.key != Some(key) {
+            anyhow::bail!(
+                "the session '{}' was started with different arguments or in a different \
+                directory (remove it to start a new session)",
+                session.display(),
+            );
+        }
+
+        let files = matches.len();
+        skip_finished(saved, matches);
+        let done = files - matches.len();
+        println!(
+            "Resuming the session '{}', where {} file{} {} already finished.",
+            session.display(),
+            style!(done, &COUNT_STYLE),
+            if done == 1 { "" } else { "s" },
+            if done == 1 { "was" } else { "were" },
+        );
+    }
+
+    // unlike an automatic resume, the session is what the user asked for
+    let recorder = crate::resume::Recorder::start(session, key, saved.as_ref())?;
+    Ok((Some(recorder), saved.map(|x| x.decisions)))
+}
+
+/// Remove the files that were finished in an earlier run and haven't changed since from
+/// `matches`.
+fn skip_finished(saved: &crate::resume::Saved, matches: &mut BTreeMap<PathBuf, MatchInfo>) {
+    matches.retain(|path, _| match std::fs::canonicalize(path) {
+        Ok(x) => !saved.is_done(&x),
+        Err(_) => true,
+    });
+}
+
+/// Read paths from stdin and show or patch each file as soon as its path is read, for
+/// `--paths-from-stdin`. Files that can't be searched are reported and skipped, since files
+/// commonly disappear between when a path is printed and when it's read. `found` is set if any
+/// file has matches.
+fn stream_paths(
+    args: &Args,
+    config: &ReplaceOptions,
+    filters: &PathFilters,
+    decisions: &mut HunkDecisions,
+    journal: &mut crate::undo::Journal,
+    found: &mut bool,
+) -> anyhow::Result<()> {
+    let search = SearchConfig::from_args(args);
+
+    for line in std::io::stdin().lock().lines() {
+        let line = line.context("could not read a path from stdin")?;
+        if line.is_empty() {
+            continue;
+        }
+
+        let path = PathBuf::from(line);
+        if filters.is_excluded(&path) {
+            continue;
+        }
+
+        let match_info = match search_file(config, &search, &path) {
+            Ok(Some(x)) => x,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("{e:#}");
+                continue;
+            }
+        };
+
+        *found = true;
+        let mut matches = BTreeMap::from([(path, match_info)]);
+        patch_files(args, config, &mut matches, args.show, decisions, journal)?;
+        std::io::stdout().flush()?;
+    }
+
+    Ok(())
+}
+
+/// Read stdin for filtering it to stdout, and take stdout for the result. The prompts are read from
+/// and shown on the terminal instead, or with `--apply` the messages are printed to stderr. Returns
+/// the input and the original stdout.
+fn read_stdin_input(args: &mut Args) -> anyhow::Result<(Vec<u8>, File)> {
+    if std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+        anyhow::bail!("no paths were given to search, and stdin is a terminal");
+    }
+
+    let unsupported = [
+        (args.show, "--show"),
+        (args.json, "--json"),
+        (args.check, "--check"),
+        (args.serve, "--serve"),
+        (args.menu, "--menu"),
+        (args.sandbox.is_some(), "--sandbox"),
+        (args.search_only, "--search-only"),
+        (args.idempotent_check, "--idempotent-check"),
+        (args.write_plan.is_some(), "--write-plan"),
+        (args.output_patch.is_some(), "--output-patch"),
+        (args.atomic, "--atomic"),
+        (args.stdout.is_some(), "--stdout"),
+    ];
+    if let Some((_, flag)) = unsupported.iter().find(|(given, _)| *given) {
+        anyhow::bail!("'{flag}' can't be used when filtering stdin");
+    }
+
+    let mut input = Vec::new();
+    std::io::stdin()
+        .lock()
+        .read_to_end(&mut input)
+        .context("could not read stdin")?;
+    args.paths.clear();
+
+    let output = match args.apply {
+        true => crate::util::divert_stdout(&std::io::stderr()),
+        false => {
+            crate::util::reopen_stdin_tty()
+                .context("could not open the terminal to prompt for input")?;
+            let tty = File::options().write(true).open("/dev/tty");
+            tty.and_then(|tty| crate::util::divert_stdout(&tty))
+        }
+    };
+    let output = output.context("could not open the terminal to prompt for input")?;
+
+    Ok((input, output))
+}
+
+/// Find and replace the matches in `input` that was read from stdin, prompting for each hunk
+/// unless `--apply` was given, and write the result to `output`.
+fn filter_stdin(
+    args: &Args,
+    config: &ReplaceOptions,
+    keep_line: &KeepLine,
+    input: &[u8],
+    mut output: File,
+) -> anyhow::Result<ExitCode> {
+    let path = Path::new("<stdin>");
+    let search = SearchConfig::from_args(args);
+
+    let mut lines = match config.header {
+        Some(header) => {
+            crate::util::header_lines(config.matcher, input, header).unwrap_or_default()
+        }
+        None => {
+            let mut lines = Vec::new();
+            let mut sink = LineSink {
+                on_match: |line_num: u64, line: &[u8]| {
+                    // line numbers are given starting from 1
+                    let line_num = line_num.checked_sub(1).unwrap();
+                    if (search.skip_nul_lines && line.contains(&b'\0'))
+                        || !keep_line(path, line_num, line)
+                    {
+                        return true;
+                    }
+
+                    // with `--multiline`, every line of a match is a matching line
+                    let line_count = match search.multi_line {
+                        true => crate::util::count_lines(line, b'\n'),
+                        false => 1,
+                    };
+                    for line_num in line_num..line_num + line_count {
+                        if lines.last().is_none_or(|x| *x < line_num) {
+                            lines.push(line_num);
+                        }
+                    }
+                    !config.only.is_some_and(|x| x == OnlyMatch::First)
+                },
+                binary: false,
+            };
+            let (mut searcher, _) = search.build();
+            searcher
+                .search_slice(config.matcher, input, &mut sink)
+                .context("could not search stdin")?;
+            if sink.binary {
+                anyhow::bail!("stdin has binary data (use '--binary=search' to search it)");
+            }
+            lines
+        }
+    };
+
+    if let Some(markers) = config.between {
+        restrict_between(markers, input, &mut lines, config.line_terminator);
+    }
+    restrict_lines(
+        config.matcher,
+        config.columns.as_ref(),
+        config.windows.as_ref(),
+        config.only,
+        input,
+        &mut lines,
+        config.line_terminator,
+    )?;
+
+    let progress = crate::ui::Progress::new(vec![config.hunk_ranges(&lines).len() as u64]);
+    let paths = FilePaths {
+        display: path,
+        patch: path,
+        real: path,
+    };
+    let sticky = std::cell::Cell::new(None);
+    let mut hunk_id = 0;
+
+    let mut new = Vec::new();
+    let outcome = replace_matches(
+        config,
+        path,
+        input,
+        Some(&mut new),
+        &mut lines,
+        None,
+        |hunk| {
+            hunk_id += 1;
+            let input = match sticky.get() {
+                _ if args.apply => Some(MenuOption::Yes),
+                Some(MenuOption::ApplyFile | MenuOption::ApplyAll) => Some(MenuOption::Yes),
+                Some(MenuOption::SkipFile) => Some(MenuOption::No),
+                _ => None,
+            };
+            prompt_hunk(
+                config,
+                paths,
+                hunk,
+                input,
+                &progress,
+                hunk_id,
+                None,
+                None,
+                Some(&sticky),
+                None,
+                None,
+            )
+        },
+    )
+    .context("could not read stdin")?;
+
+    // nothing was written if no hunks were applied
+    let result = match outcome.write_file {
+        WriteFile::Yes => &new,
+        WriteFile::No => input,
+    };
+    output
+        .write_all(result)
+        .context("could not write to stdout")?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// The interactive main menu, where the user can choose which files to patch and when.
+fn main_menu(
+    args: &Args,
+    config: &ReplaceOptions,
+    matches: &mut BTreeMap<PathBuf, MatchInfo>,
+    decisions: &mut HunkDecisions,
+    journal: &mut crate::undo::Journal,
+) -> anyhow::Result<()> {
+    // files that the user chose not to patch
+    let mut deselected = BTreeMap::new();
+
+    loop {
+        println!();
+
+        match crate::ui::main_menu_prompt() {
+            MainMenuOption::Status => {
+                if matches.is_empty() && deselected.is_empty() {
+                    println!("No files remaining.");
+                }
+
+                let mut files: Vec<_> = matches
+                    .iter()
+                    .map(|(path, info)| (path, info, true))
+                    .chain(deselected.iter().map(|(path, info)| (path, info, false)))
+                    .collect();
+                files.sort_by_key(|x| x.0);
+
+                for (_, info, selected) in files {
+                    let count = info.lines.len();
+                    println!(
+                        "[{}] {} ({} match{})",
+                        if selected { "x" } else { " " },
+                        info.display_path.display(),
+                        style!(count, &COUNT_STYLE),
+                        if count == 1 { "" } else { "es" },
+                    );
+                }
+            }
+            MainMenuOption::Patch => {
+                patch_files(args, config, matches, false, decisions, journal)?;
+            }
+            MainMenuOption::Diff => {
+                patch_files(args, config, matches, true, decisions, journal)?;
+            }
+            MainMenuOption::Select => {
+                let previous: HashSet<PathBuf> = matches.keys().cloned().collect();
+                matches.append(&mut deselected);
+
+                let selected = select_files(
+                    matches,
+                    |x| previous.contains(x),
+                    args.group_by_dir,
+                    args.natural_sort,
+                );
+                let selected = selected.unwrap_or(previous);
+
+                let unselected_paths: Vec<_> = matches
+                    .keys()
+                    .filter(|path| !selected.contains(*path))
+                    .cloned()
+                    .collect();
+                for path in unselected_paths {
+                    let info = matches.remove(&path).unwrap();
+                    deselected.insert(path, info);
+                }
+            }
+            MainMenuOption::Quit => return Ok(()),
+        }
+    }
+}
+
+/// Ask the user which files to include, starting with the files where `selected` returns true.
+/// Returns `None` if the user quit, otherwise the selected files.
+fn select_files(
+    matches: &BTreeMap<PathBuf, MatchInfo>,
+    selected: impl Fn(&Path) -> bool,
+    group_by_dir: bool,
+    natural_sort: bool,
+) -> Option<HashSet<PathBuf>> {
+    let order = file_order(matches, group_by_dir, natural_sort);
+
+    let files: Vec<_> = order
+        .iter()
+        .map(|path| {
+            let info = &matches[path];
+            (info.display_path.as_path(), info.lines.len())
+        })
+        .collect();
+
+    let dirs = match group_by_dir {
+        true => crate::util::dir_groups(&files.iter().map(|x| x.0).collect::<Vec<_>>()),
+        false => Vec::new(),
+    };
+
+    let initial = order.iter().map(|x| selected(x)).collect();
+    let selected = crate::ui::file_selection_prompt(&files, &dirs, initial)?;
+
+    Some(
+        order
+            .into_iter()
+            .zip(selected)
+            .filter(|(_, selected)| *selected)
+            .map(|(path, _)| path)
+            .collect(),
+    )
+}
+
+/// The order to show the files in. If `group_by_dir` is true, the files in each directory are
+/// kept together. If `natural_sort` is true, the paths are sorted with
+/// [`crate::util::natural_cmp`].
+fn file_order(
+    matches: &BTreeMap<PathBuf, MatchInfo>,
+    group_by_dir: bool,
+    natural_sort: bool,
+) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = matches.keys().cloned().collect();
+    if group_by_dir {
+        crate::util::sort_by_dir(&mut paths, natural_sort);
+    } else if natural_sort {
+        paths.sort_by(|a, b| crate::util::natural_cmp(a, b));
+    }
+    paths
+}
+
+/// Print the number of matches and files in each directory.
+fn print_dir_summary(matches: &BTreeMap<PathBuf, MatchInfo>, natural_sort: bool) {
+    let order = file_order(matches, true, natural_sort);
+    let infos: Vec<&MatchInfo> = order.iter().map(|path| &matches[path]).collect();
+    let paths: Vec<&Path> = infos.iter().map(|x| x.display_path.as_path()).collect();
+
+    println!();
+    for (dir, range) in crate::util::dir_groups(&paths) {
+        let count: usize = infos[range.clone()].iter().map(|x| x.lines.len()).sum();
+        println!(
+            "  {}/: {} match{} in {} file{}",
+            dir.display(),
+            style!(count, &COUNT_STYLE),
+            if count == 1 { "" } else { "es" },
+            style!(range.len(), &COUNT_STYLE),
+            if range.len() == 1 { "" } else { "s" },
+        );
+    }
+}
+
+/// Returns the path to use in patch headers for the file at `path`, which is relative to the
+/// canonical directory `root` if given, or else to the root of the git repository (or to the
+/// current directory if not in a git repository).
+fn patch_path(path: &Path, root: Option<&Path>, status: &mut crate::git::StatusCache) -> PathBuf {
+    let relative = match root {
+        Some(root) => std::fs::canonicalize(path)
+            .map(|x| crate::util::relative_path(&x, root))
+            .map_err(Into::into),
+        None => display_path(path, PathDisplay::FromRoot, status),
+    };
+    relative.unwrap_or_else(|_| path.strip_prefix("./").unwrap_or(path).to_path_buf())
+}
+
+/// Returns an error if two of the files at `paths` would have the same path in patches, which
+/// happens when they're in different repositories, or if a file is outside of the `--patch-root`
+/// directory `root`.
+fn check_patch_paths<'a>(
+    paths: impl IntoIterator<Item = &'a PathBuf>,
+    root: Option<&Path>,
+) -> anyhow::Result<()> {
+    let mut status = crate::git::StatusCache::default();
+    let mut seen = HashMap::new();
+
+    for path in paths {
+        let patch_path = patch_path(path, root, &mut status);
+
+        if let Some(root) = root {
+            if !patch_path
+                .components()
+                .all(|x| matches!(x, std::path::Component::Normal(_)))
+            {
+                anyhow::bail!(
+                    "'{}' is outside of the patch root '{}'",
+                    path.display(),
+                    root.display(),
+                );
+            }
+        }
+
+        let Some(other) = seen.insert(patch_path.clone(), path) else {
+            continue;
+        };
+        // the same file can be found through different paths
+        if std::fs::canonicalize(other).ok() != std::fs::canonicalize(path).ok() {
+            anyhow::bail!(
+                "'{}' and '{}' would both be written as '{}' in patches; use '--patch-root' to \
+                choose the directory that patch paths are relative to",
+                other.display(),
+                path.display(),
+                patch_path.display(),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The file that the file at `path` is paired with by `--paired`, which has the same name but the
+/// other extension of the pair.
+fn companion(pairs: &[(String, String)], path: &Path) -> Option<PathBuf> {
+    let ext = path.extension()?;
+    pairs.iter().find_map(|(a, b)| match ext {
+        x if x == a.as_str() => Some(path.with_extension(b)),
+        x if x == b.as_str() => Some(path.with_extension(a)),
+        _ => None,
+    })
+}
+
+/// Returns the path to show to the user for the file at `path`.
+fn display_path(
+    path: &Path,
+    mode: PathDisplay,
+    status: &mut crate::git::StatusCache,
+) -> anyhow::Result<PathBuf> {
+    let path = std::fs::canonicalize(path)?;
+    let cwd = std::fs::canonicalize(std::env::current_dir()?)?;
+
+    Ok(match mode {
+        PathDisplay::Absolute => path,
+        PathDisplay::Relative => crate::util::relative_path(&path, &cwd),
+        PathDisplay::FromRoot => match status.toplevel(&path) {
+            Ok(toplevel) => path.strip_prefix(toplevel).unwrap().to_path_buf(),
+            // not in a git repository
+            Err(_) => crate::util::relative_path(&path, &cwd),
+        },
+    })
+}
+
+/// Print each match. Returns a failure exit code if there were any matches.
+fn check_matches(
+    args: &Args,
+    options: &ReplaceOptions,
+    matches: &BTreeMap<PathBuf, MatchInfo>,
+) -> anyhow::Result<ExitCode> {
+    let msg = format!("Found a match for \"{}\"", args.find);
+    let mut sarif_results = Vec::new();
+
+    for (path, match_info) in matches {
+        if args.format == OutputFormat::Github {
+            for line in &match_info.lines {
+                let annotation = crate::output::github_annotation(
+                    "error",
+                    &match_info.display_path,
+                    *line..*line + 1,
+                    &msg,
+                );
+                println!("{annotation}");
+            }
+            continue;
+        }
+
+        let print_line = |line_num, line: &[u8]| {
+            if args.format == OutputFormat::Sarif {
+                let mut template = None;
+                let replacement =
+                    options
+                        .replacement
+                        .for_line(&mut template, &match_info.display_path, line_num);
+                let replacements = crate::util::match_replacements(
+                    options.matcher,
+                    replacement,
+                    &options.line_filter(&match_info.display_path, line_num, line),
+                    line,
+                )
+                .unwrap();
+                for x in replacements {
+                    sarif_results.push(crate::output::sarif_result(
+                        &msg,
+                        &match_info.display_path,
+                        line_num,
+                        line,
+                        x.range,
+                        &x.replacement,
+                    ));
+                }
+                return;
+            }
+
+            let line = crate::ui::escape_ansi(line);
+            let path = match_info.display_path.display();
+            println!("{path}:{}:{}", line_num + 1, line.as_bstr());
+        };
+
+        for_each_line(path, &match_info.lines, options.line_terminator, print_line)
+            .with_context(|| format!("could not read file '{}'", path.display()))?;
+    }
+
+    if args.format == OutputFormat::Sarif {
+        println!("{}", crate::output::sarif_log(sarif_results));
+    }
+
+    if matches.is_empty() {
+        return Ok(ExitCode::SUCCESS);
+    }
+    Ok(ExitCode::FAILURE)
+}
+
+/// Print each distinct matched text and its number of occurrences, most frequent first.
+fn print_match_summary(
+    matcher: &PatternMatcher,
+    matches: &BTreeMap<PathBuf, MatchInfo>,
+    line_terminator: u8,
+) -> anyhow::Result<()> {
+    let mut counts = BTreeMap::new();
+
+    for (path, match_info) in matches {
+        for_each_line(path, &match_info.lines, line_terminator, |_, line| {
+            crate::util::count_matched_texts(matcher, line, &mut counts).unwrap();
+        })
+        .with_context(|| format!("could not read file '{}'", path.display()))?;
+    }
+
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    // a stable sort, so texts with the same count remain sorted
+    counts.sort_by_key(|x| std::cmp::Reverse(x.1));
+
+    let width = counts.first().map(|x| x.1.to_string().len()).unwrap_or(0);
+
+    println!();
+    for (text, count) in counts {
+        let text = crate::ui::escape_ansi(&text);
+        let count = format!("{count:>width$}");
+        println!("  {}  {}", style!(count, &COUNT_STYLE), text.as_bstr());
+    }
+
+    Ok(())
+}
+
+/// Print the hunks of the matching lines with their context, for `--search-only`. Like grep, the
+/// line numbers of matching lines are followed by ":", and those of context lines by "-".
+fn print_search_hunks(
+    options: &ReplaceOptions,
+    matches: &BTreeMap<PathBuf, MatchInfo>,
+) -> anyhow::Result<ExitCode> {
+    for (path, match_info) in matches {
+        // the context lines of nearby hunks may overlap
+        let hunks = options.hunk_ranges(&match_info.lines);
+        let mut shown: Vec<u64> = hunks.iter().flat_map(|x| x.shown.clone()).collect();
+        shown.sort_unstable();
+        shown.dedup();
+
+        println!();
+        println!(
+            "{}",
+            style!(match_info.display_path.display(), &FILENAME_STYLE)
+        );
+
+        let mut last = None;
+        let print_line = |line_num, line: &[u8]| {
+            // separate the lines that aren't adjacent
+            if last.is_some_and(|x| x + 1 != line_num) {
+                println!("--");
+            }
+            last = Some(line_num);
+            let separator = match match_info.lines.binary_search(&line_num) {
+                Ok(_) => ':',
+                Err(_) => '-',
+            };
+            let line = crate::ui::escape_ansi(line);
+            println!("{}{separator}{}", line_num + 1, line.as_bstr());
+        };
+
+        for_each_line(path, &shown, options.line_terminator, print_line)
+            .with_context(|| format!("could not read file '{}'", path.display()))?;
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Print an estimate of the size of the changes from the matching lines alone, without reading the
+/// files.
+fn print_estimate(matches: &BTreeMap<PathBuf, MatchInfo>, padding: u64, merge: HunkMerge) {
+    let hunks: usize = matches
+        .values()
+        .map(|x| crate::util::hunk_ranges(&x.lines, padding, merge).len())
+        .sum();
+    let lines: usize = matches.values().map(|x| x.lines.len()).sum();
+    let largest = matches.values().max_by_key(|x| x.lines.len()).unwrap();
+
+    println!(
+        "Estimated changes: {} hunk{} in {} file{}, with about {} line{} removed and {} added. The \
+        most changed file is {} ({} line{}).",
+        style!(hunks, &COUNT_STYLE),
+        if hunks == 1 { "" } else { "s" },
+        style!(matches.len(), &COUNT_STYLE),
+        if matches.len() == 1 { "" } else { "s" },
+        style!(lines, &COUNT_STYLE),
+        if lines == 1 { "" } else { "s" },
+        style!(lines, &COUNT_STYLE),
+        largest.display_path.display(),
+        style!(largest.lines.len(), &COUNT_STYLE),
+        if largest.lines.len() == 1 { "" } else { "s" },
+    );
+}
+
+/// Search again with some of the restrictions removed, and print the near-misses that might
+/// explain why nothing matched.
+fn explain_no_matches(
+    args: &Args,
+    config: &crate::config::Config,
+    pattern: &str,
+    is_changed: &KeepLine,
+) -> anyhow::Result<()> {
+    let build_matcher = |case_insensitive| {
+        let options = MatcherOptions {
+            case_insensitive,
+            line_terminator: args.line_terminator,
+            multi_line: false,
+        };
+        crate::engine::build(pattern, args.engine, options)
+    };
+    let matcher = build_matcher(args.ignore_case)?;
+    let filters = || PathFilters::from_args(args, config);
+    let search = SearchConfig::from_args(args);
+
+    let find = |matcher: &PatternMatcher, filters: PathFilters, search: &SearchConfig| {
+        let filter = SearchFilter::default();
+        find_matches(matcher, &args.paths, filters, search, true, None, filter).unwrap_or_default()
+    };
+
+    // the near-misses are only the lines that weren't found by an earlier search
+    let mut found = BTreeMap::<PathBuf, MatchInfo>::new();
+    let mut explained = false;
+    let mut report = |mut matches: BTreeMap<PathBuf, MatchInfo>, reason: &str| {
+        for (path, info) in &mut matches {
+            if let Some(found) = found.get(path) {
+                info.lines.retain(|x| !found.lines.contains(x));
+            }
+        }
+        matches.retain(|_, info| !info.lines.is_empty());
+
+        let count: usize = matches.values().map(|x| x.lines.len()).sum();
+        if let Some((path, info)) = matches.iter().next() {
+            println!(
+                "- {} matching line{} {reason}, such as {}:{}",
+                style!(count, &COUNT_STYLE),
+                if count == 1 { "" } else { "s" },
+                path.display(),
+                info.lines[0] + 1,
+            );
+            explained = true;
+        }
+
+        for (path, info) in matches {
+            match found.entry(path) {
+                Entry::Occupied(mut x) => x.get_mut().lines.extend(info.lines),
+                Entry::Vacant(x) => {
+                    x.insert(info);
+                }
+            }
+        }
+    };
+
+    println!("\nLooking for near-misses...");
+
+    let unchanged = find(&matcher, filters()?, &search);
+    let unchanged = unchanged
+        .into_iter()
+        .filter_map(|(path, mut info)| {
+            let lines = std::mem::take(&mut info.lines);
+            let mut kept = Vec::new();
+            for_each_line(
+                &path,
+                &lines,
+                args.line_terminator.unwrap_or(b'\n'),
+                |num, line| {
+                    if !is_changed(&path, num, line) {
+                        kept.push(num);
+                    }
+                },
+            )
+            .ok()?;
+            info.lines = kept;
+            Some((path, info))
+        })
+        .collect();
+    report(unchanged, "that the replacement wouldn't change");
+
+    if !args.ignore_case {
+        let matches = find(&build_matcher(true)?, filters()?, &search);
+        report(matches, "when using '--ignore-case'");
+    }
+
+    if !args.glob.is_empty() || !args.exclude.is_empty() {
+        let matches = find(&matcher, PathFilters::new(&[], &[], &[], config)?, &search);
+        report(matches, "in files excluded by '--glob' or '--exclude'");
+    }
+
+    let mut unfiltered = filters()?;
+    unfiltered.standard_filters = false;
+    let matches = find(&matcher, unfiltered, &search);
+    report(matches, "in hidden files or files excluded by ignore rules");
+
+    if args.binary == BinaryMode::Skip {
+        let matches = find(&matcher, filters()?, &SearchConfig::new(b'\n'));
+        report(
+            matches,
+            "in binary files, which are skipped by '--binary skip'",
+        );
+    }
+
+    if !explained {
+        println!("No near-misses were found.");
+    }
+
+    Ok(())
+}
+
+/// A line that's changed again when its matches are replaced a second time.
+struct CompoundingLine<'a> {
+    path: &'a Path,
+    /// The (0-indexed) line number.
+    line_num: u64,
+    /// The line after replacing the matches once.
+    once: Vec<u8>,
+    /// The line after replacing the matches twice.
+    twice: Vec<u8>,
+}
+
+/// Find the matching lines where replacing the matches a second time would change the line again,
+/// which means that running the same command twice would compound the changes (for example "foo"
+/// -> "foofoo"). At most one line is returned for each file, and only the first file's line is
+/// returned if `all_files` is false.
+fn find_compounding_lines<'a>(
+    options: &ReplaceOptions,
+    matches: &'a BTreeMap<PathBuf, MatchInfo>,
+    all_files: bool,
+) -> anyhow::Result<Vec<CompoundingLine<'a>>> {
+    // with `--first-only`, `--last-only`, `--max-count`, or `--nth`, a second run is expected to
+    // replace a different match
+    if options.only.is_some() || options.limited.is_some() {
+        return Ok(Vec::new());
+    }
+
+    let mut found = Vec::new();
+
+    for (path, match_info) in matches {
+        let mut found_in_file = None;
+
+        for_each_line(
+            path,
+            &match_info.lines,
+            options.line_terminator,
+            |line_num, line| {
+                if found_in_file.is_some() {
+                    return;
+                }
+
+                let mut template = None;
+                let replacement = options.replacement.for_line(&mut template, path, line_num);
+                let replace = |line: &[u8]| {
+                    let mut dest = Vec::new();
+                    let filter = options.line_filter(path, line_num, line);
+                    crate::util::replace_regex(
+                        options.matcher,
+                        replacement,
+                        &filter,
+                        line,
+                        &mut dest,
+                    )
+                    .map(|_| dest)
+                };
+
+                let Ok(once) = replace(line) else {
+                    return;
+                };
+                if let Ok(twice) = replace(&once) {
+                    if once != twice {
+                        found_in_file = Some(CompoundingLine {
+                            path: &match_info.display_path,
+                            line_num,
+                            once,
+                            twice,
+                        });
+                    }
+                }
+            },
+        )
+        .with_context(|| format!("could not read file '{}'", path.display()))?;
+
+        found.extend(found_in_file);
+        if !all_files && !found.is_empty() {
+            break;
+        }
+    }
+
+    Ok(found)
+}
+
+/// Print each file where replacing the matches a second time would change the file again, without
+/// modifying any files, for `--idempotent-check`. Returns a failure exit code if there are any.
+fn idempotent_check(
+    options: &ReplaceOptions,
+    matches: &BTreeMap<PathBuf, MatchInfo>,
+) -> anyhow::Result<ExitCode> {
+    let lines = find_compounding_lines(options, matches, true)?;
+
+    for line in &lines {
+        warning!(
+            "{}:{}: replacing the matches again would change \"{}\" to \"{}\"",
+            line.path.display(),
+            line.line_num + 1,
+            crate::ui::escape_ansi(&line.once).as_bstr(),
+            crate::ui::escape_ansi(&line.twice).as_bstr(),
+        );
+    }
+
+    println!(
+        "\n{} of {} file{} would change again if the replacement was applied twice.",
+        style!(lines.len(), &COUNT_STYLE),
+        style!(matches.len(), &COUNT_STYLE),
+        if matches.len() == 1 { "" } else { "s" },
+    );
+
+    Ok(match lines.is_empty() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    })
+}
+
+/// Print the text of each match, one per line. If `unique` is true, each distinct text is only
+/// printed the first time that it's found.
+fn print_matched_texts(
+    matcher: &PatternMatcher,
+    matches: &BTreeMap<PathBuf, MatchInfo>,
+    unique: bool,
+) -> anyhow::Result<()> {
+    use grep_matcher::Matcher;
+
+    let mut seen = std::collections::HashSet::new();
+
+    for (path, match_info) in matches {
+        for_each_line(path, &match_info.lines, b'\n', |_, line| {
+            matcher
+                .find_iter(line, |m| {
+                    if !unique || seen.insert(line[m].to_vec()) {
+                        println!("{}", crate::ui::escape_ansi(&line[m]).as_bstr());
+                    }
+                    true
+                })
+                .unwrap();
+        })
+        .with_context(|| format!("could not read file '{}'", path.display()))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,140 @@
+//! Lockfiles of the runs that are in progress, so that two runs over overlapping paths don't
+//! silently overwrite each other's changes. Each run that may modify files writes
+//! "$XDG_STATE_HOME/repatch/locks/<PID>", with the absolute path of each of its search roots on
+//! its own line. The lockfile is removed when the run ends, and the lockfiles of processes that
+//! are no longer running are removed by the next run.
+
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::ui::warning;
+
+/// The lockfile of this run, which is removed when dropped.
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Write the lockfile for the search roots `roots`. Returns an error if another run is using an
+/// overlapping path, or only warns if `force` is true.
+pub fn acquire(roots: &[PathBuf], force: bool) -> anyhow::Result<Option<Lock>> {
+    let Some(dir) = crate::history::state_dir() else {
+        return Ok(None);
+    };
+    let dir = dir.join("locks");
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("could not create '{}'", dir.display()))?;
+
+    let roots: Vec<PathBuf> = roots
+        .iter()
+        .map(|x| std::fs::canonicalize(x).unwrap_or_else(|_| x.clone()))
+        .collect();
+
+    let entries =
+        std::fs::read_dir(&dir).with_context(|| format!("could not read '{}'", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let Some(pid) = path
+            .file_name()
+            .and_then(|x| x.to_str()?.parse::<i32>().ok())
+        else {
+            continue;
+        };
+        if pid == std::process::id() as i32 {
+            continue;
+        }
+        if !is_running(pid) {
+            let _ = std::fs::remove_file(&path);
+            continue;
+        }
+        // the other run may have ended
+        let Ok(other) = std::fs::read(&path) else {
+            continue;
+        };
+        let other = parse(&other);
+
+        let Some((ours, theirs)) = overlap(&roots, &other) else {
+            continue;
+        };
+        if !force {
+            anyhow::bail!(
+                "another repatch process (pid {pid}) is running on '{}', which overlaps '{}' (use \
+                '--force' to run anyway)",
+                theirs.display(),
+                ours.display(),
+            );
+        }
+        warning!(
+            "another repatch process (pid {pid}) is running on '{}', which overlaps '{}'",
+            theirs.display(),
+            ours.display(),
+        );
+    }
+
+    let mut list = Vec::new();
+    for root in &roots {
+        // the list can't contain paths with newlines
+        if root.as_os_str().as_bytes().contains(&b'\n') {
+            continue;
+        }
+        list.extend_from_slice(root.as_os_str().as_bytes());
+        list.push(b'\n');
+    }
+
+    let path = dir.join(std::process::id().to_string());
+    std::fs::write(&path, list).with_context(|| format!("could not write '{}'", path.display()))?;
+
+    Ok(Some(Lock { path }))
+}
+
+/// Whether the process `pid` is running.
+pub fn is_running(pid: i32) -> bool {
+    // sends no signal, but checks that the process exists
+    let rv = unsafe { libc::kill(pid, 0) };
+    rv == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn parse(s: &[u8]) -> Vec<PathBuf> {
+    s.split(|x| *x == b'\n')
+        .filter(|x| !x.is_empty())
+        .map(|x| PathBuf::from(std::ffi::OsString::from_vec(x.to_vec())))
+        .collect()
+}
+
+/// The first pair of paths where one contains the other.
+fn overlap<'a>(ours: &'a [PathBuf], theirs: &'a [PathBuf]) -> Option<(&'a Path, &'a Path)> {
+    ours.iter()
+        .flat_map(|a| theirs.iter().map(move |b| (a.as_path(), b.as_path())))
+        .find(|(a, b)| a.starts_with(b) || b.starts_with(a))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlap() {
+        let paths = |x: &[&str]| x.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        let ours = paths(&["/a/b", "/c"]);
+        assert_eq!(
+            overlap(&ours, &paths(&["/a"])),
+            Some((Path::new("/a/b"), Path::new("/a"))),
+        );
+        assert_eq!(
+            overlap(&ours, &paths(&["/c/d/e"])),
+            Some((Path::new("/c"), Path::new("/c/d/e"))),
+        );
+        assert_eq!(overlap(&ours, &paths(&["/a/bc", "/d"])), None);
+        assert_eq!(overlap(&ours, &[]), None);
+
+        assert_eq!(parse(b"/a\n\n/b c\n"), paths(&["/a", "/b c"]));
+    }
+}
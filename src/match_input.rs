@@ -0,0 +1,148 @@
+//! Reading the lines to patch from the output of other tools, rather than searching for them.
+
+use std::path::PathBuf;
+
+/// Parse the output of `rg --json`. Returns the path and (0-indexed) line number of each matching
+/// line.
+pub fn parse_rg_json(input: &str) -> anyhow::Result<Vec<(PathBuf, u64)>> {
+    let mut rv = Vec::new();
+
+    for (line_num, line) in input.lines().enumerate() {
+        let line_num = line_num + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let event =
+            crate::json::parse(line).map_err(|e| anyhow::anyhow!("line {line_num}: {e}"))?;
+
+        // the other event types ("begin", "end", "context", and "summary") aren't needed
+        if event.get("type").and_then(|x| x.as_str()) != Some("match") {
+            continue;
+        }
+
+        let data = event.get("data");
+        let path = data.and_then(|x| x.get("path"));
+        let Some(path) = path.and_then(|x| x.get("text")).and_then(|x| x.as_str()) else {
+            if path.and_then(|x| x.get("bytes")).is_some() {
+                anyhow::bail!("line {line_num}: paths that aren't valid UTF-8 are not supported");
+            }
+            anyhow::bail!("line {line_num}: the match has no path");
+        };
+
+        let Some(first_line) = data
+            .and_then(|x| x.get("line_number"))
+            .and_then(|x| x.as_u64())
+            .filter(|x| *x > 0)
+        else {
+            anyhow::bail!(
+                "line {line_num}: the match has no line number (was rg run with --no-line-number?)"
+            );
+        };
+
+        // a multiline match (`rg -U`) includes all of the lines that it spans
+        let num_lines = data
+            .and_then(|x| x.get("lines"))
+            .and_then(|x| x.get("text"))
+            .and_then(|x| x.as_str())
+            .map(|x| x.lines().count().max(1))
+            .unwrap_or(1);
+
+        for offset in 0..num_lines {
+            // line numbers are given starting from 1
+            rv.push((PathBuf::from(path), first_line - 1 + offset as u64));
+        }
+    }
+
+    Ok(rv)
+}
+
+/// Parse lines starting with "PATH:LINE", such as the output of `grep -n` or compiler diagnostics.
+/// Lines without a path and line number are ignored. Leading whitespace and a "--> " prefix (as
+/// in rustc diagnostics) are skipped. Returns the path and (0-indexed) line number of each line.
+pub fn parse_lines(input: &str) -> Vec<(PathBuf, u64)> {
+    input.lines().filter_map(parse_line).collect()
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, u64)> {
+    let line = line.trim_start();
+    let line = line.strip_prefix("--> ").unwrap_or(line);
+
+    // the path may contain ':', so use the first ':' that is followed by a line number
+    for (idx, _) in line.match_indices(':') {
+        let (path, rest) = (&line[..idx], &line[idx + 1..]);
+        let num_len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if path.is_empty()
+            || num_len == 0
+            || !matches!(rest[num_len..].chars().next(), None | Some(':'))
+        {
+            continue;
+        }
+        // line numbers are given starting from 1
+        let line_num = rest[..num_len].parse::<u64>().ok()?.checked_sub(1)?;
+        return Some((PathBuf::from(path), line_num));
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rg_json() {
+        let input = r#"
+{"type":"begin","data":{"path":{"text":"src/a.rs"}}}
+{"type":"match","data":{"path":{"text":"src/a.rs"},"lines":{"text":"foo 1\n"},"line_number":1,"absolute_offset":0,"submatches":[]}}
+{"type":"context","data":{"path":{"text":"src/a.rs"},"lines":{"text":"bar\n"},"line_number":2}}
+{"type":"match","data":{"path":{"text":"src/a.rs"},"lines":{"text":"foo 2\nfoo 3\n"},"line_number":3,"absolute_offset":10,"submatches":[]}}
+{"type":"end","data":{"path":{"text":"src/a.rs"},"binary_offset":null,"stats":{}}}
+{"type":"summary","data":{"elapsed_total":{"secs":0,"nanos":1},"stats":{}}}
+"#;
+        assert_eq!(
+            parse_rg_json(input).unwrap(),
+            [
+                (PathBuf::from("src/a.rs"), 0),
+                (PathBuf::from("src/a.rs"), 2),
+                (PathBuf::from("src/a.rs"), 3),
+            ],
+        );
+
+        assert!(parse_rg_json("{").is_err());
+        assert!(parse_rg_json(r#"{"type":"match","data":{"line_number":1}}"#).is_err());
+        assert!(parse_rg_json(
+            r#"{"type":"match","data":{"path":{"bytes":"/w=="},"line_number":1}}"#
+        )
+        .is_err());
+        assert!(parse_rg_json(
+            r#"{"type":"match","data":{"path":{"text":"a"},"line_number":null}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_lines() {
+        let input = "\
+src/a.rs:3:foo 2
+src/a.rs:1:5: warning: foo
+error: something went wrong
+   --> src/b:c.rs:10:2
+a:b:c
+x.rs:0:ignored
+:4:
+src/c.rs:2
+";
+        assert_eq!(
+            parse_lines(input),
+            [
+                (PathBuf::from("src/a.rs"), 2),
+                (PathBuf::from("src/a.rs"), 0),
+                (PathBuf::from("src/b:c.rs"), 9),
+                (PathBuf::from("src/c.rs"), 1),
+            ],
+        );
+    }
+}
@@ -0,0 +1,676 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::cli::NormalizationForm;
+use regex_syntax::ast::{
+    Alternation, Ast, Concat, Flags, Group, GroupKind, HexLiteralKind, Literal, LiteralKind,
+};
+
+const HANGUL_S_BASE: u32 = 0xAC00;
+const HANGUL_L_BASE: u32 = 0x1100;
+const HANGUL_V_BASE: u32 = 0x1161;
+const HANGUL_T_BASE: u32 = 0x11A7;
+const HANGUL_L_COUNT: u32 = 19;
+const HANGUL_V_COUNT: u32 = 21;
+const HANGUL_T_COUNT: u32 = 28;
+const HANGUL_N_COUNT: u32 = HANGUL_V_COUNT * HANGUL_T_COUNT;
+const HANGUL_S_COUNT: u32 = HANGUL_L_COUNT * HANGUL_N_COUNT;
+
+/// A character in its composed form (if it has one) and its canonical decomposition.
+#[derive(Debug, PartialEq, Eq)]
+struct Unit {
+    composed: Option<char>,
+    decomposed: String,
+}
+
+/// Append the canonical decomposition of `c` to `out`.
+fn decompose_into(c: char, out: &mut String) {
+    let s_index = u32::from(c).wrapping_sub(HANGUL_S_BASE);
+    if s_index < HANGUL_S_COUNT {
+        let l = HANGUL_L_BASE + s_index / HANGUL_N_COUNT;
+        let v = HANGUL_V_BASE + (s_index % HANGUL_N_COUNT) / HANGUL_T_COUNT;
+        let t = HANGUL_T_BASE + s_index % HANGUL_T_COUNT;
+        out.extend([l, v].map(|x| char::from_u32(x).unwrap()));
+        if t != HANGUL_T_BASE {
+            out.push(char::from_u32(t).unwrap());
+        }
+        return;
+    }
+
+    match COMPOSITIONS.binary_search_by_key(&c, |x| x.0) {
+        Ok(i) => out.push_str(COMPOSITIONS[i].1),
+        Err(_) => out.push(c),
+    }
+}
+
+/// Returns the composed character and the number of chars used from the start of `chars`, if the
+/// start of `chars` is a decomposed hangul syllable.
+fn compose_hangul(chars: &[char]) -> Option<(char, usize)> {
+    let l_index = u32::from(*chars.first()?).checked_sub(HANGUL_L_BASE)?;
+    let v_index = u32::from(*chars.get(1)?).checked_sub(HANGUL_V_BASE)?;
+    if l_index >= HANGUL_L_COUNT || v_index >= HANGUL_V_COUNT {
+        return None;
+    }
+
+    let lv = HANGUL_S_BASE + l_index * HANGUL_N_COUNT + v_index * HANGUL_T_COUNT;
+
+    let t_index = chars
+        .get(2)
+        .and_then(|t| u32::from(*t).checked_sub(HANGUL_T_BASE))
+        .filter(|t| (1..HANGUL_T_COUNT).contains(t));
+
+    Some(match t_index {
+        Some(t_index) => (char::from_u32(lv + t_index)?, 3),
+        None => (char::from_u32(lv)?, 2),
+    })
+}
+
+/// Split the text into units, where each unit is either a character without a canonical
+/// composition, or a sequence of characters that can be canonically composed into a single
+/// character. Composition is greedy and doesn't reorder combining marks, so this is not a complete
+/// implementation of the Unicode normalization algorithm, but it handles the common cases.
+fn units(text: &str) -> Vec<Unit> {
+    static DECOMPOSITIONS: OnceLock<HashMap<&str, char>> = OnceLock::new();
+    let decompositions =
+        DECOMPOSITIONS.get_or_init(|| COMPOSITIONS.iter().map(|(c, s)| (*s, *c)).collect());
+
+    let mut decomposed = String::new();
+    for c in text.chars() {
+        decompose_into(c, &mut decomposed);
+    }
+    let chars: Vec<char> = decomposed.chars().collect();
+
+    let mut units = Vec::new();
+    let mut i = 0;
+
+    'outer: while i < chars.len() {
+        if let Some((composed, len)) = compose_hangul(&chars[i..]) {
+            units.push(Unit {
+                composed: Some(composed),
+                decomposed: chars[i..i + len].iter().collect(),
+            });
+            i += len;
+            continue;
+        }
+
+        // the longest decomposition in the table is 4 chars
+        for len in (2..=std::cmp::min(4, chars.len() - i)).rev() {
+            let seq: String = chars[i..i + len].iter().collect();
+            if let Some(composed) = decompositions.get(seq.as_str()) {
+                units.push(Unit {
+                    composed: Some(*composed),
+                    decomposed: seq,
+                });
+                i += len;
+                continue 'outer;
+            }
+        }
+
+        units.push(Unit {
+            composed: None,
+            decomposed: chars[i].to_string(),
+        });
+        i += 1;
+    }
+
+    units
+}
+
+/// Returns the text in normalization form C (composed).
+pub fn nfc(text: &str) -> String {
+    units(text)
+        .into_iter()
+        .map(|x| match x.composed {
+            Some(c) => c.to_string(),
+            None => x.decomposed,
+        })
+        .collect()
+}
+
+/// Returns the text in normalization form D (decomposed).
+pub fn nfd(text: &str) -> String {
+    units(text).into_iter().map(|x| x.decomposed).collect()
+}
+
+/// The normalization form of the text, if it has characters with a canonical composition in only
+/// one of the forms.
+pub fn form(text: &str) -> Option<NormalizationForm> {
+    match (nfc(text) == text, nfd(text) == text) {
+        (true, false) => Some(NormalizationForm::Nfc),
+        (false, true) => Some(NormalizationForm::Nfd),
+        // there's nothing to compose or decompose, or the text has both forms
+        _ => None,
+    }
+}
+
+/// Rewrite a regex pattern so that it matches text regardless of whether the text is in a composed
+/// or decomposed form. Each literal character (or sequence of characters) with a canonical
+/// composition is replaced by a non-capturing group matching either form. Literals within
+/// bracketed character classes are not rewritten.
+pub fn pattern(pattern: &str) -> Result<String, Box<regex_syntax::ast::Error>> {
+    let mut ast = regex_syntax::ast::parse::Parser::new()
+        .parse(pattern)
+        .map_err(Box::new)?;
+    rewrite(&mut ast);
+
+    let mut rv = String::new();
+    regex_syntax::ast::print::Printer::new()
+        .print(&ast, &mut rv)
+        .unwrap();
+    Ok(rv)
+}
+
+fn rewrite(ast: &mut Ast) {
+    match ast {
+        Ast::Literal(x) => {
+            let literal = Literal::clone(x);
+            *ast = Ast::concat(Concat {
+                span: literal.span,
+                asts: rewrite_literals(vec![literal]),
+            });
+        }
+        Ast::Repetition(x) => rewrite(&mut x.ast),
+        Ast::Group(x) => rewrite(&mut x.ast),
+        Ast::Alternation(x) => x.asts.iter_mut().for_each(rewrite),
+        Ast::Concat(x) => {
+            let mut asts = Vec::new();
+            let mut literals = Vec::new();
+
+            // consecutive literals are rewritten together since a decomposed character is made up
+            // of several literals
+            for mut ast in std::mem::take(&mut x.asts) {
+                if let Ast::Literal(x) = &ast {
+                    literals.push(Literal::clone(x));
+                    continue;
+                }
+
+                asts.extend(rewrite_literals(std::mem::take(&mut literals)));
+                rewrite(&mut ast);
+                asts.push(ast);
+            }
+            asts.extend(rewrite_literals(literals));
+
+            x.asts = asts;
+        }
+        _ => {}
+    }
+}
+
+fn rewrite_literals(literals: Vec<Literal>) -> Vec<Ast> {
+    let Some(span) = literals.first().map(|x| x.span) else {
+        return Vec::new();
+    };
+
+    let text: String = literals.iter().map(|x| x.c).collect();
+    let units = units(&text);
+
+    if units.iter().all(|x| x.composed.is_none()) {
+        // leave the literals as they were written
+        return literals.into_iter().map(Ast::literal).collect();
+    }
+
+    let literal = |c: char| {
+        // use an escape for anything that isn't trivially safe (meta characters, whitespace when
+        // the 'x' flag is set, etc)
+        let kind = if c.is_ascii_alphanumeric() {
+            LiteralKind::Verbatim
+        } else {
+            LiteralKind::HexBrace(HexLiteralKind::X)
+        };
+        Ast::literal(Literal { span, kind, c })
+    };
+
+    let mut asts = Vec::new();
+
+    for unit in units {
+        let Some(composed) = unit.composed else {
+            asts.extend(unit.decomposed.chars().map(literal));
+            continue;
+        };
+
+        let decomposed = Ast::concat(Concat {
+            span,
+            asts: unit.decomposed.chars().map(literal).collect(),
+        });
+
+        asts.push(Ast::group(Group {
+            span,
+            kind: GroupKind::NonCapturing(Flags {
+                span,
+                items: Vec::new(),
+            }),
+            ast: Box::new(Ast::alternation(Alternation {
+                span,
+                asts: vec![literal(composed), decomposed],
+            })),
+        }));
+    }
+
+    asts
+}
+
+/// Characters that have a canonical decomposition and are not excluded from composition, sorted by
+/// the composed character. Generated from the Unicode 14.0.0 character database. Hangul syllables
+/// are handled algorithmically and are not included.
+#[rustfmt::skip]
+const COMPOSITIONS: &[(char, &str)] = &[
+    ('\u{c0}', "A\u{300}"), ('\u{c1}', "A\u{301}"), ('\u{c2}', "A\u{302}"), ('\u{c3}', "A\u{303}"),
+    ('\u{c4}', "A\u{308}"), ('\u{c5}', "A\u{30a}"), ('\u{c7}', "C\u{327}"), ('\u{c8}', "E\u{300}"),
+    ('\u{c9}', "E\u{301}"), ('\u{ca}', "E\u{302}"), ('\u{cb}', "E\u{308}"), ('\u{cc}', "I\u{300}"),
+    ('\u{cd}', "I\u{301}"), ('\u{ce}', "I\u{302}"), ('\u{cf}', "I\u{308}"), ('\u{d1}', "N\u{303}"),
+    ('\u{d2}', "O\u{300}"), ('\u{d3}', "O\u{301}"), ('\u{d4}', "O\u{302}"), ('\u{d5}', "O\u{303}"),
+    ('\u{d6}', "O\u{308}"), ('\u{d9}', "U\u{300}"), ('\u{da}', "U\u{301}"), ('\u{db}', "U\u{302}"),
+    ('\u{dc}', "U\u{308}"), ('\u{dd}', "Y\u{301}"), ('\u{e0}', "a\u{300}"), ('\u{e1}', "a\u{301}"),
+    ('\u{e2}', "a\u{302}"), ('\u{e3}', "a\u{303}"), ('\u{e4}', "a\u{308}"), ('\u{e5}', "a\u{30a}"),
+    ('\u{e7}', "c\u{327}"), ('\u{e8}', "e\u{300}"), ('\u{e9}', "e\u{301}"), ('\u{ea}', "e\u{302}"),
+    ('\u{eb}', "e\u{308}"), ('\u{ec}', "i\u{300}"), ('\u{ed}', "i\u{301}"), ('\u{ee}', "i\u{302}"),
+    ('\u{ef}', "i\u{308}"), ('\u{f1}', "n\u{303}"), ('\u{f2}', "o\u{300}"), ('\u{f3}', "o\u{301}"),
+    ('\u{f4}', "o\u{302}"), ('\u{f5}', "o\u{303}"), ('\u{f6}', "o\u{308}"), ('\u{f9}', "u\u{300}"),
+    ('\u{fa}', "u\u{301}"), ('\u{fb}', "u\u{302}"), ('\u{fc}', "u\u{308}"), ('\u{fd}', "y\u{301}"),
+    ('\u{ff}', "y\u{308}"), ('\u{100}', "A\u{304}"), ('\u{101}', "a\u{304}"),
+    ('\u{102}', "A\u{306}"), ('\u{103}', "a\u{306}"), ('\u{104}', "A\u{328}"),
+    ('\u{105}', "a\u{328}"), ('\u{106}', "C\u{301}"), ('\u{107}', "c\u{301}"),
+    ('\u{108}', "C\u{302}"), ('\u{109}', "c\u{302}"), ('\u{10a}', "C\u{307}"),
+    ('\u{10b}', "c\u{307}"), ('\u{10c}', "C\u{30c}"), ('\u{10d}', "c\u{30c}"),
+    ('\u{10e}', "D\u{30c}"), ('\u{10f}', "d\u{30c}"), ('\u{112}', "E\u{304}"),
+    ('\u{113}', "e\u{304}"), ('\u{114}', "E\u{306}"), ('\u{115}', "e\u{306}"),
+    ('\u{116}', "E\u{307}"), ('\u{117}', "e\u{307}"), ('\u{118}', "E\u{328}"),
+    ('\u{119}', "e\u{328}"), ('\u{11a}', "E\u{30c}"), ('\u{11b}', "e\u{30c}"),
+    ('\u{11c}', "G\u{302}"), ('\u{11d}', "g\u{302}"), ('\u{11e}', "G\u{306}"),
+    ('\u{11f}', "g\u{306}"), ('\u{120}', "G\u{307}"), ('\u{121}', "g\u{307}"),
+    ('\u{122}', "G\u{327}"), ('\u{123}', "g\u{327}"), ('\u{124}', "H\u{302}"),
+    ('\u{125}', "h\u{302}"), ('\u{128}', "I\u{303}"), ('\u{129}', "i\u{303}"),
+    ('\u{12a}', "I\u{304}"), ('\u{12b}', "i\u{304}"), ('\u{12c}', "I\u{306}"),
+    ('\u{12d}', "i\u{306}"), ('\u{12e}', "I\u{328}"), ('\u{12f}', "i\u{328}"),
+    ('\u{130}', "I\u{307}"), ('\u{134}', "J\u{302}"), ('\u{135}', "j\u{302}"),
+    ('\u{136}', "K\u{327}"), ('\u{137}', "k\u{327}"), ('\u{139}', "L\u{301}"),
+    ('\u{13a}', "l\u{301}"), ('\u{13b}', "L\u{327}"), ('\u{13c}', "l\u{327}"),
+    ('\u{13d}', "L\u{30c}"), ('\u{13e}', "l\u{30c}"), ('\u{143}', "N\u{301}"),
+    ('\u{144}', "n\u{301}"), ('\u{145}', "N\u{327}"), ('\u{146}', "n\u{327}"),
+    ('\u{147}', "N\u{30c}"), ('\u{148}', "n\u{30c}"), ('\u{14c}', "O\u{304}"),
+    ('\u{14d}', "o\u{304}"), ('\u{14e}', "O\u{306}"), ('\u{14f}', "o\u{306}"),
+    ('\u{150}', "O\u{30b}"), ('\u{151}', "o\u{30b}"), ('\u{154}', "R\u{301}"),
+    ('\u{155}', "r\u{301}"), ('\u{156}', "R\u{327}"), ('\u{157}', "r\u{327}"),
+    ('\u{158}', "R\u{30c}"), ('\u{159}', "r\u{30c}"), ('\u{15a}', "S\u{301}"),
+    ('\u{15b}', "s\u{301}"), ('\u{15c}', "S\u{302}"), ('\u{15d}', "s\u{302}"),
+    ('\u{15e}', "S\u{327}"), ('\u{15f}', "s\u{327}"), ('\u{160}', "S\u{30c}"),
+    ('\u{161}', "s\u{30c}"), ('\u{162}', "T\u{327}"), ('\u{163}', "t\u{327}"),
+    ('\u{164}', "T\u{30c}"), ('\u{165}', "t\u{30c}"), ('\u{168}', "U\u{303}"),
+    ('\u{169}', "u\u{303}"), ('\u{16a}', "U\u{304}"), ('\u{16b}', "u\u{304}"),
+    ('\u{16c}', "U\u{306}"), ('\u{16d}', "u\u{306}"), ('\u{16e}', "U\u{30a}"),
+    ('\u{16f}', "u\u{30a}"), ('\u{170}', "U\u{30b}"), ('\u{171}', "u\u{30b}"),
+    ('\u{172}', "U\u{328}"), ('\u{173}', "u\u{328}"), ('\u{174}', "W\u{302}"),
+    ('\u{175}', "w\u{302}"), ('\u{176}', "Y\u{302}"), ('\u{177}', "y\u{302}"),
+    ('\u{178}', "Y\u{308}"), ('\u{179}', "Z\u{301}"), ('\u{17a}', "z\u{301}"),
+    ('\u{17b}', "Z\u{307}"), ('\u{17c}', "z\u{307}"), ('\u{17d}', "Z\u{30c}"),
+    ('\u{17e}', "z\u{30c}"), ('\u{1a0}', "O\u{31b}"), ('\u{1a1}', "o\u{31b}"),
+    ('\u{1af}', "U\u{31b}"), ('\u{1b0}', "u\u{31b}"), ('\u{1cd}', "A\u{30c}"),
+    ('\u{1ce}', "a\u{30c}"), ('\u{1cf}', "I\u{30c}"), ('\u{1d0}', "i\u{30c}"),
+    ('\u{1d1}', "O\u{30c}"), ('\u{1d2}', "o\u{30c}"), ('\u{1d3}', "U\u{30c}"),
+    ('\u{1d4}', "u\u{30c}"), ('\u{1d5}', "U\u{308}\u{304}"), ('\u{1d6}', "u\u{308}\u{304}"),
+    ('\u{1d7}', "U\u{308}\u{301}"), ('\u{1d8}', "u\u{308}\u{301}"), ('\u{1d9}', "U\u{308}\u{30c}"),
+    ('\u{1da}', "u\u{308}\u{30c}"), ('\u{1db}', "U\u{308}\u{300}"), ('\u{1dc}', "u\u{308}\u{300}"),
+    ('\u{1de}', "A\u{308}\u{304}"), ('\u{1df}', "a\u{308}\u{304}"), ('\u{1e0}', "A\u{307}\u{304}"),
+    ('\u{1e1}', "a\u{307}\u{304}"), ('\u{1e2}', "\u{c6}\u{304}"), ('\u{1e3}', "\u{e6}\u{304}"),
+    ('\u{1e6}', "G\u{30c}"), ('\u{1e7}', "g\u{30c}"), ('\u{1e8}', "K\u{30c}"),
+    ('\u{1e9}', "k\u{30c}"), ('\u{1ea}', "O\u{328}"), ('\u{1eb}', "o\u{328}"),
+    ('\u{1ec}', "O\u{328}\u{304}"), ('\u{1ed}', "o\u{328}\u{304}"), ('\u{1ee}', "\u{1b7}\u{30c}"),
+    ('\u{1ef}', "\u{292}\u{30c}"), ('\u{1f0}', "j\u{30c}"), ('\u{1f4}', "G\u{301}"),
+    ('\u{1f5}', "g\u{301}"), ('\u{1f8}', "N\u{300}"), ('\u{1f9}', "n\u{300}"),
+    ('\u{1fa}', "A\u{30a}\u{301}"), ('\u{1fb}', "a\u{30a}\u{301}"), ('\u{1fc}', "\u{c6}\u{301}"),
+    ('\u{1fd}', "\u{e6}\u{301}"), ('\u{1fe}', "\u{d8}\u{301}"), ('\u{1ff}', "\u{f8}\u{301}"),
+    ('\u{200}', "A\u{30f}"), ('\u{201}', "a\u{30f}"), ('\u{202}', "A\u{311}"),
+    ('\u{203}', "a\u{311}"), ('\u{204}', "E\u{30f}"), ('\u{205}', "e\u{30f}"),
+    ('\u{206}', "E\u{311}"), ('\u{207}', "e\u{311}"), ('\u{208}', "I\u{30f}"),
+    ('\u{209}', "i\u{30f}"), ('\u{20a}', "I\u{311}"), ('\u{20b}', "i\u{311}"),
+    ('\u{20c}', "O\u{30f}"), ('\u{20d}', "o\u{30f}"), ('\u{20e}', "O\u{311}"),
+    ('\u{20f}', "o\u{311}"), ('\u{210}', "R\u{30f}"), ('\u{211}', "r\u{30f}"),
+    ('\u{212}', "R\u{311}"), ('\u{213}', "r\u{311}"), ('\u{214}', "U\u{30f}"),
+    ('\u{215}', "u\u{30f}"), ('\u{216}', "U\u{311}"), ('\u{217}', "u\u{311}"),
+    ('\u{218}', "S\u{326}"), ('\u{219}', "s\u{326}"), ('\u{21a}', "T\u{326}"),
+    ('\u{21b}', "t\u{326}"), ('\u{21e}', "H\u{30c}"), ('\u{21f}', "h\u{30c}"),
+    ('\u{226}', "A\u{307}"), ('\u{227}', "a\u{307}"), ('\u{228}', "E\u{327}"),
+    ('\u{229}', "e\u{327}"), ('\u{22a}', "O\u{308}\u{304}"), ('\u{22b}', "o\u{308}\u{304}"),
+    ('\u{22c}', "O\u{303}\u{304}"), ('\u{22d}', "o\u{303}\u{304}"), ('\u{22e}', "O\u{307}"),
+    ('\u{22f}', "o\u{307}"), ('\u{230}', "O\u{307}\u{304}"), ('\u{231}', "o\u{307}\u{304}"),
+    ('\u{232}', "Y\u{304}"), ('\u{233}', "y\u{304}"), ('\u{385}', "\u{a8}\u{301}"),
+    ('\u{386}', "\u{391}\u{301}"), ('\u{388}', "\u{395}\u{301}"), ('\u{389}', "\u{397}\u{301}"),
+    ('\u{38a}', "\u{399}\u{301}"), ('\u{38c}', "\u{39f}\u{301}"), ('\u{38e}', "\u{3a5}\u{301}"),
+    ('\u{38f}', "\u{3a9}\u{301}"), ('\u{390}', "\u{3b9}\u{308}\u{301}"),
+    ('\u{3aa}', "\u{399}\u{308}"), ('\u{3ab}', "\u{3a5}\u{308}"), ('\u{3ac}', "\u{3b1}\u{301}"),
+    ('\u{3ad}', "\u{3b5}\u{301}"), ('\u{3ae}', "\u{3b7}\u{301}"), ('\u{3af}', "\u{3b9}\u{301}"),
+    ('\u{3b0}', "\u{3c5}\u{308}\u{301}"), ('\u{3ca}', "\u{3b9}\u{308}"),
+    ('\u{3cb}', "\u{3c5}\u{308}"), ('\u{3cc}', "\u{3bf}\u{301}"), ('\u{3cd}', "\u{3c5}\u{301}"),
+    ('\u{3ce}', "\u{3c9}\u{301}"), ('\u{3d3}', "\u{3d2}\u{301}"), ('\u{3d4}', "\u{3d2}\u{308}"),
+    ('\u{400}', "\u{415}\u{300}"), ('\u{401}', "\u{415}\u{308}"), ('\u{403}', "\u{413}\u{301}"),
+    ('\u{407}', "\u{406}\u{308}"), ('\u{40c}', "\u{41a}\u{301}"), ('\u{40d}', "\u{418}\u{300}"),
+    ('\u{40e}', "\u{423}\u{306}"), ('\u{419}', "\u{418}\u{306}"), ('\u{439}', "\u{438}\u{306}"),
+    ('\u{450}', "\u{435}\u{300}"), ('\u{451}', "\u{435}\u{308}"), ('\u{453}', "\u{433}\u{301}"),
+    ('\u{457}', "\u{456}\u{308}"), ('\u{45c}', "\u{43a}\u{301}"), ('\u{45d}', "\u{438}\u{300}"),
+    ('\u{45e}', "\u{443}\u{306}"), ('\u{476}', "\u{474}\u{30f}"), ('\u{477}', "\u{475}\u{30f}"),
+    ('\u{4c1}', "\u{416}\u{306}"), ('\u{4c2}', "\u{436}\u{306}"), ('\u{4d0}', "\u{410}\u{306}"),
+    ('\u{4d1}', "\u{430}\u{306}"), ('\u{4d2}', "\u{410}\u{308}"), ('\u{4d3}', "\u{430}\u{308}"),
+    ('\u{4d6}', "\u{415}\u{306}"), ('\u{4d7}', "\u{435}\u{306}"), ('\u{4da}', "\u{4d8}\u{308}"),
+    ('\u{4db}', "\u{4d9}\u{308}"), ('\u{4dc}', "\u{416}\u{308}"), ('\u{4dd}', "\u{436}\u{308}"),
+    ('\u{4de}', "\u{417}\u{308}"), ('\u{4df}', "\u{437}\u{308}"), ('\u{4e2}', "\u{418}\u{304}"),
+    ('\u{4e3}', "\u{438}\u{304}"), ('\u{4e4}', "\u{418}\u{308}"), ('\u{4e5}', "\u{438}\u{308}"),
+    ('\u{4e6}', "\u{41e}\u{308}"), ('\u{4e7}', "\u{43e}\u{308}"), ('\u{4ea}', "\u{4e8}\u{308}"),
+    ('\u{4eb}', "\u{4e9}\u{308}"), ('\u{4ec}', "\u{42d}\u{308}"), ('\u{4ed}', "\u{44d}\u{308}"),
+    ('\u{4ee}', "\u{423}\u{304}"), ('\u{4ef}', "\u{443}\u{304}"), ('\u{4f0}', "\u{423}\u{308}"),
+    ('\u{4f1}', "\u{443}\u{308}"), ('\u{4f2}', "\u{423}\u{30b}"), ('\u{4f3}', "\u{443}\u{30b}"),
+    ('\u{4f4}', "\u{427}\u{308}"), ('\u{4f5}', "\u{447}\u{308}"), ('\u{4f8}', "\u{42b}\u{308}"),
+    ('\u{4f9}', "\u{44b}\u{308}"), ('\u{622}', "\u{627}\u{653}"), ('\u{623}', "\u{627}\u{654}"),
+    ('\u{624}', "\u{648}\u{654}"), ('\u{625}', "\u{627}\u{655}"), ('\u{626}', "\u{64a}\u{654}"),
+    ('\u{6c0}', "\u{6d5}\u{654}"), ('\u{6c2}', "\u{6c1}\u{654}"), ('\u{6d3}', "\u{6d2}\u{654}"),
+    ('\u{929}', "\u{928}\u{93c}"), ('\u{931}', "\u{930}\u{93c}"), ('\u{934}', "\u{933}\u{93c}"),
+    ('\u{9cb}', "\u{9c7}\u{9be}"), ('\u{9cc}', "\u{9c7}\u{9d7}"), ('\u{b48}', "\u{b47}\u{b56}"),
+    ('\u{b4b}', "\u{b47}\u{b3e}"), ('\u{b4c}', "\u{b47}\u{b57}"), ('\u{b94}', "\u{b92}\u{bd7}"),
+    ('\u{bca}', "\u{bc6}\u{bbe}"), ('\u{bcb}', "\u{bc7}\u{bbe}"), ('\u{bcc}', "\u{bc6}\u{bd7}"),
+    ('\u{c48}', "\u{c46}\u{c56}"), ('\u{cc0}', "\u{cbf}\u{cd5}"), ('\u{cc7}', "\u{cc6}\u{cd5}"),
+    ('\u{cc8}', "\u{cc6}\u{cd6}"), ('\u{cca}', "\u{cc6}\u{cc2}"),
+    ('\u{ccb}', "\u{cc6}\u{cc2}\u{cd5}"), ('\u{d4a}', "\u{d46}\u{d3e}"),
+    ('\u{d4b}', "\u{d47}\u{d3e}"), ('\u{d4c}', "\u{d46}\u{d57}"), ('\u{dda}', "\u{dd9}\u{dca}"),
+    ('\u{ddc}', "\u{dd9}\u{dcf}"), ('\u{ddd}', "\u{dd9}\u{dcf}\u{dca}"),
+    ('\u{dde}', "\u{dd9}\u{ddf}"), ('\u{1026}', "\u{1025}\u{102e}"),
+    ('\u{1b06}', "\u{1b05}\u{1b35}"), ('\u{1b08}', "\u{1b07}\u{1b35}"),
+    ('\u{1b0a}', "\u{1b09}\u{1b35}"), ('\u{1b0c}', "\u{1b0b}\u{1b35}"),
+    ('\u{1b0e}', "\u{1b0d}\u{1b35}"), ('\u{1b12}', "\u{1b11}\u{1b35}"),
+    ('\u{1b3b}', "\u{1b3a}\u{1b35}"), ('\u{1b3d}', "\u{1b3c}\u{1b35}"),
+    ('\u{1b40}', "\u{1b3e}\u{1b35}"), ('\u{1b41}', "\u{1b3f}\u{1b35}"),
+    ('\u{1b43}', "\u{1b42}\u{1b35}"), ('\u{1e00}', "A\u{325}"), ('\u{1e01}', "a\u{325}"),
+    ('\u{1e02}', "B\u{307}"), ('\u{1e03}', "b\u{307}"), ('\u{1e04}', "B\u{323}"),
+    ('\u{1e05}', "b\u{323}"), ('\u{1e06}', "B\u{331}"), ('\u{1e07}', "b\u{331}"),
+    ('\u{1e08}', "C\u{327}\u{301}"), ('\u{1e09}', "c\u{327}\u{301}"), ('\u{1e0a}', "D\u{307}"),
+    ('\u{1e0b}', "d\u{307}"), ('\u{1e0c}', "D\u{323}"), ('\u{1e0d}', "d\u{323}"),
+    ('\u{1e0e}', "D\u{331}"), ('\u{1e0f}', "d\u{331}"), ('\u{1e10}', "D\u{327}"),
+    ('\u{1e11}', "d\u{327}"), ('\u{1e12}', "D\u{32d}"), ('\u{1e13}', "d\u{32d}"),
+    ('\u{1e14}', "E\u{304}\u{300}"), ('\u{1e15}', "e\u{304}\u{300}"),
+    ('\u{1e16}', "E\u{304}\u{301}"), ('\u{1e17}', "e\u{304}\u{301}"), ('\u{1e18}', "E\u{32d}"),
+    ('\u{1e19}', "e\u{32d}"), ('\u{1e1a}', "E\u{330}"), ('\u{1e1b}', "e\u{330}"),
+    ('\u{1e1c}', "E\u{327}\u{306}"), ('\u{1e1d}', "e\u{327}\u{306}"), ('\u{1e1e}', "F\u{307}"),
+    ('\u{1e1f}', "f\u{307}"), ('\u{1e20}', "G\u{304}"), ('\u{1e21}', "g\u{304}"),
+    ('\u{1e22}', "H\u{307}"), ('\u{1e23}', "h\u{307}"), ('\u{1e24}', "H\u{323}"),
+    ('\u{1e25}', "h\u{323}"), ('\u{1e26}', "H\u{308}"), ('\u{1e27}', "h\u{308}"),
+    ('\u{1e28}', "H\u{327}"), ('\u{1e29}', "h\u{327}"), ('\u{1e2a}', "H\u{32e}"),
+    ('\u{1e2b}', "h\u{32e}"), ('\u{1e2c}', "I\u{330}"), ('\u{1e2d}', "i\u{330}"),
+    ('\u{1e2e}', "I\u{308}\u{301}"), ('\u{1e2f}', "i\u{308}\u{301}"), ('\u{1e30}', "K\u{301}"),
+    ('\u{1e31}', "k\u{301}"), ('\u{1e32}', "K\u{323}"), ('\u{1e33}', "k\u{323}"),
+    ('\u{1e34}', "K\u{331}"), ('\u{1e35}', "k\u{331}"), ('\u{1e36}', "L\u{323}"),
+    ('\u{1e37}', "l\u{323}"), ('\u{1e38}', "L\u{323}\u{304}"), ('\u{1e39}', "l\u{323}\u{304}"),
+    ('\u{1e3a}', "L\u{331}"), ('\u{1e3b}', "l\u{331}"), ('\u{1e3c}', "L\u{32d}"),
+    ('\u{1e3d}', "l\u{32d}"), ('\u{1e3e}', "M\u{301}"), ('\u{1e3f}', "m\u{301}"),
+    ('\u{1e40}', "M\u{307}"), ('\u{1e41}', "m\u{307}"), ('\u{1e42}', "M\u{323}"),
+    ('\u{1e43}', "m\u{323}"), ('\u{1e44}', "N\u{307}"), ('\u{1e45}', "n\u{307}"),
+    ('\u{1e46}', "N\u{323}"), ('\u{1e47}', "n\u{323}"), ('\u{1e48}', "N\u{331}"),
+    ('\u{1e49}', "n\u{331}"), ('\u{1e4a}', "N\u{32d}"), ('\u{1e4b}', "n\u{32d}"),
+    ('\u{1e4c}', "O\u{303}\u{301}"), ('\u{1e4d}', "o\u{303}\u{301}"),
+    ('\u{1e4e}', "O\u{303}\u{308}"), ('\u{1e4f}', "o\u{303}\u{308}"),
+    ('\u{1e50}', "O\u{304}\u{300}"), ('\u{1e51}', "o\u{304}\u{300}"),
+    ('\u{1e52}', "O\u{304}\u{301}"), ('\u{1e53}', "o\u{304}\u{301}"), ('\u{1e54}', "P\u{301}"),
+    ('\u{1e55}', "p\u{301}"), ('\u{1e56}', "P\u{307}"), ('\u{1e57}', "p\u{307}"),
+    ('\u{1e58}', "R\u{307}"), ('\u{1e59}', "r\u{307}"), ('\u{1e5a}', "R\u{323}"),
+    ('\u{1e5b}', "r\u{323}"), ('\u{1e5c}', "R\u{323}\u{304}"), ('\u{1e5d}', "r\u{323}\u{304}"),
+    ('\u{1e5e}', "R\u{331}"), ('\u{1e5f}', "r\u{331}"), ('\u{1e60}', "S\u{307}"),
+    ('\u{1e61}', "s\u{307}"), ('\u{1e62}', "S\u{323}"), ('\u{1e63}', "s\u{323}"),
+    ('\u{1e64}', "S\u{301}\u{307}"), ('\u{1e65}', "s\u{301}\u{307}"),
+    ('\u{1e66}', "S\u{30c}\u{307}"), ('\u{1e67}', "s\u{30c}\u{307}"),
+    ('\u{1e68}', "S\u{323}\u{307}"), ('\u{1e69}', "s\u{323}\u{307}"), ('\u{1e6a}', "T\u{307}"),
+    ('\u{1e6b}', "t\u{307}"), ('\u{1e6c}', "T\u{323}"), ('\u{1e6d}', "t\u{323}"),
+    ('\u{1e6e}', "T\u{331}"), ('\u{1e6f}', "t\u{331}"), ('\u{1e70}', "T\u{32d}"),
+    ('\u{1e71}', "t\u{32d}"), ('\u{1e72}', "U\u{324}"), ('\u{1e73}', "u\u{324}"),
+    ('\u{1e74}', "U\u{330}"), ('\u{1e75}', "u\u{330}"), ('\u{1e76}', "U\u{32d}"),
+    ('\u{1e77}', "u\u{32d}"), ('\u{1e78}', "U\u{303}\u{301}"), ('\u{1e79}', "u\u{303}\u{301}"),
+    ('\u{1e7a}', "U\u{304}\u{308}"), ('\u{1e7b}', "u\u{304}\u{308}"), ('\u{1e7c}', "V\u{303}"),
+    ('\u{1e7d}', "v\u{303}"), ('\u{1e7e}', "V\u{323}"), ('\u{1e7f}', "v\u{323}"),
+    ('\u{1e80}', "W\u{300}"), ('\u{1e81}', "w\u{300}"), ('\u{1e82}', "W\u{301}"),
+    ('\u{1e83}', "w\u{301}"), ('\u{1e84}', "W\u{308}"), ('\u{1e85}', "w\u{308}"),
+    ('\u{1e86}', "W\u{307}"), ('\u{1e87}', "w\u{307}"), ('\u{1e88}', "W\u{323}"),
+    ('\u{1e89}', "w\u{323}"), ('\u{1e8a}', "X\u{307}"), ('\u{1e8b}', "x\u{307}"),
+    ('\u{1e8c}', "X\u{308}"), ('\u{1e8d}', "x\u{308}"), ('\u{1e8e}', "Y\u{307}"),
+    ('\u{1e8f}', "y\u{307}"), ('\u{1e90}', "Z\u{302}"), ('\u{1e91}', "z\u{302}"),
+    ('\u{1e92}', "Z\u{323}"), ('\u{1e93}', "z\u{323}"), ('\u{1e94}', "Z\u{331}"),
+    ('\u{1e95}', "z\u{331}"), ('\u{1e96}', "h\u{331}"), ('\u{1e97}', "t\u{308}"),
+    ('\u{1e98}', "w\u{30a}"), ('\u{1e99}', "y\u{30a}"), ('\u{1e9b}', "\u{17f}\u{307}"),
+    ('\u{1ea0}', "A\u{323}"), ('\u{1ea1}', "a\u{323}"), ('\u{1ea2}', "A\u{309}"),
+    ('\u{1ea3}', "a\u{309}"), ('\u{1ea4}', "A\u{302}\u{301}"), ('\u{1ea5}', "a\u{302}\u{301}"),
+    ('\u{1ea6}', "A\u{302}\u{300}"), ('\u{1ea7}', "a\u{302}\u{300}"),
+    ('\u{1ea8}', "A\u{302}\u{309}"), ('\u{1ea9}', "a\u{302}\u{309}"),
+    ('\u{1eaa}', "A\u{302}\u{303}"), ('\u{1eab}', "a\u{302}\u{303}"),
+    ('\u{1eac}', "A\u{323}\u{302}"), ('\u{1ead}', "a\u{323}\u{302}"),
+    ('\u{1eae}', "A\u{306}\u{301}"), ('\u{1eaf}', "a\u{306}\u{301}"),
+    ('\u{1eb0}', "A\u{306}\u{300}"), ('\u{1eb1}', "a\u{306}\u{300}"),
+    ('\u{1eb2}', "A\u{306}\u{309}"), ('\u{1eb3}', "a\u{306}\u{309}"),
+    ('\u{1eb4}', "A\u{306}\u{303}"), ('\u{1eb5}', "a\u{306}\u{303}"),
+    ('\u{1eb6}', "A\u{323}\u{306}"), ('\u{1eb7}', "a\u{323}\u{306}"), ('\u{1eb8}', "E\u{323}"),
+    ('\u{1eb9}', "e\u{323}"), ('\u{1eba}', "E\u{309}"), ('\u{1ebb}', "e\u{309}"),
+    ('\u{1ebc}', "E\u{303}"), ('\u{1ebd}', "e\u{303}"), ('\u{1ebe}', "E\u{302}\u{301}"),
+    ('\u{1ebf}', "e\u{302}\u{301}"), ('\u{1ec0}', "E\u{302}\u{300}"),
+    ('\u{1ec1}', "e\u{302}\u{300}"), ('\u{1ec2}', "E\u{302}\u{309}"),
+    ('\u{1ec3}', "e\u{302}\u{309}"), ('\u{1ec4}', "E\u{302}\u{303}"),
+    ('\u{1ec5}', "e\u{302}\u{303}"), ('\u{1ec6}', "E\u{323}\u{302}"),
+    ('\u{1ec7}', "e\u{323}\u{302}"), ('\u{1ec8}', "I\u{309}"), ('\u{1ec9}', "i\u{309}"),
+    ('\u{1eca}', "I\u{323}"), ('\u{1ecb}', "i\u{323}"), ('\u{1ecc}', "O\u{323}"),
+    ('\u{1ecd}', "o\u{323}"), ('\u{1ece}', "O\u{309}"), ('\u{1ecf}', "o\u{309}"),
+    ('\u{1ed0}', "O\u{302}\u{301}"), ('\u{1ed1}', "o\u{302}\u{301}"),
+    ('\u{1ed2}', "O\u{302}\u{300}"), ('\u{1ed3}', "o\u{302}\u{300}"),
+    ('\u{1ed4}', "O\u{302}\u{309}"), ('\u{1ed5}', "o\u{302}\u{309}"),
+    ('\u{1ed6}', "O\u{302}\u{303}"), ('\u{1ed7}', "o\u{302}\u{303}"),
+    ('\u{1ed8}', "O\u{323}\u{302}"), ('\u{1ed9}', "o\u{323}\u{302}"),
+    ('\u{1eda}', "O\u{31b}\u{301}"), ('\u{1edb}', "o\u{31b}\u{301}"),
+    ('\u{1edc}', "O\u{31b}\u{300}"), ('\u{1edd}', "o\u{31b}\u{300}"),
+    ('\u{1ede}', "O\u{31b}\u{309}"), ('\u{1edf}', "o\u{31b}\u{309}"),
+    ('\u{1ee0}', "O\u{31b}\u{303}"), ('\u{1ee1}', "o\u{31b}\u{303}"),
+    ('\u{1ee2}', "O\u{31b}\u{323}"), ('\u{1ee3}', "o\u{31b}\u{323}"), ('\u{1ee4}', "U\u{323}"),
+    ('\u{1ee5}', "u\u{323}"), ('\u{1ee6}', "U\u{309}"), ('\u{1ee7}', "u\u{309}"),
+    ('\u{1ee8}', "U\u{31b}\u{301}"), ('\u{1ee9}', "u\u{31b}\u{301}"),
+    ('\u{1eea}', "U\u{31b}\u{300}"), ('\u{1eeb}', "u\u{31b}\u{300}"),
+    ('\u{1eec}', "U\u{31b}\u{309}"), ('\u{1eed}', "u\u{31b}\u{309}"),
+    ('\u{1eee}', "U\u{31b}\u{303}"), ('\u{1eef}', "u\u{31b}\u{303}"),
+    ('\u{1ef0}', "U\u{31b}\u{323}"), ('\u{1ef1}', "u\u{31b}\u{323}"), ('\u{1ef2}', "Y\u{300}"),
+    ('\u{1ef3}', "y\u{300}"), ('\u{1ef4}', "Y\u{323}"), ('\u{1ef5}', "y\u{323}"),
+    ('\u{1ef6}', "Y\u{309}"), ('\u{1ef7}', "y\u{309}"), ('\u{1ef8}', "Y\u{303}"),
+    ('\u{1ef9}', "y\u{303}"), ('\u{1f00}', "\u{3b1}\u{313}"), ('\u{1f01}', "\u{3b1}\u{314}"),
+    ('\u{1f02}', "\u{3b1}\u{313}\u{300}"), ('\u{1f03}', "\u{3b1}\u{314}\u{300}"),
+    ('\u{1f04}', "\u{3b1}\u{313}\u{301}"), ('\u{1f05}', "\u{3b1}\u{314}\u{301}"),
+    ('\u{1f06}', "\u{3b1}\u{313}\u{342}"), ('\u{1f07}', "\u{3b1}\u{314}\u{342}"),
+    ('\u{1f08}', "\u{391}\u{313}"), ('\u{1f09}', "\u{391}\u{314}"),
+    ('\u{1f0a}', "\u{391}\u{313}\u{300}"), ('\u{1f0b}', "\u{391}\u{314}\u{300}"),
+    ('\u{1f0c}', "\u{391}\u{313}\u{301}"), ('\u{1f0d}', "\u{391}\u{314}\u{301}"),
+    ('\u{1f0e}', "\u{391}\u{313}\u{342}"), ('\u{1f0f}', "\u{391}\u{314}\u{342}"),
+    ('\u{1f10}', "\u{3b5}\u{313}"), ('\u{1f11}', "\u{3b5}\u{314}"),
+    ('\u{1f12}', "\u{3b5}\u{313}\u{300}"), ('\u{1f13}', "\u{3b5}\u{314}\u{300}"),
+    ('\u{1f14}', "\u{3b5}\u{313}\u{301}"), ('\u{1f15}', "\u{3b5}\u{314}\u{301}"),
+    ('\u{1f18}', "\u{395}\u{313}"), ('\u{1f19}', "\u{395}\u{314}"),
+    ('\u{1f1a}', "\u{395}\u{313}\u{300}"), ('\u{1f1b}', "\u{395}\u{314}\u{300}"),
+    ('\u{1f1c}', "\u{395}\u{313}\u{301}"), ('\u{1f1d}', "\u{395}\u{314}\u{301}"),
+    ('\u{1f20}', "\u{3b7}\u{313}"), ('\u{1f21}', "\u{3b7}\u{314}"),
+    ('\u{1f22}', "\u{3b7}\u{313}\u{300}"), ('\u{1f23}', "\u{3b7}\u{314}\u{300}"),
+    ('\u{1f24}', "\u{3b7}\u{313}\u{301}"), ('\u{1f25}', "\u{3b7}\u{314}\u{301}"),
+    ('\u{1f26}', "\u{3b7}\u{313}\u{342}"), ('\u{1f27}', "\u{3b7}\u{314}\u{342}"),
+    ('\u{1f28}', "\u{397}\u{313}"), ('\u{1f29}', "\u{397}\u{314}"),
+    ('\u{1f2a}', "\u{397}\u{313}\u{300}"), ('\u{1f2b}', "\u{397}\u{314}\u{300}"),
+    ('\u{1f2c}', "\u{397}\u{313}\u{301}"), ('\u{1f2d}', "\u{397}\u{314}\u{301}"),
+    ('\u{1f2e}', "\u{397}\u{313}\u{342}"), ('\u{1f2f}', "\u{397}\u{314}\u{342}"),
+    ('\u{1f30}', "\u{3b9}\u{313}"), ('\u{1f31}', "\u{3b9}\u{314}"),
+    ('\u{1f32}', "\u{3b9}\u{313}\u{300}"), ('\u{1f33}', "\u{3b9}\u{314}\u{300}"),
+    ('\u{1f34}', "\u{3b9}\u{313}\u{301}"), ('\u{1f35}', "\u{3b9}\u{314}\u{301}"),
+    ('\u{1f36}', "\u{3b9}\u{313}\u{342}"), ('\u{1f37}', "\u{3b9}\u{314}\u{342}"),
+    ('\u{1f38}', "\u{399}\u{313}"), ('\u{1f39}', "\u{399}\u{314}"),
+    ('\u{1f3a}', "\u{399}\u{313}\u{300}"), ('\u{1f3b}', "\u{399}\u{314}\u{300}"),
+    ('\u{1f3c}', "\u{399}\u{313}\u{301}"), ('\u{1f3d}', "\u{399}\u{314}\u{301}"),
+    ('\u{1f3e}', "\u{399}\u{313}\u{342}"), ('\u{1f3f}', "\u{399}\u{314}\u{342}"),
+    ('\u{1f40}', "\u{3bf}\u{313}"), ('\u{1f41}', "\u{3bf}\u{314}"),
+    ('\u{1f42}', "\u{3bf}\u{313}\u{300}"), ('\u{1f43}', "\u{3bf}\u{314}\u{300}"),
+    ('\u{1f44}', "\u{3bf}\u{313}\u{301}"), ('\u{1f45}', "\u{3bf}\u{314}\u{301}"),
+    ('\u{1f48}', "\u{39f}\u{313}"), ('\u{1f49}', "\u{39f}\u{314}"),
+    ('\u{1f4a}', "\u{39f}\u{313}\u{300}"), ('\u{1f4b}', "\u{39f}\u{314}\u{300}"),
+    ('\u{1f4c}', "\u{39f}\u{313}\u{301}"), ('\u{1f4d}', "\u{39f}\u{314}\u{301}"),
+    ('\u{1f50}', "\u{3c5}\u{313}"), ('\u{1f51}', "\u{3c5}\u{314}"),
+    ('\u{1f52}', "\u{3c5}\u{313}\u{300}"), ('\u{1f53}', "\u{3c5}\u{314}\u{300}"),
+    ('\u{1f54}', "\u{3c5}\u{313}\u{301}"), ('\u{1f55}', "\u{3c5}\u{314}\u{301}"),
+    ('\u{1f56}', "\u{3c5}\u{313}\u{342}"), ('\u{1f57}', "\u{3c5}\u{314}\u{342}"),
+    ('\u{1f59}', "\u{3a5}\u{314}"), ('\u{1f5b}', "\u{3a5}\u{314}\u{300}"),
+    ('\u{1f5d}', "\u{3a5}\u{314}\u{301}"), ('\u{1f5f}', "\u{3a5}\u{314}\u{342}"),
+    ('\u{1f60}', "\u{3c9}\u{313}"), ('\u{1f61}', "\u{3c9}\u{314}"),
+    ('\u{1f62}', "\u{3c9}\u{313}\u{300}"), ('\u{1f63}', "\u{3c9}\u{314}\u{300}"),
+    ('\u{1f64}', "\u{3c9}\u{313}\u{301}"), ('\u{1f65}', "\u{3c9}\u{314}\u{301}"),
+    ('\u{1f66}', "\u{3c9}\u{313}\u{342}"), ('\u{1f67}', "\u{3c9}\u{314}\u{342}"),
+    ('\u{1f68}', "\u{3a9}\u{313}"), ('\u{1f69}', "\u{3a9}\u{314}"),
+    ('\u{1f6a}', "\u{3a9}\u{313}\u{300}"), ('\u{1f6b}', "\u{3a9}\u{314}\u{300}"),
+    ('\u{1f6c}', "\u{3a9}\u{313}\u{301}"), ('\u{1f6d}', "\u{3a9}\u{314}\u{301}"),
+    ('\u{1f6e}', "\u{3a9}\u{313}\u{342}"), ('\u{1f6f}', "\u{3a9}\u{314}\u{342}"),
+    ('\u{1f70}', "\u{3b1}\u{300}"), ('\u{1f72}', "\u{3b5}\u{300}"), ('\u{1f74}', "\u{3b7}\u{300}"),
+    ('\u{1f76}', "\u{3b9}\u{300}"), ('\u{1f78}', "\u{3bf}\u{300}"), ('\u{1f7a}', "\u{3c5}\u{300}"),
+    ('\u{1f7c}', "\u{3c9}\u{300}"), ('\u{1f80}', "\u{3b1}\u{313}\u{345}"),
+    ('\u{1f81}', "\u{3b1}\u{314}\u{345}"), ('\u{1f82}', "\u{3b1}\u{313}\u{300}\u{345}"),
+    ('\u{1f83}', "\u{3b1}\u{314}\u{300}\u{345}"), ('\u{1f84}', "\u{3b1}\u{313}\u{301}\u{345}"),
+    ('\u{1f85}', "\u{3b1}\u{314}\u{301}\u{345}"), ('\u{1f86}', "\u{3b1}\u{313}\u{342}\u{345}"),
+    ('\u{1f87}', "\u{3b1}\u{314}\u{342}\u{345}"), ('\u{1f88}', "\u{391}\u{313}\u{345}"),
+    ('\u{1f89}', "\u{391}\u{314}\u{345}"), ('\u{1f8a}', "\u{391}\u{313}\u{300}\u{345}"),
+    ('\u{1f8b}', "\u{391}\u{314}\u{300}\u{345}"), ('\u{1f8c}', "\u{391}\u{313}\u{301}\u{345}"),
+    ('\u{1f8d}', "\u{391}\u{314}\u{301}\u{345}"), ('\u{1f8e}', "\u{391}\u{313}\u{342}\u{345}"),
+    ('\u{1f8f}', "\u{391}\u{314}\u{342}\u{345}"), ('\u{1f90}', "\u{3b7}\u{313}\u{345}"),
+    ('\u{1f91}', "\u{3b7}\u{314}\u{345}"), ('\u{1f92}', "\u{3b7}\u{313}\u{300}\u{345}"),
+    ('\u{1f93}', "\u{3b7}\u{314}\u{300}\u{345}"), ('\u{1f94}', "\u{3b7}\u{313}\u{301}\u{345}"),
+    ('\u{1f95}', "\u{3b7}\u{314}\u{301}\u{345}"), ('\u{1f96}', "\u{3b7}\u{313}\u{342}\u{345}"),
+    ('\u{1f97}', "\u{3b7}\u{314}\u{342}\u{345}"), ('\u{1f98}', "\u{397}\u{313}\u{345}"),
+    ('\u{1f99}', "\u{397}\u{314}\u{345}"), ('\u{1f9a}', "\u{397}\u{313}\u{300}\u{345}"),
+    ('\u{1f9b}', "\u{397}\u{314}\u{300}\u{345}"), ('\u{1f9c}', "\u{397}\u{313}\u{301}\u{345}"),
+    ('\u{1f9d}', "\u{397}\u{314}\u{301}\u{345}"), ('\u{1f9e}', "\u{397}\u{313}\u{342}\u{345}"),
+    ('\u{1f9f}', "\u{397}\u{314}\u{342}\u{345}"), ('\u{1fa0}', "\u{3c9}\u{313}\u{345}"),
+    ('\u{1fa1}', "\u{3c9}\u{314}\u{345}"), ('\u{1fa2}', "\u{3c9}\u{313}\u{300}\u{345}"),
+    ('\u{1fa3}', "\u{3c9}\u{314}\u{300}\u{345}"), ('\u{1fa4}', "\u{3c9}\u{313}\u{301}\u{345}"),
+    ('\u{1fa5}', "\u{3c9}\u{314}\u{301}\u{345}"), ('\u{1fa6}', "\u{3c9}\u{313}\u{342}\u{345}"),
+    ('\u{1fa7}', "\u{3c9}\u{314}\u{342}\u{345}"), ('\u{1fa8}', "\u{3a9}\u{313}\u{345}"),
+    ('\u{1fa9}', "\u{3a9}\u{314}\u{345}"), ('\u{1faa}', "\u{3a9}\u{313}\u{300}\u{345}"),
+    ('\u{1fab}', "\u{3a9}\u{314}\u{300}\u{345}"), ('\u{1fac}', "\u{3a9}\u{313}\u{301}\u{345}"),
+    ('\u{1fad}', "\u{3a9}\u{314}\u{301}\u{345}"), ('\u{1fae}', "\u{3a9}\u{313}\u{342}\u{345}"),
+    ('\u{1faf}', "\u{3a9}\u{314}\u{342}\u{345}"), ('\u{1fb0}', "\u{3b1}\u{306}"),
+    ('\u{1fb1}', "\u{3b1}\u{304}"), ('\u{1fb2}', "\u{3b1}\u{300}\u{345}"),
+    ('\u{1fb3}', "\u{3b1}\u{345}"), ('\u{1fb4}', "\u{3b1}\u{301}\u{345}"),
+    ('\u{1fb6}', "\u{3b1}\u{342}"), ('\u{1fb7}', "\u{3b1}\u{342}\u{345}"),
+    ('\u{1fb8}', "\u{391}\u{306}"), ('\u{1fb9}', "\u{391}\u{304}"), ('\u{1fba}', "\u{391}\u{300}"),
+    ('\u{1fbc}', "\u{391}\u{345}"), ('\u{1fc1}', "\u{a8}\u{342}"),
+    ('\u{1fc2}', "\u{3b7}\u{300}\u{345}"), ('\u{1fc3}', "\u{3b7}\u{345}"),
+    ('\u{1fc4}', "\u{3b7}\u{301}\u{345}"), ('\u{1fc6}', "\u{3b7}\u{342}"),
+    ('\u{1fc7}', "\u{3b7}\u{342}\u{345}"), ('\u{1fc8}', "\u{395}\u{300}"),
+    ('\u{1fca}', "\u{397}\u{300}"), ('\u{1fcc}', "\u{397}\u{345}"), ('\u{1fcd}', "\u{1fbf}\u{300}"),
+    ('\u{1fce}', "\u{1fbf}\u{301}"), ('\u{1fcf}', "\u{1fbf}\u{342}"),
+    ('\u{1fd0}', "\u{3b9}\u{306}"), ('\u{1fd1}', "\u{3b9}\u{304}"),
+    ('\u{1fd2}', "\u{3b9}\u{308}\u{300}"), ('\u{1fd6}', "\u{3b9}\u{342}"),
+    ('\u{1fd7}', "\u{3b9}\u{308}\u{342}"), ('\u{1fd8}', "\u{399}\u{306}"),
+    ('\u{1fd9}', "\u{399}\u{304}"), ('\u{1fda}', "\u{399}\u{300}"), ('\u{1fdd}', "\u{1ffe}\u{300}"),
+    ('\u{1fde}', "\u{1ffe}\u{301}"), ('\u{1fdf}', "\u{1ffe}\u{342}"),
+    ('\u{1fe0}', "\u{3c5}\u{306}"), ('\u{1fe1}', "\u{3c5}\u{304}"),
+    ('\u{1fe2}', "\u{3c5}\u{308}\u{300}"), ('\u{1fe4}', "\u{3c1}\u{313}"),
+    ('\u{1fe5}', "\u{3c1}\u{314}"), ('\u{1fe6}', "\u{3c5}\u{342}"),
+    ('\u{1fe7}', "\u{3c5}\u{308}\u{342}"), ('\u{1fe8}', "\u{3a5}\u{306}"),
+    ('\u{1fe9}', "\u{3a5}\u{304}"), ('\u{1fea}', "\u{3a5}\u{300}"), ('\u{1fec}', "\u{3a1}\u{314}"),
+    ('\u{1fed}', "\u{a8}\u{300}"), ('\u{1ff2}', "\u{3c9}\u{300}\u{345}"),
+    ('\u{1ff3}', "\u{3c9}\u{345}"), ('\u{1ff4}', "\u{3c9}\u{301}\u{345}"),
+    ('\u{1ff6}', "\u{3c9}\u{342}"), ('\u{1ff7}', "\u{3c9}\u{342}\u{345}"),
+    ('\u{1ff8}', "\u{39f}\u{300}"), ('\u{1ffa}', "\u{3a9}\u{300}"), ('\u{1ffc}', "\u{3a9}\u{345}"),
+    ('\u{219a}', "\u{2190}\u{338}"), ('\u{219b}', "\u{2192}\u{338}"),
+    ('\u{21ae}', "\u{2194}\u{338}"), ('\u{21cd}', "\u{21d0}\u{338}"),
+    ('\u{21ce}', "\u{21d4}\u{338}"), ('\u{21cf}', "\u{21d2}\u{338}"),
+    ('\u{2204}', "\u{2203}\u{338}"), ('\u{2209}', "\u{2208}\u{338}"),
+    ('\u{220c}', "\u{220b}\u{338}"), ('\u{2224}', "\u{2223}\u{338}"),
+    ('\u{2226}', "\u{2225}\u{338}"), ('\u{2241}', "\u{223c}\u{338}"),
+    ('\u{2244}', "\u{2243}\u{338}"), ('\u{2247}', "\u{2245}\u{338}"),
+    ('\u{2249}', "\u{2248}\u{338}"), ('\u{2260}', "\u{3d}\u{338}"), ('\u{2262}', "\u{2261}\u{338}"),
+    ('\u{226d}', "\u{224d}\u{338}"), ('\u{226e}', "\u{3c}\u{338}"), ('\u{226f}', "\u{3e}\u{338}"),
+    ('\u{2270}', "\u{2264}\u{338}"), ('\u{2271}', "\u{2265}\u{338}"),
+    ('\u{2274}', "\u{2272}\u{338}"), ('\u{2275}', "\u{2273}\u{338}"),
+    ('\u{2278}', "\u{2276}\u{338}"), ('\u{2279}', "\u{2277}\u{338}"),
+    ('\u{2280}', "\u{227a}\u{338}"), ('\u{2281}', "\u{227b}\u{338}"),
+    ('\u{2284}', "\u{2282}\u{338}"), ('\u{2285}', "\u{2283}\u{338}"),
+    ('\u{2288}', "\u{2286}\u{338}"), ('\u{2289}', "\u{2287}\u{338}"),
+    ('\u{22ac}', "\u{22a2}\u{338}"), ('\u{22ad}', "\u{22a8}\u{338}"),
+    ('\u{22ae}', "\u{22a9}\u{338}"), ('\u{22af}', "\u{22ab}\u{338}"),
+    ('\u{22e0}', "\u{227c}\u{338}"), ('\u{22e1}', "\u{227d}\u{338}"),
+    ('\u{22e2}', "\u{2291}\u{338}"), ('\u{22e3}', "\u{2292}\u{338}"),
+    ('\u{22ea}', "\u{22b2}\u{338}"), ('\u{22eb}', "\u{22b3}\u{338}"),
+    ('\u{22ec}', "\u{22b4}\u{338}"), ('\u{22ed}', "\u{22b5}\u{338}"),
+    ('\u{304c}', "\u{304b}\u{3099}"), ('\u{304e}', "\u{304d}\u{3099}"),
+    ('\u{3050}', "\u{304f}\u{3099}"), ('\u{3052}', "\u{3051}\u{3099}"),
+    ('\u{3054}', "\u{3053}\u{3099}"), ('\u{3056}', "\u{3055}\u{3099}"),
+    ('\u{3058}', "\u{3057}\u{3099}"), ('\u{305a}', "\u{3059}\u{3099}"),
+    ('\u{305c}', "\u{305b}\u{3099}"), ('\u{305e}', "\u{305d}\u{3099}"),
+    ('\u{3060}', "\u{305f}\u{3099}"), ('\u{3062}', "\u{3061}\u{3099}"),
+    ('\u{3065}', "\u{3064}\u{3099}"), ('\u{3067}', "\u{3066}\u{3099}"),
+    ('\u{3069}', "\u{3068}\u{3099}"), ('\u{3070}', "\u{306f}\u{3099}"),
+    ('\u{3071}', "\u{306f}\u{309a}"), ('\u{3073}', "\u{3072}\u{3099}"),
+    ('\u{3074}', "\u{3072}\u{309a}"), ('\u{3076}', "\u{3075}\u{3099}"),
+    ('\u{3077}', "\u{3075}\u{309a}"), ('\u{3079}', "\u{3078}\u{3099}"),
+    ('\u{307a}', "\u{3078}\u{309a}"), ('\u{307c}', "\u{307b}\u{3099}"),
+    ('\u{307d}', "\u{307b}\u{309a}"), ('\u{3094}', "\u{3046}\u{3099}"),
+    ('\u{309e}', "\u{309d}\u{3099}"), ('\u{30ac}', "\u{30ab}\u{3099}"),
+    ('\u{30ae}', "\u{30ad}\u{3099}"), ('\u{30b0}', "\u{30af}\u{3099}"),
+    ('\u{30b2}', "\u{30b1}\u{3099}"), ('\u{30b4}', "\u{30b3}\u{3099}"),
+    ('\u{30b6}', "\u{30b5}\u{3099}"), ('\u{30b8}', "\u{30b7}\u{3099}"),
+    ('\u{30ba}', "\u{30b9}\u{3099}"), ('\u{30bc}', "\u{30bb}\u{3099}"),
+    ('\u{30be}', "\u{30bd}\u{3099}"), ('\u{30c0}', "\u{30bf}\u{3099}"),
+    ('\u{30c2}', "\u{30c1}\u{3099}"), ('\u{30c5}', "\u{30c4}\u{3099}"),
+    ('\u{30c7}', "\u{30c6}\u{3099}"), ('\u{30c9}', "\u{30c8}\u{3099}"),
+    ('\u{30d0}', "\u{30cf}\u{3099}"), ('\u{30d1}', "\u{30cf}\u{309a}"),
+    ('\u{30d3}', "\u{30d2}\u{3099}"), ('\u{30d4}', "\u{30d2}\u{309a}"),
+    ('\u{30d6}', "\u{30d5}\u{3099}"), ('\u{30d7}', "\u{30d5}\u{309a}"),
+    ('\u{30d9}', "\u{30d8}\u{3099}"), ('\u{30da}', "\u{30d8}\u{309a}"),
+    ('\u{30dc}', "\u{30db}\u{3099}"), ('\u{30dd}', "\u{30db}\u{309a}"),
+    ('\u{30f4}', "\u{30a6}\u{3099}"), ('\u{30f7}', "\u{30ef}\u{3099}"),
+    ('\u{30f8}', "\u{30f0}\u{3099}"), ('\u{30f9}', "\u{30f1}\u{3099}"),
+    ('\u{30fa}', "\u{30f2}\u{3099}"), ('\u{30fe}', "\u{30fd}\u{3099}"),
+    ('\u{1109a}', "\u{11099}\u{110ba}"), ('\u{1109c}', "\u{1109b}\u{110ba}"),
+    ('\u{110ab}', "\u{110a5}\u{110ba}"), ('\u{1112e}', "\u{11131}\u{11127}"),
+    ('\u{1112f}', "\u{11132}\u{11127}"), ('\u{1134b}', "\u{11347}\u{1133e}"),
+    ('\u{1134c}', "\u{11347}\u{11357}"), ('\u{114bb}', "\u{114b9}\u{114ba}"),
+    ('\u{114bc}', "\u{114b9}\u{114b0}"), ('\u{114be}', "\u{114b9}\u{114bd}"),
+    ('\u{115ba}', "\u{115b8}\u{115af}"), ('\u{115bb}', "\u{115b9}\u{115af}"),
+    ('\u{11938}', "\u{11935}\u{11930}"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use grep_matcher::Matcher;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(nfd("caf\u{e9}"), "cafe\u{301}");
+        assert_eq!(nfc("cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(nfc("caf\u{e9}"), "caf\u{e9}");
+        assert_eq!(nfd("cafe\u{301}"), "cafe\u{301}");
+
+        // multiple combining marks
+        assert_eq!(nfd("\u{1e09}"), "c\u{327}\u{301}");
+        assert_eq!(nfc("c\u{327}\u{301}"), "\u{1e09}");
+
+        // hangul
+        assert_eq!(nfd("\u{d55c}"), "\u{1112}\u{1161}\u{11ab}");
+        assert_eq!(nfc("\u{1112}\u{1161}\u{11ab}"), "\u{d55c}");
+        assert_eq!(nfc("\u{1112}\u{1161}"), "\u{d558}");
+    }
+
+    #[test]
+    fn test_form() {
+        assert!(matches!(form("caf\u{e9}"), Some(NormalizationForm::Nfc)));
+        assert!(matches!(form("cafe\u{301}"), Some(NormalizationForm::Nfd)));
+        assert!(form("cafe").is_none());
+        assert!(form("caf\u{e9} cafe\u{301}").is_none());
+    }
+
+    #[test]
+    fn test_pattern() {
+        // patterns without composable characters shouldn't change
+        assert_eq!(pattern("foo(.*)bar").unwrap(), "foo(.*)bar");
+        assert_eq!(pattern(r"\x41\.[é]").unwrap(), r"\x41\.[é]");
+
+        for find in ["caf\u{e9}", "cafe\u{301}", "(caf\u{e9})+", "caf\u{e9}|x"] {
+            let matcher = grep_regex::RegexMatcher::new(&pattern(find).unwrap()).unwrap();
+            assert!(matcher.is_match(b"a caf\xc3\xa9 b").unwrap());
+            assert!(matcher.is_match(b"a cafe\xcc\x81 b").unwrap());
+            assert!(!matcher.is_match(b"a cafe b").unwrap());
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::path::Path;
+
+use crate::json::Value;
+
+/// Returns a GitHub Actions workflow command which annotates the (0-indexed) lines of the file.
+/// The `level` should be one of "error", "warning", or "notice".
+pub fn github_annotation(
+    level: &str,
+    path: &Path,
+    lines: std::ops::Range<u64>,
+    message: &str,
+) -> String {
+    // github expects paths relative to the repository root
+    let path = path.strip_prefix("./").unwrap_or(path);
+    let path = github_escape_property(&path.to_string_lossy());
+
+    // github uses 1-indexed line numbers
+    let start = lines.start + 1;
+    let end = std::cmp::max(lines.end, start);
+
+    format!(
+        "::{level} file={path},line={start},endLine={end}::{}",
+        github_escape_data(message),
+    )
+}
+
+/// Escape the message of a GitHub Actions workflow command.
+fn github_escape_data(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escape a property value of a GitHub Actions workflow command.
+fn github_escape_property(s: &str) -> String {
+    github_escape_data(s)
+        .replace(':', "%3A")
+        .replace(',', "%2C")
+}
+
+/// Returns a SARIF 2.1.0 log containing the given results.
+pub fn sarif_log(results: Vec<Value>) -> Value {
+    let rule = Value::object([
+        ("id", RULE_ID.into()),
+        (
+            "shortDescription",
+            Value::object([("text", "Text matching a regex pattern".into())]),
+        ),
+    ]);
+
+    let driver = Value::object([
+        ("name", env!("CARGO_PKG_NAME").into()),
+        ("version", env!("CARGO_PKG_VERSION").into()),
+        ("informationUri", env!("CARGO_PKG_REPOSITORY").into()),
+        ("rules", vec![rule].into()),
+    ]);
+
+    let run = Value::object([
+        ("tool", Value::object([("driver", driver)])),
+        ("columnKind", "unicodeCodePoints".into()),
+        ("results", results.into()),
+    ]);
+
+    Value::object([
+        ("version", "2.1.0".into()),
+        (
+            "$schema",
+            "https://json.schemastore.org/sarif-2.1.0.json".into(),
+        ),
+        ("runs", vec![run].into()),
+    ])
+}
+
+const RULE_ID: &str = "repatch/match";
+
+/// Returns a SARIF result for a match within the (0-indexed) line `line_num`, including a fix which
+/// replaces the match with `replacement`.
+pub fn sarif_result(
+    message: &str,
+    path: &Path,
+    line_num: u64,
+    line: &[u8],
+    matched: std::ops::Range<usize>,
+    replacement: &[u8],
+) -> Value {
+    let path = path.strip_prefix("./").unwrap_or(path);
+    let uri = Value::object([("uri", path.to_string_lossy().as_ref().into())]);
+
+    // SARIF uses 1-indexed line and column numbers, and we count columns in unicode code points
+    let column = |offset| String::from_utf8_lossy(&line[..offset]).chars().count() + 1;
+    let region = Value::object([
+        ("startLine", (line_num + 1).into()),
+        ("startColumn", column(matched.start).into()),
+        ("endLine", (line_num + 1).into()),
+        ("endColumn", column(matched.end).into()),
+    ]);
+
+    let replacement = String::from_utf8_lossy(replacement);
+
+    let location = Value::object([(
+        "physicalLocation",
+        Value::object([
+            ("artifactLocation", uri.clone()),
+            ("region", region.clone()),
+        ]),
+    )]);
+
+    let fix = Value::object([
+        (
+            "description",
+            Value::object([("text", format!("Replace with \"{replacement}\"").into())]),
+        ),
+        (
+            "artifactChanges",
+            vec![Value::object([
+                ("artifactLocation", uri),
+                (
+                    "replacements",
+                    vec![Value::object([
+                        ("deletedRegion", region),
+                        (
+                            "insertedContent",
+                            Value::object([("text", replacement.as_ref().into())]),
+                        ),
+                    ])]
+                    .into(),
+                ),
+            ])]
+            .into(),
+        ),
+    ]);
+
+    Value::object([
+        ("ruleId", RULE_ID.into()),
+        ("level", "error".into()),
+        ("message", Value::object([("text", message.into())])),
+        ("locations", vec![location].into()),
+        ("fixes", vec![fix].into()),
+    ])
+}
+
+/// The lines of a hunk for a `--json` event, which start at the (0-indexed) line `start`. The
+/// range is 1-indexed and doesn't include "end". Like ripgrep's `--json`, the lines are given as
+/// "text" if they're valid UTF-8, and otherwise as base64-encoded "bytes".
+pub fn json_lines(start: u64, lines: &[u8], line_terminator: u8) -> Value {
+    let end = start + crate::util::count_lines(lines, line_terminator);
+    let data = match std::str::from_utf8(lines) {
+        Ok(x) => ("text", x.into()),
+        Err(_) => ("bytes", base64(lines).into()),
+    };
+    Value::object([
+        ("start", (start + 1).into()),
+        ("end", (end + 1).into()),
+        data,
+    ])
+}
+
+/// Encode `bytes` as standard base64 with padding.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, x)| n | u32::from(*x) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_github_annotation() {
+        assert_eq!(
+            github_annotation("error", Path::new("./src/a,b.rs"), 4..5, "100%\nfoo"),
+            "::error file=src/a%2Cb.rs,line=5,endLine=5::100%25%0Afoo",
+        );
+        assert_eq!(
+            github_annotation("notice", Path::new("a:b"), 0..3, "foo"),
+            "::notice file=a%3Ab,line=1,endLine=3::foo",
+        );
+        // an empty range (for example if a hunk removed all lines)
+        assert_eq!(
+            github_annotation("notice", Path::new("a"), 2..2, "foo"),
+            "::notice file=a,line=3,endLine=3::foo",
+        );
+    }
+
+    #[test]
+    fn test_sarif() {
+        let results = vec![
+            sarif_result("foo", Path::new("./a.rs"), 0, b"foo()", 0..3, b"bar"),
+            sarif_result(
+                "foo",
+                Path::new("b.rs"),
+                9,
+                "é = foo".as_bytes(),
+                5..8,
+                b"bar",
+            ),
+        ];
+        // the log is valid json
+        let log = crate::json::parse(&sarif_log(results).to_string()).unwrap();
+
+        let path = |value: &Value, keys: &[&str]| {
+            keys.iter().fold(value.clone(), |value, key| {
+                match (value, key.parse::<usize>()) {
+                    (Value::Array(x), Ok(i)) => x[i].clone(),
+                    (value, _) => value.get(key).unwrap().clone(),
+                }
+            })
+        };
+        assert_eq!(path(&log, &["version"]), "2.1.0".into());
+        assert_eq!(
+            path(&log, &["$schema"]),
+            "https://json.schemastore.org/sarif-2.1.0.json".into(),
+        );
+
+        let Value::Array(results) = path(&log, &["runs", "0", "results"]) else {
+            panic!("the results aren't an array");
+        };
+        assert_eq!(results.len(), 2);
+
+        let location = |i: usize| path(&results[i], &["locations", "0", "physicalLocation"]);
+        let region = |i, key| path(&location(i), &["region", key]).as_u64().unwrap();
+        assert_eq!(
+            path(&location(0), &["artifactLocation", "uri"]),
+            "a.rs".into(),
+        );
+        assert_eq!(region(0, "startLine"), 1);
+        assert_eq!(region(0, "endLine"), 1);
+        assert_eq!((region(0, "startColumn"), region(0, "endColumn")), (1, 4));
+
+        assert_eq!(
+            path(&location(1), &["artifactLocation", "uri"]),
+            "b.rs".into(),
+        );
+        assert_eq!(region(1, "startLine"), 10);
+        assert_eq!(region(1, "endLine"), 10);
+        // columns are counted in code points
+        assert_eq!((region(1, "startColumn"), region(1, "endColumn")), (5, 8));
+        assert_eq!(path(&results[1], &["ruleId"]), RULE_ID.into());
+    }
+
+    #[test]
+    fn test_json_lines() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob\xff"), "Zm9vYv8=");
+
+        assert_eq!(
+            json_lines(2, b"a\nb\n", b'\n').to_string(),
+            r#"{"start":3,"end":5,"text":"a\nb\n"}"#,
+        );
+        assert_eq!(
+            json_lines(0, b"\xff\n", b'\n').to_string(),
+            r#"{"start":1,"end":2,"bytes":"/wo="}"#,
+        );
+        // a hunk that removed all of its lines
+        assert_eq!(
+            json_lines(4, b"", b'\n').to_string(),
+            r#"{"start":5,"end":5,"text":""}"#,
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! Paging of long output that doesn't need any user input, such as the output of `--show`.
+//!
+//! Stdout is redirected to a pipe that's read by `$PAGER`, or by a minimal built-in pager if
+//! `$PAGER` isn't set.
+//!
+//! When prompting, a hunk that's taller than the terminal is shown with its own pager instead,
+//! which exits before the prompt is shown.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Context;
+
+pub struct Pager {
+    /// The original stdout.
+    stdout: OwnedFd,
+    reader: Reader,
+}
+
+enum Reader {
+    Command(Child),
+    BuiltIn(std::thread::JoinHandle<()>),
+}
+
+/// Start paging stdout. Returns `None` if stdout isn't a terminal, or if `$PAGER` is empty or
+/// "cat".
+pub fn start() -> anyhow::Result<Option<Pager>> {
+    if !std::io::stdout().is_terminal() {
+        return Ok(None);
+    }
+
+    let cmd = std::env::var_os("PAGER");
+    if cmd.as_ref().is_some_and(|x| x.is_empty() || x == "cat") {
+        return Ok(None);
+    }
+
+    // the terminal size must be checked before stdout is redirected
+    let size = crate::ui::terminal_size();
+
+    std::io::stdout().flush()?;
+    let stdout = std::io::stdout().as_fd().try_clone_to_owned()?;
+    let (read, write) = std::io::pipe()?;
+
+    let reader = match cmd {
+        Some(cmd) => {
+            let child = Command::new("sh")
+                .arg("-c")
+                .arg(&cmd)
+                // like git, don't page short output and keep the colors
+                .env("LESS", std::env::var_os("LESS").unwrap_or("FRX".into()))
+                .stdin(Stdio::from(read))
+                .stdout(stdout.try_clone()?)
+                .spawn()
+                .with_context(|| format!("could not run the pager '{}'", cmd.to_string_lossy()))?;
+            Reader::Command(child)
+        }
+        None => {
+            let output = File::from(stdout.try_clone()?);
+            Reader::BuiltIn(std::thread::spawn(move || built_in(read, output, size)))
+        }
+    };
+
+    if unsafe { libc::dup2(write.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    // like git, exit quietly if the pager is closed before all of the output was written
+    unsafe { libc::signal(libc::SIGPIPE, libc::SIG_DFL) };
+
+    Ok(Some(Pager { stdout, reader }))
+}
+
+impl Pager {
+    /// Wait for the user to close the pager, and restore stdout.
+    pub fn finish(self) {
+        let _ = std::io::stdout().flush();
+
+        // this closes the last write end of the pipe, so the pager will see the end of the output
+        unsafe { libc::dup2(self.stdout.as_raw_fd(), libc::STDOUT_FILENO) };
+
+        match self.reader {
+            Reader::Command(mut child) => {
+                let _ = child.wait();
+            }
+            Reader::BuiltIn(thread) => {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+/// Whether hunks that are taller than the terminal are paged when prompting.
+static PAGE_HUNKS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_page_hunks(page: bool) {
+    PAGE_HUNKS.store(page, Ordering::Relaxed);
+}
+
+/// Show the formatted hunk `hunk` with `$REPATCH_PAGER`, `$PAGER`, or "less -R" if it's taller than
+/// the terminal, and wait for the pager to exit so that the prompt is shown after the hunk.
+/// Returns false if the hunk wasn't paged, in which case it should be printed instead.
+pub fn page_hunk(hunk: &str) -> bool {
+    if !PAGE_HUNKS.load(Ordering::Relaxed)
+        || !std::io::stdout().is_terminal()
+        || !std::io::stdin().is_terminal()
+    {
+        return false;
+    }
+
+    // the prompt needs a row below the hunk
+    let (cols, rows) = crate::ui::terminal_size();
+    let hunk_rows: usize = hunk.lines().map(|x| line_rows(x, cols)).sum();
+    if hunk_rows < rows {
+        return false;
+    }
+
+    let cmd = ["REPATCH_PAGER", "PAGER"]
+        .into_iter()
+        .find_map(|x| std::env::var_os(x).filter(|x| !x.is_empty()))
+        .unwrap_or("less -R".into());
+    if cmd == "cat" {
+        return false;
+    }
+
+    let _ = std::io::stdout().flush();
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(&cmd)
+        .stdin(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(x) => x,
+        Err(e) => {
+            crate::ui::error!("could not run the pager '{}': {e}", cmd.to_string_lossy());
+            return false;
+        }
+    };
+
+    // the pager is allowed to exit without reading everything
+    let _ = writeln!(child.stdin.take().unwrap(), "{hunk}");
+    let _ = child.wait();
+    true
+}
+
+/// The number of rows of the terminal that `line` takes up, since a line that's wider than the
+/// terminal is wrapped onto several rows.
+fn line_rows(line: &str, cols: usize) -> usize {
+    let text = crate::ui::strip_ansi(line);
+    let width = crate::util::display_width(text.trim_end_matches(['\n', '\r']).as_bytes());
+    width.div_ceil(cols).max(1)
+}
+
+/// Show a page of lines at a time, and wait for the user to press enter before showing the next.
+fn built_in(input: impl Read, mut output: File, (cols, rows): (usize, usize)) {
+    let mut input = BufReader::new(input);
+    let mut tty = File::open("/dev/tty").map(BufReader::new).ok();
+
+    let page_len = rows.saturating_sub(1).max(1);
+    let mut shown = 0;
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        match input.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+
+        let line_rows = line_rows(&String::from_utf8_lossy(&line), cols);
+
+        if shown > 0 && shown + line_rows > page_len {
+            let _ = write!(output, "--More-- (enter: next page, q: quit) ");
+            let _ = output.flush();
+
+            let mut answer = String::new();
+            let quit = match tty.as_mut() {
+                Some(tty) => tty.read_line(&mut answer).unwrap_or(0) == 0 || answer.trim() == "q",
+                None => true,
+            };
+
+            if !crate::ui::is_plain() {
+                // remove the prompt
+                let _ = write!(output, "\x1b[1A\x1b[2K");
+            }
+
+            if quit {
+                // the rest of the output must still be read so that writing it doesn't block
+                let _ = std::io::copy(&mut input, &mut std::io::sink());
+                return;
+            }
+
+            shown = 0;
+        }
+
+        if output.write_all(&line).is_err() {
+            return;
+        }
+        shown += line_rows;
+    }
+}
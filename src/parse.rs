@@ -1,9 +1,15 @@
-use bstr::ByteSlice;
+use bstr::{ByteSlice, ByteVec};
 
+/// The lines of `bytes` without their "\n" or "\r\n" line endings, and the position of each line
+/// within `bytes`.
 pub fn lines_with_pos(bytes: &[u8]) -> impl Iterator<Item = (&[u8], usize)> {
-    bytes.lines().scan(0, |line_start, line| {
+    bytes.lines_with_terminator().scan(0, |line_start, line| {
         let x = *line_start;
-        *line_start += line.len() + 1;
+        *line_start += line.len();
+        let line = match line.strip_suffix(b"\n") {
+            Some(x) => x.strip_suffix(b"\r").unwrap_or(x),
+            None => line,
+        };
         Some((line, x))
     })
 }
@@ -19,20 +25,275 @@ pub fn patch_block_header(bytes: &[u8]) -> Option<((u64, u64), (u64, u64))> {
     let range_1 = range_1.strip_prefix(b"-")?;
     let range_2 = range_2.strip_prefix(b" +")?;
 
-    let mut range_1 = range_1.split_at(range_1.find_byte(b',')?);
-    let mut range_2 = range_2.split_at(range_2.find_byte(b',')?);
+    Some((patch_range(range_1)?, patch_range(range_2)?))
+}
+
+/// Parses a range such as "4,2" from a patch block header. The line count is optional and defaults
+/// to 1.
+fn patch_range(bytes: &[u8]) -> Option<(u64, u64)> {
+    let Some(comma) = bytes.find_byte(b',') else {
+        return Some((bytes_as_u64(bytes)?, 1));
+    };
+
+    let (start, count) = bytes.split_at(comma);
+    let count = count.strip_prefix(b",")?;
+
+    Some((bytes_as_u64(start)?, bytes_as_u64(count)?))
+}
+
+/// Parses a single byte, which may be written as an escape sequence such as `\r`, `\0`, or `\x1e`.
+pub fn escaped_byte(s: &str) -> Option<u8> {
+    match Vec::unescape_bytes(s).as_slice() {
+        [x] => Some(*x),
+        _ => None,
+    }
+}
+
+/// Parses a 1-indexed inclusive range such as "4" or "2-7".
+pub fn index_range(s: &str) -> Option<std::ops::RangeInclusive<u64>> {
+    let (start, end) = match s.split_once('-') {
+        Some((start, end)) => (start.trim().parse().ok()?, end.trim().parse().ok()?),
+        None => {
+            let x = s.trim().parse().ok()?;
+            (x, x)
+        }
+    };
+
+    if start == 0 || start > end {
+        return None;
+    }
+
+    Some(start..=end)
+}
+
+/// Parses a duration such as "30s", "5m", "1h", or "500ms". A number without a unit is in
+/// seconds.
+pub fn duration(s: &str) -> Option<std::time::Duration> {
+    let s = s.trim();
+    let idx = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(idx);
+    let num: u64 = num.parse().ok()?;
+
+    let secs = match unit {
+        "ms" => return Some(std::time::Duration::from_millis(num)),
+        "" | "s" => num,
+        "m" => num.checked_mul(60)?,
+        "h" => num.checked_mul(60 * 60)?,
+        _ => return None,
+    };
+
+    Some(std::time::Duration::from_secs(secs))
+}
+
+/// Parses a size in bytes such as "512", "512K", "64M", or "1G". The units are powers of 1024.
+pub fn byte_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let idx = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(idx);
+    let num: u64 = num.parse().ok()?;
+
+    let shift = match unit {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        _ => return None,
+    };
+
+    num.checked_mul(1 << shift)
+}
+
+/// Parses a range of byte offsets such as "10..20", where the end is exclusive.
+pub fn byte_range(s: &str) -> Option<std::ops::Range<u64>> {
+    let (start, end) = s.split_once("..")?;
+    let start = start.trim().parse().ok()?;
+    let end = end.trim().parse().ok()?;
+
+    if start > end {
+        return None;
+    }
+
+    Some(start..end)
+}
+
+/// Parse a pair of file extensions like "h:c", without their leading dots.
+pub fn extension_pair(s: &str) -> Option<(String, String)> {
+    let (a, b) = s.split_once(':')?;
+    let valid = |x: &str| !x.is_empty() && !x.contains(['.', '/', ':']);
+    if !valid(a) || !valid(b) || a == b {
+        return None;
+    }
+    Some((a.to_string(), b.to_string()))
+}
+
+/// Parse a change to a file's permission bits, such as "644" or "go-w,u+x", like `chmod`. A
+/// symbolic change without "u", "g", "o", or "a" applies to all of them, regardless of the umask.
+pub fn mode_change(s: &str) -> Option<crate::util::ModeChange> {
+    use crate::util::ModeClause;
+
+    if !s.is_empty() && s.bytes().all(|x| x.is_ascii_digit()) {
+        let mode = u32::from_str_radix(s, 8).ok().filter(|x| *x <= 0o777)?;
+        return Some(crate::util::ModeChange(vec![ModeClause {
+            who: 0o777,
+            op: b'=',
+            perms: mode,
+        }]));
+    }
+
+    let mut clauses = Vec::new();
+    for clause in s.split(',') {
+        let op_idx = clause.find(['+', '-', '='])?;
+        let (who, rest) = clause.split_at(op_idx);
+        let (op, perms) = rest.split_at(1);
+
+        let mut who_bits = 0;
+        for x in who.chars() {
+            who_bits |= match x {
+                'u' => 0o700,
+                'g' => 0o070,
+                'o' => 0o007,
+                'a' => 0o777,
+                _ => return None,
+            };
+        }
+        let mut perm_bits = 0;
+        for x in perms.chars() {
+            perm_bits |= match x {
+                'r' => 0o444,
+                'w' => 0o222,
+                'x' => 0o111,
+                _ => return None,
+            };
+        }
+
+        clauses.push(ModeClause {
+            who: if who_bits == 0 { 0o777 } else { who_bits },
+            op: op.as_bytes()[0],
+            perms: perm_bits,
+        });
+    }
+    Some(crate::util::ModeChange(clauses))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lines_with_pos() {
+        let lines: Vec<_> = lines_with_pos(b"a\r\n\xff\nb\rc\n\nd").collect();
+        assert_eq!(
+            lines,
+            [
+                (&b"a"[..], 0),
+                (b"\xff", 3),
+                (b"b\rc", 5),
+                (b"", 9),
+                (b"d", 10)
+            ],
+        );
+    }
+
+    #[test]
+    fn test_patch_block_header() {
+        assert_eq!(
+            patch_block_header(b"@@ -4,2 +5,3 @@"),
+            Some(((4, 2), (5, 3)))
+        );
+        assert_eq!(patch_block_header(b"@@ -4 +5 @@"), Some(((4, 1), (5, 1))));
+        assert_eq!(patch_block_header(b"@@ -0,0 +1 @@"), Some(((0, 0), (1, 1))));
+        assert_eq!(patch_block_header(b"@@ -4,2 +5,3"), None);
+        assert_eq!(patch_block_header(b"@@ -4, +5,3 @@"), None);
+    }
+
+    #[test]
+    fn test_escaped_byte() {
+        assert_eq!(escaped_byte(";"), Some(b';'));
+        assert_eq!(escaped_byte(r"\r"), Some(b'\r'));
+        assert_eq!(escaped_byte(r"\0"), Some(0));
+        assert_eq!(escaped_byte(r"\x1e"), Some(0x1e));
+        assert_eq!(escaped_byte(""), None);
+        assert_eq!(escaped_byte("ab"), None);
+        assert_eq!(escaped_byte("é"), None);
+    }
+
+    #[test]
+    fn test_index_range() {
+        assert_eq!(index_range("4"), Some(4..=4));
+        assert_eq!(index_range("2-7"), Some(2..=7));
+        assert_eq!(index_range(" 2 - 7 "), Some(2..=7));
+        assert_eq!(index_range("3-3"), Some(3..=3));
+        assert_eq!(index_range("0"), None);
+        assert_eq!(index_range("7-2"), None);
+        assert_eq!(index_range("2-"), None);
+        assert_eq!(index_range("foo"), None);
+    }
+
+    #[test]
+    fn test_duration() {
+        use std::time::Duration;
+        assert_eq!(duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(duration("30"), Some(Duration::from_secs(30)));
+        assert_eq!(duration("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(duration(""), None);
+        assert_eq!(duration("s"), None);
+        assert_eq!(duration("1.5s"), None);
+        assert_eq!(duration("3d"), None);
+    }
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(byte_range("10..20"), Some(10..20));
+        assert_eq!(byte_range("0..0"), Some(0..0));
+        assert_eq!(byte_range(" 3 .. 4 "), Some(3..4));
+        assert_eq!(byte_range("20..10"), None);
+        assert_eq!(byte_range("10"), None);
+        assert_eq!(byte_range("10..=20"), None);
+        assert_eq!(byte_range("..20"), None);
+    }
 
-    range_1.1 = range_1.1.strip_prefix(b",")?;
-    range_2.1 = range_2.1.strip_prefix(b",")?;
+    #[test]
+    fn test_byte_size() {
+        assert_eq!(byte_size("512"), Some(512));
+        assert_eq!(byte_size("512K"), Some(512 * 1024));
+        assert_eq!(byte_size("64M"), Some(64 * 1024 * 1024));
+        assert_eq!(byte_size("1G"), Some(1024 * 1024 * 1024));
+        assert_eq!(byte_size(""), None);
+        assert_eq!(byte_size("M"), None);
+        assert_eq!(byte_size("1.5M"), None);
+        assert_eq!(byte_size("1T"), None);
+        assert_eq!(byte_size("99999999999999999999G"), None);
+    }
 
-    let range_1 = (
-        crate::parse::bytes_as_u64(range_1.0)?,
-        crate::parse::bytes_as_u64(range_1.1)?,
-    );
-    let range_2 = (
-        crate::parse::bytes_as_u64(range_2.0)?,
-        crate::parse::bytes_as_u64(range_2.1)?,
-    );
+    #[test]
+    fn test_mode_change() {
+        let apply = |s, mode| mode_change(s).map(|x| x.apply(mode));
+        assert_eq!(apply("644", 0o777), Some(0o644));
+        assert_eq!(apply("0640", 0o777), Some(0o640));
+        assert_eq!(apply("o-w", 0o777), Some(0o775));
+        assert_eq!(apply("go-w,u+x", 0o666), Some(0o744));
+        assert_eq!(apply("a=r", 0o755), Some(0o444));
+        assert_eq!(apply("+x", 0o600), Some(0o711));
+        assert_eq!(apply("g=", 0o775), Some(0o705));
+        assert_eq!(mode_change(""), None);
+        assert_eq!(mode_change("1777"), None);
+        assert_eq!(mode_change("888"), None);
+        assert_eq!(mode_change("o+s"), None);
+        assert_eq!(mode_change("w"), None);
+        assert_eq!(mode_change("o-w,"), None);
+    }
 
-    Some((range_1, range_2))
+    #[test]
+    fn test_extension_pair() {
+        let pair = |a: &str, b: &str| Some((a.to_string(), b.to_string()));
+        assert_eq!(extension_pair("h:c"), pair("h", "c"));
+        assert_eq!(extension_pair("hpp:cpp"), pair("hpp", "cpp"));
+        assert_eq!(extension_pair("h"), None);
+        assert_eq!(extension_pair("h:"), None);
+        assert_eq!(extension_pair(".h:.c"), None);
+        assert_eq!(extension_pair("c:c"), None);
+        assert_eq!(extension_pair("a:b:c"), None);
+    }
 }
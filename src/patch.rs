@@ -0,0 +1,1622 @@
+//! Patching the files with matches, where each hunk is shown to the user (or decided by the
+//! options) and the chosen hunks are written back to the files.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+use anyhow::Context as anyhowContext;
+use bstr::ByteSlice;
+use grep_regex::RegexMatcher;
+
+use crate::cli::{Args, InteractiveMode, OutputFormat, UnwritableMode};
+use crate::decisions::{Decision, Decisions};
+use crate::json::Value;
+use crate::profile::{measure, Phase};
+use crate::replace::{replace_matches, Continue, Hunk, ReplaceOptions, ReplaceOutcome, WriteFile};
+use crate::search::{build_globset, glob_matches, search_file, MatchInfo, SearchConfig};
+use crate::ui::{
+    error, style, warning, BatchOption, FilePaths, MenuOption, PatchOption, COUNT_STYLE,
+};
+use crate::util::{ReplaceFileError, Replacement};
+use crate::{companion, export_context, file_diff, file_order, patch_path};
+
+/// The answers to hunks that are read from or written to files.
+pub(crate) struct HunkDecisions {
+    /// The decisions to replay, from `--replay-decisions`.
+    pub(crate) replay: Option<Decisions>,
+    /// The decisions to export, for `--export-decisions`.
+    pub(crate) export: Option<Decisions>,
+    /// The hunks rejected in earlier runs, for `--skip-previously-rejected`.
+    pub(crate) rejected: Option<Decisions>,
+    /// The hunks rejected in this run, for `--remember-rejected`.
+    pub(crate) remember: Option<Decisions>,
+    /// The hunks to apply, which are written to a plan instead of the files, for `--write-plan`.
+    pub(crate) plan: Option<crate::plan::Plan>,
+    /// The diff of each file with hunks to apply, which is written instead of the files, for
+    /// `--output-patch`.
+    pub(crate) output_patch: Option<Vec<Vec<u8>>>,
+    /// The changed hunks of each file, for `--report`.
+    pub(crate) report: Option<crate::report::Report>,
+    /// The progress of this run, which is saved so that it can be resumed if it's interrupted.
+    pub(crate) resume: Option<crate::resume::Recorder>,
+    /// The answers from the interrupted run that this run resumes.
+    pub(crate) resumed: Option<Decisions>,
+    /// The new files, which are written once every hunk has been answered, for `--atomic`.
+    pub(crate) transaction: Option<crate::transaction::Transaction>,
+}
+
+/// Show or patch each file, in order. Files that were patched are removed from `matches`. Returns
+/// what happened to each file that was shown or patched.
+pub(crate) fn patch_files(
+    args: &Args,
+    config: &ReplaceOptions,
+    matches: &mut BTreeMap<PathBuf, MatchInfo>,
+    show: bool,
+    decisions: &mut HunkDecisions,
+    journal: &mut crate::undo::Journal,
+) -> anyhow::Result<Vec<FileOutcome>> {
+    let done = AtomicBool::new(false);
+    let prefetched = std::sync::Mutex::new(Prefetched::default());
+
+    std::thread::scope(|scope| {
+        let mut prefetch = Prefetch {
+            scope,
+            done: &done,
+            prefetched: &prefetched,
+            next: 0,
+        };
+        let rv = patch_files_with(
+            args,
+            config,
+            matches,
+            show,
+            decisions,
+            journal,
+            &mut prefetch,
+        );
+
+        // stop prefetching any remaining files
+        done.store(true, Ordering::Relaxed);
+        rv
+    })
+}
+
+/// The number of upcoming files that are prefetched.
+const PREFETCH_FILES: usize = 4;
+
+/// Computes the diffs of the hunks of upcoming files in background threads while the user answers
+/// the prompts of the current file, so that large files don't cause long pauses between prompts.
+struct Prefetch<'scope, 'env> {
+    scope: &'scope std::thread::Scope<'scope, 'env>,
+    /// Set when the remaining files don't need to be prefetched.
+    done: &'env AtomicBool,
+    /// The diffs of the hunks that were prefetched.
+    prefetched: &'env std::sync::Mutex<Prefetched>,
+    /// The index of the next file to prefetch.
+    next: usize,
+}
+
+/// The diffs of the prefetched hunks that haven't been shown yet, by their file index (in the
+/// order that the files are patched) and hunk index.
+#[derive(Default)]
+struct Prefetched {
+    /// The first hunk that hasn't been shown or skipped. The diffs of earlier hunks aren't kept.
+    next: (usize, u64),
+    /// The diffs of each hunk, with the [`hunk_hash`](crate::decisions::hunk_hash) of the hunk
+    /// that they were computed for.
+    diffs: BTreeMap<(usize, u64), (u64, crate::ui::HunkDiffs)>,
+}
+
+impl Prefetched {
+    /// Drop the diffs of the hunks before `next`, which won't be shown.
+    fn skip_to(&mut self, next: (usize, u64)) {
+        self.next = self.next.max(next);
+        self.diffs = self.diffs.split_off(&self.next);
+    }
+
+    /// Add the diffs of the hunk `hunk` of the file `file`, unless it was already shown or
+    /// skipped.
+    fn insert(&mut self, file: usize, hunk: &Hunk, diffs: crate::ui::HunkDiffs) {
+        if (file, hunk.idx) >= self.next {
+            let hash = crate::decisions::hunk_hash(hunk.original, hunk.replaced);
+            self.diffs.insert((file, hunk.idx), (hash, diffs));
+        }
+    }
+
+    /// Remove and return the diffs of the hunk `hunk` of the file `file` if they were prefetched
+    /// for the same hunk, and drop the diffs of the hunks before it.
+    fn take(&mut self, file: usize, hunk: &Hunk) -> Option<crate::ui::HunkDiffs> {
+        let prefetched = self.diffs.remove(&(file, hunk.idx));
+        self.skip_to((file, hunk.idx + 1));
+        let (hash, diffs) = prefetched?;
+        // the hunk may have been changed by the hunk filter
+        (hash == crate::decisions::hunk_hash(hunk.original, hunk.replaced)).then_some(diffs)
+    }
+}
+
+impl<'env> Prefetch<'_, 'env> {
+    /// Start prefetching the files in `order` that are up to [`PREFETCH_FILES`] after the current
+    /// file.
+    fn ahead(
+        &mut self,
+        config: &ReplaceOptions<'env>,
+        matches: &BTreeMap<PathBuf, MatchInfo>,
+        order: &[PathBuf],
+        current: usize,
+    ) {
+        // the diffs of the earlier files won't be shown
+        self.prefetched.lock().unwrap().skip_to((current, 0));
+
+        self.next = self.next.max(current + 1);
+        let end = order.len().min(current + 1 + PREFETCH_FILES);
+
+        while self.next < end {
+            let file = self.next;
+            let path = order[file].clone();
+            let match_info = &matches[&path];
+            let display_path = match_info.display_path.clone();
+            let mut lines = match_info.lines.clone();
+            let eol = match_info.eol;
+            let options = config.without_prompt_timeout();
+            let done = self.done;
+            let prefetched = self.prefetched;
+
+            self.scope.spawn(move || {
+                let Ok(src) = File::open(&path) else {
+                    return;
+                };
+                let terminator = options.line_terminator;
+                // a read error is reported when the file is patched
+                let _ = replace_matches(
+                    &options,
+                    &display_path,
+                    &src,
+                    None::<&File>,
+                    &mut lines,
+                    eol,
+                    |hunk| {
+                        // stop once the file has been shown
+                        let shown = prefetched.lock().unwrap().next.0 > file;
+                        if done.load(Ordering::Relaxed) || shown {
+                            return PatchOption::Quit;
+                        }
+                        // the hunks are shown with the same terminator swap as in `prompt_hunk`
+                        let diffs = crate::ui::HunkDiffs::new(
+                            &crate::util::swap_bytes(hunk.original, terminator, b'\n'),
+                            &crate::util::swap_bytes(hunk.replaced, terminator, b'\n'),
+                            options.max_line_len,
+                        );
+                        prefetched.lock().unwrap().insert(file, hunk, diffs);
+                        PatchOption::WriteOriginal
+                    },
+                );
+            });
+
+            self.next += 1;
+        }
+    }
+}
+
+fn patch_files_with<'env>(
+    args: &Args,
+    config: &ReplaceOptions<'env>,
+    matches: &mut BTreeMap<PathBuf, MatchInfo>,
+    show: bool,
+    decisions: &mut HunkDecisions,
+    journal: &mut crate::undo::Journal,
+    prefetch: &mut Prefetch<'_, 'env>,
+) -> anyhow::Result<Vec<FileOutcome>> {
+    let search = SearchConfig::from_args(args);
+
+    // the files that we've replaced
+    let mut patched = Vec::new();
+
+    // the original contents of the modified files that haven't been verified yet
+    let mut originals = Vec::new();
+
+    let mut status = crate::git::StatusCache::default();
+
+    // the user chose to apply the patches in the remaining files
+    let mut apply_rest = false;
+
+    // a search that the user entered at the prompt, where the hunks are skipped until one matches
+    let hunk_search = std::cell::Cell::new(None);
+
+    // an answer at the prompt that also applies to the rest of the file or to all remaining files
+    let sticky = std::cell::Cell::new(None);
+
+    // answers at the prompt that also apply to later similar hunks, for `--group-similar`
+    let similar = std::cell::Cell::new(None);
+    let mut similar_answers = HashMap::new();
+
+    // the patch of the applied changes, for `--apply-log`
+    let mut apply_log = match &args.apply_log {
+        Some(path) => Some(
+            File::create(path)
+                .with_context(|| format!("could not create the log '{}'", path.display()))?,
+        ),
+        None => None,
+    };
+
+    let num_files = matches.len();
+
+    // the diffs of the upcoming hunks that were prefetched
+    let prefetched = prefetch.prefetched;
+
+    let order = file_order(matches, args.group_by_dir, args.natural_sort);
+
+    let auto_yes = build_globset(&args.auto_yes)?;
+    let auto_no = build_globset(&args.auto_no)?;
+
+    let progress = crate::ui::Progress::new(
+        order
+            .iter()
+            .map(|x| {
+                let lines = &matches[x].lines;
+                config.hunk_ranges(lines).len() as u64
+            })
+            .collect(),
+    );
+
+    // the directory of each file and the files in each directory, for `--group-by-dir`
+    let display_paths: Vec<PathBuf> = order
+        .iter()
+        .map(|x| matches[x].display_path.clone())
+        .collect();
+    let dirs = match args.group_by_dir {
+        true => crate::util::dir_groups(
+            &display_paths
+                .iter()
+                .map(|x| x.as_path())
+                .collect::<Vec<_>>(),
+        ),
+        false => Vec::new(),
+    };
+
+    // the ID of the last hunk, counting from 1 across all files
+    let mut hunk_id = 0;
+
+    // the answers for the files whose `--paired` companion was answered first
+    let mut pair_answers = HashMap::new();
+
+    // what happened to each file that was shown or patched
+    let mut outcomes = Vec::new();
+
+    // the files and hunks that would be changed, for `--show --format json`
+    let json = args.format == OutputFormat::Json;
+    let mut json_files = Vec::new();
+
+    // the shown or applied hunks are added to the report, for `--report`
+    let report = decisions.report.is_some();
+
+    // each hunk and file is printed as a json object instead of being shown, for `--json`
+    let json_events = args.json;
+    let started_all = std::time::Instant::now();
+    // the outcomes that have been printed as json objects
+    let mut json_printed = 0;
+
+    // hunks are shown as word diffs instead of patches, for `--show --format word-diff`
+    let word_diff = args.format == OutputFormat::WordDiff;
+
+    // hunks aren't shown as patches with `--apply` or with json or word-diff output (which require
+    // `--show`), scripts would print their errors twice, and the command run before each file may
+    // change the file
+    let prefetch_files = !args.apply
+        && !json
+        && !json_events
+        && !word_diff
+        && !matches!(config.replacement, Replacement::Script(..))
+        && args.pre_file_cmd.is_none();
+
+    // loop over each file that has matches
+    for (files_done, path) in order.iter().enumerate() {
+        progress.set_file(files_done);
+        let started = std::time::Instant::now();
+
+        if json_events {
+            print_json_files(&outcomes[json_printed..], matches);
+            json_printed = outcomes.len();
+        }
+
+        // the answers for the rest of the previous file don't apply to this file
+        if sticky.take() == Some(MenuOption::ApplyAll) {
+            apply_rest = true;
+        }
+
+        if prefetch_files {
+            prefetch.ahead(config, matches, &order, files_done);
+        }
+
+        // separate files by a newline (with '--only', only files with shown hunks are separated)
+        if !json && !json_events && args.only.is_empty() {
+            println!();
+        }
+
+        if let (Some(batch_size), false, false) = (args.batch_size, show, apply_rest) {
+            if files_done > 0 && (files_done as u64).is_multiple_of(batch_size) {
+                match crate::ui::batch_prompt(files_done, num_files - files_done) {
+                    BatchOption::Continue => println!(),
+                    BatchOption::Stop => break,
+                    BatchOption::ApplyRest => {
+                        println!();
+                        apply_rest = true;
+                    }
+                }
+            }
+        }
+
+        if let (Some((dir, range)), false) = (
+            dirs.iter().find(|x| x.1.start == files_done),
+            json || json_events,
+        ) {
+            let count: usize = order[range.clone()]
+                .iter()
+                .map(|x| matches[x].lines.len())
+                .sum();
+            println!(
+                "Directory {}/ ({} match{} in {} file{})\n",
+                dir.display(),
+                style!(count, &COUNT_STYLE),
+                if count == 1 { "" } else { "es" },
+                style!(range.len(), &COUNT_STYLE),
+                if range.len() == 1 { "" } else { "s" },
+            );
+        }
+
+        let match_info = matches.get_mut(path).unwrap();
+
+        if let Some(reason) = match_info.vanished(path) {
+            warning!(
+                "skipping '{}' since {reason} after it was searched",
+                match_info.display_path.display(),
+            );
+            patched.push(path.clone());
+            continue;
+        }
+
+        if let (Some(cmd), false) = (&args.pre_file_cmd, show) {
+            run_path_cmd(cmd, path)?;
+
+            // the command may have modified the file, so we need to search it again
+            if std::fs::metadata(path)?.modified()? != match_info.modified {
+                let Some(mut new_info) = search_file(config, &search, path)? else {
+                    // there aren't any matches anymore
+                    patched.push(path.clone());
+                    continue;
+                };
+                new_info.display_path = std::mem::take(&mut match_info.display_path);
+                *match_info = new_info;
+            }
+        }
+
+        let patch_path = patch_path(path, args.patch_root.as_deref(), &mut status);
+        let paths = FilePaths {
+            display: &match_info.display_path,
+            patch: &patch_path,
+            real: path,
+        };
+
+        // If '--show' is set, the program should effectively do a dry run where it shows the
+        // changes without making any modifications. While we could write a simpler function, we
+        // instead use the same `replace_file` function to ensure that the behaviour is the same as
+        // what would normally happen.
+
+        // an error from the `--hunk-filter` command
+        let mut filter_error = None;
+        let hunk_filter = args.hunk_filter.as_deref();
+
+        let auto_answer = if glob_matches(&auto_no, path) {
+            Some(MenuOption::No)
+        } else if glob_matches(&auto_yes, path) || args.apply || apply_rest {
+            Some(MenuOption::Yes)
+        } else if let Some(answer) = pair_answers.remove(path) {
+            answer
+        } else if let (false, Some(other)) = (show, companion(&args.paired, path)) {
+            // both files of the pair are answered with one prompt
+            let other_display =
+                companion(&args.paired, &match_info.display_path).unwrap_or_else(|| other.clone());
+            let answer = crate::ui::pair_prompt(&match_info.display_path, &other_display);
+            pair_answers.insert(other, answer);
+            answer
+        } else {
+            None
+        };
+
+        // with `--interactive=once`, all hunks of the file are answered with one prompt, and an
+        // edited file is written as-is
+        let mut edited = None;
+        let auto_answer = match (auto_answer, args.interactive, show) {
+            (None, InteractiveMode::Once, false) => {
+                Some(prompt_file(config, paths, match_info, &mut edited)?)
+            }
+            (x, _, _) => x,
+        };
+
+        if show {
+            // we want to only show the patches, but not actually change anything
+            let src = std::fs::File::open(path)
+                .with_context(|| format!("could not read file '{}'", path.display()))?;
+
+            let mut file_hunks = 0;
+            let mut json_hunks = Vec::new();
+            let mut line_offset = 0;
+            let mut report_hunks = Vec::new();
+
+            // perform the find & replace, but with no output file
+            let decide = with_hunk_filter(hunk_filter, path, &mut filter_error, |hunk| {
+                hunk_id += 1;
+
+                if !args.only.is_empty() && !args.only.iter().any(|x| x.contains(&hunk_id)) {
+                    return PatchOption::WriteOriginal;
+                }
+
+                if file_hunks == 0 && !args.only.is_empty() && !json && !json_events {
+                    println!();
+                    // the file's header is normally shown with its first hunk
+                    if hunk.idx != 0 {
+                        crate::ui::print_file_header(paths);
+                    }
+                }
+                file_hunks += 1;
+
+                if report {
+                    report_hunks.push(crate::preview::PreviewHunk {
+                        start_line: hunk.start_line,
+                        original: hunk.original.to_vec(),
+                        replaced: hunk.replaced.to_vec(),
+                    });
+                }
+
+                if json {
+                    json_hunks.push(Value::object([
+                        ("id", hunk_id.into()),
+                        ("line", (hunk.start_line + 1).into()),
+                        ("original", hunk.original.to_str_lossy().into_owned().into()),
+                        ("replaced", hunk.replaced.to_str_lossy().into_owned().into()),
+                        ("matches", json_match_ids(hunk)),
+                    ]));
+                    return PatchOption::WriteOriginal;
+                }
+
+                if json_events {
+                    // the replaced lines are where they would be if the earlier hunks were applied
+                    line_offset +=
+                        print_json_hunk(config, paths, hunk, hunk_id, false, line_offset);
+                    return PatchOption::WriteOriginal;
+                }
+
+                if word_diff {
+                    let swap_terminator =
+                        |bytes| crate::util::swap_bytes(bytes, config.line_terminator, b'\n');
+                    crate::ui::print_word_diff(
+                        &swap_terminator(hunk.original),
+                        &swap_terminator(hunk.replaced),
+                        (hunk.idx == 0).then_some(paths),
+                        hunk.start_line,
+                        hunk_id,
+                    );
+                    return PatchOption::WriteOriginal;
+                }
+
+                let diffs = prefetched.lock().unwrap().take(files_done, hunk);
+                prompt_hunk(
+                    config,
+                    paths,
+                    hunk,
+                    Some(MenuOption::No),
+                    &progress,
+                    hunk_id,
+                    None,
+                    None,
+                    None,
+                    None,
+                    diffs.as_ref(),
+                )
+            });
+            let outcome = replace_matches(
+                config,
+                paths.display,
+                &src,
+                None::<&File>,
+                &mut match_info.lines,
+                match_info.eol,
+                decide,
+            );
+
+            if let Some(e) = filter_error {
+                return Err(e);
+            }
+
+            let outcome = match outcome {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("could not read file '{}': {e}", path.display());
+                    outcomes.push(FileOutcome::failed(path, started, e));
+                    continue;
+                }
+            };
+
+            outcomes.push(FileOutcome {
+                shown_hunks: file_hunks,
+                ..FileOutcome::new(path, started)
+            });
+
+            if let Some(report) = &mut decisions.report {
+                report.add(&match_info.display_path, report_hunks);
+            }
+
+            if !json_hunks.is_empty() {
+                json_files.push(Value::object([
+                    (
+                        "path",
+                        match_info
+                            .display_path
+                            .to_string_lossy()
+                            .into_owned()
+                            .into(),
+                    ),
+                    ("hunks", json_hunks.into()),
+                ]));
+            }
+
+            // we provided `MenuOption::No`, so we shouldn't expect it to want to write
+            assert_eq!(outcome.cont, Continue::Yes);
+            assert_eq!(outcome.write_file, WriteFile::No);
+        } else {
+            // keep a copy of the original file for `repatch undo`, and in case the verification
+            // command fails
+            let original = match std::fs::read(path) {
+                Ok(x) => x,
+                Err(e) if args.apply && e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    // one protected file shouldn't stop an automated run
+                    warning!("skipping '{}' since it can't be read: {e}", path.display());
+                    outcomes.push(FileOutcome::failed(path, started, e));
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let file_hash = crate::util::fnv1a(&original);
+
+            // rejected hunks and the progress of the run are remembered by their absolute path
+            let canonical_path =
+                match decisions.rejected.is_some()
+                    || decisions.remember.is_some()
+                    || decisions.resume.is_some()
+                {
+                    true => Some(std::fs::canonicalize(path).with_context(|| {
+                        format!("could not resolve the path '{}'", path.display())
+                    })?),
+                    false => None,
+                };
+
+            // the diff of the new file is saved instead of replacing the file
+            let output_patch = decisions.output_patch.is_some();
+            let mut file_diff_bytes = None;
+
+            // the new file is kept until the end of the run, for `--atomic`
+            let atomic = decisions.transaction.is_some();
+            let mut staged = None;
+
+            // the applied hunks, for `--report`
+            let mut report_hunks = Vec::new();
+
+            // files that we can't replace ourselves are written some other way
+            let unwritable = match (args.unwritable, crate::util::is_writable(path)) {
+                _ if output_patch => None,
+                (UnwritableMode::Fail, _) | (_, true) => None,
+                (mode, false) => Some(mode),
+            };
+
+            // replace the file with a new file that we'll write to
+            let write = |original: &File, new: &File| {
+                if let Some(edited) = &edited {
+                    if let Err(e) = measure(Phase::Write, || (&*new).write_all(edited)) {
+                        return (false, Err(e));
+                    }
+                    let outcome = ReplaceOutcome {
+                        cont: Continue::Yes,
+                        write_file: WriteFile::Yes,
+                        changed_lines: Vec::new(),
+                        skipped_hunks: 0,
+                        edited_hunks: 0,
+                        replacements: 0,
+                    };
+                    return (true, Ok(outcome));
+                }
+
+                // perform the find & replace
+                let mut line_offset = 0;
+                let decide = with_hunk_filter(hunk_filter, path, &mut filter_error, |hunk| {
+                    let hash = crate::decisions::hunk_hash(hunk.original, hunk.replaced);
+                    hunk_id += 1;
+
+                    let similarity = args.group_similar.then(|| {
+                        crate::util::similarity_key(hunk.original, hunk.replaced, hunk.matches)
+                    });
+
+                    let replayed = decisions.replay.as_ref().and_then(|x| x.get(path, hash));
+                    let rejected = match (&decisions.rejected, &canonical_path) {
+                        (Some(rejected), Some(canonical)) => rejected.get(canonical, hash),
+                        _ => None,
+                    };
+
+                    let resumed = match (&decisions.resumed, &canonical_path) {
+                        (Some(resumed), Some(canonical)) => resumed.get(canonical, hash),
+                        _ => None,
+                    };
+
+                    let input = match replayed.or(rejected).or(resumed) {
+                        Some(Decision::Yes) => Some(MenuOption::Yes),
+                        Some(Decision::No) => Some(MenuOption::No),
+                        None if !args.apply_hunks.is_empty() => {
+                            match args.apply_hunks.iter().any(|x| x.contains(&hunk_id)) {
+                                true => Some(MenuOption::Yes),
+                                false => Some(MenuOption::No),
+                            }
+                        }
+                        None => auto_answer
+                            .or(match sticky.get() {
+                                Some(MenuOption::ApplyFile | MenuOption::ApplyAll) => {
+                                    Some(MenuOption::Yes)
+                                }
+                                Some(MenuOption::SkipFile) => Some(MenuOption::No),
+                                _ => None,
+                            })
+                            .or(match similarity.and_then(|x| similar_answers.get(&x)) {
+                                Some(MenuOption::ApplySimilar) => Some(MenuOption::Yes),
+                                Some(MenuOption::SkipSimilar) => Some(MenuOption::No),
+                                _ => None,
+                            }),
+                    };
+
+                    // hunks that would be prompted for are skipped until one matches the search
+                    if let (None, Some(matcher)) = (input, hunk_search.take()) {
+                        let is_match = |x| grep_matcher::Matcher::is_match(&matcher, x).unwrap();
+                        if !is_match(hunk.original) && !is_match(hunk.replaced) {
+                            hunk_search.set(Some(matcher));
+                            return PatchOption::WriteOriginal;
+                        }
+                    }
+
+                    // notes are only saved with the exported decisions
+                    let note = std::cell::Cell::new(None);
+                    let note_cell = decisions.export.is_some().then_some(&note);
+                    let diffs = prefetched.lock().unwrap().take(files_done, hunk);
+                    let prompt = || {
+                        prompt_hunk(
+                            config,
+                            paths,
+                            hunk,
+                            input,
+                            &progress,
+                            hunk_id,
+                            note_cell,
+                            Some(&hunk_search),
+                            Some(&sticky),
+                            args.group_similar.then_some(&similar),
+                            diffs.as_ref(),
+                        )
+                    };
+                    let mut option = match (json_events, input) {
+                        // nothing is shown or asked with `--json`
+                        (true, Some(MenuOption::Yes)) => {
+                            PatchOption::WriteNew(hunk.replaced.to_vec())
+                        }
+                        (true, _) => PatchOption::WriteOriginal,
+                        (false, _) => prompt(),
+                    };
+
+                    if json_events {
+                        let applied = matches!(option, PatchOption::WriteNew(_));
+                        let delta =
+                            print_json_hunk(config, paths, hunk, hunk_id, applied, line_offset);
+                        if applied {
+                            line_offset += delta;
+                        }
+                    }
+
+                    // the file may have been rewritten while the prompt was shown without its
+                    // modified time changing, so the hunk is asked about again if its lines don't
+                    // hash to what was shown
+                    if let (None, PatchOption::WriteNew(_)) = (input, &option) {
+                        let lines = crate::util::count_lines(hunk.original, config.line_terminator);
+                        let current = hunk_checksum(
+                            path,
+                            hunk.start_line..hunk.start_line + lines,
+                            config.line_terminator,
+                        );
+                        if current.ok() != Some(crate::util::fnv1a(hunk.original)) {
+                            warning!(
+                                "the lines of this hunk in '{}' were changed by another program \
+                                since they were shown",
+                                paths.display.display(),
+                            );
+                            option = prompt();
+                        }
+                    }
+
+                    if let (Some(key), Some(answer)) = (similarity, similar.take()) {
+                        similar_answers.insert(key, answer);
+                    }
+
+                    if let (true, PatchOption::WriteNew(x)) = (report, &option) {
+                        report_hunks.push(crate::preview::PreviewHunk {
+                            start_line: hunk.start_line,
+                            original: hunk.original.to_vec(),
+                            replaced: x.clone(),
+                        });
+                    }
+
+                    if let (Some(plan), PatchOption::WriteNew(x)) = (&mut decisions.plan, &option) {
+                        plan.add_hunk(&patch_path, file_hash, hunk.start_line, hunk.original, x);
+                    }
+
+                    if let (Some(remember), Some(canonical), None, PatchOption::WriteOriginal) =
+                        (&mut decisions.remember, &canonical_path, input, &option)
+                    {
+                        remember.insert(canonical, hash, Decision::No);
+                    }
+
+                    if let (Some(resume), Some(canonical)) =
+                        (&mut decisions.resume, &canonical_path)
+                    {
+                        match &option {
+                            PatchOption::WriteNew(x) if x == hunk.replaced => {
+                                resume.hunk(canonical, hash, Decision::Yes)
+                            }
+                            PatchOption::WriteOriginal => {
+                                resume.hunk(canonical, hash, Decision::No)
+                            }
+                            _ => {}
+                        }
+                    }
+
+                    if let Some(export) = decisions.export.as_mut() {
+                        match &option {
+                            PatchOption::WriteNew(x) if x == hunk.replaced => {
+                                export.insert(path, hash, Decision::Yes)
+                            }
+                            PatchOption::WriteOriginal => export.insert(path, hash, Decision::No),
+                            // edited hunks and quitting aren't recorded
+                            _ => {}
+                        }
+
+                        if let Some(note) = note.take() {
+                            export.insert_note(path, hash, note);
+                        }
+                        export.insert_match_ids(path, hash, hunk.match_ids.to_vec());
+                    }
+
+                    option
+                });
+                let outcome = replace_matches(
+                    config,
+                    paths.display,
+                    original,
+                    Some(new),
+                    &mut match_info.lines,
+                    match_info.eol,
+                    decide,
+                );
+                let mut outcome = match outcome {
+                    Ok(x) => x,
+                    // the new file is incomplete, so the original file is kept
+                    Err(e) => return (false, Err(e)),
+                };
+
+                // the hunks after a failed hunk filter weren't decided, so the original file is
+                // kept
+                if filter_error.is_some() {
+                    return (false, Ok(outcome));
+                }
+
+                // the applied hunks were written to the plan instead
+                if decisions.plan.is_some() {
+                    outcome.write_file = WriteFile::No;
+                }
+
+                // inform `replace_file` whether it should replace the file or not
+                (outcome.write_file == WriteFile::Yes, Ok(outcome))
+            };
+            let outcome = match unwritable {
+                None if output_patch || atomic => {
+                    crate::util::replace_file_via(path, Some(match_info.modified), write, |new| {
+                        let mut replaced = Vec::new();
+                        (&*new).read_to_end(&mut replaced)?;
+                        match output_patch {
+                            true => {
+                                let context = export_context(args.export_context);
+                                file_diff_bytes =
+                                    Some(file_diff(&patch_path, &original, &replaced, context))
+                            }
+                            false => staged = Some(replaced),
+                        }
+                        Ok(())
+                    })
+                }
+                None => {
+                    crate::util::replace_file(path, Some(match_info.modified), args.fsync, write)
+                }
+                Some(mode) => {
+                    crate::util::replace_file_via(path, Some(match_info.modified), write, |new| {
+                        write_unwritable(mode, path, &original, new)
+                    })
+                }
+            };
+
+            if let Some(e) = filter_error {
+                return Err(e);
+            }
+
+            // handle errors
+            let mut outcome = match outcome {
+                Ok(Ok(x)) => x,
+                Ok(Err(e)) => {
+                    // the file wasn't changed, so we can continue with the other files
+                    error!("could not patch file '{}': {e}", path.display());
+                    outcomes.push(FileOutcome::failed(path, started, e));
+                    continue;
+                }
+                Err(ReplaceFileError::Io(e))
+                    if args.apply && e.kind() == std::io::ErrorKind::PermissionDenied =>
+                {
+                    warning!(
+                        "skipping '{}' since it can't be written: {e}",
+                        path.display()
+                    );
+                    outcomes.push(FileOutcome::failed(path, started, e));
+                    continue;
+                }
+                Err(ReplaceFileError::Io(e)) => {
+                    return Err(e)
+                        .with_context(|| format!("could not replace file '{}'", path.display()))
+                }
+                Err(ReplaceFileError::ModifiedTimeChanged) => {
+                    return Err(anyhow::anyhow!(
+                        "the file '{}' was modified by another program\n\
+                        Discarding all patches to this file and exiting.",
+                        path.display(),
+                    ))
+                }
+            };
+
+            patched.push(path.clone());
+
+            // the patch file hasn't been applied yet
+            if unwritable == Some(UnwritableMode::Patch) {
+                outcome.write_file = WriteFile::No;
+            }
+
+            if let (Some(diffs), Some(diff)) = (&mut decisions.output_patch, file_diff_bytes) {
+                diffs.push(diff);
+                outcome.write_file = WriteFile::No;
+            }
+
+            if let (Some(transaction), Some(new)) = (&mut decisions.transaction, staged) {
+                transaction.stage(path, match_info.modified, original.clone(), new);
+                outcome.write_file = WriteFile::No;
+            }
+
+            if let (WriteFile::Yes, None) = (outcome.write_file, unwritable) {
+                journal.record(path, original.clone())?;
+            }
+
+            if let (Some(log), WriteFile::Yes) = (&mut apply_log, outcome.write_file) {
+                let context = export_context(args.apply_log_context);
+                let new = std::fs::read(path)?;
+                log.write_all(&file_diff(&patch_path, &original, &new, context))
+                    .context("could not write to the apply log")?;
+            }
+
+            if let (Some(cmd), WriteFile::Yes) = (&args.post_file_cmd, outcome.write_file) {
+                run_path_cmd(cmd, path)?;
+            }
+
+            if let (Some(cmd), WriteFile::Yes) = (&args.verify_cmd, outcome.write_file) {
+                originals.push((path.clone(), original));
+                if !args.verify_once {
+                    verify(cmd, &originals)?;
+                    originals.clear();
+                }
+            }
+
+            if args.format == OutputFormat::Github {
+                for lines in &outcome.changed_lines {
+                    let msg = format!("Replaced \"{}\" with \"{}\"", args.find, args.replacement());
+                    println!(
+                        "{}",
+                        crate::output::github_annotation(
+                            "notice",
+                            &match_info.display_path,
+                            lines.clone(),
+                            &msg,
+                        ),
+                    );
+                }
+            }
+
+            let written = outcome.write_file == WriteFile::Yes && unwritable.is_none();
+            outcomes.push(FileOutcome {
+                applied_hunks: outcome.changed_lines.len() as u64,
+                skipped_hunks: outcome.skipped_hunks,
+                edited_hunks: outcome.edited_hunks,
+                replacements: outcome.replacements,
+                written,
+                bytes_written: match written {
+                    true => std::fs::metadata(path)?.len(),
+                    false => 0,
+                },
+                ..FileOutcome::new(path, started)
+            });
+
+            if let Some(report) = &mut decisions.report {
+                report.add(&match_info.display_path, report_hunks);
+            }
+
+            // the file isn't finished until it's written, which an `--atomic` run does at the end,
+            // and the file that the user quit in may still have unanswered hunks
+            if let (Some(resume), Some(canonical), false, Continue::Yes) =
+                (&mut decisions.resume, &canonical_path, atomic, outcome.cont)
+            {
+                resume.done(canonical);
+            }
+
+            if outcome.cont == Continue::No {
+                if let Some(transaction) = &mut decisions.transaction {
+                    transaction.quit = true;
+                }
+                break;
+            }
+        }
+    }
+
+    if hunk_search.take().is_some() {
+        println!("\nNo later hunks matched the search.");
+    }
+
+    if let (Some(cmd), false) = (&args.verify_cmd, originals.is_empty()) {
+        verify(cmd, &originals)?;
+    }
+
+    let shown_files = outcomes.iter().filter(|x| x.shown_hunks > 0).count();
+    let shown_hunks: u64 = outcomes.iter().map(|x| x.shown_hunks).sum();
+    let failed_files = outcomes.iter().filter(|x| x.error.is_some()).count();
+
+    if json_events {
+        print_json_files(&outcomes[json_printed..], matches);
+        let sum = |f: fn(&FileOutcome) -> u64| outcomes.iter().map(f).sum::<u64>();
+        let summary = Value::object([
+            ("type", "summary".into()),
+            ("files", outcomes.len().into()),
+            ("failed_files", failed_files.into()),
+            ("shown_hunks", shown_hunks.into()),
+            ("applied_hunks", sum(|x| x.applied_hunks).into()),
+            ("skipped_hunks", sum(|x| x.skipped_hunks).into()),
+            ("edited_hunks", sum(|x| x.edited_hunks).into()),
+            ("replacements", sum(|x| x.replacements).into()),
+            ("bytes_written", sum(|x| x.bytes_written).into()),
+            (
+                "elapsed_ms",
+                (started_all.elapsed().as_millis() as u64).into(),
+            ),
+        ]);
+        crate::ui::write_stdout(format!("{summary}\n").as_bytes())?;
+    } else if show && json {
+        let output = Value::object([
+            ("files", shown_files.into()),
+            ("hunks", shown_hunks.into()),
+            ("changes", json_files.into()),
+        ]);
+        println!("{output}");
+    } else if show && !args.paths_from_stdin {
+        println!(
+            "\n{} hunk{} in {} file{} would be changed.",
+            style!(shown_hunks, &COUNT_STYLE),
+            if shown_hunks == 1 { "" } else { "s" },
+            style!(shown_files, &COUNT_STYLE),
+            if shown_files == 1 { "" } else { "s" },
+        );
+    }
+
+    for path in patched {
+        matches.remove(&path);
+    }
+
+    if failed_files > 0 {
+        anyhow::bail!(
+            "{} file{} could not be patched",
+            style!(failed_files, &COUNT_STYLE),
+            if failed_files == 1 { "" } else { "s" },
+        );
+    }
+
+    Ok(outcomes)
+}
+
+/// Print a hunk as a json object for `--json`, where `line_offset` is the number of lines that
+/// the earlier hunks of the file added. Returns the number of lines that the hunk adds.
+fn print_json_hunk(
+    options: &ReplaceOptions,
+    paths: FilePaths,
+    hunk: &Hunk,
+    hunk_id: u64,
+    applied: bool,
+    line_offset: i64,
+) -> i64 {
+    let term = options.line_terminator;
+    let new_start = hunk.start_line.saturating_add_signed(line_offset);
+    let original = crate::output::json_lines(hunk.start_line, hunk.original, term);
+    let replaced = crate::output::json_lines(new_start, hunk.replaced, term);
+
+    let event = Value::object([
+        ("type", "hunk".into()),
+        ("path", paths.display.to_string_lossy().into_owned().into()),
+        ("id", hunk_id.into()),
+        ("original", original),
+        ("replaced", replaced),
+        ("matches", json_match_ids(hunk)),
+        ("applied", applied.into()),
+    ]);
+    let _ = crate::ui::write_stdout(format!("{event}\n").as_bytes());
+
+    let count = |x| crate::util::count_lines(x, term) as i64;
+    count(hunk.replaced) - count(hunk.original)
+}
+
+/// The ids of the hunk's matches as a json array.
+fn json_match_ids(hunk: &Hunk) -> Value {
+    let ids: Vec<Value> = hunk.match_ids.iter().map(|x| x.as_str().into()).collect();
+    ids.into()
+}
+
+/// Print the outcome of each file as a json object for `--json`.
+fn print_json_files(outcomes: &[FileOutcome], matches: &BTreeMap<PathBuf, MatchInfo>) {
+    for outcome in outcomes {
+        let path = matches
+            .get(&outcome.path)
+            .map_or(&outcome.path, |x| &x.display_path);
+        let mut members = vec![
+            ("type", "file".into()),
+            ("path", path.to_string_lossy().into_owned().into()),
+            ("shown_hunks", outcome.shown_hunks.into()),
+            ("applied_hunks", outcome.applied_hunks.into()),
+            ("skipped_hunks", outcome.skipped_hunks.into()),
+            ("bytes_written", outcome.bytes_written.into()),
+            ("elapsed_ms", (outcome.duration.as_millis() as u64).into()),
+        ];
+        if let Some(error) = &outcome.error {
+            members.push(("error", error.as_str().into()));
+        }
+        let _ = crate::ui::write_stdout(format!("{}\n", Value::object(members)).as_bytes());
+    }
+}
+
+/// Write the new file `new` to `path` for a file that we can't replace ourselves.
+fn write_unwritable(
+    mode: UnwritableMode,
+    path: &Path,
+    original: &[u8],
+    new: &File,
+) -> std::io::Result<()> {
+    match mode {
+        UnwritableMode::Fail => unreachable!(),
+        UnwritableMode::Sudo => {
+            // `tee` overwrites the file in place, so its owner and permissions don't change
+            let status = Command::new("sudo")
+                .arg("tee")
+                .arg("--")
+                .arg(path)
+                .stdin(new.try_clone()?)
+                .stdout(std::process::Stdio::null())
+                .status()
+                .map_err(|e| std::io::Error::other(format!("could not run 'sudo': {e}")))?;
+            if !status.success() {
+                return Err(std::io::Error::other(format!(
+                    "'sudo tee' failed ({status})"
+                )));
+            }
+        }
+        UnwritableMode::Patch => {
+            let mut replaced = Vec::new();
+            std::io::Read::read_to_end(&mut &*new, &mut replaced)?;
+            let patch = diffy::create_patch_bytes(original, &replaced).to_bytes();
+
+            let (mut file, patch_path) = tempfile::Builder::new()
+                .prefix("repatch-")
+                .suffix(".patch")
+                .tempfile()?
+                .keep()
+                .map_err(|e| e.error)?;
+            file.write_all(&patch)?;
+
+            let quote =
+                |path: &Path| crate::history::shell_quote(&path.to_string_lossy()).into_owned();
+            println!(
+                "The file '{}' isn't writable, so its changes were saved to '{}'. To apply them, \
+                run:\n  sudo patch {} {}",
+                path.display(),
+                patch_path.display(),
+                quote(path),
+                quote(&patch_path),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the verification command. If it fails, restore the original contents of the files and
+/// return an error.
+fn verify(cmd: &str, originals: &[(PathBuf, Vec<u8>)]) -> anyhow::Result<()> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .status()
+        .with_context(|| format!("could not run the command '{cmd}'"))?;
+
+    if status.success() {
+        return Ok(());
+    }
+
+    for (path, contents) in originals {
+        crate::util::replace_contents(path, contents)
+            .with_context(|| format!("could not restore the file '{}'", path.display()))?;
+    }
+
+    let paths: Vec<_> = originals
+        .iter()
+        .map(|(path, _)| format!("'{}'", path.display()))
+        .collect();
+
+    anyhow::bail!(
+        "the verification command '{cmd}' failed ({status}) after modifying {}\n\
+        The original contents were restored.",
+        paths.join(", "),
+    );
+}
+
+/// Wrap `decide` so that each hunk's replacement is first passed through the hunk filter command
+/// `cmd`. Hunks that are vetoed by the command aren't passed to `decide`. If the command can't be
+/// run, the error is stored in `error` and the wrapper returns [`PatchOption::Quit`].
+fn with_hunk_filter<'a>(
+    cmd: Option<&'a str>,
+    path: &'a Path,
+    error: &'a mut Option<anyhow::Error>,
+    mut decide: impl FnMut(&Hunk) -> PatchOption + 'a,
+) -> impl FnMut(&Hunk) -> PatchOption + 'a {
+    move |hunk| {
+        let Some(cmd) = cmd else {
+            return decide(hunk);
+        };
+
+        match filter_hunk(cmd, path, hunk) {
+            // the replacement may no longer change anything
+            Ok(Some(x)) if x == hunk.original => PatchOption::WriteOriginal,
+            // the filtered replacement can't be split into the matches
+            Ok(Some(x)) => decide(&Hunk {
+                replaced: &x,
+                matches: &[],
+                match_ids: &[],
+                ..*hunk
+            }),
+            Ok(None) => PatchOption::WriteOriginal,
+            Err(e) => {
+                *error = Some(e);
+                PatchOption::Quit
+            }
+        }
+    }
+}
+
+/// Pipe the hunk's replacement to the shell command `cmd`. Returns the command's output if it
+/// exits with 0, or `None` if it exits with 1 (a veto).
+fn filter_hunk(cmd: &str, path: &Path, hunk: &Hunk) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut original = crate::workspace::named_tempfile("hunk-", "")?;
+    original.write_all(hunk.original)?;
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("REPATCH_PATH", path)
+        .env("REPATCH_LINE", (hunk.start_line + 1).to_string())
+        .env("REPATCH_ORIGINAL", original.path())
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("could not run the hunk filter '{cmd}'"))?;
+
+    let mut stdin = child.stdin.take().unwrap();
+
+    // write from another thread so that the command can't block on a full stdout pipe
+    let output = std::thread::scope(|s| {
+        s.spawn(move || {
+            // the command is allowed to exit without reading everything
+            let _ = stdin.write_all(hunk.replaced);
+        });
+        child.wait_with_output()
+    })?;
+
+    match output.status.code() {
+        Some(0) => Ok(Some(output.stdout)),
+        Some(1) => Ok(None),
+        _ => anyhow::bail!(
+            "the hunk filter '{cmd}' failed for the file '{}' ({})",
+            path.display(),
+            output.status,
+        ),
+    }
+}
+
+/// Run the shell command `cmd` for the file at `path`, and return an error if it fails.
+fn run_path_cmd(cmd: &str, path: &Path) -> anyhow::Result<()> {
+    let status = crate::util::run_path_cmd(cmd, path)
+        .with_context(|| format!("could not run the command '{cmd}'"))?;
+
+    if !status.success() {
+        anyhow::bail!(
+            "the command '{cmd}' failed for the file '{}' ({status})",
+            path.display(),
+        );
+    }
+
+    Ok(())
+}
+
+/// The hash of the lines `lines` of the file at `path`.
+fn hunk_checksum(
+    path: &Path,
+    lines: std::ops::Range<u64>,
+    line_terminator: u8,
+) -> std::io::Result<u64> {
+    let mut src = BufReader::new(File::open(path)?);
+    let mut region = Vec::new();
+    let mut buf = Vec::new();
+
+    for line_num in 0..lines.end {
+        buf.clear();
+        if src.read_until(line_terminator, &mut buf)? == 0 {
+            // EOF
+            break;
+        }
+        if line_num >= lines.start {
+            region.extend_from_slice(&buf);
+        }
+    }
+
+    Ok(crate::util::fnv1a(&region))
+}
+
+/// A summary of the file's size and modified time, and with `--blame` the most recent commit of
+/// the hunk's lines, to show with the hunk.
+/// The encoding, line endings, size, and number of matches of the file, which are shown with its
+/// first hunk so that a file with an unexpected encoding or line endings is noticed.
+fn file_summary(options: &ReplaceOptions, path: &Path) -> Option<String> {
+    if !options.details {
+        return None;
+    }
+
+    let bytes = std::fs::read(path).ok()?;
+    let mut matches = 0;
+    match options.exclude_lines {
+        None => grep_matcher::Matcher::find_iter(options.matcher, &bytes, |_| {
+            matches += 1;
+            true
+        })
+        .ok()?,
+        // the matches on the excluded lines aren't counted
+        Some(exclude) => {
+            for line in bytes.split_inclusive(|x| *x == options.line_terminator) {
+                if !grep_matcher::Matcher::is_match(exclude, line).ok()? {
+                    grep_matcher::Matcher::find_iter(options.matcher, line, |_| {
+                        matches += 1;
+                        true
+                    })
+                    .ok()?;
+                }
+            }
+        }
+    }
+
+    Some(format!(
+        "{}, {}, {}, {matches} match{}",
+        crate::util::describe_encoding(&bytes),
+        crate::util::describe_line_endings(&bytes),
+        crate::ui::format_size(bytes.len() as u64),
+        if matches == 1 { "" } else { "es" },
+    ))
+}
+
+fn hunk_details(options: &ReplaceOptions, path: &Path, hunk: &Hunk) -> Option<String> {
+    if !options.details {
+        return None;
+    }
+
+    let now = SystemTime::now();
+    let mut details = Vec::new();
+
+    if let Ok(metadata) = std::fs::metadata(path) {
+        let mut file = crate::ui::format_size(metadata.len());
+        let modified = metadata.modified().ok();
+        if let (Some(age), false) = (
+            modified.and_then(|x| now.duration_since(x).ok()),
+            crate::ui::is_test_mode(),
+        ) {
+            file.push_str(&format!(", modified {}", crate::ui::format_age(age)));
+        }
+        details.push(file);
+    }
+
+    if options.blame {
+        let lines = crate::util::count_lines(hunk.original, options.line_terminator);
+        let blame = crate::git::blame(path, hunk.start_line..hunk.start_line + lines);
+        match blame {
+            Some(crate::git::BlameSummary { commit: None, .. }) => {
+                details.push("has uncommitted changes".to_string());
+            }
+            Some(crate::git::BlameSummary {
+                commit: Some(commit),
+                author,
+                time,
+            }) => {
+                let age = now
+                    .duration_since(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(time))
+                    .unwrap_or_default();
+                details.push(format!(
+                    "last changed by {author} in {commit}, {}",
+                    crate::ui::format_age(age),
+                ));
+            }
+            None => details.push("not tracked by git".to_string()),
+        }
+    }
+
+    (!details.is_empty()).then(|| details.join("; "))
+}
+
+/// Show all of a file's changes together and ask whether to apply them, for
+/// `--interactive=once`. Returns the answer for every hunk of the file. If the user edited the
+/// changes, the answer is yes and `edited` is set to the new file.
+fn prompt_file(
+    options: &ReplaceOptions,
+    paths: FilePaths,
+    match_info: &MatchInfo,
+    edited: &mut Option<Vec<u8>>,
+) -> anyhow::Result<MenuOption> {
+    let swap_terminator = |bytes| crate::util::swap_bytes(bytes, options.line_terminator, b'\n');
+
+    let original = std::fs::read(paths.real)
+        .with_context(|| format!("could not read file '{}'", paths.real.display()))?;
+
+    // replace every hunk to get the new file
+    let new = crate::workspace::tempfile()?;
+    let src = File::open(paths.real)
+        .with_context(|| format!("could not read file '{}'", paths.real.display()))?;
+    replace_matches(
+        options,
+        paths.display,
+        &src,
+        Some(&new),
+        &mut match_info.lines.clone(),
+        match_info.eol,
+        |hunk| PatchOption::WriteNew(hunk.replaced.to_vec()),
+    )
+    .with_context(|| format!("could not read file '{}'", paths.real.display()))?;
+    let mut replaced = Vec::new();
+    (&new).seek(std::io::SeekFrom::Start(0))?;
+    (&new).read_to_end(&mut replaced)?;
+
+    if replaced == original {
+        return Ok(MenuOption::No);
+    }
+
+    let original = swap_terminator(&original);
+    let context = usize::try_from(options.padding).unwrap_or(usize::MAX);
+    let diff = file_diff(paths.patch, &original, &swap_terminator(&replaced), context);
+
+    Ok(match crate::ui::file_prompt(&original, &diff) {
+        PatchOption::WriteNew(x) => {
+            let x = swap_terminator(&x).into_owned();
+            if x != replaced {
+                *edited = Some(x);
+            }
+            MenuOption::Yes
+        }
+        PatchOption::WriteOriginal => MenuOption::No,
+        PatchOption::Quit => MenuOption::Quit,
+    })
+}
+
+/// The capture groups of each match in the hunk and their values, to show when prompted.
+fn hunk_captures(options: &ReplaceOptions, path: &Path, hunk: &Hunk) -> String {
+    let mut out = Vec::new();
+
+    let lines = hunk
+        .original
+        .split_inclusive(|x| *x == options.line_terminator);
+    for (line_num, line) in (hunk.start_line..).zip(lines) {
+        let filter = options.line_filter(path, line_num, line);
+        let Ok(matches) = crate::util::match_captures(options.matcher, &filter, line) else {
+            continue;
+        };
+
+        for groups in matches {
+            let mut groups = groups.into_iter().enumerate();
+            let (_, whole) = groups.next().unwrap();
+            let whole = &line[whole.unwrap()];
+            writeln!(out, "line {}: {:?}", line_num + 1, whole.as_bstr()).unwrap();
+
+            for (i, group) in groups {
+                let name = match options.capture_names.get(i) {
+                    Some(Some(name)) => format!("${i} (${name})"),
+                    _ => format!("${i}"),
+                };
+                match group {
+                    Some(range) => writeln!(out, "  {name} = {:?}", line[range].as_bstr()),
+                    None => writeln!(out, "  {name} didn't participate in the match"),
+                }
+                .unwrap();
+            }
+        }
+    }
+
+    if out.is_empty() {
+        return "No matches in this hunk".to_string();
+    }
+
+    let out = crate::ui::escape_ansi(&out);
+    out.trim_end().to_str_lossy().into_owned()
+}
+
+/// Show the hunk to the user and ask them what to do. The file path is only shown for the first
+/// hunk of the file.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn prompt_hunk(
+    options: &ReplaceOptions,
+    paths: FilePaths,
+    hunk: &Hunk,
+    input: Option<MenuOption>,
+    progress: &crate::ui::Progress,
+    hunk_id: u64,
+    note: Option<&std::cell::Cell<Option<String>>>,
+    search: Option<&std::cell::Cell<Option<RegexMatcher>>>,
+    sticky: Option<&std::cell::Cell<Option<MenuOption>>>,
+    similar: Option<&std::cell::Cell<Option<MenuOption>>>,
+    diffs: Option<&crate::ui::HunkDiffs>,
+) -> PatchOption {
+    // patches are line-based, so if we're using a different line terminator we swap it with the
+    // newline character while the patch is being shown and edited
+    let swap_terminator = |bytes| crate::util::swap_bytes(bytes, options.line_terminator, b'\n');
+
+    let details = hunk_details(options, paths.real, hunk);
+    let file_summary = match hunk.idx {
+        0 => file_summary(options, paths.real),
+        _ => None,
+    };
+    let captures = hunk_captures(options, paths.display, hunk);
+
+    // the matches are within the hunk with the swapped line terminators
+    let matches: Vec<_> = hunk
+        .matches
+        .iter()
+        .map(|x| crate::util::MatchReplacement {
+            range: x.range.clone(),
+            replacement: swap_terminator(&x.replacement).into_owned(),
+        })
+        .collect();
+
+    // the numbered replacements to choose between, starting with `<REPLACE>`
+    let mut choices = Vec::new();
+    if let (false, Replacement::Template(replace) | Replacement::Literal(replace)) =
+        (hunk.alternatives.is_empty(), options.replacement)
+    {
+        let labels = std::iter::once(replace.to_str_lossy().into_owned())
+            .chain(options.alternatives.iter().cloned());
+        let replaced =
+            std::iter::once(hunk.replaced).chain(hunk.alternatives.iter().map(Vec::as_slice));
+        for (label, replaced) in labels.zip(replaced) {
+            choices.push(crate::ui::Choice {
+                label,
+                replaced: swap_terminator(replaced).into_owned(),
+            });
+        }
+    }
+
+    let option = crate::ui::patch_prompt(
+        &swap_terminator(hunk.original),
+        &swap_terminator(hunk.replaced),
+        (hunk.idx == 0).then_some(paths),
+        (hunk.idx, hunk.count),
+        hunk.start_line,
+        input,
+        crate::ui::PromptOptions {
+            timeout: options.prompt_timeout.as_ref(),
+            max_line_len: options.max_line_len,
+            diffs,
+            fold_context: options.fold_context,
+            diff_viewer: options.diff_viewer.map(|cmd| crate::ui::DiffViewer {
+                cmd,
+                path: paths.patch,
+            }),
+            progress: Some(progress),
+            hunk_id: Some(hunk_id),
+            details: details.as_deref(),
+            file_summary: file_summary.as_deref(),
+            captures: Some(&captures),
+            choices: &choices,
+            matches: &matches,
+            note,
+            search,
+            sticky,
+            similar,
+        },
+    );
+
+    let PatchOption::WriteNew(x) = option else {
+        return option;
+    };
+
+    let x = swap_terminator(&x).into_owned();
+
+    // this theoretically shouldn't be needed and it might panic on false positives, but it's
+    // unlikely that a patch would remove all lines of the hunk (the user isn't asked if the answer
+    // was chosen for them, such as with `--apply`)
+    if x.trim().is_empty() && input.is_none() {
+        // TODO: remove this when we're more confident in the patches
+        let msg =
+            "This patch removes all lines of the hunk. Are you sure that you want to continue \
+            [y/n]?";
+        if !crate::ui::yes_no_prompt(msg) {
+            // write the hunk without applying the patch
+            return PatchOption::Quit;
+        }
+    }
+
+    PatchOption::WriteNew(x)
+}
+
+/// What happened to a file that was shown or patched. The summary at the end of a run is counted
+/// from these.
+#[derive(Clone, Debug, Default)]
+pub struct FileOutcome {
+    pub path: PathBuf,
+    /// The hunks that were shown without being applied, with `--show`.
+    pub shown_hunks: u64,
+    /// The hunks that were applied.
+    pub applied_hunks: u64,
+    /// The hunks that were answered without being applied.
+    pub skipped_hunks: u64,
+    /// The applied hunks that were edited before being applied.
+    pub edited_hunks: u64,
+    /// The matches that were replaced by the applied hunks, not counting edited hunks.
+    pub replacements: u64,
+    /// Whether the file was written.
+    pub written: bool,
+    /// Why the file couldn't be patched, if it couldn't.
+    pub error: Option<String>,
+    /// The size of the new file, or 0 if the file wasn't written.
+    pub bytes_written: u64,
+    /// How long the file took to show or patch, including the time spent answering prompts.
+    pub duration: std::time::Duration,
+}
+
+impl FileOutcome {
+    pub(crate) fn new(path: &Path, started: std::time::Instant) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            duration: started.elapsed(),
+            ..Default::default()
+        }
+    }
+
+    fn failed(path: &Path, started: std::time::Instant, error: impl std::fmt::Display) -> Self {
+        Self {
+            error: Some(error.to_string()),
+            ..Self::new(path, started)
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! Plans, which record the hunks chosen during a run so that they can be applied later with
+//! `repatch execute`, possibly in another checkout of the repository.
+//!
+//! A plan is a JSON object with the find and replace arguments and the hunks of each file. Paths
+//! are relative to the root of the git repository (or to the current directory if not in a git
+//! repository), and each file has a hash of its contents so that the hunks are only applied to
+//! files that haven't changed since the plan was made.
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use anyhow::Context;
+
+use crate::json::Value;
+use crate::ui::{style, COUNT_STYLE};
+use crate::util::fnv1a;
+
+/// The plan format version, which must match when executing.
+const VERSION: u64 = 1;
+
+#[derive(Debug, PartialEq)]
+pub struct Plan {
+    find: String,
+    replace: String,
+    line_terminator: u8,
+    files: Vec<PlanFile>,
+}
+
+#[derive(Debug, PartialEq)]
+struct PlanFile {
+    path: PathBuf,
+    /// A hash of the file contents when the plan was made.
+    hash: u64,
+    /// The hunks in order of their lines.
+    hunks: Vec<PlanHunk>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct PlanHunk {
+    /// The (0-indexed) line of the file where the hunk starts.
+    line: u64,
+    original: Vec<u8>,
+    replaced: Vec<u8>,
+}
+
+impl Plan {
+    pub fn new(find: &str, replace: &str, line_terminator: u8) -> Self {
+        Self {
+            find: find.to_string(),
+            replace: replace.to_string(),
+            line_terminator,
+            files: Vec::new(),
+        }
+    }
+
+    /// Add a hunk of the file at `path` (relative to the root), whose contents have the hash
+    /// `file_hash`. Hunks must be added in order.
+    pub fn add_hunk(
+        &mut self,
+        path: &Path,
+        file_hash: u64,
+        line: u64,
+        original: &[u8],
+        replaced: &[u8],
+    ) {
+        let hunk = PlanHunk {
+            line,
+            original: original.to_vec(),
+            replaced: replaced.to_vec(),
+        };
+
+        match self.files.last_mut() {
+            Some(file) if file.path == path => file.hunks.push(hunk),
+            _ => self.files.push(PlanFile {
+                path: path.to_path_buf(),
+                hash: file_hash,
+                hunks: vec![hunk],
+            }),
+        }
+    }
+
+    /// The number of files and hunks in the plan.
+    pub fn counts(&self) -> (usize, usize) {
+        let hunks = self.files.iter().map(|x| x.hunks.len()).sum();
+        (self.files.len(), hunks)
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let json = self
+            .to_json()
+            .with_context(|| format!("could not write the plan '{}'", path.display()))?;
+        std::fs::write(path, format!("{json}\n"))
+            .with_context(|| format!("could not write the plan '{}'", path.display()))
+    }
+
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read the plan '{}'", path.display()))?;
+        let value = crate::json::parse(&contents)
+            .map_err(|e| anyhow::anyhow!("{e}"))
+            .and_then(|x| Self::from_json(&x))
+            .with_context(|| format!("invalid plan '{}'", path.display()))?;
+        Ok(value)
+    }
+
+    fn to_json(&self) -> anyhow::Result<Value> {
+        let text = |path: &Path, bytes: &[u8]| match std::str::from_utf8(bytes) {
+            Ok(x) => Ok(Value::from(x)),
+            Err(_) => Err(anyhow::anyhow!(
+                "the hunks of '{}' aren't valid UTF-8",
+                path.display(),
+            )),
+        };
+
+        let mut files = Vec::new();
+        for file in &self.files {
+            let Some(path) = file.path.to_str() else {
+                anyhow::bail!("the path '{}' isn't valid UTF-8", file.path.display());
+            };
+
+            let mut hunks = Vec::new();
+            for hunk in &file.hunks {
+                hunks.push(Value::object([
+                    ("line", hunk.line.into()),
+                    ("original", text(&file.path, &hunk.original)?),
+                    ("replaced", text(&file.path, &hunk.replaced)?),
+                ]));
+            }
+
+            files.push(Value::object([
+                ("path", path.into()),
+                ("hash", format!("{:016x}", file.hash).into()),
+                ("hunks", hunks.into()),
+            ]));
+        }
+
+        Ok(Value::object([
+            ("version", VERSION.into()),
+            ("find", self.find.as_str().into()),
+            ("replace", self.replace.as_str().into()),
+            ("line_terminator", u64::from(self.line_terminator).into()),
+            ("files", files.into()),
+        ]))
+    }
+
+    fn from_json(value: &Value) -> anyhow::Result<Self> {
+        fn invalid() -> anyhow::Error {
+            anyhow::anyhow!("missing or invalid fields")
+        }
+        fn str<'a>(value: &'a Value, key: &str) -> anyhow::Result<&'a str> {
+            value.get(key).and_then(Value::as_str).ok_or_else(invalid)
+        }
+        fn array<'a>(value: &'a Value, key: &str) -> anyhow::Result<&'a [Value]> {
+            match value.get(key) {
+                Some(Value::Array(x)) => Ok(x),
+                _ => Err(invalid()),
+            }
+        }
+
+        let version = value.get("version").and_then(Value::as_u64);
+        if version != Some(VERSION) {
+            anyhow::bail!("unsupported plan version (expected {VERSION})");
+        }
+
+        let line_terminator = value
+            .get("line_terminator")
+            .and_then(Value::as_u64)
+            .and_then(|x| u8::try_from(x).ok())
+            .ok_or_else(invalid)?;
+
+        let mut files = Vec::new();
+        for file in array(value, "files")? {
+            let hash = u64::from_str_radix(str(file, "hash")?, 16).map_err(|_| invalid())?;
+
+            let mut hunks = Vec::new();
+            for hunk in array(file, "hunks")? {
+                hunks.push(PlanHunk {
+                    line: hunk
+                        .get("line")
+                        .and_then(Value::as_u64)
+                        .ok_or_else(invalid)?,
+                    original: str(hunk, "original")?.as_bytes().to_vec(),
+                    replaced: str(hunk, "replaced")?.as_bytes().to_vec(),
+                });
+            }
+
+            files.push(PlanFile {
+                path: PathBuf::from(str(file, "path")?),
+                hash,
+                hunks,
+            });
+        }
+
+        Ok(Self {
+            find: str(value, "find")?.to_string(),
+            replace: str(value, "replace")?.to_string(),
+            line_terminator,
+            files,
+        })
+    }
+}
+
+/// Apply the hunks to the file contents. Returns `None` if a hunk's original lines aren't in the
+/// file.
+fn apply_hunks(bytes: &[u8], hunks: &[PlanHunk], line_terminator: u8) -> Option<Vec<u8>> {
+    let lines: Vec<&[u8]> = bytes.split_inclusive(|x| *x == line_terminator).collect();
+
+    let mut new = Vec::new();
+    let mut current_line = 0;
+
+    for hunk in hunks {
+        let start = usize::try_from(hunk.line).ok()?;
+        let len =
+            usize::try_from(crate::util::count_lines(&hunk.original, line_terminator)).ok()?;
+        if start < current_line {
+            return None;
+        }
+
+        if lines.get(start..start + len)?.concat() != hunk.original {
+            return None;
+        }
+
+        new.extend(lines[current_line..start].concat());
+        new.extend_from_slice(&hunk.replaced);
+        current_line = start + len;
+    }
+
+    new.extend(lines[current_line..].concat());
+    Some(new)
+}
+
+/// Apply the plan at `path` to the files that haven't changed since it was made. Returns a failure
+/// exit code if any files were skipped.
+pub fn execute(path: &Path) -> anyhow::Result<ExitCode> {
+    let plan = Plan::read(path)?;
+
+    let cwd = std::env::current_dir()?;
+    let root = crate::git::toplevel(&cwd).unwrap_or(cwd);
+
+    println!(
+        "Executing the plan to replace \"{}\" with \"{}\".",
+        plan.find, plan.replace,
+    );
+
+    let mut journal = crate::undo::Journal::default();
+    let mut applied_files: u64 = 0;
+    let mut applied_hunks: u64 = 0;
+    let mut skipped: u64 = 0;
+
+    for file in &plan.files {
+        let full_path = root.join(&file.path);
+
+        let original = match std::fs::read(&full_path) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Skipped '{}': {e}", file.path.display());
+                skipped += 1;
+                continue;
+            }
+        };
+
+        if fnv1a(&original) != file.hash {
+            println!(
+                "Skipped '{}': the file has changed since the plan was made",
+                file.path.display(),
+            );
+            skipped += 1;
+            continue;
+        }
+
+        let Some(new) = apply_hunks(&original, &file.hunks, plan.line_terminator) else {
+            println!("Skipped '{}': the hunks don't apply", file.path.display());
+            skipped += 1;
+            continue;
+        };
+
+        journal.record(&full_path, original)?;
+        crate::util::replace_contents(&full_path, &new)
+            .with_context(|| format!("could not replace file '{}'", full_path.display()))?;
+
+        applied_files += 1;
+        applied_hunks += file.hunks.len() as u64;
+    }
+
+    journal.save()?;
+
+    println!(
+        "Applied {} hunk{} in {} file{}.",
+        style!(applied_hunks, &COUNT_STYLE),
+        if applied_hunks == 1 { "" } else { "s" },
+        style!(applied_files, &COUNT_STYLE),
+        if applied_files == 1 { "" } else { "s" },
+    );
+
+    if skipped > 0 {
+        println!(
+            "Skipped {} file{}.",
+            style!(skipped, &COUNT_STYLE),
+            if skipped == 1 { "" } else { "s" },
+        );
+        return Ok(ExitCode::FAILURE);
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut plan = Plan::new("foo", "bar", b'\n');
+        plan.add_hunk(Path::new("src/a.rs"), 0xff, 0, b"foo\n", b"bar\n");
+        plan.add_hunk(Path::new("src/a.rs"), 0xff, 9, b"a foo\n", b"a bar\n");
+        plan.add_hunk(Path::new("b.rs"), 0x1, 2, b"\"foo\"\n", b"\"bar\"\n");
+        assert_eq!(plan.counts(), (2, 3));
+
+        let json = plan.to_json().unwrap().to_string();
+        let parsed = Plan::from_json(&crate::json::parse(&json).unwrap()).unwrap();
+        assert_eq!(parsed, plan);
+
+        assert!(Plan::from_json(&crate::json::parse("{}").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_apply_hunks() {
+        let hunk = |line, original: &[u8], replaced: &[u8]| PlanHunk {
+            line,
+            original: original.to_vec(),
+            replaced: replaced.to_vec(),
+        };
+
+        let bytes = b"a\nfoo\nb\nfoo";
+        let hunks = [hunk(1, b"foo\n", b"x\ny\n"), hunk(3, b"foo", b"z")];
+        assert_eq!(apply_hunks(bytes, &hunks, b'\n').unwrap(), b"a\nx\ny\nb\nz",);
+
+        // the original lines don't match
+        assert_eq!(
+            apply_hunks(bytes, &[hunk(0, b"foo\n", b"x\n")], b'\n'),
+            None
+        );
+        // past the end of the file
+        assert_eq!(
+            apply_hunks(bytes, &[hunk(4, b"foo\n", b"x\n")], b'\n'),
+            None
+        );
+        // out of order
+        assert_eq!(
+            apply_hunks(bytes, &[hunks[1].clone(), hunks[0].clone()], b'\n'),
+            None
+        );
+    }
+}
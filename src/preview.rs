@@ -0,0 +1,489 @@
+//! A read-only preview of the changes for `repatch serve-preview`, which is served as a static
+//! HTML page so that the changes can be reviewed in a browser before they're applied.
+//!
+//! The page is rendered once when the server starts, with each hunk of each file and some basic
+//! syntax highlighting based on the file's extension. Only `GET /` and `HEAD /` are answered, and
+//! nothing is ever modified.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use bstr::ByteSlice;
+
+use crate::replace::ReplaceOptions;
+use crate::search::MatchInfo;
+use crate::ui::{style, PatchOption, COUNT_STYLE};
+
+/// A hunk where something was replaced.
+pub struct PreviewHunk {
+    /// The (0-indexed) line of the file where the hunk starts.
+    pub start_line: u64,
+    pub original: Vec<u8>,
+    pub replaced: Vec<u8>,
+}
+
+pub struct PreviewFile {
+    pub path: PathBuf,
+    pub hunks: Vec<PreviewHunk>,
+}
+
+/// Serve the preview on `host:port` until the program is interrupted.
+pub fn serve(
+    options: &ReplaceOptions,
+    matches: &BTreeMap<PathBuf, MatchInfo>,
+    host: &str,
+    port: u16,
+) -> anyhow::Result<()> {
+    let files = collect(options, matches)?;
+    let hunks: usize = files.iter().map(|x| x.hunks.len()).sum();
+    let summary = format!(
+        "{hunks} hunk{} in {} file{}.",
+        if hunks == 1 { "" } else { "s" },
+        files.len(),
+        if files.len() == 1 { "" } else { "s" },
+    );
+    let page = html("re:patch preview", &summary, &files, false);
+
+    let listener = TcpListener::bind((host, port))
+        .with_context(|| format!("could not listen on {host}:{port}"))?;
+    let addr = listener.local_addr()?;
+
+    println!(
+        "Serving a preview of {} hunk{} in {} file{} at http://{addr}/ (press ctrl-c to stop).",
+        style!(hunks, &COUNT_STYLE),
+        if hunks == 1 { "" } else { "s" },
+        style!(files.len(), &COUNT_STYLE),
+        if files.len() == 1 { "" } else { "s" },
+    );
+
+    // a client that disconnects or misbehaves shouldn't stop the server
+    for stream in listener.incoming().flatten() {
+        let _ = handle(stream, &page);
+    }
+
+    Ok(())
+}
+
+/// The hunks of each file where something would be replaced.
+fn collect(
+    options: &ReplaceOptions,
+    matches: &BTreeMap<PathBuf, MatchInfo>,
+) -> anyhow::Result<Vec<PreviewFile>> {
+    let mut files = Vec::new();
+
+    for (path, match_info) in matches {
+        let src = File::open(path)
+            .with_context(|| format!("could not read file '{}'", path.display()))?;
+        let mut hunks = Vec::new();
+
+        crate::replace::replace_matches(
+            options,
+            &match_info.display_path,
+            &src,
+            None::<&File>,
+            &mut match_info.lines.clone(),
+            match_info.eol,
+            |hunk| {
+                hunks.push(PreviewHunk {
+                    start_line: hunk.start_line,
+                    original: hunk.original.to_vec(),
+                    replaced: hunk.replaced.to_vec(),
+                });
+                PatchOption::WriteOriginal
+            },
+        )
+        .with_context(|| format!("could not read file '{}'", path.display()))?;
+
+        if !hunks.is_empty() {
+            files.push(PreviewFile {
+                path: match_info.display_path.clone(),
+                hunks,
+            });
+        }
+    }
+
+    Ok(files)
+}
+
+/// Answer one request with the page.
+fn handle(mut stream: TcpStream, page: &str) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // the headers aren't needed
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let (status, body, head) = respond(&request, page);
+    let content_type = match status {
+        "200 OK" => "text/html; charset=utf-8",
+        _ => "text/plain; charset=utf-8",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+        Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+        body.len(),
+    )?;
+    if !head {
+        stream.write_all(body.as_bytes())?;
+    }
+    stream.flush()
+}
+
+/// The status and body of the response to the request line `request`, and whether the body should
+/// be omitted.
+fn respond<'a>(request: &str, page: &'a str) -> (&'static str, &'a str, bool) {
+    let mut parts = request.split_whitespace();
+    let (method, target) = (parts.next(), parts.next());
+    // the query string is ignored
+    let path = target.map(|x| x.split('?').next().unwrap());
+
+    match (method, path) {
+        (Some("GET"), Some("/")) => ("200 OK", page, false),
+        (Some("HEAD"), Some("/")) => ("200 OK", page, true),
+        (Some("GET" | "HEAD"), _) => ("404 Not Found", "not found\n", false),
+        _ => (
+            "405 Method Not Allowed",
+            "the preview is read-only\n",
+            false,
+        ),
+    }
+}
+
+const STYLESHEET: &str = "\
+body { font-family: sans-serif; margin: 2em; }
+h2, summary { font-size: 1.1em; font-family: monospace; margin-top: 2em; }
+summary { cursor: pointer; margin-bottom: 1em; }
+table { border-collapse: collapse; font-family: monospace; width: 100%; margin-bottom: 1em; }
+td { padding: 0 0.5em; white-space: pre-wrap; vertical-align: top; }
+td.num { color: #888; text-align: right; width: 1%; user-select: none; }
+tr.hunk td { background: #eef; color: #558; }
+tr.del td.line { background: #fdd; }
+tr.ins td.line { background: #dfd; }
+.k { color: #a0a; font-weight: bold; }
+.s { color: #a50; }
+.c { color: #888; font-style: italic; }
+.n { color: #05a; }
+";
+
+/// The page with the hunks of each file, where each file's hunks can be collapsed if
+/// `collapsible` is true.
+pub fn html(title: &str, summary: &str, files: &[PreviewFile], collapsible: bool) -> String {
+    let title = escape(title);
+
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    out.push_str(&format!("<title>{title}</title>\n"));
+    out.push_str(&format!("<style>\n{STYLESHEET}</style>\n</head>\n<body>\n"));
+    out.push_str(&format!("<h1>{title}</h1>\n<p>{}</p>\n", escape(summary)));
+
+    for file in files {
+        let path = escape(&file.path.to_string_lossy());
+        let lang = Lang::from_path(&file.path);
+        if collapsible {
+            let count = file.hunks.len();
+            out.push_str(&format!(
+                "<details id=\"{path}\" open>\n<summary>{path} ({count} hunk{})</summary>\n",
+                if count == 1 { "" } else { "s" },
+            ));
+        } else {
+            out.push_str(&format!("<h2 id=\"{path}\">{path}</h2>\n"));
+        }
+        out.push_str("<table>\n");
+        for hunk in &file.hunks {
+            hunk_rows(hunk, lang, &mut out);
+        }
+        out.push_str("</table>\n");
+        if collapsible {
+            out.push_str("</details>\n");
+        }
+    }
+
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// The diff of the hunk's original and replaced lines, where the line numbers are relative to the
+/// start of the hunk.
+pub fn hunk_patch(hunk: &PreviewHunk) -> diffy::Patch<'_, [u8]> {
+    // the hunk already has the context lines, so all of them are kept
+    let lines = crate::util::count_lines(&hunk.original, b'\n')
+        .max(crate::util::count_lines(&hunk.replaced, b'\n'));
+    diffy::DiffOptions::new()
+        .set_context_len(usize::try_from(lines).unwrap_or(usize::MAX))
+        .create_patch_bytes(&hunk.original, &hunk.replaced)
+}
+
+/// Append a table row for each line of the hunk's diff.
+fn hunk_rows(hunk: &PreviewHunk, lang: Option<Lang>, out: &mut String) {
+    let patch = hunk_patch(hunk);
+
+    for diff_hunk in patch.hunks() {
+        let mut old = hunk.start_line + diff_hunk.old_range().start() as u64;
+        let mut new = hunk.start_line + diff_hunk.new_range().start() as u64;
+        out.push_str(&format!(
+            "<tr class=\"hunk\"><td class=\"num\"></td><td class=\"num\"></td>\
+            <td>@@ -{old} +{new} @@</td></tr>\n",
+        ));
+
+        for line in diff_hunk.lines() {
+            let (class, text, old_num, new_num) = match line {
+                diffy::Line::Context(x) => ("ctx", x, Some(old), Some(new)),
+                diffy::Line::Delete(x) => ("del", x, Some(old), None),
+                diffy::Line::Insert(x) => ("ins", x, None, Some(new)),
+            };
+            old += u64::from(old_num.is_some());
+            new += u64::from(new_num.is_some());
+
+            let num = |x: Option<u64>| x.map(|x| x.to_string()).unwrap_or_default();
+            let marker = match class {
+                "del" => '-',
+                "ins" => '+',
+                _ => ' ',
+            };
+            let text = text.to_str_lossy();
+            let text = text.trim_end_matches(['\n', '\r']);
+            out.push_str(&format!(
+                "<tr class=\"{class}\"><td class=\"num\">{}</td><td class=\"num\">{}</td>\
+                <td class=\"line\">{marker}{}</td></tr>\n",
+                num(old_num),
+                num(new_num),
+                highlight(text, lang),
+            ));
+        }
+    }
+}
+
+/// Escape text for HTML.
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The languages with syntax highlighting, which only differ by their comments and strings.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Lang {
+    /// Languages with "//" comments.
+    CLike,
+    /// Languages with "#" comments.
+    Script,
+    /// Languages with "--" comments.
+    Sql,
+}
+
+impl Lang {
+    fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?;
+        Some(match ext {
+            "rs" | "c" | "h" | "cc" | "cpp" | "hpp" | "cs" | "go" | "java" | "js" | "jsx"
+            | "ts" | "tsx" | "kt" | "swift" | "scala" | "dart" | "php" => Self::CLike,
+            "py" | "rb" | "sh" | "bash" | "zsh" | "pl" | "toml" | "yaml" | "yml" | "r" => {
+                Self::Script
+            }
+            "sql" | "lua" | "hs" => Self::Sql,
+            _ => return None,
+        })
+    }
+
+    fn comment(self) -> &'static str {
+        match self {
+            Self::CLike => "//",
+            Self::Script => "#",
+            Self::Sql => "--",
+        }
+    }
+
+    /// Whether single quotes start a string, rather than a character or lifetime.
+    fn single_quote_strings(self) -> bool {
+        self != Self::CLike
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    "as",
+    "async",
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "def",
+    "default",
+    "do",
+    "elif",
+    "else",
+    "enum",
+    "export",
+    "extends",
+    "false",
+    "False",
+    "fn",
+    "for",
+    "from",
+    "func",
+    "function",
+    "if",
+    "impl",
+    "import",
+    "in",
+    "interface",
+    "let",
+    "local",
+    "loop",
+    "match",
+    "mod",
+    "mut",
+    "new",
+    "nil",
+    "None",
+    "null",
+    "package",
+    "pub",
+    "return",
+    "select",
+    "self",
+    "static",
+    "struct",
+    "switch",
+    "then",
+    "this",
+    "throw",
+    "trait",
+    "true",
+    "True",
+    "try",
+    "type",
+    "use",
+    "var",
+    "where",
+    "while",
+    "with",
+    "yield",
+];
+
+/// Escape a line for HTML, with spans for the keywords, strings, comments, and numbers.
+fn highlight(line: &str, lang: Option<Lang>) -> String {
+    let Some(lang) = lang else {
+        return escape(line);
+    };
+
+    let span = |class: &str, text: &str| format!("<span class=\"{class}\">{}</span>", escape(text));
+
+    let mut out = String::new();
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        if rest.starts_with(lang.comment()) {
+            out.push_str(&span("c", rest));
+            break;
+        }
+
+        if c == '"' || (c == '\'' && lang.single_quote_strings()) {
+            // the string continues until the next unescaped quote, or the end of the line
+            let mut end = rest.len();
+            let mut escaped = false;
+            for (i, x) in rest.char_indices().skip(1) {
+                match x {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    x if x == c => {
+                        end = i + x.len_utf8();
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            out.push_str(&span("s", &rest[..end]));
+            rest = &rest[end..];
+            continue;
+        }
+
+        if c.is_alphanumeric() || c == '_' {
+            let end = rest
+                .find(|x: char| !x.is_alphanumeric() && x != '_')
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            if c.is_ascii_digit() {
+                out.push_str(&span("n", word));
+            } else if KEYWORDS.contains(&word) {
+                out.push_str(&span("k", word));
+            } else {
+                out.push_str(&escape(word));
+            }
+            rest = &rest[end..];
+            continue;
+        }
+
+        out.push_str(&escape(&rest[..c.len_utf8()]));
+        rest = &rest[c.len_utf8()..];
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight() {
+        let rust = Some(Lang::CLike);
+        assert_eq!(
+            highlight("let x = \"a<b\"; // 1", rust),
+            "<span class=\"k\">let</span> x = <span class=\"s\">&quot;a&lt;b&quot;</span>; \
+            <span class=\"c\">// 1</span>",
+        );
+        assert_eq!(
+            highlight("f('a', 2x)", rust),
+            "f(&#39;a&#39;, <span class=\"n\">2x</span>)"
+        );
+        assert_eq!(
+            highlight("s = 'it\\'s' # x", Some(Lang::Script)),
+            "s = <span class=\"s\">&#39;it\\&#39;s&#39;</span> <span class=\"c\"># x</span>",
+        );
+        assert_eq!(highlight("if <x>", None), "if &lt;x&gt;");
+        assert_eq!(Lang::from_path(Path::new("a/b.rs")), Some(Lang::CLike));
+        assert_eq!(Lang::from_path(Path::new("README")), None);
+    }
+
+    #[test]
+    fn test_respond() {
+        assert_eq!(
+            respond("GET / HTTP/1.1\r\n", "page"),
+            ("200 OK", "page", false)
+        );
+        assert_eq!(respond("GET /?x=1 HTTP/1.1\r\n", "page").0, "200 OK");
+        assert_eq!(
+            respond("HEAD / HTTP/1.1\r\n", "page"),
+            ("200 OK", "page", true)
+        );
+        assert_eq!(respond("GET /a HTTP/1.1\r\n", "page").0, "404 Not Found");
+        assert_eq!(
+            respond("POST / HTTP/1.1\r\n", "page").0,
+            "405 Method Not Allowed"
+        );
+        assert_eq!(respond("", "page").0, "405 Method Not Allowed");
+    }
+}
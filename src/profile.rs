@@ -0,0 +1,88 @@
+//! Timing of each phase of a run, for `--profile`.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static START: OnceLock<Instant> = OnceLock::new();
+/// The total nanoseconds spent in each phase, indexed by `Phase`.
+static TOTALS: [AtomicU64; Phase::list().len()] =
+    [const { AtomicU64::new(0) }; Phase::list().len()];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Walking the directory tree.
+    Walk,
+    /// Reading and searching files.
+    Search,
+    /// Performing the replacements and generating the patches.
+    Diff,
+    /// Waiting for the user to answer a prompt or edit a patch.
+    Prompt,
+    /// Writing the new files.
+    Write,
+}
+
+impl Phase {
+    pub const fn list() -> &'static [Self] {
+        &[
+            Self::Walk,
+            Self::Search,
+            Self::Diff,
+            Self::Prompt,
+            Self::Write,
+        ]
+    }
+
+    const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Walk => "walking",
+            Self::Search => "searching",
+            Self::Diff => "diffing",
+            Self::Prompt => "prompting",
+            Self::Write => "writing",
+        }
+    }
+}
+
+/// Start recording the time spent in each phase.
+pub fn enable() {
+    START.get_or_init(Instant::now);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Add to the time spent in a phase.
+pub fn add(phase: Phase, duration: Duration) {
+    if ENABLED.load(Ordering::Relaxed) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        TOTALS[phase as usize].fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+/// Run `f`, and add its run time to the time spent in a phase.
+pub fn measure<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+
+    let start = Instant::now();
+    let rv = f();
+    add(phase, start.elapsed());
+    rv
+}
+
+/// Print the time spent in each phase to stderr, if enabled.
+pub fn report() {
+    let Some(start) = START.get() else {
+        return;
+    };
+
+    eprintln!("\nProfile:");
+    for phase in Phase::list() {
+        let nanos = TOTALS[*phase as usize].load(Ordering::Relaxed);
+        let secs = Duration::from_nanos(nanos).as_secs_f64();
+        eprintln!("  {:<10} {secs:>9.3}s", phase.as_str());
+    }
+    eprintln!("  {:<10} {:>9.3}s", "total", start.elapsed().as_secs_f64());
+}
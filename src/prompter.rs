@@ -0,0 +1,367 @@
+//! Reading the answers to prompts. Answers are read with a small built-in line editor when stdin
+//! and stdout are terminals, so that a typo can be fixed before pressing enter and earlier answers
+//! can be recalled with the up and down arrows. Otherwise (or with `--no-readline`, `--plain`, or
+//! `TERM=dumb`) each line is read from stdin as it is.
+
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::sync::Mutex;
+
+/// Reads the answer to a prompt, after the prompt has been printed.
+pub trait Prompter: Send {
+    /// Read a line into `buf`, including the "\n" line ending, like [`BufRead::read_line`].
+    /// Returns 0 at the end of the input.
+    fn read_line(&mut self, buf: &mut String) -> std::io::Result<usize>;
+}
+
+/// Reads each line from stdin as it is.
+pub struct Plain;
+
+impl Prompter for Plain {
+    fn read_line(&mut self, buf: &mut String) -> std::io::Result<usize> {
+        std::io::stdin().lock().read_line(buf)
+    }
+}
+
+/// Reads each line from a reader and prints it after the prompt, as if it had been typed, for
+/// `--test-mode`. The answers of an end-to-end test can then be read in its output.
+pub struct Echo<R> {
+    input: R,
+}
+
+impl<R: BufRead + Send> Echo<R> {
+    pub fn new(input: R) -> Self {
+        Self { input }
+    }
+}
+
+impl<R: BufRead + Send> Prompter for Echo<R> {
+    fn read_line(&mut self, buf: &mut String) -> std::io::Result<usize> {
+        let start = buf.len();
+        let len = self.input.read_line(buf)?;
+
+        let line = &buf[start..];
+        let mut stdout = std::io::stdout().lock();
+        match line.ends_with('\n') {
+            true => write!(stdout, "{line}")?,
+            // the end of the input
+            false => writeln!(stdout, "{line}")?,
+        }
+        stdout.flush()?;
+
+        Ok(len)
+    }
+}
+
+/// A line editor with a history of the earlier answers.
+#[derive(Default)]
+pub struct Readline {
+    history: Vec<String>,
+}
+
+/// A key press of the line editor.
+#[derive(Debug, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    /// Delete from the start of the line to the cursor (ctrl-u).
+    KillStart,
+    /// Delete from the cursor to the end of the line (ctrl-k).
+    KillEnd,
+    /// Delete the word before the cursor (ctrl-w).
+    KillWord,
+    /// End the input if the line is empty (ctrl-d).
+    Eof,
+    Interrupt,
+    Other,
+}
+
+/// Split the bytes read from the terminal into key presses.
+fn parse_keys(input: &[u8]) -> Vec<Key> {
+    let input = String::from_utf8_lossy(input);
+    let mut chars = input.chars().peekable();
+    let mut keys = Vec::new();
+
+    while let Some(c) = chars.next() {
+        let key = match c {
+            '\r' | '\n' => Key::Enter,
+            '\x7f' | '\x08' => Key::Backspace,
+            '\x01' => Key::Home,
+            '\x05' => Key::End,
+            '\x02' => Key::Left,
+            '\x06' => Key::Right,
+            '\x10' => Key::Up,
+            '\x0e' => Key::Down,
+            '\x15' => Key::KillStart,
+            '\x0b' => Key::KillEnd,
+            '\x17' => Key::KillWord,
+            '\x04' => Key::Eof,
+            '\x03' => Key::Interrupt,
+            '\x1b' if chars.next_if(|x| *x == '[' || *x == 'O').is_some() => {
+                // the parameters and the final character of the escape sequence
+                let mut params = String::new();
+                let mut last = None;
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        last = Some(c);
+                        break;
+                    }
+                    params.push(c);
+                }
+                match (params.as_str(), last) {
+                    ("", Some('A')) => Key::Up,
+                    ("", Some('B')) => Key::Down,
+                    ("", Some('C')) => Key::Right,
+                    ("", Some('D')) => Key::Left,
+                    ("", Some('H')) | ("1" | "7", Some('~')) => Key::Home,
+                    ("", Some('F')) | ("4" | "8", Some('~')) => Key::End,
+                    ("3", Some('~')) => Key::Delete,
+                    _ => Key::Other,
+                }
+            }
+            c if c.is_control() => Key::Other,
+            c => Key::Char(c),
+        };
+        keys.push(key);
+    }
+
+    keys
+}
+
+/// The line being edited.
+#[derive(Debug, Default)]
+struct Line {
+    chars: Vec<char>,
+    /// The position of the cursor, in chars.
+    cursor: usize,
+}
+
+impl Line {
+    fn set(&mut self, text: &str) {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    /// Apply an editing key.
+    fn edit(&mut self, key: &Key) {
+        match key {
+            Key::Char(c) => {
+                self.chars.insert(self.cursor, *c);
+                self.cursor += 1;
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.chars.remove(self.cursor);
+            }
+            Key::Delete if self.cursor < self.chars.len() => {
+                self.chars.remove(self.cursor);
+            }
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.chars.len()),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.chars.len(),
+            Key::KillStart => {
+                self.chars.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::KillEnd => self.chars.truncate(self.cursor),
+            Key::KillWord => {
+                let mut start = self.cursor;
+                while start > 0 && self.chars[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                while start > 0 && !self.chars[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                self.chars.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Readline {
+    fn edit_line(&mut self) -> std::io::Result<Option<String>> {
+        let terminal = crate::ui::RawTerminal::new(/* alternate_screen= */ false)?;
+        let mut stdin = std::io::stdin().lock();
+        let mut stdout = std::io::stdout().lock();
+
+        let mut line = Line::default();
+        // the position in the history, and the line that was being edited before moving through
+        // the history
+        let mut history_pos = self.history.len();
+        let mut edited = String::new();
+        // the position of the terminal's cursor, relative to the start of the line
+        let mut shown_cursor = 0;
+
+        loop {
+            let mut buf = [0; 64];
+            let len = stdin.read(&mut buf)?;
+            if len == 0 {
+                return Ok(None);
+            }
+
+            let mut entered = false;
+            for key in parse_keys(&buf[..len]) {
+                match key {
+                    Key::Enter => {
+                        entered = true;
+                        break;
+                    }
+                    Key::Eof if line.chars.is_empty() => return Ok(None),
+                    Key::Interrupt => {
+                        writeln!(stdout)?;
+                        drop(terminal);
+                        unsafe { libc::raise(libc::SIGINT) };
+                        return Ok(None);
+                    }
+                    Key::Up if history_pos > 0 => {
+                        if history_pos == self.history.len() {
+                            edited = line.text();
+                        }
+                        history_pos -= 1;
+                        line.set(&self.history[history_pos]);
+                    }
+                    Key::Down if history_pos < self.history.len() => {
+                        history_pos += 1;
+                        match self.history.get(history_pos) {
+                            Some(x) => line.set(x),
+                            None => line.set(&edited),
+                        }
+                    }
+                    key => line.edit(&key),
+                }
+            }
+
+            // redraw the line from its start, and then move the cursor back into place
+            if shown_cursor > 0 {
+                write!(stdout, "\x1b[{shown_cursor}D")?;
+            }
+            write!(stdout, "{}\x1b[K", line.text())?;
+            let after = line.chars.len() - line.cursor;
+            if after > 0 {
+                write!(stdout, "\x1b[{after}D")?;
+            }
+            shown_cursor = line.cursor;
+
+            if entered {
+                writeln!(stdout)?;
+                return Ok(Some(line.text()));
+            }
+            stdout.flush()?;
+        }
+    }
+}
+
+impl Prompter for Readline {
+    fn read_line(&mut self, buf: &mut String) -> std::io::Result<usize> {
+        let Some(line) = self.edit_line()? else {
+            return Ok(0);
+        };
+
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+
+        buf.push_str(&line);
+        buf.push('\n');
+        Ok(line.len() + 1)
+    }
+}
+
+static PROMPTER: Mutex<Option<Box<dyn Prompter>>> = Mutex::new(None);
+
+/// Use the line editor if stdin and stdout are terminals that support it, unless `plain` is true.
+pub fn init(plain: bool) {
+    let dumb = std::env::var_os("TERM").is_none_or(|x| x == "dumb");
+    let terminal = std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+    if !plain && !dumb && terminal {
+        set(Box::new(Readline::default()));
+    }
+}
+
+pub fn set(prompter: Box<dyn Prompter>) {
+    *PROMPTER.lock().unwrap() = Some(prompter);
+}
+
+/// Read the answer to a prompt into `buf`, like [`BufRead::read_line`].
+pub fn read_line(buf: &mut String) -> std::io::Result<usize> {
+    match PROMPTER.lock().unwrap().as_mut() {
+        Some(prompter) => prompter.read_line(buf),
+        None => Plain.read_line(buf),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_echo() {
+        let mut echo = Echo::new(std::io::Cursor::new("y\n\nq"));
+        let mut buf = String::new();
+        assert_eq!(echo.read_line(&mut buf).unwrap(), 2);
+        assert_eq!(echo.read_line(&mut buf).unwrap(), 1);
+        assert_eq!(echo.read_line(&mut buf).unwrap(), 1);
+        assert_eq!(echo.read_line(&mut buf).unwrap(), 0);
+        assert_eq!(buf, "y\n\nq");
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        assert_eq!(
+            parse_keys(b"a\x1b[D\x7f\x1b[3~\x1bOH\r"),
+            [
+                Key::Char('a'),
+                Key::Left,
+                Key::Backspace,
+                Key::Delete,
+                Key::Home,
+                Key::Enter,
+            ],
+        );
+        assert_eq!(
+            parse_keys("é\x15".as_bytes()),
+            [Key::Char('é'), Key::KillStart]
+        );
+        assert_eq!(parse_keys(b"\x1b[1;5C"), [Key::Other]);
+    }
+
+    #[test]
+    fn test_line_edit() {
+        let mut line = Line::default();
+        for key in parse_keys(b"ys\x1b[Dx\x1b[C\x7fn") {
+            line.edit(&key);
+        }
+        assert_eq!(line.text(), "yxn");
+        assert_eq!(line.cursor, 3);
+
+        line.set("foo bar  baz");
+        line.edit(&Key::Left);
+        line.edit(&Key::Left);
+        line.edit(&Key::KillWord);
+        assert_eq!(line.text(), "foo bar  az");
+        line.edit(&Key::KillWord);
+        assert_eq!(line.text(), "foo az");
+        line.edit(&Key::KillEnd);
+        assert_eq!(line.text(), "foo ");
+        line.edit(&Key::Home);
+        line.edit(&Key::Delete);
+        assert_eq!(line.text(), "oo ");
+        line.edit(&Key::End);
+        line.edit(&Key::KillStart);
+        assert_eq!(line.text(), "");
+    }
+}
@@ -0,0 +1,845 @@
+//! Building the hunks of a file from its matching lines, and writing the new file with the
+//! hunks that were chosen.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use grep_regex::RegexMatcher;
+
+use crate::cli::{HunkMerge, NormalizationForm};
+use crate::engine::PatternMatcher;
+use crate::profile::{measure, Phase};
+use crate::search::window_columns;
+use crate::ui::{warning, PatchOption};
+use crate::util::{MatchFilter, Replacement};
+
+/// A hunk of the original file, and the hunk after the find & replace.
+pub struct Hunk<'a> {
+    /// The index of the hunk within the file.
+    pub(crate) idx: u64,
+    /// The number of hunks in the file, including hunks where nothing was replaced.
+    pub(crate) count: u64,
+    /// The (0-indexed) line of the file where the hunk starts.
+    pub(crate) start_line: u64,
+    pub(crate) original: &'a [u8],
+    pub(crate) replaced: &'a [u8],
+    /// The hunk with each of the other replacements that the user can choose, for
+    /// `--replace-one-of`.
+    pub(crate) alternatives: &'a [Vec<u8>],
+    /// Each match that the hunk replaces, where the range is within `original`. This is empty if
+    /// the replacement can't be split into its matches, such as with `--header`.
+    pub(crate) matches: &'a [crate::util::MatchReplacement],
+    /// The stable id of each of the matches.
+    pub(crate) match_ids: &'a [String],
+}
+
+impl Hunk<'_> {
+    /// The index of the hunk within the file, including hunks where nothing was replaced.
+    pub fn index(&self) -> u64 {
+        self.idx
+    }
+
+    /// The number of hunks in the file, including hunks where nothing was replaced.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The (0-indexed) line of the file where the hunk starts.
+    pub fn start_line(&self) -> u64 {
+        self.start_line
+    }
+
+    /// The lines of the original file.
+    pub fn original(&self) -> &[u8] {
+        self.original
+    }
+
+    /// The lines after the find & replace.
+    pub fn replaced(&self) -> &[u8] {
+        self.replaced
+    }
+}
+
+/// The lines of the file being replaced, which are read as they're needed. The lines that may
+/// still be shown in a hunk are kept, since the context lines of hunks can overlap.
+struct FileLines<R> {
+    src: BufReader<R>,
+    line_terminator: u8,
+    /// The lines that were read and kept, starting from the line `start`.
+    lines: std::collections::VecDeque<Vec<u8>>,
+    start: u64,
+    /// The lines before this line were written to the new file, or replaced by a hunk.
+    written: u64,
+}
+
+impl<R: Read> FileLines<R> {
+    fn new(src: R, line_terminator: u8) -> Self {
+        Self {
+            src: BufReader::new(src),
+            line_terminator,
+            lines: Default::default(),
+            start: 0,
+            written: 0,
+        }
+    }
+
+    /// The line after the last line that was read.
+    fn end(&self) -> u64 {
+        self.start + self.lines.len() as u64
+    }
+
+    /// Read the lines up to and including the line `line_num`, or until the end of the file.
+    fn read_to(&mut self, line_num: u64) -> std::io::Result<()> {
+        while self.end() <= line_num {
+            let mut line = Vec::new();
+            self.src.read_until(self.line_terminator, &mut line)?;
+            if line.is_empty() {
+                // EOF
+                break;
+            }
+            self.lines.push_back(line);
+        }
+        Ok(())
+    }
+
+    /// The original lines within `range`, which must not have been forgotten yet.
+    fn get(&mut self, range: &std::ops::RangeInclusive<u64>) -> std::io::Result<Vec<u8>> {
+        self.read_to(*range.end())?;
+        let end = (range.end().saturating_add(1).min(self.end()) - self.start) as usize;
+        let start = (range.start().saturating_sub(self.start) as usize).min(end);
+        let mut bytes = Vec::new();
+        for line in self.lines.range(start..end) {
+            bytes.extend_from_slice(line);
+        }
+        Ok(bytes)
+    }
+
+    /// Write the original lines that haven't been written yet up to (but not including) the line
+    /// `end` to `dest`, and forget the written lines before the line `keep`.
+    fn write_to<W: Write>(
+        &mut self,
+        mut dest: Option<&mut BufWriter<W>>,
+        end: u64,
+        keep: u64,
+    ) -> std::io::Result<()> {
+        while self.written < end {
+            self.read_to(self.written)?;
+            let Some(line) = self.lines.get((self.written - self.start) as usize) else {
+                // EOF
+                break;
+            };
+            if let Some(dest) = &mut dest {
+                measure(Phase::Write, || dest.write_all(line))?;
+            }
+            self.written += 1;
+
+            while self.start < self.written.min(keep) && !self.lines.is_empty() {
+                self.lines.pop_front();
+                self.start += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Perform the find & replace on `src`, writing the new file to `empty_dest`. For each hunk where
+/// something was replaced, `decide` is called to choose what should be written. If reading `src`
+/// or writing `empty_dest` fails, the new file is incomplete and must not replace the original.
+///
+/// `src` and `empty_dest` are usually files, but can be any reader and writer, such as when
+/// filtering stdin.
+pub(crate) fn replace_matches<R: Read, W: Write>(
+    options: &ReplaceOptions,
+    display_path: &Path,
+    src: R,
+    empty_dest: Option<W>,
+    line_nums: &mut [u64],
+    eol: Option<crate::git::Eol>,
+    mut decide: impl FnMut(&Hunk) -> PatchOption,
+) -> std::io::Result<ReplaceOutcome> {
+    let mut lines = FileLines::new(src, options.line_terminator);
+    let mut dest = empty_dest.map(BufWriter::new);
+
+    // group nearby lines into hunks
+    line_nums.sort();
+    let hunk_ranges = options.hunk_ranges(line_nums);
+    let hunk_count: u64 = hunk_ranges.len().try_into().unwrap();
+
+    // did we make any of our own changes to `dest`?
+    let mut made_change = false;
+
+    // do we want the program to continue after we return?
+    let mut cont = Continue::Yes;
+
+    // the lines of the new file that were changed by each applied hunk
+    let mut changed_lines = Vec::new();
+
+    // the number of lines added to (or removed from) the new file so far
+    let mut line_offset: i128 = 0;
+
+    // the number of hunks that weren't applied
+    let mut skipped_hunks = 0;
+
+    // the number of applied hunks that the user edited, and the number of matches replaced by the
+    // hunks that weren't edited
+    let mut edited_hunks = 0;
+    let mut replacements = 0;
+
+    let mut match_ids = crate::decisions::MatchIds::new(display_path);
+
+    for (hunk_idx, hunk_range) in hunk_ranges.into_iter().enumerate() {
+        let hunk_idx: u64 = hunk_idx.try_into().unwrap();
+        let (shown, changed) = (hunk_range.shown, hunk_range.changed);
+
+        // copy file lines to dest file until we get to the first line that the hunk can change
+        lines.write_to(dest.as_mut(), *changed.start(), *shown.start())?;
+
+        let current_hunk = lines.get(&shown)?;
+        let hunk_start_line = *shown.start();
+
+        // the context lines before and after the lines that the hunk can change, which are only
+        // shown
+        let before = match changed.start() > shown.start() {
+            true => lines.get(&(hunk_start_line..=changed.start() - 1))?,
+            false => Vec::new(),
+        };
+        let after = match changed.end() < shown.end() {
+            true => lines.get(&(changed.end() + 1..=*shown.end()))?,
+            false => Vec::new(),
+        };
+
+        // find & replace within the matched lines of this hunk, and keep each replaced match
+        let replace_hunk = |replacement: Replacement| {
+            let mut replaced_hunk = Vec::new();
+            let mut hunk_matches = Vec::new();
+            let is_changed = |line_num: &u64| {
+                changed.contains(line_num) && line_nums.binary_search(line_num).is_ok()
+            };
+            let mut lines = (hunk_start_line..)
+                .zip(current_hunk.split_inclusive(|x| *x == options.line_terminator))
+                .peekable();
+            // the position of the next line within the hunk
+            let mut offset = 0;
+            while let Some((line_num, line)) = lines.next() {
+                let line_start = offset;
+                offset += line.len();
+
+                if !is_changed(&line_num) {
+                    // a context line
+                    replaced_hunk.extend_from_slice(line);
+                    continue;
+                }
+                if let Some(header) = options.header {
+                    if line_num == line_nums[0] {
+                        match eol {
+                            Some(eol) => {
+                                replaced_hunk.extend(crate::util::convert_eol(header, eol))
+                            }
+                            None => replaced_hunk.extend_from_slice(header),
+                        }
+                    }
+                    // the lines of an outdated header are replaced
+                    if !grep_matcher::Matcher::is_match(options.matcher, line).unwrap() {
+                        replaced_hunk.extend_from_slice(line);
+                    }
+                    continue;
+                }
+                if let Some(eol) = options.convert_eol {
+                    replaced_hunk.extend(crate::util::convert_eol(line, eol));
+                    continue;
+                }
+
+                // with `--multiline`, a match may continue on the following matching lines, so
+                // they're replaced together
+                if options.multiline {
+                    while let Some((_, next)) = lines.next_if(|(x, _)| is_changed(x)) {
+                        offset += next.len();
+                    }
+                }
+                let line = &current_hunk[line_start..offset];
+                let mut template = None;
+                let replacement = replacement.for_line(&mut template, display_path, line_num);
+                let replaced_start = replaced_hunk.len();
+                let replaced_matches = measure(Phase::Diff, || {
+                    crate::util::replace_regex(
+                        options.matcher,
+                        replacement,
+                        &options.line_filter(display_path, line_num, line),
+                        line,
+                        &mut replaced_hunk,
+                    )
+                })
+                .unwrap();
+
+                if replaced_hunk[replaced_start..] == *line {
+                    continue;
+                }
+
+                for m in replaced_matches {
+                    let replacement = &replaced_hunk[m.replacement];
+                    if replacement != &line[m.range.clone()] {
+                        hunk_matches.push(crate::util::MatchReplacement {
+                            range: line_start + m.range.start..line_start + m.range.end,
+                            replacement: replacement.to_vec(),
+                        });
+                    }
+                }
+
+                if options.fix_whitespace {
+                    let trimmed = crate::util::trim_trailing_whitespace(
+                        &replaced_hunk[replaced_start..],
+                        options.line_terminator,
+                    );
+                    replaced_hunk.truncate(replaced_start);
+                    replaced_hunk.extend(trimmed);
+                }
+
+                // changed lines get the line endings that git would write for the file
+                if let (Some(eol), b'\n') = (options.fix_eol.or(eol), options.line_terminator) {
+                    let converted = crate::util::convert_eol(&replaced_hunk[replaced_start..], eol);
+                    replaced_hunk.truncate(replaced_start);
+                    replaced_hunk.extend(converted);
+                }
+            }
+            (replaced_hunk, hunk_matches)
+        };
+        let (replaced_hunk, hunk_matches) = replace_hunk(options.replacement);
+        let hunk_match_ids: Vec<_> = hunk_matches
+            .iter()
+            .map(|m| match_ids.next(&current_hunk[m.range.clone()], &m.replacement))
+            .collect();
+
+        // check if anything changed
+        if current_hunk == replaced_hunk {
+            // nothing changed, so the original lines are written later
+            continue;
+        }
+
+        // the hunk with each of the other `--replace-one-of` replacements
+        let alternatives: Vec<_> = options
+            .alternatives
+            .iter()
+            .map(|x| match options.replacement {
+                Replacement::Literal(_) => replace_hunk(Replacement::Literal(x.as_bytes())).0,
+                _ => replace_hunk(Replacement::Template(x.as_bytes())).0,
+            })
+            .collect();
+
+        let hunk = Hunk {
+            idx: hunk_idx,
+            count: hunk_count,
+            start_line: hunk_start_line,
+            original: &current_hunk,
+            replaced: &replaced_hunk,
+            alternatives: &alternatives,
+            matches: &hunk_matches,
+            match_ids: &hunk_match_ids,
+        };
+
+        // ask what to do
+        match decide(&hunk) {
+            PatchOption::WriteNew(x) => {
+                // only the lines that the hunk can change are written, since the context lines
+                // may belong to other hunks
+                let Some(new) = x
+                    .strip_prefix(before.as_slice())
+                    .and_then(|x| x.strip_suffix(after.as_slice()))
+                else {
+                    warning!(
+                        "the hunk at {}:{} was skipped since its context lines were edited, which \
+                        can't be applied when its context is shared with other hunks",
+                        display_path.display(),
+                        hunk_start_line + 1,
+                    );
+                    skipped_hunks += 1;
+                    continue;
+                };
+
+                // write the new hunk
+                if let Some(ref mut dest) = dest {
+                    measure(Phase::Write, || dest.write_all(new))?;
+                    made_change = true;
+
+                    let original = lines.get(&changed)?;
+                    let old_count = crate::util::count_lines(&original, options.line_terminator);
+                    let new_count = crate::util::count_lines(new, options.line_terminator);
+                    let new_start =
+                        u64::try_from(i128::from(*changed.start()) + line_offset).unwrap();
+                    changed_lines.push(new_start..new_start + new_count);
+                    line_offset += i128::from(new_count) - i128::from(old_count);
+                }
+                lines.written = lines.written.max(changed.end().saturating_add(1));
+
+                if x == replaced_hunk || alternatives.contains(&x) {
+                    replacements += hunk_matches.len() as u64;
+                } else if let Some(n) =
+                    crate::util::count_applied_matches(&current_hunk, &hunk_matches, &x)
+                {
+                    // only some of the matches were chosen
+                    replacements += n;
+                } else {
+                    edited_hunks += 1;
+                }
+            }
+            PatchOption::WriteOriginal => {
+                // the original lines are written later
+                skipped_hunks += 1;
+            }
+            PatchOption::Quit => {
+                cont = Continue::No;
+                break;
+            }
+        }
+    }
+
+    if !made_change {
+        return Ok(ReplaceOutcome {
+            cont,
+            write_file: WriteFile::No,
+            changed_lines,
+            skipped_hunks,
+            edited_hunks,
+            replacements,
+        });
+    }
+
+    // if we made changes, there must have been a destination file
+    let Some(mut dest) = dest else {
+        panic!("Changes were apparently written, but we have no dest file");
+    };
+
+    // write the lines that were read but not written yet
+    let end = lines.end();
+    lines.write_to(Some(&mut dest), end, end)?;
+
+    // TODO: we could possibly make this copy faster on specific Linux filesystems using
+    // `FICLONERANGE`
+
+    measure(Phase::Write, || {
+        // write out any internally buffered data in `src`
+        std::io::copy(&mut lines.src.buffer(), &mut dest)?;
+
+        // unwrap the `File`s (when given files) to hopefully take advantage of `copy_file_range`
+        // during `std::io::copy`
+        let mut src = lines.src.into_inner();
+        let mut dest = dest.into_inner().map_err(|e| e.into_error())?;
+
+        // write remainder of file
+        std::io::copy(&mut src, &mut dest)
+    })?;
+
+    Ok(ReplaceOutcome {
+        cont,
+        write_file: WriteFile::Yes,
+        changed_lines,
+        skipped_hunks,
+        edited_hunks,
+        replacements,
+    })
+}
+
+pub(crate) struct ReplaceOptions<'a> {
+    pub(crate) matcher: &'a PatternMatcher,
+    pub(crate) replacement: Replacement<'a>,
+    pub(crate) padding: u64,
+    /// When the hunks of nearby matching lines are combined.
+    pub(crate) hunk_merge: HunkMerge,
+    pub(crate) line_terminator: u8,
+    /// Only matches starting within these (1-indexed) columns are replaced.
+    pub(crate) columns: Option<std::ops::RangeInclusive<u64>>,
+    /// Only matches that aren't part of a longer identifier are replaced, for `--identifier`.
+    pub(crate) identifiers: Option<&'a crate::identifier::Identifiers>,
+    /// The normalization form of replacements whose match doesn't show one, for `--normalize`.
+    pub(crate) normalize: Option<NormalizationForm>,
+    /// Only the first or last match of each file is replaced. The matching lines must already be
+    /// restricted to the first or last line.
+    pub(crate) only: Option<OnlyMatch>,
+    /// The answer to take if a patch prompt isn't answered in time.
+    pub(crate) prompt_timeout: Option<crate::ui::PromptTimeout>,
+    /// Lines wider than this many columns are shortened when shown.
+    pub(crate) max_line_len: Option<usize>,
+    /// Unchanged lines more than this many lines away from a change are folded when shown.
+    pub(crate) fold_context: Option<usize>,
+    /// The shell command that hunks are piped through to be shown, for `--diff-viewer`.
+    pub(crate) diff_viewer: Option<&'a str>,
+    /// A match can span several adjacent matching lines, which are replaced together, for
+    /// `--multiline`.
+    pub(crate) multiline: bool,
+    /// The columns of each line that are within the `--byte-range` ranges. Lines without a window
+    /// have no matches that can be replaced.
+    pub(crate) windows: Option<LineWindows>,
+    /// The columns of the matches of each line that are within the `--max-count` and `--nth`
+    /// limits, for each file by its display path. Lines without a window have no matches that can
+    /// be replaced.
+    pub(crate) limited: Option<&'a BTreeMap<PathBuf, LineWindows>>,
+    /// Only lines between the begin and end marker lines are replaced, for `--between`.
+    pub(crate) between: Option<(&'a str, &'a str)>,
+    /// The matching lines that also match this are skipped, for `--exclude-lines`.
+    pub(crate) exclude_lines: Option<&'a RegexMatcher>,
+    /// Other replacement templates that the user can choose instead of `replacement` when
+    /// prompted, for `--replace-one-of`.
+    pub(crate) alternatives: &'a [String],
+    /// The name of each capture group of the pattern, to show the captures of a hunk when
+    /// prompted.
+    pub(crate) capture_names: &'a [Option<String>],
+    /// Show the file's size and modified time with each hunk, which isn't shown with `--hook`.
+    pub(crate) details: bool,
+    /// Show the most recent commit of each hunk's lines when prompting, for `--blame`.
+    pub(crate) blame: bool,
+    /// The header that each file should start with, for `--ensure-header`. The header replaces
+    /// the matching lines of a hunk, or is inserted before them if they don't match.
+    pub(crate) header: Option<&'a [u8]>,
+    /// Convert the line endings of the matching lines, for `--convert-eol`.
+    pub(crate) convert_eol: Option<crate::git::Eol>,
+    /// Remove trailing whitespace from the changed lines, for `--fix-whitespace`.
+    pub(crate) fix_whitespace: bool,
+    /// The line endings of the changed lines, overriding the file's "eol" attribute.
+    pub(crate) fix_eol: Option<crate::git::Eol>,
+}
+
+/// The columns of each (0-indexed) line that matches can start within.
+pub(crate) type LineWindows = BTreeMap<u64, std::ops::RangeInclusive<u64>>;
+
+impl<'a> ReplaceOptions<'a> {
+    /// Options that replace every match of `matcher` with `replacement`, with no context lines and
+    /// none of the optional behaviours.
+    pub(crate) fn new(matcher: &'a PatternMatcher, replacement: Replacement<'a>) -> Self {
+        Self {
+            matcher,
+            replacement,
+            padding: 0,
+            hunk_merge: HunkMerge::Exact,
+            line_terminator: b'\n',
+            columns: None,
+            identifiers: None,
+            normalize: None,
+            only: None,
+            prompt_timeout: None,
+            max_line_len: None,
+            fold_context: None,
+            diff_viewer: None,
+            multiline: false,
+            windows: None,
+            limited: None,
+            between: None,
+            exclude_lines: None,
+            alternatives: &[],
+            capture_names: &[],
+            details: false,
+            blame: false,
+            header: None,
+            convert_eol: None,
+            fix_whitespace: false,
+            fix_eol: None,
+        }
+    }
+
+    /// The hunks of the (sorted) matching lines `line_nums`.
+    pub(crate) fn hunk_ranges(&self, line_nums: &[u64]) -> Vec<crate::util::HunkRange> {
+        let hunks = crate::util::hunk_ranges(line_nums, self.padding, self.hunk_merge);
+        match self.multiline {
+            true => crate::util::join_adjacent_matches(hunks, line_nums),
+            false => hunks,
+        }
+    }
+
+    /// A copy of the options without a prompt timeout, which can be sent to other threads.
+    pub(crate) fn without_prompt_timeout(&self) -> ReplaceOptions<'a> {
+        ReplaceOptions {
+            matcher: self.matcher,
+            replacement: self.replacement,
+            padding: self.padding,
+            hunk_merge: self.hunk_merge,
+            line_terminator: self.line_terminator,
+            columns: self.columns.clone(),
+            identifiers: self.identifiers,
+            normalize: self.normalize,
+            only: self.only,
+            prompt_timeout: None,
+            max_line_len: self.max_line_len,
+            fold_context: self.fold_context,
+            diff_viewer: self.diff_viewer,
+            multiline: self.multiline,
+            windows: self.windows.clone(),
+            limited: self.limited,
+            between: self.between,
+            exclude_lines: self.exclude_lines,
+            alternatives: self.alternatives,
+            capture_names: self.capture_names,
+            details: self.details,
+            blame: self.blame,
+            header: self.header,
+            convert_eol: self.convert_eol,
+            fix_whitespace: self.fix_whitespace,
+            fix_eol: self.fix_eol,
+        }
+    }
+
+    /// The matches that should be replaced within any line of the file `path`, ignoring the
+    /// per-line restrictions.
+    pub(crate) fn path_filter(&self, path: &Path) -> MatchFilter {
+        MatchFilter {
+            columns: self.columns.clone(),
+            identifier: self.identifiers.map(|x| x.chars(path)),
+            normalize: self.normalize,
+            line_terminator: self.line_terminator,
+        }
+    }
+
+    /// The matches that should be replaced within a matching line of the file `path`, which is the
+    /// display path if there are `--max-count` or `--nth` limits.
+    pub(crate) fn line_filter(&self, path: &Path, line_num: u64, line: &[u8]) -> MatchFilter {
+        let mut filter = MatchFilter {
+            columns: window_columns(self.columns.as_ref(), self.windows.as_ref(), line_num),
+            ..self.path_filter(path)
+        };
+        if let Some(limited) = self.limited {
+            // column 0 is never matched
+            let window = limited
+                .get(path)
+                .and_then(|x| x.get(&line_num))
+                .cloned()
+                .unwrap_or(0..=0);
+            filter.columns = Some(match filter.columns {
+                Some(x) => *window.start().max(x.start())..=*window.end().min(x.end()),
+                None => window,
+            });
+        }
+        let Some(only) = self.only else {
+            return filter;
+        };
+
+        let starts = crate::util::match_starts(self.matcher, &filter, line);
+        let start = match only {
+            OnlyMatch::First => starts.first(),
+            OnlyMatch::Last => starts.last(),
+        };
+
+        // a range containing only the chosen match, or no matches (column 0) if there isn't one
+        let column = start.map_or(0, |x| *x as u64 + 1);
+        filter.columns = Some(column..=column);
+        filter
+    }
+}
+
+/// Which match of each file should be replaced.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum OnlyMatch {
+    First,
+    Last,
+}
+
+pub(crate) struct ReplaceOutcome {
+    pub(crate) cont: Continue,
+    pub(crate) write_file: WriteFile,
+    /// The (0-indexed) lines of the new file that were changed, for each applied hunk.
+    pub(crate) changed_lines: Vec<std::ops::Range<u64>>,
+    /// The number of hunks that weren't applied.
+    pub(crate) skipped_hunks: u64,
+    /// The number of applied hunks that were edited.
+    pub(crate) edited_hunks: u64,
+    /// The number of matches that the applied hunks replaced, not counting edited hunks.
+    pub(crate) replacements: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum WriteFile {
+    Yes,
+    No,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Continue {
+    Yes,
+    No,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A hunk that was shown, as (index, count, start line, original, replaced).
+    type ShownHunk = (u64, u64, u64, String, String);
+
+    /// Replace the matches on the lines `line_nums` of `src`, answering each hunk with `answer`.
+    /// Returns the hunks that were shown, the outcome, and the new file if it was written.
+    fn replace(
+        options: &ReplaceOptions,
+        src: &str,
+        line_nums: &[u64],
+        mut answer: impl FnMut(&Hunk) -> PatchOption,
+    ) -> (Vec<ShownHunk>, ReplaceOutcome, Option<String>) {
+        let mut hunks = Vec::new();
+        let mut dest = Vec::new();
+        let outcome = replace_matches(
+            options,
+            Path::new("file"),
+            src.as_bytes(),
+            Some(&mut dest),
+            &mut line_nums.to_vec(),
+            None,
+            |hunk| {
+                hunks.push((
+                    hunk.index(),
+                    hunk.count(),
+                    hunk.start_line(),
+                    String::from_utf8(hunk.original().to_vec()).unwrap(),
+                    String::from_utf8(hunk.replaced().to_vec()).unwrap(),
+                ));
+                answer(hunk)
+            },
+        )
+        .unwrap();
+        let dest = (outcome.write_file == WriteFile::Yes).then(|| String::from_utf8(dest).unwrap());
+        (hunks, outcome, dest)
+    }
+
+    fn accept(hunk: &Hunk) -> PatchOption {
+        PatchOption::WriteNew(hunk.replaced().to_vec())
+    }
+
+    fn accept_with(new: &str) -> PatchOption {
+        PatchOption::WriteNew(new.as_bytes().to_vec())
+    }
+
+    fn hunk(idx: u64, count: u64, start_line: u64, original: &str, replaced: &str) -> ShownHunk {
+        (idx, count, start_line, original.into(), replaced.into())
+    }
+
+    #[test]
+    fn test_replace_matches() {
+        let matcher = PatternMatcher::new("foo").unwrap();
+        let options = ReplaceOptions {
+            padding: 1,
+            ..ReplaceOptions::new(&matcher, Replacement::Template(b"bar"))
+        };
+        let src = "foo\na\nb\nc\nd\nfoo\nfoo x foo\ne\nf\n";
+
+        // the line numbers don't need to be sorted
+        let (hunks, outcome, dest) = replace(&options, src, &[6, 0, 5], accept);
+        assert_eq!(
+            hunks,
+            [
+                hunk(0, 2, 0, "foo\na\n", "bar\na\n"),
+                hunk(1, 2, 4, "d\nfoo\nfoo x foo\ne\n", "d\nbar\nbar x bar\ne\n"),
+            ],
+        );
+        assert_eq!(dest.unwrap(), "bar\na\nb\nc\nd\nbar\nbar x bar\ne\nf\n");
+        assert_eq!(outcome.cont, Continue::Yes);
+        assert_eq!(outcome.changed_lines, [0..2, 4..8]);
+        assert_eq!(outcome.skipped_hunks, 0);
+        assert_eq!(outcome.replacements, 4);
+
+        // a matching line where nothing is replaced isn't shown, but its hunk is still counted
+        let (hunks, _, dest) = replace(&options, src, &[1, 5], accept);
+        assert_eq!(
+            hunks,
+            [hunk(1, 2, 4, "d\nfoo\nfoo x foo\n", "d\nbar\nfoo x foo\n")]
+        );
+        assert_eq!(dest.unwrap(), "foo\na\nb\nc\nd\nbar\nfoo x foo\ne\nf\n");
+
+        // no matching lines
+        let (hunks, outcome, dest) = replace(&options, src, &[], accept);
+        assert!(hunks.is_empty());
+        assert_eq!(outcome.write_file, WriteFile::No);
+        assert_eq!(dest, None);
+    }
+
+    #[test]
+    fn test_replace_matches_merge() {
+        let matcher = PatternMatcher::new("foo").unwrap();
+        let src = "foo\na\nfoo\nb\nc\nfoo\nd\n";
+        let options = |hunk_merge| ReplaceOptions {
+            padding: 1,
+            hunk_merge,
+            ..ReplaceOptions::new(&matcher, Replacement::Template(b"bar"))
+        };
+        let new = "bar\na\nbar\nb\nc\nbar\nd\n";
+
+        // the context lines of the first two matches overlap, and the context lines of the last
+        // two matches are next to each other
+        let (hunks, _, dest) = replace(&options(HunkMerge::Exact), src, &[0, 2, 5], accept);
+        assert_eq!(
+            hunks,
+            [
+                hunk(0, 2, 0, "foo\na\nfoo\nb\n", "bar\na\nbar\nb\n"),
+                hunk(1, 2, 4, "c\nfoo\nd\n", "c\nbar\nd\n"),
+            ],
+        );
+        assert_eq!(dest.unwrap(), new);
+
+        let (hunks, _, dest) = replace(&options(HunkMerge::Aggressive), src, &[0, 2, 5], accept);
+        assert_eq!(hunks, [hunk(0, 1, 0, "foo\na\nfoo\nb\nc\nfoo\nd\n", new)],);
+        assert_eq!(dest.unwrap(), new);
+
+        // the hunks share their context lines, which are only written once
+        let (hunks, _, dest) = replace(&options(HunkMerge::Never), src, &[0, 2, 5], accept);
+        assert_eq!(
+            hunks,
+            [
+                hunk(0, 3, 0, "foo\na\n", "bar\na\n"),
+                hunk(1, 3, 1, "a\nfoo\nb\n", "a\nbar\nb\n"),
+                hunk(2, 3, 4, "c\nfoo\nd\n", "c\nbar\nd\n"),
+            ],
+        );
+        assert_eq!(dest.unwrap(), new);
+    }
+
+    #[test]
+    fn test_replace_matches_answers() {
+        let matcher = PatternMatcher::new("foo").unwrap();
+        let options = ReplaceOptions::new(&matcher, Replacement::Template(b"bar"));
+        let src = "foo\na\nfoo\nb\nfoo\n";
+
+        // only the accepted hunks are written
+        let mut answers = [
+            PatchOption::WriteOriginal,
+            accept_with("baz\n"),
+            accept_with("bar\n"),
+        ]
+        .into_iter();
+        let (hunks, outcome, dest) =
+            replace(&options, src, &[0, 2, 4], |_| answers.next().unwrap());
+        assert_eq!(hunks.len(), 3);
+        assert_eq!(dest.unwrap(), "foo\na\nbaz\nb\nbar\n");
+        assert_eq!(outcome.skipped_hunks, 1);
+        assert_eq!(outcome.edited_hunks, 1);
+        assert_eq!(outcome.replacements, 1);
+
+        // nothing is written if no hunks are accepted
+        let (hunks, outcome, dest) =
+            replace(&options, src, &[0, 2, 4], |_| PatchOption::WriteOriginal);
+        assert_eq!(hunks.len(), 3);
+        assert_eq!(outcome.write_file, WriteFile::No);
+        assert_eq!(outcome.skipped_hunks, 3);
+        assert_eq!(dest, None);
+
+        // the remaining hunks aren't shown after quitting, but the accepted hunks are still
+        // written
+        let mut answers = [accept_with("bar\n"), PatchOption::Quit].into_iter();
+        let (hunks, outcome, dest) =
+            replace(&options, src, &[0, 2, 4], |_| answers.next().unwrap());
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(outcome.cont, Continue::No);
+        assert_eq!(dest.unwrap(), "bar\na\nfoo\nb\nfoo\n");
+    }
+
+    #[test]
+    fn test_replace_matches_edited_context() {
+        let matcher = PatternMatcher::new("foo").unwrap();
+        let options = ReplaceOptions {
+            padding: 1,
+            hunk_merge: HunkMerge::Never,
+            ..ReplaceOptions::new(&matcher, Replacement::Template(b"bar"))
+        };
+        let src = "foo\na\nfoo\n";
+
+        // the context line "a" is shared by both hunks, so a hunk where it was edited is skipped
+        let mut answers = [accept_with("bar\nA\n"), accept_with("a\nbar\n")].into_iter();
+        let (hunks, outcome, dest) = replace(&options, src, &[0, 2], |_| answers.next().unwrap());
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(outcome.skipped_hunks, 1);
+        assert_eq!(dest.unwrap(), "foo\na\nbar\n");
+    }
+}
@@ -0,0 +1,148 @@
+//! A report of the changes for `--report`, written as Markdown or HTML so that it can be attached
+//! to a ticket or a document describing a large change. Each file has a collapsible section with
+//! its hunks, which are shown with all of their context lines.
+
+use std::path::Path;
+
+use anyhow::Context;
+use bstr::ByteSlice;
+
+use crate::preview::{PreviewFile, PreviewHunk};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Markdown,
+    Html,
+}
+
+impl ReportFormat {
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "md" | "markdown" => Some(Self::Markdown),
+            "html" => Some(Self::Html),
+            _ => None,
+        }
+    }
+}
+
+/// The changed hunks of each file.
+#[derive(Default)]
+pub struct Report {
+    files: Vec<PreviewFile>,
+}
+
+impl Report {
+    /// Add the changed hunks of a file. Files without any hunks aren't included.
+    pub fn add(&mut self, path: &Path, hunks: Vec<PreviewHunk>) {
+        if !hunks.is_empty() {
+            self.files.push(PreviewFile {
+                path: path.to_path_buf(),
+                hunks,
+            });
+        }
+    }
+
+    /// Write the report to `path`, where `applied` is whether the changes were applied rather than
+    /// only shown.
+    pub fn write(&self, path: &Path, format: ReportFormat, applied: bool) -> anyhow::Result<()> {
+        let hunks: usize = self.files.iter().map(|x| x.hunks.len()).sum();
+        let summary = format!(
+            "{hunks} hunk{} in {} file{} {}.",
+            if hunks == 1 { "" } else { "s" },
+            self.files.len(),
+            if self.files.len() == 1 { "" } else { "s" },
+            match (applied, hunks == 1) {
+                (true, true) => "was changed",
+                (true, false) => "were changed",
+                (false, _) => "would be changed",
+            },
+        );
+
+        let report = match format {
+            ReportFormat::Markdown => markdown(&summary, &self.files),
+            ReportFormat::Html => {
+                crate::preview::html("re:patch report", &summary, &self.files, true)
+            }
+        };
+        std::fs::write(path, report)
+            .with_context(|| format!("could not write the report '{}'", path.display()))
+    }
+}
+
+/// The report as Markdown, with each file in a `<details>` block, which GitHub and most other
+/// renderers show as a collapsible section.
+fn markdown(summary: &str, files: &[PreviewFile]) -> String {
+    let mut out = format!("# re:patch report\n\n{summary}\n");
+
+    for file in files {
+        let count = file.hunks.len();
+        let mut diff = String::new();
+        for hunk in &file.hunks {
+            hunk_diff(hunk, &mut diff);
+        }
+
+        // the fence must be longer than any run of backticks in the diff
+        let longest = diff.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+        let fence = "`".repeat(longest.max(2) + 1);
+
+        out.push_str(&format!(
+            "\n<details open>\n<summary><code>{}</code> ({count} hunk{})</summary>\n\n\
+            {fence}diff\n{diff}{fence}\n\n</details>\n",
+            crate::preview::escape(&file.path.to_string_lossy()),
+            if count == 1 { "" } else { "s" },
+        ));
+    }
+
+    out
+}
+
+/// Append the hunk's diff in the unified format, with the line numbers of the file.
+fn hunk_diff(hunk: &PreviewHunk, out: &mut String) {
+    let patch = crate::preview::hunk_patch(hunk);
+
+    for diff_hunk in patch.hunks() {
+        let (old, new) = (diff_hunk.old_range(), diff_hunk.new_range());
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            hunk.start_line + old.start() as u64,
+            old.len(),
+            hunk.start_line + new.start() as u64,
+            new.len(),
+        ));
+
+        for line in diff_hunk.lines() {
+            let (marker, text) = match line {
+                diffy::Line::Context(x) => (' ', x),
+                diffy::Line::Delete(x) => ('-', x),
+                diffy::Line::Insert(x) => ('+', x),
+            };
+            let text = text.to_str_lossy();
+            out.push(marker);
+            out.push_str(text.trim_end_matches(['\n', '\r']));
+            out.push('\n');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markdown() {
+        let files = [PreviewFile {
+            path: "src/a<b>.rs".into(),
+            hunks: vec![PreviewHunk {
+                start_line: 9,
+                original: b"x\nfoo\n```\n".to_vec(),
+                replaced: b"x\nbar\n```\n".to_vec(),
+            }],
+        }];
+        assert_eq!(
+            markdown("1 hunk in 1 file was changed.", &files),
+            "# re:patch report\n\n1 hunk in 1 file was changed.\n\n\
+            <details open>\n<summary><code>src/a&lt;b&gt;.rs</code> (1 hunk)</summary>\n\n\
+            ````diff\n@@ -10,3 +10,3 @@\n x\n-foo\n+bar\n ```\n````\n\n</details>\n",
+        );
+    }
+}
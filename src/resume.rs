@@ -0,0 +1,238 @@
+//! The progress of an interactive run, so that if the run crashes or is killed, running the same
+//! command again can resume from the first file that wasn't finished instead of starting over.
+//!
+//! The progress is saved in "$XDG_STATE_HOME/repatch/resume/<KEY>", where the key is a hash of the
+//! current directory and the arguments, or in the file given with `--session`. Each line is
+//! appended as soon as it's known, so the file is complete up to the moment that the run stopped.
+//! The first line is "args <KEY>". A "hunk <HASH> <yes|no> <PATH>" line is an answered hunk, and a
+//! "done <HASH> <PATH>" line is a file that was finished, with the hash of its contents at the
+//! time. The paths are absolute. The state directory's file is removed when the run ends normally,
+//! but a session file is kept.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::decisions::{Decision, Decisions};
+
+/// The key of a run, which is the same for runs of the same command in the same directory.
+pub fn key(args: &impl std::fmt::Debug) -> anyhow::Result<u64> {
+    let cwd = std::env::current_dir()?;
+    let mut bytes = cwd.as_os_str().as_bytes().to_vec();
+    bytes.push(0);
+    bytes.extend_from_slice(format!("{args:?}").as_bytes());
+    Ok(crate::util::fnv1a(&bytes))
+}
+
+/// The file in the state directory that the progress of the run with the key `key` is saved in.
+pub fn state_path(key: u64) -> anyhow::Result<PathBuf> {
+    let dir = crate::history::state_dir()
+        .ok_or_else(|| anyhow::anyhow!("could not find the state directory"))?;
+    Ok(dir.join("resume").join(format!("{key:016x}")))
+}
+
+/// The hash of the contents of the file at `path`.
+fn content_hash(path: &Path) -> std::io::Result<u64> {
+    Ok(crate::util::fnv1a(&std::fs::read(path)?))
+}
+
+/// The progress of an earlier run that was interrupted.
+#[derive(Debug, Default)]
+pub struct Saved {
+    /// The key of the run's arguments.
+    pub key: Option<u64>,
+    /// The files that were finished, and the hash of each file's contents when it was finished.
+    pub done: BTreeMap<PathBuf, u64>,
+    /// The hunks that were answered, including those of the file that wasn't finished.
+    pub decisions: Decisions,
+}
+
+impl Saved {
+    /// Whether the file at the absolute path `path` was finished and hasn't changed since.
+    pub fn is_done(&self, path: &Path) -> bool {
+        self.done
+            .get(path)
+            .is_some_and(|hash| content_hash(path).is_ok_and(|x| x == *hash))
+    }
+}
+
+/// Read the progress of an earlier run from `path`. Returns `None` if there wasn't an earlier run,
+/// or if it didn't get as far as answering anything.
+pub fn load(path: &Path) -> anyhow::Result<Option<Saved>> {
+    let contents = match std::fs::read(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("could not read '{}'", path.display())),
+    };
+
+    let saved = parse(&contents);
+    if saved.done.is_empty() && saved.decisions.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(saved))
+}
+
+fn parse(s: &[u8]) -> Saved {
+    let mut saved = Saved::default();
+
+    let path = |x: &[u8]| PathBuf::from(std::ffi::OsString::from_vec(x.to_vec()));
+    let hash = |x: &[u8]| {
+        std::str::from_utf8(x)
+            .ok()
+            .and_then(|x| u64::from_str_radix(x, 16).ok())
+    };
+
+    // a line that was only partly written when the run stopped is ignored
+    for line in s.split(|x| *x == b'\n') {
+        if let Some(key) = line.strip_prefix(b"args ") {
+            saved.key = hash(key);
+            continue;
+        }
+        if let Some(line) = line.strip_prefix(b"done ") {
+            let mut fields = line.splitn(2, |x| *x == b' ');
+            if let (Some(Some(hash)), Some(file)) = (fields.next().map(hash), fields.next()) {
+                saved.done.insert(path(file), hash);
+            }
+            continue;
+        }
+        let Some(line) = line.strip_prefix(b"hunk ") else {
+            continue;
+        };
+
+        let mut fields = line.splitn(3, |x| *x == b' ');
+        let (Some(Some(hash)), Some(decision), Some(file)) =
+            (fields.next().map(hash), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let decision = match decision {
+            b"yes" => Decision::Yes,
+            b"no" => Decision::No,
+            _ => continue,
+        };
+        saved.decisions.insert(&path(file), hash, decision);
+    }
+
+    saved
+}
+
+/// Records the progress of a run as it happens. Recording is best-effort, and a run never fails
+/// because its progress couldn't be saved.
+pub struct Recorder {
+    file: File,
+    path: PathBuf,
+}
+
+impl Recorder {
+    /// Start recording the run with the key `key` to `path`, keeping the progress of the earlier
+    /// run that it resumes, if any.
+    pub fn start(path: &Path, key: u64, resumed: Option<&Saved>) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("could not create '{}'", parent.display()))?;
+        }
+        let file =
+            File::create(path).with_context(|| format!("could not write '{}'", path.display()))?;
+
+        let mut recorder = Self {
+            file,
+            path: path.to_path_buf(),
+        };
+        recorder.append(format!("args {key:016x}").into_bytes());
+        if let Some(saved) = resumed {
+            for (file, hash, decision) in saved.decisions.iter() {
+                recorder.hunk(file, hash, decision);
+            }
+            for (file, hash) in &saved.done {
+                recorder.append_done(file, *hash);
+            }
+        }
+
+        Ok(recorder)
+    }
+
+    /// Record the answer to a hunk of the file at the absolute path `path`.
+    pub fn hunk(&mut self, path: &Path, hash: u64, decision: Decision) {
+        let mut line = format!("hunk {hash:016x} {} ", decision.as_str()).into_bytes();
+        line.extend_from_slice(path.as_os_str().as_bytes());
+        self.append(line);
+    }
+
+    /// Record that the file at the absolute path `path` was finished.
+    pub fn done(&mut self, path: &Path) {
+        // a file that can't be read is processed again when resuming
+        if let Ok(hash) = content_hash(path) {
+            self.append_done(path, hash);
+        }
+    }
+
+    fn append_done(&mut self, path: &Path, hash: u64) {
+        let mut line = format!("done {hash:016x} ").into_bytes();
+        line.extend_from_slice(path.as_os_str().as_bytes());
+        self.append(line);
+    }
+
+    fn append(&mut self, mut line: Vec<u8>) {
+        // the file can't contain paths with newlines, so these files are started over
+        if line.contains(&b'\n') {
+            return;
+        }
+        line.push(b'\n');
+        // a single write, so that a line is never interleaved with another
+        let _ = self.file.write_all(&line);
+    }
+
+    /// The run ended normally, so there's nothing to resume.
+    pub fn finish(self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let saved = parse(
+            b"args 000000000000000a\nhunk 00000000000000ff yes /a/b c\nhunk 0000000000000001 no /d\n\
+            done 0000000000000003 /a/b c\nhunk 0000000000000002 ye",
+        );
+        assert_eq!(saved.key, Some(10));
+        assert_eq!(saved.done, BTreeMap::from([(PathBuf::from("/a/b c"), 3)]));
+        assert_eq!(
+            saved.decisions.get(Path::new("/a/b c"), 0xff),
+            Some(Decision::Yes)
+        );
+        assert_eq!(saved.decisions.get(Path::new("/d"), 1), Some(Decision::No));
+        assert!(saved.decisions.get(Path::new("/d"), 2).is_none());
+
+        let saved = parse(b"");
+        assert!(saved.key.is_none() && saved.done.is_empty() && saved.decisions.is_empty());
+    }
+
+    #[test]
+    fn test_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = dir.path().join("session");
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, "foo\n").unwrap();
+
+        let mut recorder = Recorder::start(&session, 7, None).unwrap();
+        recorder.hunk(&file, 1, Decision::Yes);
+        recorder.done(&file);
+
+        let saved = load(&session).unwrap().unwrap();
+        assert_eq!(saved.key, Some(7));
+        assert_eq!(saved.decisions.get(&file, 1), Some(Decision::Yes));
+        assert!(saved.is_done(&file));
+
+        // a finished file that changed afterwards isn't finished anymore
+        std::fs::write(&file, "bar\n").unwrap();
+        assert!(!saved.is_done(&file));
+    }
+}
@@ -0,0 +1,78 @@
+//! The files that couldn't be searched in the last run, so that they can be searched again with
+//! `--retry-failed`. This includes files that couldn't be read and files that were skipped by
+//! `--file-timeout`.
+//!
+//! The list is saved in "$XDG_STATE_HOME/repatch/failed", with one absolute path per line.
+
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+fn path() -> anyhow::Result<PathBuf> {
+    let dir = crate::history::state_dir()
+        .ok_or_else(|| anyhow::anyhow!("could not find the state directory"))?;
+    Ok(dir.join("failed"))
+}
+
+/// Save the files that failed, replacing the list of an earlier run.
+pub fn save(failed: &[PathBuf]) -> anyhow::Result<()> {
+    let path = path()?;
+
+    if failed.is_empty() {
+        return match std::fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("could not remove '{}'", path.display())),
+        };
+    }
+
+    let parent = path.parent().unwrap();
+    std::fs::create_dir_all(parent)
+        .with_context(|| format!("could not create '{}'", parent.display()))?;
+
+    let mut list = Vec::new();
+    for failed in failed {
+        // the file might not exist anymore, so it may not be possible to canonicalize the path
+        let failed = std::path::absolute(failed).unwrap_or_else(|_| failed.clone());
+        // the list can't contain paths with newlines
+        if failed.as_os_str().as_bytes().contains(&b'\n') {
+            continue;
+        }
+        list.extend_from_slice(failed.as_os_str().as_bytes());
+        list.push(b'\n');
+    }
+
+    std::fs::write(&path, list).with_context(|| format!("could not write '{}'", path.display()))
+}
+
+/// The files that failed in the last run. The list is empty if no files failed.
+pub fn load() -> anyhow::Result<Vec<PathBuf>> {
+    let path = path()?;
+    match std::fs::read(&path) {
+        Ok(x) => Ok(parse(&x)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("could not read '{}'", path.display())),
+    }
+}
+
+fn parse(s: &[u8]) -> Vec<PathBuf> {
+    s.split(|x| *x == b'\n')
+        .filter(|x| !x.is_empty())
+        .map(|x| PathBuf::from(std::ffi::OsString::from_vec(x.to_vec())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(b"/a/b.rs\n\n/c d.txt\n"),
+            [PathBuf::from("/a/b.rs"), PathBuf::from("/c d.txt")],
+        );
+        assert!(parse(b"").is_empty());
+    }
+}
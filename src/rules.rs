@@ -0,0 +1,292 @@
+//! Rules files, which have many find and replace pairs that are applied in one run with
+//! `--rules`. A rules file uses the same subset of TOML as the config file, with a `[rule.NAME]`
+//! table for each rule:
+//!
+//! ```toml
+//! [rule.rename-reader]
+//! find = '\bFileReader\b'
+//! replace = 'Reader'
+//!
+//! [rule.rename-open]
+//! find = 'open_file\((\w+)\)'
+//! replace = 'open($1)'
+//! ```
+//!
+//! The lines that any rule matches are searched together, so the changes of all rules to the same
+//! lines are shown in one hunk. Rules are applied in the order of the file, and each rule matches
+//! the text as it was changed by the rules before it, so a rule can match the replacement of an
+//! earlier rule.
+
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::Context;
+use grep_matcher::{Captures, Matcher};
+use grep_regex::{RegexMatcher, RegexMatcherBuilder};
+
+use crate::config::Value;
+use crate::util::ReplacedMatch;
+
+pub struct Rule {
+    pub name: String,
+    pub find: String,
+    pub replace: String,
+    matcher: RegexMatcher,
+}
+
+/// Read the rules file at `path`, and compile each rule's pattern with the options of `builder`.
+pub fn load(path: &Path, builder: &RegexMatcherBuilder) -> anyhow::Result<Vec<Rule>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read the rules file '{}'", path.display()))?;
+    let rules = parse(&contents, builder)
+        .with_context(|| format!("invalid rules file '{}'", path.display()))?;
+
+    if rules.is_empty() {
+        anyhow::bail!("the rules file '{}' has no rules", path.display());
+    }
+
+    Ok(rules)
+}
+
+fn parse(s: &str, builder: &RegexMatcherBuilder) -> anyhow::Result<Vec<Rule>> {
+    let mut rules = Vec::new();
+
+    for (table, entries) in crate::config::parse_tables(s)? {
+        let name = match table.split_once('.') {
+            Some(("rule", name)) if !name.is_empty() => name,
+            // the top-level table
+            None if table.is_empty() && entries.is_empty() => continue,
+            None if table.is_empty() => anyhow::bail!("unknown key '{}'", entries[0].0),
+            _ => anyhow::bail!("unknown table [{table}]"),
+        };
+
+        let (mut find, mut replace) = (None, None);
+        for (key, value) in entries {
+            match (key.as_str(), value) {
+                ("find", Value::String(x)) => find = Some(x),
+                ("replace", Value::String(x)) => replace = Some(x),
+                (key, _) => anyhow::bail!("[{table}]: invalid key '{key}'"),
+            }
+        }
+        let (Some(find), Some(replace)) = (find, replace) else {
+            anyhow::bail!("[{table}]: a rule must have a 'find' and a 'replace' key");
+        };
+
+        let matcher = builder
+            .build(&find)
+            .with_context(|| format!("[{table}]: invalid pattern"))?;
+
+        rules.push(Rule {
+            name: name.to_string(),
+            find,
+            replace,
+            matcher,
+        });
+    }
+
+    Ok(rules)
+}
+
+/// Apply each rule in order to `haystack` and append the result to `dest`, where each rule
+/// replaces its matches that `keep` keeps in the text as changed by the rules before it. Returns
+/// the changed parts of the haystack, where parts that were changed by several rules (or that
+/// touch) are one match, and the range of each replacement is relative to the start of `dest`.
+pub fn replace(
+    rules: &[Rule],
+    haystack: &[u8],
+    keep: impl Fn(&[u8], grep_matcher::Match) -> bool,
+    dest: &mut Vec<u8>,
+) -> Vec<ReplacedMatch> {
+    let mut text = haystack.to_vec();
+    // the range of each changed part in the haystack and in `text`
+    let mut changes: Vec<(Range<usize>, Range<usize>)> = Vec::new();
+
+    for rule in rules {
+        let mut matches = Vec::new();
+        let mut caps = rule.matcher.new_captures().unwrap();
+        rule.matcher
+            .captures_iter(&text, &mut caps, |caps| {
+                let m = caps.get(0).unwrap();
+                if keep(&text, m) {
+                    let mut replacement = Vec::new();
+                    caps.interpolate(
+                        |name| rule.matcher.capture_index(name),
+                        &text,
+                        rule.replace.as_bytes(),
+                        &mut replacement,
+                    );
+                    matches.push((m, replacement));
+                }
+                true
+            })
+            .unwrap();
+        if matches.is_empty() {
+            continue;
+        }
+
+        let mut replaced = Vec::with_capacity(text.len());
+        let mut pos = 0;
+        for (m, replacement) in &matches {
+            replaced.extend_from_slice(&text[pos..m.start()]);
+            replaced.extend_from_slice(replacement);
+            pos = m.end();
+        }
+        replaced.extend_from_slice(&text[pos..]);
+
+        // the range in `text` of each earlier change and match, and the length of its text in the
+        // haystack or its replacement
+        let changed: Vec<_> = changes.iter().map(|(x, y)| (y.clone(), x.len())).collect();
+        let matched: Vec<_> = matches
+            .iter()
+            .map(|(m, x)| (m.start()..m.end(), x.len()))
+            .collect();
+
+        // the position that a position in `text` (that isn't within a change or match) was at in
+        // the haystack or is at in the replaced text, where `after` is whether it's after the
+        // changes or matches that are empty at that position
+        fn moved(p: usize, after: bool, ranges: &[(Range<usize>, usize)]) -> usize {
+            ranges
+                .iter()
+                .filter(|(x, _)| x.end < p || (x.end == p && (after || x.start < p)))
+                .fold(p as isize, |p, (x, len)| {
+                    p + *len as isize - x.len() as isize
+                }) as usize
+        }
+
+        // the earlier changes and the matches that overlap or touch become one change
+        let mut ranges: Vec<_> = changed
+            .iter()
+            .chain(&matched)
+            .map(|(x, _)| x.clone())
+            .collect();
+        ranges.sort_by_key(|x| (x.start, x.end));
+
+        let mut replaced_changes = Vec::new();
+        let mut ranges = ranges.into_iter().peekable();
+        while let Some(first) = ranges.next() {
+            let mut end = first.end;
+            while let Some(next) = ranges.next_if(|x| x.start <= end) {
+                end = end.max(next.end);
+            }
+            replaced_changes.push((
+                moved(first.start, false, &changed)..moved(end, true, &changed),
+                moved(first.start, false, &matched)..moved(end, true, &matched),
+            ));
+        }
+
+        text = replaced;
+        changes = replaced_changes;
+    }
+
+    let offset = dest.len();
+    dest.extend_from_slice(&text);
+    changes
+        .into_iter()
+        .map(|(orig, cur)| ReplacedMatch {
+            range: orig,
+            replacement: cur.start + offset..cur.end + offset,
+        })
+        .collect()
+}
+
+/// A pattern that matches wherever any of the rules match. The capture group names of the rules
+/// are removed, since several rules may use the same name.
+pub fn pattern(rules: &[Rule]) -> anyhow::Result<String> {
+    fn unname(ast: &mut regex_syntax::ast::Ast) {
+        use regex_syntax::ast::{Ast, GroupKind};
+        match ast {
+            Ast::Group(group) => {
+                if let GroupKind::CaptureName { name, .. } = &group.kind {
+                    group.kind = GroupKind::CaptureIndex(name.index);
+                }
+                unname(&mut group.ast);
+            }
+            Ast::Repetition(repetition) => unname(&mut repetition.ast),
+            Ast::Alternation(alternation) => alternation.asts.iter_mut().for_each(unname),
+            Ast::Concat(concat) => concat.asts.iter_mut().for_each(unname),
+            Ast::Empty(_)
+            | Ast::Flags(_)
+            | Ast::Literal(_)
+            | Ast::Dot(_)
+            | Ast::Assertion(_)
+            | Ast::ClassUnicode(_)
+            | Ast::ClassPerl(_)
+            | Ast::ClassBracketed(_) => {}
+        }
+    }
+
+    let mut alternatives = Vec::new();
+    for rule in rules {
+        let mut ast = regex_syntax::ast::parse::Parser::new()
+            .parse(&rule.find)
+            .with_context(|| format!("[rule.{}]: invalid pattern", rule.name))?;
+        unname(&mut ast);
+
+        let mut pattern = String::new();
+        regex_syntax::ast::print::Printer::new().print(&ast, &mut pattern)?;
+        // flags within a group only apply to the group
+        alternatives.push(format!("(?:{pattern})"));
+    }
+
+    Ok(alternatives.join("|"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules() {
+        let s = "[rule.a]\nfind = '(?i)(?P<x>foo)'\nreplace = '${x}d'\n\n\
+                 [rule.b]\nfind = '(?P<x>b)(a+)r'\nreplace = 'c$2'\n\n\
+                 [rule.c]\nfind = 'f'\nreplace = 'g'\n";
+        let rules = parse(s, &RegexMatcherBuilder::new()).unwrap();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[1].name, "b");
+
+        let pattern = pattern(&rules).unwrap();
+        assert_eq!(pattern, "(?:(?i)(foo))|(?:(b)(a+)r)|(?:f)");
+
+        let mut replaced = b">".to_vec();
+        let matches = replace(&rules, b"FOO baar fo", |_, _| true, &mut replaced);
+        assert_eq!(replaced, b">FOOd caa go");
+        let ranges: Vec<_> = matches
+            .iter()
+            .map(|x| (x.range.clone(), x.replacement.clone()))
+            .collect();
+        assert_eq!(ranges, [(0..3, 1..5), (4..8, 6..9), (9..10, 10..11)]);
+
+        assert!(parse("[rule.a]\nfind = 'x'\n", &RegexMatcherBuilder::new()).is_err());
+        assert!(parse("[other]\nfind = 'x'\n", &RegexMatcherBuilder::new()).is_err());
+        assert!(parse(
+            "[rule.a]\nfind = '('\nreplace = ''\n",
+            &RegexMatcherBuilder::new()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_replace_in_sequence() {
+        let s = "[rule.a]\nfind = 'foo'\nreplace = 'bar'\n\n\
+                 [rule.b]\nfind = 'bar(\\w*)'\nreplace = 'baz$1'\n\n\
+                 [rule.c]\nfind = 'z x'\nreplace = 'Z'\n";
+        let rules = parse(s, &RegexMatcherBuilder::new()).unwrap();
+
+        // rule b matches the replacement of rule a, and rule c matches across its replacement
+        let mut replaced = Vec::new();
+        let matches = replace(&rules, b"foo x barn foox", |_, _| true, &mut replaced);
+        assert_eq!(replaced, b"baZ bazn bazx");
+        let ranges: Vec<_> = matches
+            .iter()
+            .map(|x| (x.range.clone(), x.replacement.clone()))
+            .collect();
+        assert_eq!(ranges, [(0..5, 0..3), (6..10, 4..8), (11..15, 9..13)]);
+
+        // only the matches that are kept are replaced
+        let mut replaced = Vec::new();
+        let matches = replace(&rules, b"foo barn", |_, m| m.start() > 0, &mut replaced);
+        assert_eq!(replaced, b"foo bazn");
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].range, 4..8);
+    }
+}
@@ -0,0 +1,823 @@
+//! A small scripting language for computing replacements, with a syntax based on a subset of Rhai.
+//!
+//! A script is a sequence of statements, and the value of the final expression is the replacement
+//! for each match. The variable `m` is the matched text, `replace` is the `<REPLACE>` argument,
+//! and `caps` is an array of the capture groups (where `caps[0]` is the whole match and groups
+//! that didn't participate are ""). Named capture groups can be accessed with `cap("name")`.
+//!
+//! ```text
+//! // a lookup table
+//! let colors = #{ red: "#f00", green: "#0f0" };
+//!
+//! // arithmetic and conditionals
+//! let n = parse_int(caps[1]) * 2;
+//! if colors.contains(caps[2]) { colors[caps[2]] + " " + n } else { m }
+//! ```
+//!
+//! Supported values are integers, strings, booleans, arrays, object maps, and `()`. Supported
+//! functions (which can also be called as methods, like `s.len()`) are `len`, `to_string`,
+//! `to_upper`, `to_lower`, `trim`, `parse_int(s, radix?)`, `contains`, `starts_with`,
+//! `ends_with`, `replace(s, from, to)`, `sub_string(s, start, len?)`, and `cap(name_or_index)`.
+
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Unit,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Array(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Self::Unit => "()",
+            Self::Bool(_) => "bool",
+            Self::Int(_) => "int",
+            Self::Str(_) => "string",
+            Self::Array(_) => "array",
+            Self::Map(_) => "map",
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unit => Ok(()),
+            Self::Bool(x) => write!(f, "{x}"),
+            Self::Int(x) => write!(f, "{x}"),
+            Self::Str(x) => write!(f, "{x}"),
+            Self::Array(x) => {
+                let items: Vec<_> = x.iter().map(|x| x.to_string()).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Self::Map(x) => {
+                let items: Vec<_> = x.iter().map(|(k, v)| format!("{k}: {v}")).collect();
+                write!(f, "#{{{}}}", items.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ScriptError {
+    msg: String,
+}
+
+impl ScriptError {
+    fn new(msg: impl Into<String>) -> Self {
+        Self { msg: msg.into() }
+    }
+}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// A parsed script.
+#[derive(Debug)]
+pub struct Script {
+    body: Block,
+}
+
+impl Script {
+    pub fn parse(source: &str) -> Result<Self, ScriptError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let body = parser.block_contents()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(parser.error("unexpected token"));
+        }
+        Ok(Self { body })
+    }
+
+    /// Run the script for a match. `caps` are the capture groups, and `cap_index` returns the
+    /// index of a named capture group.
+    pub fn run(
+        &self,
+        replace: &str,
+        caps: &[String],
+        cap_index: &dyn Fn(&str) -> Option<usize>,
+    ) -> Result<Value, ScriptError> {
+        let mut env = Env {
+            vars: vec![
+                ("replace".to_string(), Value::Str(replace.to_string())),
+                ("m".to_string(), Value::Str(caps[0].clone())),
+                (
+                    "caps".to_string(),
+                    Value::Array(caps.iter().cloned().map(Value::Str).collect()),
+                ),
+            ],
+            caps,
+            cap_index,
+        };
+
+        env.block(&self.body)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Int(i64),
+    Str(String),
+    Ident(String),
+    Punct(&'static str),
+}
+
+/// Punctuation, where longer tokens must come first.
+const PUNCTUATION: &[&str] = &[
+    "#{", "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "!", "=", "(",
+    ")", "[", "]", "{", "}", ",", ";", ":", ".",
+];
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, ScriptError> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+
+    loop {
+        rest = rest.trim_start();
+
+        // comments
+        if let Some(x) = rest.strip_prefix("//") {
+            rest = x.split_once('\n').map(|x| x.1).unwrap_or("");
+            continue;
+        }
+        if let Some(x) = rest.strip_prefix("/*") {
+            let Some((_, x)) = x.split_once("*/") else {
+                return Err(ScriptError::new("unterminated comment"));
+            };
+            rest = x;
+            continue;
+        }
+
+        let pos = source.len() - rest.len();
+        let Some(c) = rest.chars().next() else {
+            break;
+        };
+
+        if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let x = rest[..len]
+                .parse()
+                .map_err(|_| ScriptError::new(format!("integer too large at {pos}")))?;
+            tokens.push((Token::Int(x), pos));
+            rest = &rest[len..];
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !c.is_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push((Token::Ident(rest[..len].to_string()), pos));
+            rest = &rest[len..];
+        } else if c == '`' {
+            // a raw string
+            let Some((x, remaining)) = rest[1..].split_once('`') else {
+                return Err(ScriptError::new(format!("unterminated string at {pos}")));
+            };
+            tokens.push((Token::Str(x.to_string()), pos));
+            rest = remaining;
+        } else if c == '"' {
+            let mut x = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i + 2,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => x.push('\n'),
+                        Some((_, 't')) => x.push('\t'),
+                        Some((_, 'r')) => x.push('\r'),
+                        Some((_, '0')) => x.push('\0'),
+                        Some((_, c @ ('\\' | '"'))) => x.push(c),
+                        _ => return Err(ScriptError::new(format!("invalid escape at {pos}"))),
+                    },
+                    Some((_, c)) => x.push(c),
+                    None => return Err(ScriptError::new(format!("unterminated string at {pos}"))),
+                }
+            };
+            tokens.push((Token::Str(x), pos));
+            rest = &rest[end..];
+        } else if let Some(p) = PUNCTUATION.iter().find(|p| rest.starts_with(**p)) {
+            tokens.push((Token::Punct(p), pos));
+            rest = &rest[p.len()..];
+        } else {
+            return Err(ScriptError::new(format!(
+                "unexpected character '{c}' at {pos}"
+            )));
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+struct Block {
+    stmts: Vec<Stmt>,
+    /// The final expression, if it wasn't followed by a semicolon.
+    result: Option<Box<Expr>>,
+}
+
+#[derive(Debug)]
+enum Stmt {
+    Let(String, Expr),
+    Assign(String, Expr),
+    Expr(Expr),
+}
+
+#[derive(Debug)]
+enum Expr {
+    Lit(Value),
+    Var(String),
+    Array(Vec<Expr>),
+    Map(Vec<(String, Expr)>),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    Field(Box<Expr>, String),
+    Call(String, Vec<Expr>),
+    If(Box<Expr>, Block, Option<Box<Expr>>),
+    Block(Block),
+}
+
+impl Expr {
+    /// Block-like expressions can be used as statements without a semicolon.
+    fn is_block_like(&self) -> bool {
+        matches!(self, Self::If(..) | Self::Block(_))
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, msg: &str) -> ScriptError {
+        match self.tokens.get(self.pos) {
+            Some((_, pos)) => ScriptError::new(format!("{msg} at {pos}")),
+            None => ScriptError::new(format!("{msg} at the end of the script")),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|x| &x.0)
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Punct(x)) if *x == punct) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Ident(x)) if x == keyword) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), ScriptError> {
+        if !self.eat(punct) {
+            return Err(self.error(&format!("expected '{punct}'")));
+        }
+        Ok(())
+    }
+
+    fn ident(&mut self) -> Result<String, ScriptError> {
+        match self.peek() {
+            Some(Token::Ident(x)) => {
+                let x = x.clone();
+                self.pos += 1;
+                Ok(x)
+            }
+            _ => Err(self.error("expected an identifier")),
+        }
+    }
+
+    /// Statements up to a '}' or the end of the script.
+    fn block_contents(&mut self) -> Result<Block, ScriptError> {
+        let mut stmts = Vec::new();
+
+        loop {
+            if matches!(self.peek(), None | Some(Token::Punct("}"))) {
+                return Ok(Block {
+                    stmts,
+                    result: None,
+                });
+            }
+
+            if self.eat(";") {
+                continue;
+            }
+
+            if self.eat_keyword("let") {
+                let name = self.ident()?;
+                self.expect("=")?;
+                stmts.push(Stmt::Let(name, self.expr()?));
+                self.expect(";")?;
+                continue;
+            }
+
+            let expr = self.expr()?;
+
+            if let Expr::Var(name) = &expr {
+                if self.eat("=") {
+                    stmts.push(Stmt::Assign(name.clone(), self.expr()?));
+                    self.expect(";")?;
+                    continue;
+                }
+            }
+
+            if self.eat(";") {
+                stmts.push(Stmt::Expr(expr));
+            } else if matches!(self.peek(), None | Some(Token::Punct("}"))) {
+                return Ok(Block {
+                    stmts,
+                    result: Some(Box::new(expr)),
+                });
+            } else if expr.is_block_like() {
+                stmts.push(Stmt::Expr(expr));
+            } else {
+                return Err(self.error("expected ';'"));
+            }
+        }
+    }
+
+    fn block(&mut self) -> Result<Block, ScriptError> {
+        self.expect("{")?;
+        let block = self.block_contents()?;
+        self.expect("}")?;
+        Ok(block)
+    }
+
+    fn expr(&mut self) -> Result<Expr, ScriptError> {
+        self.binary(0)
+    }
+
+    /// Binary operators, from lowest to highest precedence.
+    fn binary(&mut self, level: usize) -> Result<Expr, ScriptError> {
+        const LEVELS: &[&[&str]] = &[
+            &["||"],
+            &["&&"],
+            &["==", "!=", "<=", ">=", "<", ">"],
+            &["+", "-"],
+            &["*", "/", "%"],
+        ];
+
+        let Some(ops) = LEVELS.get(level) else {
+            return self.unary();
+        };
+
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = ops.iter().find(|op| self.eat(op)) {
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ScriptError> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.postfix()
+    }
+
+    fn postfix(&mut self) -> Result<Expr, ScriptError> {
+        let mut expr = self.primary()?;
+
+        loop {
+            if self.eat("[") {
+                let index = self.expr()?;
+                self.expect("]")?;
+                expr = Expr::Index(Box::new(expr), Box::new(index));
+            } else if self.eat(".") {
+                let name = self.ident()?;
+                if self.eat("(") {
+                    // a method call is the same as a function call with the object as the first
+                    // argument
+                    let mut args = vec![expr];
+                    args.extend(self.args(")")?);
+                    expr = Expr::Call(name, args);
+                } else {
+                    expr = Expr::Field(Box::new(expr), name);
+                }
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    /// Comma-separated expressions up to `end`.
+    fn args(&mut self, end: &str) -> Result<Vec<Expr>, ScriptError> {
+        let mut args = Vec::new();
+        while !self.eat(end) {
+            args.push(self.expr()?);
+            if !self.eat(",") {
+                self.expect(end)?;
+                break;
+            }
+        }
+        Ok(args)
+    }
+
+    fn primary(&mut self) -> Result<Expr, ScriptError> {
+        let Some(token) = self.peek().cloned() else {
+            return Err(self.error("expected an expression"));
+        };
+
+        match token {
+            Token::Int(x) => {
+                self.pos += 1;
+                Ok(Expr::Lit(Value::Int(x)))
+            }
+            Token::Str(x) => {
+                self.pos += 1;
+                Ok(Expr::Lit(Value::Str(x)))
+            }
+            Token::Ident(x) => match x.as_str() {
+                "true" | "false" => {
+                    self.pos += 1;
+                    Ok(Expr::Lit(Value::Bool(x == "true")))
+                }
+                "if" => {
+                    self.pos += 1;
+                    self.if_expr()
+                }
+                _ => {
+                    self.pos += 1;
+                    if self.eat("(") {
+                        return Ok(Expr::Call(x, self.args(")")?));
+                    }
+                    Ok(Expr::Var(x))
+                }
+            },
+            Token::Punct("(") => {
+                self.pos += 1;
+                if self.eat(")") {
+                    return Ok(Expr::Lit(Value::Unit));
+                }
+                let expr = self.expr()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Token::Punct("[") => {
+                self.pos += 1;
+                Ok(Expr::Array(self.args("]")?))
+            }
+            Token::Punct("#{") => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                while !self.eat("}") {
+                    let key = match self.peek() {
+                        Some(Token::Ident(x) | Token::Str(x)) => x.clone(),
+                        _ => return Err(self.error("expected a map key")),
+                    };
+                    self.pos += 1;
+                    self.expect(":")?;
+                    members.push((key, self.expr()?));
+                    if !self.eat(",") {
+                        self.expect("}")?;
+                        break;
+                    }
+                }
+                Ok(Expr::Map(members))
+            }
+            Token::Punct("{") => Ok(Expr::Block(self.block()?)),
+            Token::Punct(_) => Err(self.error("expected an expression")),
+        }
+    }
+
+    /// An if expression, after the "if".
+    fn if_expr(&mut self) -> Result<Expr, ScriptError> {
+        let cond = self.expr()?;
+        let then = self.block()?;
+
+        let otherwise = if self.eat_keyword("else") {
+            if self.eat_keyword("if") {
+                Some(Box::new(self.if_expr()?))
+            } else {
+                Some(Box::new(Expr::Block(self.block()?)))
+            }
+        } else {
+            None
+        };
+
+        Ok(Expr::If(Box::new(cond), then, otherwise))
+    }
+}
+
+struct Env<'a> {
+    vars: Vec<(String, Value)>,
+    caps: &'a [String],
+    cap_index: &'a dyn Fn(&str) -> Option<usize>,
+}
+
+impl Env<'_> {
+    fn block(&mut self, block: &Block) -> Result<Value, ScriptError> {
+        // variables declared in the block go out of scope at the end of the block
+        let num_vars = self.vars.len();
+        let rv = self.block_inner(block);
+        self.vars.truncate(num_vars);
+        rv
+    }
+
+    fn block_inner(&mut self, block: &Block) -> Result<Value, ScriptError> {
+        for stmt in &block.stmts {
+            match stmt {
+                Stmt::Let(name, expr) => {
+                    let value = self.eval(expr)?;
+                    self.vars.push((name.clone(), value));
+                }
+                Stmt::Assign(name, expr) => {
+                    let value = self.eval(expr)?;
+                    *self.var(name)? = value;
+                }
+                Stmt::Expr(expr) => {
+                    self.eval(expr)?;
+                }
+            }
+        }
+
+        match &block.result {
+            Some(x) => self.eval(x),
+            None => Ok(Value::Unit),
+        }
+    }
+
+    fn var(&mut self, name: &str) -> Result<&mut Value, ScriptError> {
+        self.vars
+            .iter_mut()
+            .rev()
+            .find(|(x, _)| x == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| ScriptError::new(format!("unknown variable '{name}'")))
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value, ScriptError> {
+        Ok(match expr {
+            Expr::Lit(x) => x.clone(),
+            Expr::Var(name) => self.var(name)?.clone(),
+            Expr::Array(x) => {
+                Value::Array(x.iter().map(|x| self.eval(x)).collect::<Result<_, _>>()?)
+            }
+            Expr::Map(x) => Value::Map(
+                x.iter()
+                    .map(|(k, v)| Ok((k.clone(), self.eval(v)?)))
+                    .collect::<Result<_, ScriptError>>()?,
+            ),
+            Expr::Not(x) => Value::Bool(!self.bool(x)?),
+            Expr::Neg(x) => match self.eval(x)? {
+                Value::Int(x) => Value::Int(x.checked_neg().ok_or_else(overflow)?),
+                x => return Err(type_error("-", &[x])),
+            },
+            Expr::Binary("&&", lhs, rhs) => Value::Bool(self.bool(lhs)? && self.bool(rhs)?),
+            Expr::Binary("||", lhs, rhs) => Value::Bool(self.bool(lhs)? || self.bool(rhs)?),
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                let rhs = self.eval(rhs)?;
+                binary(op, lhs, rhs)?
+            }
+            Expr::Index(x, index) => {
+                let x = self.eval(x)?;
+                let index = self.eval(index)?;
+                match (x, index) {
+                    (Value::Array(x), Value::Int(i)) => usize::try_from(i)
+                        .ok()
+                        .and_then(|i| x.get(i).cloned())
+                        .ok_or_else(|| ScriptError::new(format!("index {i} is out of bounds")))?,
+                    (Value::Str(x), Value::Int(i)) => usize::try_from(i)
+                        .ok()
+                        .and_then(|i| x.chars().nth(i))
+                        .map(|c| Value::Str(c.to_string()))
+                        .ok_or_else(|| ScriptError::new(format!("index {i} is out of bounds")))?,
+                    // like rhai, a missing property is `()`
+                    (Value::Map(x), Value::Str(key)) => x.get(&key).cloned().unwrap_or(Value::Unit),
+                    (x, index) => return Err(type_error("[]", &[x, index])),
+                }
+            }
+            Expr::Field(x, name) => match self.eval(x)? {
+                Value::Map(x) => x.get(name).cloned().unwrap_or(Value::Unit),
+                x => return Err(type_error(&format!(".{name}"), &[x])),
+            },
+            Expr::Call(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|x| self.eval(x))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.call(name, args)?
+            }
+            Expr::If(cond, then, otherwise) => {
+                if self.bool(cond)? {
+                    self.block(then)?
+                } else if let Some(otherwise) = otherwise {
+                    self.eval(otherwise)?
+                } else {
+                    Value::Unit
+                }
+            }
+            Expr::Block(x) => self.block(x)?,
+        })
+    }
+
+    fn bool(&mut self, expr: &Expr) -> Result<bool, ScriptError> {
+        match self.eval(expr)? {
+            Value::Bool(x) => Ok(x),
+            x => Err(ScriptError::new(format!(
+                "expected a bool, but found {}",
+                x.type_name(),
+            ))),
+        }
+    }
+
+    fn call(&self, name: &str, args: Vec<Value>) -> Result<Value, ScriptError> {
+        use Value::*;
+
+        Ok(match (name, args.as_slice()) {
+            ("len", [Str(x)]) => Int(x.chars().count() as i64),
+            ("len", [Array(x)]) => Int(x.len() as i64),
+            ("len", [Map(x)]) => Int(x.len() as i64),
+            ("to_string", [x]) => Str(x.to_string()),
+            ("to_upper", [Str(x)]) => Str(x.to_uppercase()),
+            ("to_lower", [Str(x)]) => Str(x.to_lowercase()),
+            ("trim", [Str(x)]) => Str(x.trim().to_string()),
+            ("parse_int", [Str(x)]) => Int(parse_int(x, 10)?),
+            ("parse_int", [Str(x), Int(radix)]) if (2..=36).contains(radix) => {
+                Int(parse_int(x, *radix as u32)?)
+            }
+            ("contains", [Str(x), Str(y)]) => Bool(x.contains(y.as_str())),
+            ("contains", [Array(x), y]) => Bool(x.contains(y)),
+            ("contains", [Map(x), Str(y)]) => Bool(x.contains_key(y)),
+            ("starts_with", [Str(x), Str(y)]) => Bool(x.starts_with(y.as_str())),
+            ("ends_with", [Str(x), Str(y)]) => Bool(x.ends_with(y.as_str())),
+            ("replace", [Str(x), Str(from), Str(to)]) => Str(x.replace(from.as_str(), to)),
+            ("sub_string", [Str(x), Int(start)]) => Str(sub_string(x, *start, i64::MAX)),
+            ("sub_string", [Str(x), Int(start), Int(len)]) => Str(sub_string(x, *start, *len)),
+            ("cap", [Int(i)]) => Str(usize::try_from(*i)
+                .ok()
+                .and_then(|i| self.caps.get(i))
+                .cloned()
+                .ok_or_else(|| ScriptError::new(format!("no capture group {i}")))?),
+            ("cap", [Str(name)]) => Str((self.cap_index)(name)
+                .and_then(|i| self.caps.get(i))
+                .cloned()
+                .ok_or_else(|| ScriptError::new(format!("no capture group '{name}'")))?),
+            _ => return Err(type_error(&format!("{name}()"), &args)),
+        })
+    }
+}
+
+fn binary(op: &str, lhs: Value, rhs: Value) -> Result<Value, ScriptError> {
+    use Value::*;
+
+    Ok(match (op, lhs, rhs) {
+        ("==", x, y) => Bool(x == y),
+        ("!=", x, y) => Bool(x != y),
+        ("+", Int(x), Int(y)) => Int(x.checked_add(y).ok_or_else(overflow)?),
+        ("-", Int(x), Int(y)) => Int(x.checked_sub(y).ok_or_else(overflow)?),
+        ("*", Int(x), Int(y)) => Int(x.checked_mul(y).ok_or_else(overflow)?),
+        ("/", Int(x), Int(y)) => Int(x.checked_div(y).ok_or_else(overflow)?),
+        ("%", Int(x), Int(y)) => Int(x.checked_rem(y).ok_or_else(overflow)?),
+        // like rhai, strings can be concatenated with any value
+        ("+", Str(x), y) => Str(format!("{x}{y}")),
+        ("+", x, Str(y)) => Str(format!("{x}{y}")),
+        ("+", Array(mut x), Array(y)) => {
+            x.extend(y);
+            Array(x)
+        }
+        (op @ ("<" | ">" | "<=" | ">="), x, y) => {
+            let ordering = match (&x, &y) {
+                (Int(x), Int(y)) => x.cmp(y),
+                (Str(x), Str(y)) => x.cmp(y),
+                _ => return Err(type_error(op, &[x, y])),
+            };
+            Bool(match op {
+                "<" => ordering.is_lt(),
+                ">" => ordering.is_gt(),
+                "<=" => ordering.is_le(),
+                _ => ordering.is_ge(),
+            })
+        }
+        (op, x, y) => return Err(type_error(op, &[x, y])),
+    })
+}
+
+fn parse_int(s: &str, radix: u32) -> Result<i64, ScriptError> {
+    i64::from_str_radix(s.trim(), radix)
+        .map_err(|_| ScriptError::new(format!("could not parse '{s}' as an integer")))
+}
+
+/// The substring of `len` chars starting at char `start`.
+fn sub_string(s: &str, start: i64, len: i64) -> String {
+    let start = usize::try_from(start).unwrap_or(0);
+    let len = usize::try_from(len).unwrap_or(0);
+    s.chars().skip(start).take(len).collect()
+}
+
+fn overflow() -> ScriptError {
+    ScriptError::new("arithmetic overflow or division by zero")
+}
+
+fn type_error(op: &str, args: &[Value]) -> ScriptError {
+    let types: Vec<_> = args.iter().map(Value::type_name).collect();
+    ScriptError::new(format!(
+        "'{op}' is not supported for ({})",
+        types.join(", ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(script: &str, caps: &[&str]) -> Result<Value, ScriptError> {
+        let caps: Vec<String> = caps.iter().map(|x| x.to_string()).collect();
+        let cap_index = |name: &str| (name == "val").then_some(1);
+        Script::parse(script)?.run("r", &caps, &cap_index)
+    }
+
+    fn run_str(script: &str, caps: &[&str]) -> String {
+        run(script, caps).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_values() {
+        assert_eq!(run_str("m", &["abc"]), "abc");
+        assert_eq!(run_str("caps[1] + caps.len()", &["a=1", "1"]), "12");
+        assert_eq!(run_str("cap(\"val\").to_upper()", &["a=x", "x"]), "X");
+        assert_eq!(run_str("1 + 2 * 3 - -4 % 3", &[""]), "8");
+        assert_eq!(run_str("(1 + 2) * 3", &[""]), "9");
+        assert_eq!(run_str(r#""a\n" + `\n` + true"#, &[""]), "a\n\\ntrue");
+        assert_eq!(run_str("[1, 2] + [3]", &[""]), "[1, 2, 3]");
+        assert_eq!(run_str("#{ a: 1, \"b c\": 2 }", &[""]), "#{a: 1, b c: 2}");
+        assert_eq!(
+            run_str("parse_int(\"ff\", 16) + parse_int(\" 1 \")", &[""]),
+            "256"
+        );
+        assert_eq!(run_str("\"héllo\".sub_string(1, 3)", &[""]), "éll");
+        assert_eq!(run_str("\"a-b\".replace(\"-\", \"+\")", &[""]), "a+b");
+        assert_eq!(run_str("()", &[""]), "");
+    }
+
+    #[test]
+    fn test_statements() {
+        let script = "
+            // a lookup table
+            let colors = #{ red: \"#f00\", green: \"#0f0\" };
+            let n = parse_int(caps[1]) * 2;
+            /* a block comment */
+            if colors.contains(caps[2]) { colors[caps[2]] + \" \" + n } else { m }
+        ";
+        assert_eq!(run_str(script, &["4 red", "4", "red"]), "#f00 8");
+        assert_eq!(run_str(script, &["4 blue", "4", "blue"]), "4 blue");
+
+        let script = "
+            let x = 1;
+            { let x = 5; x = x + 1; }
+            if x > 1 { \"a\" } else if x == 1 { x = 10; } else { \"c\" }
+            x
+        ";
+        assert_eq!(run_str(script, &[""]), "10");
+
+        assert_eq!(
+            run_str("let t = #{a: 1}; t.a + \" \" + (t.b == ())", &[""]),
+            "1 true"
+        );
+        assert_eq!(run_str("replace + m", &["x"]), "rx");
+        assert_eq!(run_str("true && !false || 1 / 0 == 0", &[""]), "true");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Script::parse("let = 1;").is_err());
+        assert!(Script::parse("1 +").is_err());
+        assert!(Script::parse("\"abc").is_err());
+        assert!(Script::parse("1 2").is_err());
+        assert!(Script::parse("if true { 1 ").is_err());
+        assert!(Script::parse("a ^ b").is_err());
+
+        assert!(run("x", &[""]).is_err());
+        assert!(run("1 + true", &[""]).is_err());
+        assert!(run("1 / 0", &[""]).is_err());
+        assert!(run("caps[5]", &[""]).is_err());
+        assert!(run("cap(\"other\")", &[""]).is_err());
+        assert!(run("if 1 { 2 }", &[""]).is_err());
+        assert!(run("parse_int(\"x\")", &[""]).is_err());
+        assert!(run("9223372036854775807 + 1", &[""]).is_err());
+    }
+}
@@ -0,0 +1,1283 @@
+//! Finding the files and lines with matches, which are later replaced by the [`replace`]
+//! pipeline.
+//!
+//! [`replace`]: crate::replace
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use anyhow::Context as anyhowContext;
+use globset::GlobSet;
+use grep_matcher::LineTerminator;
+use grep_searcher::{BinaryDetection, MmapChoice, Searcher, SearcherBuilder, Sink, SinkMatch};
+use ignore::overrides::{Override, OverrideBuilder};
+use ignore::types::{Types, TypesBuilder};
+use ignore::{WalkBuilder, WalkState};
+
+use crate::cache::MatchCache;
+use crate::cli::{Args, BinaryMode, MatchSource};
+use crate::engine::PatternMatcher;
+use crate::profile::{measure, Phase};
+use crate::replace::{LineWindows, OnlyMatch, ReplaceOptions};
+use crate::ui::{error, style, warning, COUNT_STYLE};
+use crate::util::{MatchFilter, Replacement};
+
+/// Search a single file again. Returns `None` if there are no matches.
+pub(crate) fn search_file(
+    config: &ReplaceOptions,
+    search: &SearchConfig,
+    path: &Path,
+) -> anyhow::Result<Option<MatchInfo>> {
+    if let Some(header) = config.header {
+        let matches = find_header_matches(
+            config.matcher,
+            header,
+            &[path],
+            PathFilters::empty(),
+            search,
+            false,
+            None,
+        )?;
+        return match matches {
+            Ok(mut x) => Ok(x.remove(path)),
+            Err(_) => anyhow::bail!("could not search the file '{}'", path.display()),
+        };
+    }
+
+    // the options can't be shared with the search's threads, but only this file is searched
+    let filter = config.path_filter(path);
+    let (matcher, replacement) = (config.matcher, config.replacement);
+    let matches = find_matches(
+        config.matcher,
+        &[path],
+        PathFilters::empty(),
+        search,
+        false,
+        None,
+        SearchFilter {
+            keep_line: Some(&|path, line_num, line| {
+                replacement_changes_line(matcher, replacement, &filter, path, line_num, line)
+            }),
+            ..Default::default()
+        },
+    );
+
+    let Some(mut match_info) = (match matches {
+        Ok(mut x) => x.remove(path),
+        Err(_) => anyhow::bail!("could not search the file '{}'", path.display()),
+    }) else {
+        return Ok(None);
+    };
+
+    if let Some(markers) = config.between {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("could not read file '{}'", path.display()))?;
+        restrict_between(
+            markers,
+            &bytes,
+            &mut match_info.lines,
+            config.line_terminator,
+        );
+    }
+
+    File::open(path)
+        .and_then(|src| {
+            restrict_lines(
+                config.matcher,
+                config.columns.as_ref(),
+                config.windows.as_ref(),
+                config.only,
+                src,
+                &mut match_info.lines,
+                config.line_terminator,
+            )
+        })
+        .with_context(|| format!("could not read file '{}'", path.display()))?;
+
+    Ok((!match_info.lines.is_empty()).then_some(match_info))
+}
+
+/// Returns an error if there are more than `max` matching lines.
+pub(crate) fn check_max_total_matches(
+    matches: &BTreeMap<PathBuf, MatchInfo>,
+    max: Option<u64>,
+) -> anyhow::Result<()> {
+    let Some(max) = max else {
+        return Ok(());
+    };
+
+    let count: u64 = matches.values().map(|x| x.lines.len() as u64).sum();
+    if count > max {
+        anyhow::bail!(
+            "found more than {} match{}, so the search was aborted",
+            style!(max, &COUNT_STYLE),
+            if max == 1 { "" } else { "es" },
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns true if replacing the matches in the (0-indexed) line `line_num` of the file `path`
+/// would change the line. The line is assumed to change if the replacement fails.
+pub(crate) fn replacement_changes_line(
+    matcher: &PatternMatcher,
+    replacement: Replacement,
+    filter: &MatchFilter,
+    path: &Path,
+    line_num: u64,
+    line: &[u8],
+) -> bool {
+    let mut template = None;
+    let replacement = replacement.for_line(&mut template, path, line_num);
+
+    let mut replaced = Vec::new();
+    match crate::util::replace_regex(matcher, replacement, filter, line, &mut replaced) {
+        Ok(_) => replaced != line,
+        Err(_) => true,
+    }
+}
+
+/// Remove the (sorted) matching lines of `src` that don't have a match starting within `columns`
+/// and `windows`, and then keep only the first or last line if `only` is given.
+pub(crate) fn restrict_lines(
+    matcher: &PatternMatcher,
+    columns: Option<&std::ops::RangeInclusive<u64>>,
+    windows: Option<&LineWindows>,
+    only: Option<OnlyMatch>,
+    src: impl Read,
+    lines: &mut Vec<u64>,
+    line_terminator: u8,
+) -> std::io::Result<()> {
+    if columns.is_some() || windows.is_some() {
+        let mut kept = Vec::new();
+        for_each_line_in(src, lines, line_terminator, |line_num, line| {
+            let filter = MatchFilter::columns(window_columns(columns, windows, line_num));
+            if !crate::util::match_starts(matcher, &filter, line).is_empty() {
+                kept.push(line_num);
+            }
+        })?;
+        *lines = kept;
+    }
+
+    match only {
+        Some(OnlyMatch::First) => lines.truncate(1),
+        Some(OnlyMatch::Last) => {
+            let len = lines.len();
+            lines.drain(..len.saturating_sub(1));
+        }
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// Remove the matching lines of `bytes` that aren't between a line containing the begin marker
+/// and a line containing the end marker, for `--between`.
+pub(crate) fn restrict_between(
+    (begin, end): (&str, &str),
+    bytes: &[u8],
+    lines: &mut Vec<u64>,
+    line_terminator: u8,
+) {
+    let blocks =
+        crate::util::marked_blocks(bytes, begin.as_bytes(), end.as_bytes(), line_terminator);
+    lines.retain(|x| blocks.iter().any(|block| block.contains(x)));
+}
+
+/// How many of the matches of each file are replaced, for `--max-count` and `--nth`.
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct MatchLimit {
+    /// At most this many matches of a file are replaced.
+    pub(crate) max_count: Option<u64>,
+    /// Only the nth (1-indexed) match is replaced.
+    pub(crate) nth: Option<u64>,
+    /// `nth` counts the matches of the whole file instead of each line.
+    pub(crate) nth_per_file: bool,
+}
+
+/// Remove the (sorted) matching lines of `src` that have no matches within `limit`, where
+/// `filter` gives the matches of a line. Returns the (1-indexed) columns of the matches within the
+/// limit on each of the remaining lines, and the number of matches that are over the limit.
+pub(crate) fn limit_matches(
+    matcher: &PatternMatcher,
+    filter: impl Fn(u64) -> MatchFilter,
+    limit: MatchLimit,
+    src: impl Read,
+    lines: &mut Vec<u64>,
+    line_terminator: u8,
+) -> std::io::Result<(LineWindows, u64)> {
+    let mut windows = LineWindows::new();
+    let mut over_limit = 0;
+    // the number of matches in the file so far, and how many of them are replaced
+    let mut seen = 0;
+    let mut kept = 0;
+
+    for_each_line_in(src, lines, line_terminator, |line_num, line| {
+        let mut window: Option<std::ops::RangeInclusive<u64>> = None;
+        for (i, start) in crate::util::match_starts(matcher, &filter(line_num), line)
+            .into_iter()
+            .enumerate()
+        {
+            seen += 1;
+            let n = match limit.nth_per_file {
+                true => seen,
+                false => i as u64 + 1,
+            };
+            let allowed =
+                limit.nth.is_none_or(|x| x == n) && limit.max_count.is_none_or(|x| kept < x);
+            if !allowed {
+                over_limit += 1;
+                continue;
+            }
+
+            // the kept matches of a line are always adjacent, so a range of columns is enough
+            kept += 1;
+            let column = start as u64 + 1;
+            window = Some(window.map_or(column..=column, |x| *x.start()..=column));
+        }
+        if let Some(window) = window {
+            windows.insert(line_num, window);
+        }
+    })?;
+
+    lines.retain(|x| windows.contains_key(x));
+    Ok((windows, over_limit))
+}
+
+/// The columns of the line that are within both `columns` and the line's window from
+/// `--byte-range`. Lines without a window have no columns.
+pub(crate) fn window_columns(
+    columns: Option<&std::ops::RangeInclusive<u64>>,
+    windows: Option<&LineWindows>,
+    line_num: u64,
+) -> Option<std::ops::RangeInclusive<u64>> {
+    let Some(windows) = windows else {
+        return columns.cloned();
+    };
+
+    // column 0 is never matched
+    let window = windows.get(&line_num).cloned().unwrap_or(0..=0);
+    Some(match columns {
+        Some(columns) => *window.start().max(columns.start())..=*window.end().min(columns.end()),
+        None => window,
+    })
+}
+
+/// Call `f` with each of the given lines of the file (without the line terminator). The line
+/// numbers must be sorted.
+pub(crate) fn for_each_line(
+    path: &Path,
+    line_nums: &[u64],
+    line_terminator: u8,
+    f: impl FnMut(u64, &[u8]),
+) -> std::io::Result<()> {
+    for_each_line_in(File::open(path)?, line_nums, line_terminator, f)
+}
+
+/// Like [`for_each_line`], but with the lines of `src`.
+fn for_each_line_in(
+    src: impl Read,
+    line_nums: &[u64],
+    line_terminator: u8,
+    mut f: impl FnMut(u64, &[u8]),
+) -> std::io::Result<()> {
+    let mut src = BufReader::new(src);
+    let mut line_nums = line_nums.iter().peekable();
+
+    let mut buf = Vec::new();
+    let mut current_line = 0;
+
+    while let Some(next_match) = line_nums.peek() {
+        buf.clear();
+        if src.read_until(line_terminator, &mut buf)? == 0 {
+            // EOF
+            break;
+        }
+
+        if current_line == **next_match {
+            f(
+                current_line,
+                buf.strip_suffix(&[line_terminator]).unwrap_or(&buf),
+            );
+            line_nums.next();
+        }
+
+        current_line += 1;
+    }
+
+    Ok(())
+}
+
+/// Which files are searched, in addition to the ignore files.
+pub(crate) struct PathFilters<'a> {
+    /// Globs to include or exclude. These don't apply to the paths given on the command line.
+    overrides: Override,
+    /// The file types to search, for `--type`. These don't apply to the paths given on the
+    /// command line.
+    types: Types,
+    /// A gitignore-style file that applies to every search.
+    global_ignore: Option<&'a Path>,
+    /// Globs to exclude, even if they're given on the command line.
+    exclude: GlobSet,
+    /// Respect the ignore files and skip hidden files.
+    pub(crate) standard_filters: bool,
+    /// Search hidden files and directories, even if `standard_filters` is set.
+    hidden: bool,
+    /// Respect the ignore files, if `standard_filters` is set.
+    ignore_files: bool,
+    /// Respect git's ignore files, if `ignore_files` is set.
+    git_ignore: bool,
+    /// Respect git's global ignore file (`core.excludesFile`), if `git_ignore` is set.
+    git_global: bool,
+    /// Respect each repository's `.git/info/exclude` file, if `git_ignore` is set.
+    git_exclude: bool,
+    /// The files and directories that we write to, which are never searched.
+    outputs: OutputPaths,
+    /// Only the paths where this returns true are searched, for library users. Directories where
+    /// it returns false aren't walked.
+    pub(crate) predicate: Option<std::sync::Arc<PathPredicate>>,
+}
+
+/// Whether a path should be searched, given the path and whether it's a directory.
+pub(crate) type PathPredicate = dyn Fn(&Path, bool) -> bool + Send + Sync;
+
+impl<'a> PathFilters<'a> {
+    pub(crate) fn new(
+        glob: &[String],
+        file_types: &[String],
+        exclude: &[String],
+        config: &'a crate::config::Config,
+    ) -> anyhow::Result<Self> {
+        let mut overrides = OverrideBuilder::new(".");
+        for glob in glob {
+            overrides
+                .add(glob)
+                .with_context(|| format!("invalid glob '{glob}'"))?;
+        }
+
+        let mut types = TypesBuilder::new();
+        types.add_defaults();
+        for name in file_types {
+            types.select(name);
+        }
+
+        let mut globs = globset::GlobSetBuilder::new();
+        for glob in exclude {
+            globs.add(globset::Glob::new(glob).with_context(|| format!("invalid glob '{glob}'"))?);
+        }
+
+        Ok(Self {
+            overrides: overrides.build()?,
+            types: types.build()?,
+            global_ignore: config.ignore_file.as_deref(),
+            exclude: globs.build()?,
+            standard_filters: true,
+            hidden: false,
+            ignore_files: true,
+            git_ignore: true,
+            git_global: true,
+            git_exclude: true,
+            outputs: OutputPaths::default(),
+            predicate: None,
+        })
+    }
+
+    pub(crate) fn from_args(
+        args: &Args,
+        config: &'a crate::config::Config,
+    ) -> anyhow::Result<Self> {
+        let mut filters = Self::new(&args.glob, &args.file_type, &args.exclude, config)?;
+        filters.hidden = args.hidden;
+        filters.ignore_files = !args.no_ignore;
+        filters.git_ignore = !args.no_ignore_vcs;
+        filters.git_global = !args.no_ignore_global;
+        filters.git_exclude = !args.no_ignore_exclude;
+
+        // the undo journal, history, and other state files are written to the state directory
+        let state_dir = crate::history::state_dir();
+        let outputs = [
+            args.apply_log.as_ref(),
+            args.export_decisions.as_ref(),
+            args.write_plan.as_ref(),
+            args.output_patch.as_ref().filter(|x| *x != Path::new("-")),
+            args.sandbox.as_ref(),
+            args.temp_dir.as_ref(),
+            state_dir.as_ref(),
+        ];
+        for path in outputs.into_iter().flatten() {
+            filters.outputs.add(path);
+        }
+
+        Ok(filters)
+    }
+
+    pub fn empty() -> Self {
+        Self {
+            overrides: Override::empty(),
+            types: Types::empty(),
+            global_ignore: None,
+            exclude: GlobSet::empty(),
+            standard_filters: true,
+            hidden: false,
+            ignore_files: true,
+            git_ignore: true,
+            git_global: true,
+            git_exclude: true,
+            outputs: OutputPaths::default(),
+            predicate: None,
+        }
+    }
+
+    pub(crate) fn is_excluded(&self, path: &Path) -> bool {
+        glob_matches(&self.exclude, path)
+            || self.outputs.contains(path)
+            || self
+                .predicate
+                .as_ref()
+                .is_some_and(|f| !f(path, path.is_dir()))
+    }
+}
+
+/// The files and directories that we write to, such as logs and sandboxes. If these are within the
+/// searched directories, they're skipped so that we never match or rewrite our own output.
+#[derive(Clone, Debug, Default)]
+struct OutputPaths {
+    /// The absolute path of each output, with the symlinks of its parent directories resolved.
+    paths: Vec<PathBuf>,
+}
+
+impl OutputPaths {
+    /// Add the output `path`, which doesn't need to exist yet. Outputs whose parent directory
+    /// doesn't exist can't be within the searched directories, so they're ignored.
+    fn add(&mut self, path: &Path) {
+        let canonical = std::fs::canonicalize(path).or_else(|e| {
+            let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                return Err(e);
+            };
+            let parent = match parent.as_os_str().is_empty() {
+                true => Path::new("."),
+                false => parent,
+            };
+            std::fs::canonicalize(parent).map(|x| x.join(name))
+        });
+        if let Ok(path) = canonical {
+            self.paths.push(path);
+        }
+    }
+
+    /// Returns true if `path` is one of the outputs. Only paths with the same file name as an
+    /// output are resolved, so that checking the other paths is cheap.
+    fn contains(&self, path: &Path) -> bool {
+        let Some(name) = path.file_name() else {
+            return false;
+        };
+        if !self.paths.iter().any(|x| x.file_name() == Some(name)) {
+            return false;
+        }
+        std::fs::canonicalize(path).is_ok_and(|x| self.paths.contains(&x))
+    }
+}
+
+pub(crate) fn build_globset(globs: &[globset::Glob]) -> anyhow::Result<GlobSet> {
+    let mut builder = globset::GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(glob.clone());
+    }
+    Ok(builder.build()?)
+}
+
+/// Returns true if the path or its file name match the globs. A leading "./" is ignored.
+pub(crate) fn glob_matches(globs: &GlobSet, path: &Path) -> bool {
+    let path = path.strip_prefix(".").unwrap_or(path);
+    globs.is_match(path) || path.file_name().is_some_and(|x| globs.is_match(x))
+}
+
+/// How files are read when searching.
+#[derive(Clone, Debug)]
+pub(crate) struct SearchConfig {
+    builder: SearcherBuilder,
+    /// Files at least this large are memory-mapped.
+    mmap_threshold: u64,
+    /// Files at least this large are searched with a warning, unless this is 0.
+    warn_size: u64,
+    /// Files at least this large are skipped with a warning.
+    skip_size: Option<u64>,
+    /// Files with binary data are skipped, unless their `.gitattributes` say that they're text.
+    skip_binary: bool,
+    /// Matching lines that contain NUL bytes are ignored, for `--binary convert`.
+    pub(crate) skip_nul_lines: bool,
+    /// Matches can span several lines, for `--multiline`.
+    pub(crate) multi_line: bool,
+    /// Files that look like generated or vendored code are skipped, for `--no-generated`.
+    skip_generated: bool,
+    /// Files that take longer than this to search are skipped, for `--file-timeout`.
+    timeout: Option<SearchTimeout>,
+    /// The number of threads to search with, or 0 to choose automatically.
+    threads: usize,
+    /// The search stops soon after this is set, for [`SessionBuilder::cancel_token`].
+    pub(crate) cancel: Option<std::sync::Arc<AtomicBool>>,
+}
+
+/// The time budget for searching each file.
+#[derive(Clone, Debug)]
+struct SearchTimeout {
+    duration: std::time::Duration,
+    /// The pattern, for reporting which pattern was too slow.
+    pattern: String,
+}
+
+impl SearchConfig {
+    pub fn new(line_terminator: u8) -> Self {
+        let mut builder = SearcherBuilder::new();
+        builder.line_terminator(LineTerminator::byte(line_terminator));
+
+        Self {
+            builder,
+            mmap_threshold: 64 * 1024 * 1024,
+            warn_size: 0,
+            skip_size: None,
+            skip_binary: false,
+            skip_nul_lines: false,
+            multi_line: false,
+            skip_generated: false,
+            timeout: None,
+            threads: 0,
+            cancel: None,
+        }
+    }
+
+    /// Whether the search was cancelled.
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|x| x.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn from_args(args: &Args) -> Self {
+        let mut config = Self::new(args.line_terminator.unwrap_or(b'\n'));
+
+        let binary_detection = match args.binary {
+            // the lines with NUL bytes are skipped by the sink instead of converting the NUL bytes
+            // to line terminators, which would change the line numbers
+            BinaryMode::Search | BinaryMode::Convert => BinaryDetection::none(),
+            BinaryMode::Skip => BinaryDetection::quit(b'\0'),
+        };
+
+        config
+            .builder
+            .bom_sniffing(!args.no_bom_sniffing)
+            .binary_detection(binary_detection)
+            .multi_line(args.multiline)
+            .heap_limit(
+                args.max_buffer_size
+                    .map(|x| usize::try_from(x).unwrap_or(usize::MAX)),
+            );
+        config.mmap_threshold = args.mmap_threshold;
+        config.warn_size = args.warn_file_size;
+        config.skip_size = args.skip_file_size;
+        config.skip_binary = args.binary == BinaryMode::Skip;
+        config.skip_nul_lines = args.binary == BinaryMode::Convert;
+        config.multi_line = args.multiline;
+        config.skip_generated = args.no_generated;
+        config.timeout = args.file_timeout.map(|duration| SearchTimeout {
+            duration,
+            pattern: args.find.clone(),
+        });
+        config.threads = args.threads.unwrap_or(0);
+
+        config
+    }
+
+    /// The searchers for files below and above the memory-mapping threshold.
+    pub(crate) fn build(&self) -> (Searcher, Searcher) {
+        // large files are memory-mapped so that they're searched in large chunks rather than
+        // being copied through the searcher's buffer
+        let large = self
+            .builder
+            .clone()
+            // SAFETY: like ripgrep, we accept that the program may crash if the file is truncated
+            // while it's mapped
+            .memory_map(unsafe { MmapChoice::auto() })
+            .build();
+
+        (self.builder.build(), large)
+    }
+
+    /// A searcher for files that git considers to be text, even if they have binary data.
+    fn build_text(&self) -> Searcher {
+        self.builder
+            .clone()
+            .binary_detection(BinaryDetection::none())
+            .build()
+    }
+}
+
+/// A path visited by one of the threads of the walk, which is sent to be recorded by
+/// [`find_matches`].
+enum Visited {
+    /// A file that was searched, with its matching lines and the number of matches.
+    File {
+        path: PathBuf,
+        // boxed since the metadata is large on some platforms
+        meta: Box<std::fs::Metadata>,
+        lines: Vec<u64>,
+        num_matches: u64,
+        /// The lines were found in the cache.
+        cached: bool,
+    },
+    /// An error while walking or searching, and the path that couldn't be searched if it's known.
+    Error {
+        message: String,
+        path: Option<PathBuf>,
+    },
+}
+
+impl Visited {
+    fn file_error(path: &Path, e: impl std::fmt::Display) -> Self {
+        Self::Error {
+            message: format!("{}: {e}", path.display()),
+            path: Some(path.to_path_buf()),
+        }
+    }
+}
+
+/// A sink that passes each matching line and its (1-indexed) line number to a callback.
+pub(crate) struct LineSink<F> {
+    pub(crate) on_match: F,
+    /// Binary data was found, and the search stopped.
+    pub(crate) binary: bool,
+}
+
+impl<F: FnMut(u64, &[u8]) -> bool> Sink for LineSink<F> {
+    type Error = std::io::Error;
+
+    fn matched(&mut self, _searcher: &Searcher, mat: &SinkMatch) -> Result<bool, Self::Error> {
+        Ok((self.on_match)(mat.line_number().unwrap(), mat.bytes()))
+    }
+
+    fn binary_data(&mut self, _searcher: &Searcher, _offset: u64) -> Result<bool, Self::Error> {
+        self.binary = true;
+        Ok(false)
+    }
+}
+
+/// Which matching lines are recorded, and when a search can stop early.
+#[derive(Copy, Clone, Default)]
+pub(crate) struct SearchFilter<'a> {
+    /// Stop searching once there are more than this many matching lines in total.
+    pub max_total: Option<u64>,
+    /// Stop searching once this many files have matches.
+    pub max_files: Option<u64>,
+    /// Only the first matching line of each file is needed.
+    pub first_per_file: bool,
+    /// Only record the matching lines where this returns true, given the path, the (0-indexed)
+    /// line number, and the line.
+    pub keep_line: Option<&'a KeepLine<'a>>,
+    /// Called with the path of each file that couldn't be searched.
+    pub on_failure: Option<&'a dyn Fn(&Path)>,
+}
+
+pub(crate) type KeepLine<'a> = dyn Fn(&Path, u64, &[u8]) -> bool + Sync + 'a;
+
+/// Find matches. Any errors will be printed to stdout. If there is an error:
+/// - If `continue_on_err` is true, the error will be printed.
+/// - If `continue_on_err` is false, the error will be printed and it will continue to walk the
+///   filesystem looking for more errors, but it will stop searching files.
+pub(crate) fn find_matches(
+    matcher: &PatternMatcher,
+    paths: &[impl AsRef<Path>],
+    filters: PathFilters,
+    search: &SearchConfig,
+    continue_on_err: bool,
+    cache: Option<&mut MatchCache>,
+    filter: SearchFilter,
+) -> Result<BTreeMap<PathBuf, MatchInfo>, u64> {
+    let mut matches = BTreeMap::new();
+    let mut num_errors = 0;
+    let mut num_matches = 0;
+    let max_matches = filter.max_total;
+    let failed = |path: &Path| {
+        if let Some(on_failure) = filter.on_failure {
+            on_failure(path);
+        }
+    };
+
+    if paths.is_empty() {
+        return Ok(matches);
+    }
+
+    let watchdog = search.timeout.clone().map(search_watchdog);
+
+    // the walk never skips the paths that it was given, so we need to check them ourselves
+    let mut paths = paths
+        .iter()
+        .map(AsRef::as_ref)
+        .filter(|x| !filters.is_excluded(x));
+
+    let Some(first_path) = paths.next() else {
+        return Ok(matches);
+    };
+
+    let mut walk = WalkBuilder::new(first_path);
+    for path in paths {
+        walk.add(path);
+    }
+    // like ripgrep, ".rgignore" files take precedence over gitignore rules, and our own
+    // ".repatchignore" files take precedence over everything else
+    if filters.standard_filters && filters.ignore_files {
+        // like git, the global ignore file is read from `core.excludesFile` (or
+        // "$XDG_CONFIG_HOME/git/ignore" by default)
+        walk.hidden(!filters.hidden)
+            .git_ignore(filters.git_ignore)
+            .git_global(filters.git_ignore && filters.git_global)
+            .git_exclude(filters.git_ignore && filters.git_exclude);
+        walk.add_custom_ignore_filename(".rgignore");
+        walk.add_custom_ignore_filename(".repatchignore");
+        if let Some(path) = filters.global_ignore {
+            if let Some(e) = walk.add_ignore(path) {
+                error!("{}: {e}", path.display());
+                num_errors += 1;
+            }
+        }
+    } else if filters.standard_filters {
+        // only hidden files are skipped
+        walk.standard_filters(false).hidden(!filters.hidden);
+    } else {
+        walk.standard_filters(false);
+    }
+    // the files are walked in whatever order the threads reach them, so with a limit on the number
+    // of files, a single thread is used so that the same files are found each time
+    let threads = match filter.max_files {
+        Some(_) => 1,
+        None => search.threads,
+    };
+    let exclude = filters.exclude;
+    let outputs = filters.outputs;
+    let predicate = filters.predicate;
+    let walk = walk
+        .overrides(filters.overrides)
+        .types(filters.types)
+        .threads(threads)
+        .filter_entry(move |x| {
+            // with `--hidden`, the files of a repository's `.git` directory would be searched
+            let git_dir = x.depth() > 0 && x.file_name() == ".git";
+            let is_dir = x.file_type().is_some_and(|x| x.is_dir());
+            !git_dir
+                && !glob_matches(&exclude, x.path())
+                && !outputs.contains(x.path())
+                && predicate.as_ref().is_none_or(|f| f(x.path(), is_dir))
+        })
+        .build_parallel();
+
+    // set once the remaining files don't need to be searched
+    let stop = AtomicBool::new(false);
+    // set once there has been an error, after which the remaining files are only checked for
+    // errors (unless `continue_on_err` is true)
+    let errored = AtomicBool::new(num_errors > 0);
+    // the number of matches recorded so far, so that the search of a file can stop early
+    let total_matches = AtomicU64::new(0);
+    let cache = std::sync::Mutex::new(cache);
+
+    // each thread of the walk searches the files that it visits, and the results are recorded
+    // here as they arrive
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    std::thread::scope(|scope| {
+        let (stop, errored, total_matches, cache, watchdog) =
+            (&stop, &errored, &total_matches, &cache, &watchdog);
+
+        scope.spawn(move || {
+            walk.run(|| {
+                let sender = sender.clone();
+                let watchdog = watchdog.clone();
+                let (mut searcher, mut large_file_searcher) = search.build();
+                let mut walked = std::time::Instant::now();
+
+                Box::new(move |result| {
+                    crate::profile::add(Phase::Walk, walked.elapsed());
+
+                    if stop.load(Ordering::Relaxed) || search.is_cancelled() {
+                        return WalkState::Quit;
+                    }
+
+                    let visited = 'visit: {
+                        let entry = match result {
+                            Ok(x) => x,
+                            Err(e) => {
+                                break 'visit Some(Visited::Error {
+                                    message: e.to_string(),
+                                    path: walk_error_path(&e).map(Path::to_path_buf),
+                                });
+                            }
+                        };
+
+                        let path = entry.path();
+                        let meta = match std::fs::metadata(path) {
+                            Ok(x) => x,
+                            Err(e) => break 'visit Some(Visited::file_error(path, e)),
+                        };
+
+                        if meta.is_dir() {
+                            break 'visit None;
+                        }
+
+                        let size = meta.len();
+                        if search.skip_size.is_some_and(|max| size >= max) {
+                            warning!(
+                                "skipping '{}' since it's {} ('--skip-file-size')",
+                                path.display(),
+                                crate::ui::format_size(size),
+                            );
+                            break 'visit None;
+                        }
+                        if search.warn_size > 0 && size >= search.warn_size {
+                            warning!(
+                                "'{}' is {}, so it may be slow to search and patch",
+                                path.display(),
+                                crate::ui::format_size(size),
+                            );
+                        }
+
+                        if errored.load(Ordering::Relaxed) && !continue_on_err {
+                            // if we've already had an error, we still check if we can open the
+                            // remaining files
+                            break 'visit File::open(path)
+                                .err()
+                                .map(|e| Visited::file_error(path, e));
+                        }
+
+                        let cached = measure(Phase::Search, || {
+                            cache.lock().unwrap().as_mut()?.get(path, &meta)
+                        });
+                        if let Some(lines) = cached {
+                            break 'visit Some(Visited::File {
+                                path: path.to_path_buf(),
+                                num_matches: lines.len() as u64,
+                                lines,
+                                meta: Box::new(meta),
+                                cached: true,
+                            });
+                        }
+
+                        let started = std::time::Instant::now();
+                        let timed_out = || {
+                            let timeout = search.timeout.as_ref();
+                            timeout.is_some_and(|x| started.elapsed() > x.duration)
+                        };
+                        if let Some(watchdog) = &watchdog {
+                            let id = std::thread::current().id();
+                            let _ = watchdog.send((id, Some((path.to_path_buf(), started))));
+                        }
+
+                        let search_with = |searcher: &mut Searcher| {
+                            let mut lines = Vec::new();
+                            let mut num_matches = 0;
+
+                            let mut sink = LineSink {
+                                on_match: |line_num: u64, line: &[u8]| {
+                                    // line numbers are given starting from 1
+                                    let line_num = line_num.checked_sub(1).unwrap();
+
+                                    if search.skip_nul_lines && line.contains(&b'\0') {
+                                        return true;
+                                    }
+
+                                    // even though we found a match, we might replace it with the
+                                    // same value (ex: "foo" -> "foo"), in which case there's
+                                    // nothing to patch
+                                    if filter
+                                        .keep_line
+                                        .is_some_and(|keep| !keep(path, line_num, line))
+                                    {
+                                        return true;
+                                    }
+
+                                    // with `--multiline`, every line of a match is a matching line
+                                    let line_count = match search.multi_line {
+                                        true => crate::util::count_lines(line, b'\n'),
+                                        false => 1,
+                                    };
+                                    for line_num in line_num..line_num + line_count {
+                                        // a match can start on the line that the previous match
+                                        // ended
+                                        if lines.last().is_none_or(|x| *x < line_num) {
+                                            lines.push(line_num);
+                                        }
+                                    }
+
+                                    num_matches += 1;
+                                    // stop reading the file once further matches are irrelevant,
+                                    // or once it's over its time budget
+                                    let total = total_matches.load(Ordering::Relaxed) + num_matches;
+                                    max_matches.is_none_or(|max| total <= max)
+                                        && !filter.first_per_file
+                                        && !timed_out()
+                                        && !search.is_cancelled()
+                                },
+                                binary: false,
+                            };
+
+                            let result = measure(Phase::Search, || {
+                                searcher.search_path(matcher, path, &mut sink)
+                            });
+                            let binary = sink.binary;
+                            (result, lines, num_matches, binary)
+                        };
+
+                        let searcher = match meta.len() >= search.mmap_threshold {
+                            true => &mut large_file_searcher,
+                            false => &mut searcher,
+                        };
+                        let (mut result, mut lines, mut num_matches, mut binary) =
+                            search_with(searcher);
+
+                        // like git, search files with binary data if their attributes say they're
+                        // text
+                        if binary && result.is_ok() && is_git_text(path) {
+                            (result, lines, num_matches, binary) =
+                                search_with(&mut search.build_text());
+                        }
+
+                        if let Some(watchdog) = &watchdog {
+                            let _ = watchdog.send((std::thread::current().id(), None));
+                        }
+
+                        if let Err(e) = result {
+                            // could not read the file
+                            break 'visit Some(Visited::file_error(path, e));
+                        }
+
+                        if let (Some(timeout), true) = (&search.timeout, timed_out()) {
+                            // skip the file, including any matches found before the timeout
+                            break 'visit Some(Visited::Error {
+                                message: format!(
+                                    "{}: searching for '{}' took longer than the \
+                                    '--file-timeout' of {:?}",
+                                    path.display(),
+                                    timeout.pattern,
+                                    timeout.duration,
+                                ),
+                                path: Some(path.to_path_buf()),
+                            });
+                        }
+
+                        if binary {
+                            // skip the file, including any matches before the binary data
+                            lines.clear();
+                            num_matches = 0;
+                        }
+
+                        Some(Visited::File {
+                            path: path.to_path_buf(),
+                            meta: Box::new(meta),
+                            lines,
+                            num_matches,
+                            cached: false,
+                        })
+                    };
+
+                    if let Some(visited) = visited {
+                        if sender.send(visited).is_err() {
+                            return WalkState::Quit;
+                        }
+                    }
+
+                    walked = std::time::Instant::now();
+                    WalkState::Continue
+                })
+            })
+        });
+
+        for visited in receiver {
+            // the threads may have visited more files before they saw that they should stop
+            if stop.load(Ordering::Relaxed) {
+                continue;
+            }
+
+            match visited {
+                Visited::File {
+                    path,
+                    meta,
+                    lines,
+                    num_matches: file_matches,
+                    cached,
+                } => {
+                    // this is only a very basic check; we may have already visited this file
+                    // through some other path (relative or absolute path, another hard link to the
+                    // same file, etc) and we don't defend against these here
+                    if matches.contains_key(&path) {
+                        // already visited this path and it had a match
+                        continue;
+                    }
+
+                    num_matches += file_matches;
+                    total_matches.store(num_matches, Ordering::Relaxed);
+                    // the search may have stopped early
+                    let over_max = max_matches.is_some_and(|max| num_matches > max);
+
+                    if let (Some(cache), false) =
+                        (cache.lock().unwrap().as_mut(), over_max || cached)
+                    {
+                        cache.insert(&path, &meta, lines.clone());
+                    }
+
+                    if !lines.is_empty() {
+                        let mut info = MatchInfo::new(&path, meta.modified().unwrap());
+                        info.lines = lines;
+                        matches.insert(path, info);
+                    }
+
+                    // the caller will see that there are too many matches, so there's no point
+                    // continuing
+                    let enough_files = filter
+                        .max_files
+                        .is_some_and(|max| matches.len() as u64 >= max);
+                    if over_max || enough_files {
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                }
+                Visited::Error { message, path } => {
+                    error!("{message}");
+                    num_errors += 1;
+                    errored.store(true, Ordering::Relaxed);
+                    if let Some(path) = path {
+                        failed(&path);
+                    }
+                }
+            }
+        }
+    });
+
+    // like git, skip files whose attributes say they're binary
+    let attributes = crate::git::attributes(matches.keys().map(PathBuf::as_path));
+    matches.retain(|path, info| {
+        let attrs = attributes.get(path).copied().unwrap_or_default();
+        info.eol = attrs.eol;
+        let binary = search.skip_binary && attrs.text == Some(false);
+        let generated = search.skip_generated && (attrs.generated || looks_generated(path));
+        !binary && !generated
+    });
+
+    if num_errors == 0 || continue_on_err {
+        Ok(matches)
+    } else {
+        Err(num_errors)
+    }
+}
+
+/// Find the files that don't start with `header`, and the lines of each file that the header
+/// replaces, for `--ensure-header`. Returns the number of errors if there were any errors and
+/// `continue_on_err` was false.
+pub(crate) fn find_header_matches(
+    matcher: &PatternMatcher,
+    header: &[u8],
+    paths: &[impl AsRef<Path>],
+    filters: PathFilters,
+    search: &SearchConfig,
+    continue_on_err: bool,
+    on_failure: Option<&dyn Fn(&Path)>,
+) -> anyhow::Result<Result<BTreeMap<PathBuf, MatchInfo>, u64>> {
+    // every line matches the empty pattern, so every file that isn't empty is found
+    let any_line = PatternMatcher::new("")?;
+    let filter = SearchFilter {
+        first_per_file: true,
+        on_failure,
+        ..Default::default()
+    };
+    let files = match find_matches(
+        &any_line,
+        paths,
+        filters,
+        search,
+        continue_on_err,
+        None,
+        filter,
+    ) {
+        Ok(x) => x,
+        Err(num_errors) => return Ok(Err(num_errors)),
+    };
+
+    let mut matches = BTreeMap::new();
+    for (path, mut info) in files {
+        let bytes = std::fs::read(&path)
+            .with_context(|| format!("could not read file '{}'", path.display()))?;
+        if let Some(lines) = crate::util::header_lines(matcher, &bytes, header) {
+            info.lines = lines;
+            matches.insert(path, info);
+        }
+    }
+
+    Ok(Ok(matches))
+}
+
+/// The path that a walk error is about, if any.
+fn walk_error_path(e: &ignore::Error) -> Option<&Path> {
+    match e {
+        ignore::Error::WithPath { path, .. } => Some(path),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            walk_error_path(err)
+        }
+        _ => None,
+    }
+}
+
+/// The thread searching a file, and the file being searched and when its search started.
+type SearchStart = (std::thread::ThreadId, Option<(PathBuf, std::time::Instant)>);
+
+/// Start a thread that warns when the search of a file takes longer than `timeout`, since the
+/// search can't be interrupted while it's within a line. Each searching thread sends the file being
+/// searched and the time that its search started, or `None` once the search finishes. The thread
+/// exits when the senders are dropped.
+fn search_watchdog(timeout: SearchTimeout) -> std::sync::mpsc::Sender<SearchStart> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    std::thread::spawn(move || {
+        let mut current = HashMap::<_, (PathBuf, std::time::Instant)>::new();
+        loop {
+            let wait = match current.values().map(|(_, started)| *started).min() {
+                Some(started) => (started + timeout.duration)
+                    .saturating_duration_since(std::time::Instant::now()),
+                None => std::time::Duration::MAX,
+            };
+            match receiver.recv_timeout(wait) {
+                Ok((thread, Some(x))) => {
+                    current.insert(thread, x);
+                }
+                Ok((thread, None)) => {
+                    current.remove(&thread);
+                }
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                    current.retain(|_, (path, started)| {
+                        if started.elapsed() < timeout.duration {
+                            return true;
+                        }
+                        warning!(
+                            "searching '{}' for '{}' is taking longer than {:?}; the file will \
+                            be skipped once its search finishes",
+                            path.display(),
+                            timeout.pattern,
+                            timeout.duration,
+                        );
+                        false
+                    });
+                }
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+
+    sender
+}
+
+/// Whether the file at `path` starts with a marker like "@generated" or "DO NOT EDIT".
+fn looks_generated(path: &Path) -> bool {
+    let mut head = Vec::new();
+    let read = File::open(path).and_then(|x| x.take(4096).read_to_end(&mut head));
+    read.is_ok() && crate::util::has_generated_marker(&head)
+}
+
+/// Whether the `.gitattributes` of the file at `path` say that it's a text file.
+fn is_git_text(path: &Path) -> bool {
+    let attributes = crate::git::attributes([path]);
+    attributes.get(path).is_some_and(|x| x.text == Some(true))
+}
+
+/// Read the matching lines from stdin, rather than searching for them. Returns the number of
+/// errors if there were any errors and `continue_on_err` was false.
+pub(crate) fn read_matches(
+    source: MatchSource,
+    continue_on_err: bool,
+) -> anyhow::Result<Result<BTreeMap<PathBuf, MatchInfo>, u64>> {
+    let mut input = String::new();
+    std::io::Read::read_to_string(&mut std::io::stdin().lock(), &mut input)
+        .context("could not read the matches from stdin")?;
+
+    let lines = match source {
+        MatchSource::RgJson => crate::match_input::parse_rg_json(&input),
+        MatchSource::Lines => Ok(crate::match_input::parse_lines(&input)),
+    }
+    .context("could not parse the matches from stdin")?;
+
+    Ok(collect_matches(lines, continue_on_err))
+}
+
+/// Build the matches from a list of files and (0-indexed) line numbers. Returns the number of
+/// errors if there were any errors and `continue_on_err` was false.
+pub(crate) fn collect_matches(
+    lines: Vec<(PathBuf, u64)>,
+    continue_on_err: bool,
+) -> Result<BTreeMap<PathBuf, MatchInfo>, u64> {
+    let mut matches = BTreeMap::new();
+    let mut num_errors = 0;
+
+    for (path, line) in lines {
+        if !matches.contains_key(&path) {
+            let meta = match std::fs::metadata(&path) {
+                Ok(x) if x.is_file() => x,
+                Ok(_) => {
+                    error!("{}: not a file", path.display());
+                    num_errors += 1;
+                    continue;
+                }
+                Err(e) => {
+                    error!("{}: {e}", path.display());
+                    num_errors += 1;
+                    continue;
+                }
+            };
+            let info = MatchInfo::new(&path, meta.modified().unwrap());
+            matches.insert(path.clone(), info);
+        }
+
+        let lines = &mut matches.get_mut(&path).unwrap().lines;
+        if !lines.contains(&line) {
+            lines.push(line);
+        }
+    }
+
+    if num_errors > 0 && !continue_on_err {
+        return Err(num_errors);
+    }
+
+    Ok(matches)
+}
+
+pub(crate) struct MatchInfo {
+    pub(crate) modified: SystemTime,
+    pub(crate) lines: Vec<u64>,
+    /// The path to show to the user.
+    pub(crate) display_path: PathBuf,
+    /// The line endings that replaced lines should have, from the file's `.gitattributes`.
+    pub(crate) eol: Option<crate::git::Eol>,
+    /// Whether the path was a symbolic link when it was searched.
+    symlink: bool,
+}
+
+impl MatchInfo {
+    pub fn new(path: &Path, modified: SystemTime) -> Self {
+        Self {
+            modified,
+            lines: Vec::new(),
+            display_path: path.to_path_buf(),
+            eol: None,
+            symlink: path.is_symlink(),
+        }
+    }
+
+    /// Why the file at `path` can't be patched anymore, if it was deleted, renamed, or replaced by
+    /// something other than a file since it was searched.
+    pub(crate) fn vanished(&self, path: &Path) -> Option<&'static str> {
+        match std::fs::metadata(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some("it was deleted or renamed"),
+            Ok(x) if x.is_dir() => Some("it's now a directory"),
+            Ok(x) if !x.is_file() => Some("it's no longer a regular file"),
+            _ if path.is_symlink() && !self.symlink => Some("it's now a symbolic link"),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,449 @@
+//! A line-delimited JSON protocol on stdin/stdout, allowing other programs (such as editor
+//! extensions) to drive a session.
+//!
+//! Each request is a JSON object on a single line with an "id", a "method", and optional "params".
+//! Each response is a JSON object on a single line with the request's "id", and either a "result"
+//! or an "error" message. Hunks are numbered from 0 within each file, and text that isn't valid
+//! UTF-8 is converted lossily.
+//!
+//! Methods:
+//! - `list_matches`: the files with matches and the number of matching lines in each.
+//! - `fetch_hunk {path, hunk}`: the original and replaced text of a hunk, the ids of the matches
+//!   that it replaces (which are the same across runs, see [`crate::decisions::MatchIds`]), and
+//!   the total number of hunks in the file.
+//! - `answer_hunk {path, hunk, answer, content?}`: answer "yes" or "no" to a hunk. With "yes", the
+//!   optional "content" is written instead of the replaced text.
+//! - `commit_file {path}`: write the answered hunks of the file. Unanswered hunks are treated as
+//!   "no". The file can't be used in any further requests.
+//! - `shutdown`: exit.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+use bstr::ByteSlice;
+
+use crate::json::Value;
+use crate::replace::ReplaceOptions;
+use crate::search::MatchInfo;
+use crate::ui::PatchOption;
+use crate::util::ReplaceFileError;
+
+/// A hunk where something was replaced.
+struct ServeHunk {
+    start_line: u64,
+    original: Vec<u8>,
+    replaced: Vec<u8>,
+    match_ids: Vec<String>,
+}
+
+struct Session<'a> {
+    options: &'a ReplaceOptions<'a>,
+    matches: BTreeMap<PathBuf, MatchInfo>,
+    /// The answered hunks of each file, where `None` means "no".
+    answers: HashMap<PathBuf, BTreeMap<usize, Option<Vec<u8>>>>,
+}
+
+/// Handle requests from stdin until stdin is closed or a `shutdown` request is received.
+pub fn serve(
+    options: &ReplaceOptions,
+    matches: BTreeMap<PathBuf, MatchInfo>,
+) -> anyhow::Result<()> {
+    let stdin = std::io::stdin().lock();
+    let stdout = std::io::stdout().lock();
+    serve_with(options, matches, stdin, stdout)
+}
+
+/// Handle requests from `input` and write the responses to `output`, until `input` ends or a
+/// `shutdown` request is received.
+fn serve_with(
+    options: &ReplaceOptions,
+    matches: BTreeMap<PathBuf, MatchInfo>,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> anyhow::Result<()> {
+    let mut session = Session {
+        options,
+        matches,
+        answers: HashMap::new(),
+    };
+
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (id, result) = match crate::json::parse(&line) {
+            Ok(request) => {
+                let id = request.get("id").cloned().unwrap_or(Value::Null);
+                (id, session.handle(&request))
+            }
+            Err(e) => (Value::Null, Err(anyhow::anyhow!("invalid request: {e}"))),
+        };
+
+        let shutdown = matches!(result, Ok(Response::Shutdown));
+
+        let response = match result {
+            Ok(Response::Value(x)) => Value::object([("id", id), ("result", x)]),
+            Ok(Response::Shutdown) => Value::object([("id", id), ("result", Value::Null)]),
+            Err(e) => Value::object([("id", id), ("error", format!("{e:#}").into())]),
+        };
+
+        writeln!(output, "{response}")?;
+        output.flush()?;
+
+        if shutdown {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+enum Response {
+    Value(Value),
+    Shutdown,
+}
+
+impl Session<'_> {
+    fn handle(&mut self, request: &Value) -> anyhow::Result<Response> {
+        let params = request.get("params").unwrap_or(&Value::Null);
+
+        let method = request
+            .get("method")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow::anyhow!("missing 'method'"))?;
+
+        let result = match method {
+            "list_matches" => self.list_matches(),
+            "fetch_hunk" => self.fetch_hunk(params)?,
+            "answer_hunk" => self.answer_hunk(params)?,
+            "commit_file" => self.commit_file(params)?,
+            "shutdown" => return Ok(Response::Shutdown),
+            x => anyhow::bail!("unknown method '{x}'"),
+        };
+
+        Ok(Response::Value(result))
+    }
+
+    fn list_matches(&self) -> Value {
+        let files = self
+            .matches
+            .iter()
+            .map(|(path, info)| {
+                Value::object([
+                    ("path", path.to_string_lossy().as_ref().into()),
+                    ("matches", info.lines.len().into()),
+                ])
+            })
+            .collect::<Vec<_>>();
+
+        Value::object([("files", files.into())])
+    }
+
+    fn fetch_hunk(&mut self, params: &Value) -> anyhow::Result<Value> {
+        let path = path_param(params)?;
+        let idx = hunk_param(params)?;
+        let hunks = self.hunks(&path)?;
+
+        let Some(hunk) = hunks.get(idx) else {
+            anyhow::bail!("'{}' has no hunk {idx}", path.display());
+        };
+
+        let answer = match self.answers.get(&path).and_then(|x| x.get(&idx)) {
+            Some(Some(_)) => "yes".into(),
+            Some(None) => "no".into(),
+            None => Value::Null,
+        };
+
+        let match_ids: Vec<Value> = hunk.match_ids.iter().map(|x| x.as_str().into()).collect();
+
+        Ok(Value::object([
+            ("hunk", idx.into()),
+            ("count", hunks.len().into()),
+            // 1-indexed, as shown to users
+            ("start_line", (hunk.start_line + 1).into()),
+            ("original", hunk.original.to_str_lossy().as_ref().into()),
+            ("replaced", hunk.replaced.to_str_lossy().as_ref().into()),
+            ("matches", match_ids.into()),
+            ("answer", answer),
+        ]))
+    }
+
+    fn answer_hunk(&mut self, params: &Value) -> anyhow::Result<Value> {
+        let path = path_param(params)?;
+        let idx = hunk_param(params)?;
+        let hunks = self.hunks(&path)?;
+
+        let Some(hunk) = hunks.get(idx) else {
+            anyhow::bail!("'{}' has no hunk {idx}", path.display());
+        };
+
+        let content = params.get("content").map(|x| {
+            x.as_str()
+                .map(|x| x.as_bytes().to_vec())
+                .ok_or_else(|| anyhow::anyhow!("'content' must be a string"))
+        });
+
+        let answer = match params.get("answer").and_then(Value::as_str) {
+            Some("yes") => Some(
+                content
+                    .transpose()?
+                    .unwrap_or_else(|| hunk.replaced.clone()),
+            ),
+            Some("no") => None,
+            _ => anyhow::bail!("'answer' must be \"yes\" or \"no\""),
+        };
+
+        self.answers.entry(path).or_default().insert(idx, answer);
+
+        Ok(Value::Null)
+    }
+
+    fn commit_file(&mut self, params: &Value) -> anyhow::Result<Value> {
+        let path = path_param(params)?;
+        let Some(mut match_info) = self.matches.remove(&path) else {
+            anyhow::bail!("'{}' has no matches", path.display());
+        };
+        let answers = self.answers.remove(&path).unwrap_or_default();
+
+        let outcome = crate::util::replace_file(
+            &path,
+            Some(match_info.modified),
+            /* fsync= */ false,
+            |src, dest| {
+                let mut idx = 0;
+                let outcome = crate::replace::replace_matches(
+                    self.options,
+                    &match_info.display_path,
+                    src,
+                    Some(dest),
+                    &mut match_info.lines,
+                    match_info.eol,
+                    |_hunk| {
+                        let option = match answers.get(&idx) {
+                            Some(Some(x)) => PatchOption::WriteNew(x.clone()),
+                            _ => PatchOption::WriteOriginal,
+                        };
+                        idx += 1;
+                        option
+                    },
+                );
+                let write =
+                    matches!(&outcome, Ok(x) if x.write_file == crate::replace::WriteFile::Yes);
+                (write, outcome)
+            },
+        );
+
+        // an error while writing the new file is reported like an error while replacing the file
+        let outcome = match outcome.and_then(|x| x.map_err(ReplaceFileError::Io)) {
+            Ok(x) => x,
+            Err(ReplaceFileError::Io(e)) => {
+                return Err(anyhow::Error::new(e)
+                    .context(format!("could not replace file '{}'", path.display())))
+            }
+            Err(ReplaceFileError::ModifiedTimeChanged) => anyhow::bail!(
+                "the file '{}' was modified by another program",
+                path.display(),
+            ),
+        };
+
+        Ok(Value::object([(
+            "written",
+            (outcome.write_file == crate::replace::WriteFile::Yes).into(),
+        )]))
+    }
+
+    /// The hunks of the file where something was replaced.
+    fn hunks(&mut self, path: &PathBuf) -> anyhow::Result<Vec<ServeHunk>> {
+        let Some(match_info) = self.matches.get_mut(path) else {
+            anyhow::bail!("'{}' has no matches", path.display());
+        };
+
+        let src = std::fs::File::open(path)?;
+        let mut hunks = Vec::new();
+
+        crate::replace::replace_matches(
+            self.options,
+            &match_info.display_path,
+            &src,
+            None::<&std::fs::File>,
+            &mut match_info.lines,
+            match_info.eol,
+            |hunk| {
+                hunks.push(ServeHunk {
+                    start_line: hunk.start_line,
+                    original: hunk.original.to_vec(),
+                    replaced: hunk.replaced.to_vec(),
+                    match_ids: hunk.match_ids.to_vec(),
+                });
+                PatchOption::WriteOriginal
+            },
+        )?;
+
+        Ok(hunks)
+    }
+}
+
+fn path_param(params: &Value) -> anyhow::Result<PathBuf> {
+    params
+        .get("path")
+        .and_then(Value::as_str)
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow::anyhow!("missing 'path'"))
+}
+
+fn hunk_param(params: &Value) -> anyhow::Result<usize> {
+    params
+        .get("hunk")
+        .and_then(Value::as_u64)
+        .and_then(|x| x.try_into().ok())
+        .ok_or_else(|| anyhow::anyhow!("missing 'hunk'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::engine::PatternMatcher;
+    use crate::util::Replacement;
+
+    /// Serve the requests for the files in `dir`, where each file has matches on the given lines,
+    /// and return the responses.
+    fn session(dir: &std::path::Path, files: &[(&str, &[u64])], requests: &[&str]) -> Vec<Value> {
+        let matcher = PatternMatcher::new("foo").unwrap();
+        let options = ReplaceOptions::new(&matcher, Replacement::Template(b"bar"));
+
+        let mut matches = BTreeMap::new();
+        for (name, lines) in files {
+            let path = dir.join(name);
+            let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+            let mut info = MatchInfo::new(&path, modified);
+            info.lines = lines.to_vec();
+            matches.insert(path, info);
+        }
+
+        let input = requests.join("\n");
+        let mut output = Vec::new();
+        serve_with(&options, matches, input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        output
+            .lines()
+            .map(|x| crate::json::parse(x).unwrap())
+            .collect()
+    }
+
+    /// A request as a line of JSON.
+    fn request(id: u64, method: &str, params: Value) -> String {
+        let request = [
+            ("id", id.into()),
+            ("method", method.into()),
+            ("params", params),
+        ];
+        Value::object(request).to_string()
+    }
+
+    fn error(response: &Value) -> &str {
+        response.get("error").and_then(Value::as_str).unwrap()
+    }
+
+    #[test]
+    fn test_serve() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "foo\nx\nfoo\n").unwrap();
+        let path = dir.path().join("a.txt");
+        let path = path.to_str().unwrap();
+        let hunk = |idx: u64| Value::object([("path", path.into()), ("hunk", idx.into())]);
+
+        let requests = [
+            request(1, "list_matches", Value::Null),
+            request(2, "fetch_hunk", hunk(1)),
+            request(
+                3,
+                "answer_hunk",
+                Value::object([
+                    ("path", path.into()),
+                    ("hunk", 1u64.into()),
+                    ("answer", "yes".into()),
+                    ("content", "baz\n".into()),
+                ]),
+            ),
+            request(4, "fetch_hunk", hunk(1)),
+            request(5, "commit_file", Value::object([("path", path.into())])),
+            request(6, "shutdown", Value::Null),
+            // ignored after the shutdown
+            request(7, "list_matches", Value::Null),
+        ];
+        let requests: Vec<_> = requests.iter().map(String::as_str).collect();
+        let responses = session(dir.path(), &[("a.txt", &[0, 2])], &requests);
+        assert_eq!(responses.len(), 6);
+
+        for (i, response) in responses.iter().enumerate() {
+            assert_eq!(response.get("id"), Some(&Value::from(i as u64 + 1)));
+            assert_eq!(response.get("error"), None, "{response}");
+        }
+
+        let file = Value::object([("path", path.into()), ("matches", 2u64.into())]);
+        let files = Value::object([("files", vec![file].into())]);
+        assert_eq!(responses[0].get("result"), Some(&files));
+
+        let hunk = responses[1].get("result").unwrap();
+        assert_eq!(hunk.get("count").and_then(Value::as_u64), Some(2));
+        assert_eq!(hunk.get("start_line").and_then(Value::as_u64), Some(3));
+        assert_eq!(hunk.get("original").and_then(Value::as_str), Some("foo\n"));
+        assert_eq!(hunk.get("replaced").and_then(Value::as_str), Some("bar\n"));
+        assert_eq!(hunk.get("answer"), Some(&Value::Null));
+
+        let hunk = responses[3].get("result").unwrap();
+        assert_eq!(hunk.get("answer").and_then(Value::as_str), Some("yes"));
+
+        let written = Value::object([("written", true.into())]);
+        assert_eq!(responses[4].get("result"), Some(&written));
+        assert_eq!(responses[5].get("result"), Some(&Value::Null));
+
+        // the unanswered hunk is kept
+        let text = std::fs::read_to_string(dir.path().join("a.txt")).unwrap();
+        assert_eq!(text, "foo\nx\nbaz\n");
+    }
+
+    #[test]
+    fn test_serve_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "foo\n").unwrap();
+        let path = dir.path().join("a.txt");
+        let path = path.to_str().unwrap();
+
+        let answer = Value::object([
+            ("path", path.into()),
+            ("hunk", 0u64.into()),
+            ("answer", "yes".into()),
+        ]);
+        let requests = [
+            request(1, "rename_file", Value::Null),
+            r#"{"id": 2, "method": "#.to_string(),
+            request(3, "commit_file", Value::object([("path", path.into())])),
+            // the file was already committed
+            request(4, "answer_hunk", answer),
+        ];
+        let requests: Vec<_> = requests.iter().map(String::as_str).collect();
+        let responses = session(dir.path(), &[("a.txt", &[0])], &requests);
+        assert_eq!(responses.len(), 4);
+
+        assert_eq!(responses[0].get("id"), Some(&Value::from(1u64)));
+        assert_eq!(error(&responses[0]), "unknown method 'rename_file'");
+
+        // the id of a request that can't be parsed isn't known
+        assert_eq!(responses[1].get("id"), Some(&Value::Null));
+        assert!(error(&responses[1]).starts_with("invalid request: "));
+
+        // with no answered hunks, nothing is written
+        let written = Value::object([("written", false.into())]);
+        assert_eq!(responses[2].get("result"), Some(&written));
+
+        assert_eq!(responses[3].get("id"), Some(&Value::from(4u64)));
+        assert_eq!(error(&responses[3]), format!("'{path}' has no matches"));
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "foo\n");
+    }
+}
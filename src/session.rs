@@ -0,0 +1,482 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use grep_regex::RegexMatcherBuilder;
+
+use crate::engine::PatternMatcher;
+use crate::replace::{Continue, ReplaceOptions, WriteFile};
+use crate::search::{MatchInfo, PathFilters, PathPredicate};
+use crate::ui::PatchOption;
+use crate::util::ReplaceFileError;
+use crate::{FileOutcome, Hunk};
+
+/// Builds a [`Session`].
+#[derive(Clone)]
+pub struct SessionBuilder {
+    case_insensitive: bool,
+    ignore_errors: bool,
+    context: u64,
+    line_terminator: u8,
+    path_filter: Option<Arc<PathPredicate>>,
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl std::fmt::Debug for SessionBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionBuilder")
+            .field("case_insensitive", &self.case_insensitive)
+            .field("ignore_errors", &self.ignore_errors)
+            .field("context", &self.context)
+            .field("line_terminator", &self.line_terminator)
+            .field("path_filter", &self.path_filter.as_ref().map(|_| ".."))
+            .field("cancel", &self.cancel)
+            .finish()
+    }
+}
+
+impl Default for SessionBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionBuilder {
+    pub fn new() -> Self {
+        Self {
+            case_insensitive: false,
+            ignore_errors: false,
+            context: 5,
+            line_terminator: b'\n',
+            path_filter: None,
+            cancel: None,
+        }
+    }
+
+    /// Case-insensitive search. Disabled by default.
+    pub fn case_insensitive(&mut self, yes: bool) -> &mut Self {
+        self.case_insensitive = yes;
+        self
+    }
+
+    /// Ignore filesystem-related errors while searching. Disabled by default.
+    pub fn ignore_errors(&mut self, yes: bool) -> &mut Self {
+        self.ignore_errors = yes;
+        self
+    }
+
+    /// The number of lines of context around each match. Matches that are close together are
+    /// combined into a single hunk. The default is 5.
+    pub fn context(&mut self, lines: u64) -> &mut Self {
+        self.context = lines;
+        self
+    }
+
+    /// The line terminator. The default is "\n".
+    pub fn line_terminator(&mut self, byte: u8) -> &mut Self {
+        self.line_terminator = byte;
+        self
+    }
+
+    /// Only search the files and directories where `filter` returns true, in addition to the
+    /// built-in ignore handling. It's given each path and whether it's a directory, and the
+    /// directories that it rejects aren't searched. By default every path is searched.
+    pub fn path_filter(
+        &mut self,
+        filter: impl Fn(&Path, bool) -> bool + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.path_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Stop searching and writing files soon after `token` is set to true, such as when the user
+    /// of a GUI cancels the operation. [`build`](Self::build) returns [`Error::Cancelled`] if it's
+    /// cancelled while searching, and [`Session::run`] returns the files that were processed
+    /// before it was cancelled. A file is either written completely or not at all, and the file
+    /// that was being processed is left for a later call of [`Session::run`].
+    pub fn cancel_token(&mut self, token: Arc<AtomicBool>) -> &mut Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Search `paths` recursively for the regex `find`, which will be replaced with `replace`.
+    /// Capture group indices and names are supported in `replace`.
+    pub fn build(
+        &self,
+        find: &str,
+        replace: &str,
+        paths: &[impl AsRef<Path>],
+    ) -> Result<Session, Error> {
+        let mut matcher = RegexMatcherBuilder::new();
+        matcher.case_insensitive(self.case_insensitive);
+        if self.line_terminator != b'\n' {
+            matcher.line_terminator(Some(self.line_terminator));
+        }
+        let matcher = PatternMatcher::from(matcher.build(find)?);
+
+        let mut filters = PathFilters::empty();
+        filters.predicate = self.path_filter.clone();
+
+        let mut search = crate::search::SearchConfig::new(self.line_terminator);
+        search.cancel = self.cancel.clone();
+
+        let matches = crate::search::find_matches(
+            &matcher,
+            paths,
+            filters,
+            &search,
+            self.ignore_errors,
+            None,
+            crate::search::SearchFilter::default(),
+        )
+        .map_err(|num_errors| Error::Search { num_errors })?;
+
+        // some of the files may not have been searched
+        if search.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+
+        Ok(Session {
+            matcher,
+            replace_with: replace.as_bytes().to_vec(),
+            padding: self.context,
+            line_terminator: self.line_terminator,
+            matches,
+            cancel: self.cancel.clone(),
+        })
+    }
+}
+
+/// A find & replace session, where each hunk is answered by a callback rather than by prompting
+/// the user.
+///
+/// ```no_run
+/// let mut session = repatch::SessionBuilder::new()
+///     .build("foo", "bar", &["src"])
+///     .unwrap();
+///
+/// // only replace hunks near the start of each file
+/// let outcomes = session
+///     .run(|_path, hunk| match hunk.start_line() < 100 {
+///         true => repatch::Answer::Yes,
+///         false => repatch::Answer::No,
+///     })
+///     .unwrap();
+/// ```
+pub struct Session {
+    matcher: PatternMatcher,
+    replace_with: Vec<u8>,
+    padding: u64,
+    line_terminator: u8,
+    /// The files that haven't been processed yet.
+    matches: BTreeMap<PathBuf, MatchInfo>,
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl Session {
+    /// The files with matches that haven't been processed yet, and the number of matching lines in
+    /// each.
+    pub fn files(&self) -> impl Iterator<Item = (&Path, usize)> {
+        self.matches
+            .iter()
+            .map(|(path, info)| (path.as_path(), info.lines.len()))
+    }
+
+    /// Call `decide` for each hunk of each file where something would be replaced, and write the
+    /// changes to the files. Returns what happened to each file that was processed.
+    ///
+    /// If `decide` returns [`Answer::Quit`], the answers for the earlier hunks of the current file
+    /// are still written and any remaining files are left for a later call. If the session's
+    /// [cancellation token](SessionBuilder::cancel_token) is set, the current file isn't written
+    /// and it's left for a later call along with the remaining files.
+    pub fn run(
+        &mut self,
+        mut decide: impl FnMut(&Path, &Hunk) -> Answer,
+    ) -> Result<Vec<FileOutcome>, Error> {
+        let options = ReplaceOptions {
+            padding: self.padding,
+            line_terminator: self.line_terminator,
+            ..ReplaceOptions::new(
+                &self.matcher,
+                crate::util::Replacement::Template(&self.replace_with),
+            )
+        };
+
+        let mut outcomes = Vec::new();
+        let is_cancelled = || {
+            self.cancel
+                .as_ref()
+                .is_some_and(|x| x.load(Ordering::Relaxed))
+        };
+
+        while !is_cancelled() {
+            let Some((path, mut match_info)) = self.matches.pop_first() else {
+                break;
+            };
+            let started = std::time::Instant::now();
+            let mut cancelled = false;
+            let outcome = crate::util::replace_file(
+                &path,
+                Some(match_info.modified),
+                /* fsync= */ false,
+                |src, dest| {
+                    let outcome = crate::replace::replace_matches(
+                        &options,
+                        &path,
+                        src,
+                        Some(dest),
+                        &mut match_info.lines,
+                        match_info.eol,
+                        |hunk| {
+                            if is_cancelled() {
+                                cancelled = true;
+                                return PatchOption::Quit;
+                            }
+                            match decide(&path, hunk) {
+                                Answer::Yes => PatchOption::WriteNew(hunk.replaced.to_vec()),
+                                Answer::No => PatchOption::WriteOriginal,
+                                Answer::Replace(x) => PatchOption::WriteNew(x),
+                                Answer::Quit => PatchOption::Quit,
+                            }
+                        },
+                    );
+                    // the file may have been cancelled after the last hunk
+                    cancelled |= is_cancelled();
+                    let write = matches!(&outcome, Ok(x) if x.write_file == WriteFile::Yes);
+                    (write && !cancelled, outcome)
+                },
+            );
+
+            // an error while writing the new file is reported like an error while replacing the
+            // file
+            let outcome = match outcome.and_then(|x| x.map_err(ReplaceFileError::Io)) {
+                Ok(x) => x,
+                Err(ReplaceFileError::Io(source)) => return Err(Error::Io { path, source }),
+                Err(ReplaceFileError::ModifiedTimeChanged) => return Err(Error::Modified(path)),
+            };
+
+            if cancelled {
+                self.matches.insert(path, match_info);
+                break;
+            }
+
+            let bytes_written = match outcome.write_file {
+                WriteFile::Yes => std::fs::metadata(&path)
+                    .map_err(|source| Error::Io {
+                        path: path.clone(),
+                        source,
+                    })?
+                    .len(),
+                WriteFile::No => 0,
+            };
+            outcomes.push(FileOutcome {
+                applied_hunks: outcome.changed_lines.len() as u64,
+                skipped_hunks: outcome.skipped_hunks,
+                bytes_written,
+                ..FileOutcome::new(&path, started)
+            });
+
+            if outcome.cont == Continue::No {
+                break;
+            }
+        }
+
+        Ok(outcomes)
+    }
+}
+
+/// The answer for a hunk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Answer {
+    /// Replace the hunk.
+    Yes,
+    /// Don't replace the hunk.
+    No,
+    /// Replace the hunk with the given bytes instead.
+    Replace(Vec<u8>),
+    /// Don't replace the hunk or any later hunks.
+    Quit,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The regex could not be built.
+    Regex(grep_regex::Error),
+    /// There were errors while searching.
+    Search { num_errors: u64 },
+    /// The file could not be replaced.
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The file was modified by another program after it was searched.
+    Modified(PathBuf),
+    /// The search was cancelled with the [cancellation token](SessionBuilder::cancel_token).
+    Cancelled,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Regex(e) => write!(f, "{e}"),
+            Self::Search { num_errors } => write!(f, "found {num_errors} errors while searching"),
+            Self::Io { path, source } => {
+                write!(f, "could not replace file '{}': {source}", path.display())
+            }
+            Self::Modified(path) => write!(
+                f,
+                "the file '{}' was modified by another program",
+                path.display(),
+            ),
+            Self::Cancelled => write!(f, "the search was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Regex(e) => Some(e),
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<grep_regex::Error> for Error {
+    fn from(e: grep_regex::Error) -> Self {
+        Self::Regex(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("vendor")).unwrap();
+        let path_1 = dir.path().join("a.txt");
+        let path_2 = dir.path().join("b.txt");
+        std::fs::write(&path_1, "foo\n").unwrap();
+        std::fs::write(&path_2, "foo\n").unwrap();
+        std::fs::write(dir.path().join("vendor/c.txt"), "foo\n").unwrap();
+
+        let session = SessionBuilder::new()
+            .path_filter(|path, is_dir| match is_dir {
+                true => !path.ends_with("vendor"),
+                false => !path.ends_with("b.txt"),
+            })
+            .build("foo", "bar", &[dir.path()])
+            .unwrap();
+        assert_eq!(session.files().collect::<Vec<_>>(), [(path_1.as_path(), 1)]);
+
+        // the filter also applies to the paths that are given
+        let session = SessionBuilder::new()
+            .path_filter(|path, _| !path.ends_with("a.txt"))
+            .build("foo", "bar", &[&path_1])
+            .unwrap();
+        assert_eq!(session.files().count(), 0);
+    }
+
+    #[test]
+    fn test_cancel_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let path_1 = dir.path().join("a.txt");
+        let path_2 = dir.path().join("b.txt");
+        std::fs::write(&path_1, "foo\nbar\nfoo\n").unwrap();
+        std::fs::write(&path_2, "foo\n").unwrap();
+
+        let token = Arc::new(AtomicBool::new(true));
+        let result = SessionBuilder::new()
+            .cancel_token(Arc::clone(&token))
+            .build("foo", "bar", &[dir.path()]);
+        assert!(matches!(result, Err(Error::Cancelled)));
+
+        token.store(false, Ordering::Relaxed);
+        let mut session = SessionBuilder::new()
+            .context(0)
+            .cancel_token(Arc::clone(&token))
+            .build("foo", "bar", &[dir.path()])
+            .unwrap();
+
+        // the file that was cancelled part way through isn't written
+        let outcomes = session
+            .run(|_, _| {
+                token.store(true, Ordering::Relaxed);
+                Answer::Yes
+            })
+            .unwrap();
+        assert!(outcomes.is_empty());
+        assert_eq!(std::fs::read(&path_1).unwrap(), b"foo\nbar\nfoo\n");
+        assert_eq!(session.files().count(), 2);
+
+        token.store(false, Ordering::Relaxed);
+        let outcomes = session.run(|_, _| Answer::Yes).unwrap();
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(std::fs::read(&path_1).unwrap(), b"bar\nbar\nbar\n");
+    }
+
+    #[test]
+    fn test_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let path_1 = dir.path().join("a.txt");
+        let path_2 = dir.path().join("b.txt");
+        std::fs::write(&path_1, "foo\nbar\nfoo\n").unwrap();
+        std::fs::write(&path_2, "foo\n").unwrap();
+
+        let mut session = SessionBuilder::new()
+            .context(0)
+            .build("f(o+)", "x$1", &[dir.path()])
+            .unwrap();
+        assert_eq!(
+            session.files().collect::<Vec<_>>(),
+            [(path_1.as_path(), 2), (path_2.as_path(), 1)],
+        );
+
+        let mut hunks = Vec::new();
+        let outcomes = session
+            .run(|path, hunk| {
+                hunks.push((
+                    path.to_path_buf(),
+                    hunk.start_line(),
+                    hunk.replaced().to_vec(),
+                ));
+                match hunks.len() {
+                    1 => Answer::Replace(b"baz\n".to_vec()),
+                    2 => Answer::Yes,
+                    _ => Answer::Quit,
+                }
+            })
+            .unwrap();
+
+        assert_eq!(
+            hunks,
+            [
+                (path_1.clone(), 0, b"xoo\n".to_vec()),
+                (path_1.clone(), 2, b"xoo\n".to_vec()),
+                (path_2.clone(), 0, b"xoo\n".to_vec()),
+            ],
+        );
+        let counts: Vec<_> = outcomes
+            .iter()
+            .map(|x| {
+                (
+                    x.path.clone(),
+                    x.applied_hunks,
+                    x.skipped_hunks,
+                    x.bytes_written,
+                )
+            })
+            .collect();
+        assert_eq!(
+            counts,
+            [(path_1.clone(), 2, 0, 12), (path_2.clone(), 0, 0, 0)]
+        );
+        assert_eq!(std::fs::read(&path_1).unwrap(), b"baz\nbar\nxoo\n");
+        assert_eq!(std::fs::read(&path_2).unwrap(), b"foo\n");
+        assert_eq!(session.files().count(), 0);
+    }
+}
@@ -0,0 +1,296 @@
+//! Templates, which are presets with parameters for common codemods. For example,
+//! `repatch template update-copyright --year 2025 src/` runs the built-in `update-copyright`
+//! template with the parameter `year` set to "2025".
+//!
+//! A template's find and replace arguments can contain placeholders like `{{year}}`, which are
+//! substituted with the parameter values. The values are escaped so that they're matched and
+//! inserted literally, which means that using a template doesn't require knowing any regex syntax.
+//! Templates can also be defined in the config file:
+//!
+//! ```toml
+//! [template.rename-logger]
+//! description = "Rename a logger method"
+//! find = 'log\.{{from}}\('
+//! replace = 'log.{{to}}('
+//! params = ["from", "to"]
+//! globs = ["*.py"]
+//! ```
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+
+use crate::config::Config;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Template {
+    pub description: String,
+    pub find: String,
+    pub replace: String,
+    pub globs: Vec<String>,
+    pub flags: Vec<String>,
+    /// The names of the parameters, which are given as `--NAME VALUE`.
+    pub params: Vec<String>,
+}
+
+impl Template {
+    /// Returns an error if a placeholder isn't one of the template's parameters.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for param in &self.params {
+            if param.is_empty() || !param.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                anyhow::bail!("invalid parameter name '{param}'");
+            }
+        }
+
+        for s in [&self.find, &self.replace] {
+            for name in placeholders(s) {
+                if !self.params.iter().any(|x| x == name) {
+                    anyhow::bail!("the placeholder '{{{{{name}}}}}' isn't a parameter");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The templates that are always available, unless the config file has a template with the same
+/// name.
+fn builtin() -> BTreeMap<String, Template> {
+    let template = |description: &str, find: &str, replace: &str, params: &[&str]| Template {
+        description: description.to_string(),
+        find: find.to_string(),
+        replace: replace.to_string(),
+        globs: Vec::new(),
+        flags: Vec::new(),
+        params: params.iter().map(|x| x.to_string()).collect(),
+    };
+
+    BTreeMap::from([
+        (
+            "update-copyright".to_string(),
+            template(
+                "Extend copyright years like \"2019\" or \"2019-2023\" to end at <YEAR>",
+                r"(Copyright (?:\([cC]\) |© )?)(\d{4})(?:-\d{4})?",
+                "${1}${2}-{{year}}",
+                &["year"],
+            ),
+        ),
+        (
+            "rename-identifier".to_string(),
+            template(
+                "Rename the identifier <FROM> to <TO>, where it isn't part of a longer word",
+                r"\b{{from}}\b",
+                "{{to}}",
+                &["from", "to"],
+            ),
+        ),
+        (
+            "trailing-whitespace".to_string(),
+            template(
+                "Remove spaces and tabs at the end of lines",
+                r"[ \t]+(?m:$)",
+                "",
+                &[],
+            ),
+        ),
+    ])
+}
+
+/// All templates, where templates in the config file take precedence over built-in templates.
+pub fn all(config: &Config) -> BTreeMap<String, Template> {
+    let mut templates = builtin();
+    templates.extend(config.templates.clone());
+    templates
+}
+
+/// The names of the built-in templates.
+pub fn builtin_names() -> Vec<String> {
+    builtin().into_keys().collect()
+}
+
+/// The names of the placeholders in `s`.
+fn placeholders(s: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = s;
+    while let Some((_, after)) = rest.split_once("{{") {
+        let Some((name, after)) = after.split_once("}}") else {
+            break;
+        };
+        names.push(name);
+        rest = after;
+    }
+    names
+}
+
+/// Substitute each placeholder in `s` with its escaped value.
+fn substitute(s: &str, values: &BTreeMap<&str, String>, escape: impl Fn(&str) -> String) -> String {
+    let mut out = s.to_string();
+    for (name, value) in values {
+        out = out.replace(&format!("{{{{{name}}}}}"), &escape(value));
+    }
+    out
+}
+
+fn print_list(config: &Config) {
+    println!("Usage: repatch template <NAME> [--<PARAM> <VALUE>]... [OPTIONS] <PATHS>...\n");
+    println!("Templates:");
+    for (name, template) in all(config) {
+        let params: Vec<_> = template
+            .params
+            .iter()
+            .map(|x| format!(" --{x} <{}>", x.to_uppercase()))
+            .collect();
+        println!("  {name}{}", params.concat());
+        if !template.description.is_empty() {
+            println!("      {}", template.description);
+        }
+    }
+}
+
+/// Expand the arguments of the `template` subcommand (`args[1]`) into the arguments of a regular
+/// run, by replacing the template name and parameters with the template's arguments. Returns
+/// `None` if the templates were listed instead.
+pub fn expand(args: Vec<OsString>, config: &Config) -> anyhow::Result<Option<Vec<OsString>>> {
+    let mut iter = args.into_iter();
+    let program = iter.next();
+    // the subcommand
+    iter.next();
+
+    let name = match iter.next() {
+        Some(x) if x != "--list" && x != "--help" && x != "-h" => x,
+        _ => {
+            print_list(config);
+            return Ok(None);
+        }
+    };
+    let name = name
+        .into_string()
+        .map_err(|_| anyhow::anyhow!("the template name is not valid UTF-8"))?;
+
+    let templates = all(config);
+    let Some(template) = templates.get(&name) else {
+        anyhow::bail!("no template named '{name}' (use 'repatch template --list' to list them)");
+    };
+
+    let mut values = BTreeMap::new();
+    let mut rest = Vec::new();
+
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            rest.push(arg);
+            rest.extend(iter.by_ref());
+            break;
+        }
+
+        let Some(flag) = arg.to_str().and_then(|x| x.strip_prefix("--")) else {
+            rest.push(arg);
+            continue;
+        };
+        let (flag, value) = match flag.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_string())),
+            None => (flag, None),
+        };
+
+        let Some(param) = template.params.iter().find(|x| *x == flag) else {
+            rest.push(arg);
+            continue;
+        };
+
+        let value = match value {
+            Some(x) => x,
+            None => iter
+                .next()
+                .and_then(|x| x.into_string().ok())
+                .ok_or_else(|| anyhow::anyhow!("'--{param}' requires a UTF-8 value"))?,
+        };
+        values.insert(param.as_str(), value);
+    }
+
+    for param in &template.params {
+        if !values.contains_key(param.as_str()) {
+            anyhow::bail!(
+                "the template '{name}' requires '--{param} <{}>'",
+                param.to_uppercase(),
+            );
+        }
+    }
+
+    let find = substitute(&template.find, &values, regex_syntax::escape);
+    let replace = substitute(&template.replace, &values, |x| x.replace('$', "$$"));
+
+    let mut args: Vec<OsString> = program.into_iter().collect();
+    args.extend(template.flags.iter().map(OsString::from));
+    args.extend(template.globs.iter().map(|x| format!("--glob={x}").into()));
+    args.push(find.into());
+    args.push(replace.into());
+    args.extend(rest);
+
+    Ok(Some(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let config = Config::default();
+        let args = |x: &[&str]| x.iter().map(OsString::from).collect::<Vec<_>>();
+
+        assert_eq!(
+            expand(
+                args(&[
+                    "repatch",
+                    "template",
+                    "rename-identifier",
+                    "--from=a.b",
+                    "-i"
+                ]),
+                &config,
+            )
+            .unwrap_err()
+            .to_string(),
+            "the template 'rename-identifier' requires '--to <TO>'",
+        );
+
+        let expanded = expand(
+            args(&[
+                "repatch",
+                "template",
+                "rename-identifier",
+                "--from=a.b",
+                "src/",
+                "--to",
+                "$x",
+                "--",
+                "--to",
+            ]),
+            &config,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            expanded,
+            args(&["repatch", r"\ba\.b\b", "$$x", "src/", "--", "--to"]),
+        );
+
+        assert!(expand(args(&["repatch", "template", "nope", "."]), &config).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        for template in builtin().values() {
+            template.validate().unwrap();
+        }
+
+        let template = Template {
+            find: "{{a}}".to_string(),
+            replace: "{{b}}".to_string(),
+            params: vec!["a".to_string()],
+            ..Default::default()
+        };
+        assert!(template.validate().is_err());
+
+        assert_eq!(placeholders("a{{b}}c{{d}}{{e"), ["b", "d"]);
+    }
+}
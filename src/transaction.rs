@@ -0,0 +1,154 @@
+//! The new files of an `--atomic` run, which are kept in memory until every hunk has been answered
+//! and are then written together. Either all of the files are replaced or none of them are.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::Context;
+
+use crate::ui::error;
+use crate::util::ReplaceFileError;
+
+/// A file to replace.
+struct StagedFile {
+    path: PathBuf,
+    /// The modified time of the file when it was read.
+    modified: SystemTime,
+    original: Vec<u8>,
+    new: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct Transaction {
+    files: Vec<StagedFile>,
+    /// The user quit before every hunk was answered, so no files will be replaced.
+    pub quit: bool,
+}
+
+impl Transaction {
+    /// Replace the file at `path` with `new` when the transaction is committed. `modified` is the
+    /// file's modified time when `original` was read.
+    pub fn stage(&mut self, path: &Path, modified: SystemTime, original: Vec<u8>, new: Vec<u8>) {
+        self.files.push(StagedFile {
+            path: path.to_path_buf(),
+            modified,
+            original,
+            new,
+        });
+    }
+
+    /// Replace every staged file, recording the originals in `journal`. If any of the files were
+    /// modified since they were read, none of them are replaced. If a file can't be replaced, the
+    /// files that were already replaced are restored.
+    pub fn commit(self, fsync: bool, journal: &mut crate::undo::Journal) -> anyhow::Result<()> {
+        if self.quit {
+            if !self.files.is_empty() {
+                println!("\nQuit before every hunk was answered, so no files were changed.");
+            }
+            return Ok(());
+        }
+
+        for file in &self.files {
+            let modified = std::fs::metadata(&file.path)
+                .and_then(|x| x.modified())
+                .with_context(|| format!("could not read '{}'", file.path.display()))?;
+            if modified != file.modified {
+                anyhow::bail!(
+                    "the file '{}' was modified by another program\n\
+                    Discarding all patches and exiting without changing any files.",
+                    file.path.display(),
+                );
+            }
+        }
+
+        for (i, file) in self.files.iter().enumerate() {
+            let replaced = crate::util::replace_file(
+                &file.path,
+                Some(file.modified),
+                fsync,
+                |_original, new| (true, (&*new).write_all(&file.new)),
+            );
+
+            let e = match replaced {
+                Ok(Ok(())) => continue,
+                Ok(Err(e)) | Err(ReplaceFileError::Io(e)) => anyhow::Error::from(e),
+                Err(ReplaceFileError::ModifiedTimeChanged) => {
+                    anyhow::anyhow!("the file was modified by another program")
+                }
+            };
+
+            // put back the files that were already replaced
+            for done in &self.files[..i] {
+                let restored = crate::util::replace_file(&done.path, None, fsync, |_, new| {
+                    (true, (&*new).write_all(&done.original))
+                });
+                match restored {
+                    Ok(Ok(())) => {}
+                    // the file can still be restored with `repatch undo`
+                    Ok(Err(e)) | Err(ReplaceFileError::Io(e)) => {
+                        error!("could not restore '{}': {e}", done.path.display());
+                        journal.record(&done.path, done.original.clone())?;
+                    }
+                    Err(ReplaceFileError::ModifiedTimeChanged) => unreachable!(),
+                }
+            }
+
+            return Err(e.context(format!(
+                "could not replace file '{}', so the other files were restored",
+                file.path.display(),
+            )));
+        }
+
+        for file in self.files {
+            journal.record(&file.path, file.original)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path_1 = dir.path().join("a.txt");
+        let path_2 = dir.path().join("b.txt");
+        std::fs::write(&path_1, "foo\n").unwrap();
+        std::fs::write(&path_2, "foo\n").unwrap();
+        let modified = |x: &Path| std::fs::metadata(x).unwrap().modified().unwrap();
+
+        let stage = || {
+            let mut transaction = Transaction::default();
+            for path in [&path_1, &path_2] {
+                let (original, new) = (b"foo\n".to_vec(), b"bar\n".to_vec());
+                transaction.stage(path, modified(path), original, new);
+            }
+            transaction
+        };
+
+        // nothing is written if a file was modified after it was read
+        let transaction = stage();
+        let file = std::fs::File::options().write(true).open(&path_2).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        let mut journal = crate::undo::Journal::default();
+        assert!(transaction.commit(false, &mut journal).is_err());
+        assert_eq!(std::fs::read(&path_1).unwrap(), b"foo\n");
+        assert_eq!(journal.paths().count(), 0);
+
+        // or if the user quit
+        let mut transaction = stage();
+        transaction.quit = true;
+        transaction.commit(false, &mut journal).unwrap();
+        assert_eq!(std::fs::read(&path_1).unwrap(), b"foo\n");
+
+        let transaction = stage();
+        transaction.commit(false, &mut journal).unwrap();
+        assert_eq!(std::fs::read(&path_1).unwrap(), b"bar\n");
+        assert_eq!(std::fs::read(&path_2).unwrap(), b"bar\n");
+        assert_eq!(journal.paths().count(), 2);
+    }
+}
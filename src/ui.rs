@@ -1,17 +1,180 @@
 use std::ffi::OsStr;
-use std::io::{BufRead, Read, Seek, Write};
+use std::io::{Read, Seek, Write};
 use std::path::Path;
 use std::process::Command;
 
 use bstr::ByteSlice;
 
+use crate::profile::{measure, Phase};
 use crate::util::label;
 
-const FILENAME_STYLE: anstyle::Style = anstyle::Style::new().bold();
+pub const FILENAME_STYLE: anstyle::Style = anstyle::Style::new().bold();
 const STAGE_STYLE: anstyle::Style = anstyle::AnsiColor::Blue.on_default().bold();
 const HELP_STYLE: anstyle::Style = anstyle::AnsiColor::Red.on_default().bold();
 pub const ERROR_STYLE: anstyle::Style = anstyle::Style::new().bold();
 pub const COUNT_STYLE: anstyle::Style = anstyle::Style::new().bold();
+const MATCH_STYLE: anstyle::Style = anstyle::AnsiColor::Green.on_default().bold();
+const FOLD_STYLE: anstyle::Style = anstyle::AnsiColor::Cyan.on_default();
+
+/// Disables all styling, for `--plain`.
+static PLAIN: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Disable all styling and show textual markers instead of colors, so that the output can be used
+/// with screen readers and dumb terminals.
+pub fn set_plain(plain: bool) {
+    PLAIN.store(plain, std::sync::atomic::Ordering::Relaxed);
+}
+
+pub fn is_plain() -> bool {
+    PLAIN.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Disables colors and other styling without changing the output otherwise, for `--hook`.
+static NO_COLOR: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+pub fn set_color(color: bool) {
+    NO_COLOR.store(!color, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Makes the output deterministic, for the hidden `--test-mode`.
+static TEST_MODE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Make the output deterministic for end-to-end tests of the interactive flow: nothing is styled,
+/// the terminal size is fixed, and times that depend on the clock aren't shown.
+pub fn set_test_mode(test: bool) {
+    TEST_MODE.store(test, std::sync::atomic::Ordering::Relaxed);
+}
+
+pub fn is_test_mode() -> bool {
+    TEST_MODE.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// The original stdout after everything except the machine-readable output was moved to stderr,
+/// for `--stdout`.
+static MACHINE_STDOUT: std::sync::Mutex<Option<std::fs::File>> = std::sync::Mutex::new(None);
+
+/// Point stdout at stderr, so that only the output of [`write_stdout`] is written to the original
+/// stdout.
+pub fn divert_to_stderr() -> std::io::Result<()> {
+    let original = crate::util::divert_stdout(&std::io::stderr())?;
+    *MACHINE_STDOUT.lock().unwrap() = Some(original);
+    Ok(())
+}
+
+/// Write machine-readable output, such as a diff or json objects, to stdout. This is the original
+/// stdout even if the other output was moved to stderr with [`divert_to_stderr`].
+pub fn write_stdout(bytes: &[u8]) -> std::io::Result<()> {
+    match &mut *MACHINE_STDOUT.lock().unwrap() {
+        Some(stdout) => stdout.write_all(bytes),
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(bytes)?;
+            stdout.flush()
+        }
+    }
+}
+
+/// Whether output should be styled, which is never the case in plain mode.
+fn use_color() -> bool {
+    !is_plain() && !NO_COLOR.load(std::sync::atomic::Ordering::Relaxed) && !is_test_mode()
+}
+
+/// The style to use in place of `style`, which is no style at all in plain mode or without colors.
+pub fn active_style(style: &anstyle::Style) -> &anstyle::Style {
+    const NO_STYLE: anstyle::Style = anstyle::Style::new();
+    if !use_color() {
+        &NO_STYLE
+    } else {
+        style
+    }
+}
+
+/// Print errors and warnings as json objects, for `--errors json`.
+static JSON_ERRORS: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+pub fn set_json_errors(json: bool) {
+    JSON_ERRORS.store(json, std::sync::atomic::Ordering::Relaxed);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+/// Print an error or warning to stderr, so that it can't be confused with the diffs and other
+/// output on stdout. Use the [`error`] and [`warning`] macros instead.
+pub fn report(level: Level, msg: std::fmt::Arguments) {
+    let (prefix, name) = match level {
+        Level::Error => ("ERROR:", "error"),
+        Level::Warning => ("WARNING:", "warning"),
+    };
+
+    if JSON_ERRORS.load(std::sync::atomic::Ordering::Relaxed) {
+        let value = crate::json::Value::object([
+            ("level", name.into()),
+            ("message", strip_ansi(&msg.to_string()).into()),
+        ]);
+        eprintln!("{value}");
+    } else {
+        eprintln!("{} {msg}", style!(prefix, &ERROR_STYLE));
+    }
+}
+
+/// The custom keys for answering the patch prompt.
+static KEYS: std::sync::OnceLock<crate::config::Keys> = std::sync::OnceLock::new();
+
+/// Use custom keys for answering the patch prompt. This can only be set once.
+pub fn set_keys(keys: crate::config::Keys) {
+    KEYS.set(keys).expect("the keys were already set");
+}
+
+/// Replace the "+" and "-" markers of the patch lines with words.
+fn plain_markers(patch: &str) -> String {
+    patch
+        .lines()
+        .map(|line| {
+            if let Some(x) = line.strip_prefix('+') {
+                format!("ADDED: {x}")
+            } else if let Some(x) = line.strip_prefix('-') {
+                format!("REMOVED: {x}")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Replace ANSI escape characters so that the text is safe to print to the terminal.
+pub fn escape_ansi(bytes: &[u8]) -> Vec<u8> {
+    const ESC_STYLE: anstyle::Style = anstyle::Style::new().invert();
+    let esc_styled = style!("ESC", &ESC_STYLE).to_string();
+
+    bytes.replace("\u{001b}", &esc_styled)
+}
+
+/// Remove the ANSI styles that were added to a message, for output that isn't meant for a
+/// terminal.
+pub fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\u{001b}' {
+            out.push(c);
+            continue;
+        }
+        // skip a CSI sequence up to and including its final byte
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if ('\u{40}'..='\u{7e}').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    out
+}
 
 /// Start the editor with a file containing the given text. Once the user closes the editor, the
 /// updated text will be returned. `None` will be returned if the editor exited with a non-zero
@@ -22,7 +185,7 @@ fn user_edit(
 ) -> Result<Option<Vec<u8>>, UserEditError> {
     #[cfg(target_os = "linux")]
     {
-        user_edit_linux(text, editor_cmd)
+        user_edit_linux(text, editor_cmd, /* allow_fallback= */ true)
     }
 
     #[cfg(not(target_os = "linux"))]
@@ -36,6 +199,7 @@ fn user_edit(
 fn user_edit_linux(
     text: &[u8],
     editor_cmd: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    allow_fallback: bool,
 ) -> Result<Option<Vec<u8>>, UserEditError> {
     use std::fs::File;
     use std::os::fd::{AsRawFd, FromRawFd};
@@ -43,9 +207,20 @@ fn user_edit_linux(
 
     let mut editor_cmd = editor_cmd.into_iter();
 
+    // the editor opens the memfd file through /proc, which may not be mounted
+    if allow_fallback && !Path::new("/proc/self/fd").is_dir() {
+        return user_edit_compat(text, editor_cmd);
+    }
+
     // create a memfd file
     let edit_file = unsafe { libc::memfd_create(c"edit".as_ptr(), libc::MFD_CLOEXEC) };
-    assert!(edit_file >= 0);
+    if edit_file < 0 {
+        // memfd files aren't supported by older kernels or some sandboxes
+        if allow_fallback {
+            return user_edit_compat(text, editor_cmd);
+        }
+        return Err(std::io::Error::last_os_error().into());
+    }
     let mut edit_file = unsafe { File::from_raw_fd(edit_file) };
 
     let edit_fd = edit_file.as_raw_fd();
@@ -92,14 +267,13 @@ fn user_edit_linux(
 }
 
 /// A platform-agnostic variant of [`user_edit`].
-#[cfg(any(test, not(target_os = "linux")))]
 fn user_edit_compat(
     text: &[u8],
     editor_cmd: impl IntoIterator<Item = impl AsRef<OsStr>>,
 ) -> Result<Option<Vec<u8>>, UserEditError> {
     let mut editor_cmd = editor_cmd.into_iter();
 
-    let edit_file = tempfile::Builder::new().tempfile()?;
+    let edit_file = crate::workspace::named_tempfile("edit-", "")?;
     let edit_path = edit_file.path();
     let mut edit_file = edit_file.as_file();
 
@@ -156,293 +330,1961 @@ impl std::fmt::Display for UserEditError {
 
 impl std::error::Error for UserEditError {}
 
-fn menu_prompt(
-    patch: &diffy::Patch<[u8]>,
-    path: Option<&Path>,
-    progress: (u64, u64),
-    line_num: u64,
-    input: Option<MenuOption>,
-) -> MenuOption {
-    // format the patch
-    let mut patch_bytes = Vec::new();
-    diffy::PatchFormatter::new()
-        .with_color()
-        .write_patch_into(patch, &mut patch_bytes)
-        .unwrap();
-
-    let patch_bytes =
-        crate::util::rewrite_patch_line_start(&patch_bytes, line_num as i128, true).unwrap();
+/// The answer to take when a patch prompt isn't answered in time.
+pub struct PromptTimeout {
+    pub duration: std::time::Duration,
+    pub default: MenuOption,
+    /// The number of prompts that timed out.
+    pub expired: std::cell::Cell<u64>,
+}
 
-    let patch = String::from_utf8_lossy(&patch_bytes);
-    let mut patch = patch.trim();
+/// Progress through the files and hunks of a run, which is shown in the prompt header.
+pub struct Progress {
+    /// The number of hunks in each file.
+    hunk_counts: Vec<u64>,
+    /// The (0-indexed) file that's being patched.
+    file: std::cell::Cell<usize>,
+    /// The number of prompts that the user has answered, and the total time it took.
+    answers: std::cell::Cell<(u64, std::time::Duration)>,
+}
 
-    if let Some(path) = path {
-        // show the file path
-        style_println!(
-            &FILENAME_STYLE,
-            "diff --{} {}",
-            env!("CARGO_PKG_NAME"),
-            path.display()
-        );
-    } else {
-        // remove the first two lines ('---' and '+++')
-        let start = patch.match_indices('\n').nth(1).unwrap().0 + 1;
-        patch = &patch[start..];
+impl Progress {
+    pub fn new(hunk_counts: Vec<u64>) -> Self {
+        Self {
+            hunk_counts,
+            file: std::cell::Cell::new(0),
+            answers: std::cell::Cell::new((0, std::time::Duration::ZERO)),
+        }
     }
-    println!("{patch}");
 
-    if let Some(input) = input {
-        return input;
+    /// Set the (0-indexed) file that's being patched.
+    pub fn set_file(&self, file: usize) {
+        self.file.set(file);
     }
 
-    let options = MenuOption::list()
-        .iter()
-        .map(|x| x.as_char())
-        .chain(std::iter::once("?"))
-        .collect::<Vec<&str>>()
-        .join(",");
+    /// The header shown before the first hunk of the current file, such as "File 3 of 17".
+    fn file_header(&self) -> String {
+        format!("File {} of {}", self.file.get() + 1, self.hunk_counts.len())
+    }
 
-    let help = MenuOption::list()
-        .iter()
-        .map(|x| [x.as_char(), x.help()].join(" - "))
-        .chain(std::iter::once("? - print help".to_string()))
-        .collect::<Vec<String>>()
-        .join("\n");
+    /// Record the time that the user took to answer a prompt.
+    fn record_answer(&self, duration: std::time::Duration) {
+        let (count, total) = self.answers.get();
+        self.answers.set((count + 1, total + duration));
+    }
 
-    loop {
-        style_print!(
-            &STAGE_STYLE,
-            "({}/{}) Apply this patch [{options}]? ",
-            progress.0 + 1,
-            progress.1,
+    /// The prompt header for the hunk `idx` of the `count` hunks in the current file, such as "file
+    /// 3/17, hunk 2/5, 41 hunks remaining, about 2m 30s left".
+    fn header(&self, idx: u64, count: u64) -> String {
+        let file = self.file.get();
+        // the current file's hunk count may have changed since it was searched, so we use the
+        // count from the hunk itself
+        let later_files: u64 = self.hunk_counts.get(file + 1..).unwrap_or(&[]).iter().sum();
+        let remaining = count.saturating_sub(idx + 1) + later_files;
+
+        let mut header = format!(
+            "file {}/{}, hunk {}/{count}, {remaining} hunk{} remaining",
+            file + 1,
+            self.hunk_counts.len(),
+            idx + 1,
+            if remaining == 1 { "" } else { "s" },
         );
-        std::io::stdout().flush().unwrap();
-
-        // get the command from the user
-        let mut input = String::new();
-        std::io::stdin().lock().read_line(&mut input).unwrap();
 
-        match input.trim().parse() {
-            Ok(x) => return x,
-            Err(_) => {
-                // could not parse the input, so print help text and patch then restart
-                style_println!(&HELP_STYLE, "{help}");
-                println!("{patch}");
-            }
+        let (answers, total) = self.answers.get();
+        if answers > 0 && remaining > 0 && !is_test_mode() {
+            // include this prompt, which hasn't been answered yet
+            let eta = total.mul_f64((remaining + 1) as f64 / answers as f64);
+            header.push_str(&format!(", about {} left", format_duration(eta)));
         }
+
+        header
     }
 }
 
-pub fn yes_no_prompt(prompt: &str) -> bool {
-    loop {
-        style_print!(&STAGE_STYLE, "{prompt} ");
-        std::io::stdout().flush().unwrap();
+/// Format a duration with at most two units, such as "1h 5m", "2m 30s", or "45s".
+fn format_duration(duration: std::time::Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m {s}s"),
+        (h, m, _) => format!("{h}h {m}m"),
+    }
+}
 
-        let mut input = String::new();
-        std::io::stdin().lock().read_line(&mut input).unwrap();
+/// Format a file size using binary units, such as "512 B" or "1.5 KiB".
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
 
-        match input.trim().chars().next() {
-            Some('y') => return true,
-            Some('n') => return false,
-            _ => {}
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
         }
+        size /= 1024.0;
+        unit = next;
     }
+    format!("{size:.1} {unit}")
 }
 
-pub fn patch_prompt(
-    original: &[u8],
-    replaced: &[u8],
-    mut src_path: Option<&Path>,
-    progress: (u64, u64),
-    line_num: u64,
-    input: Option<MenuOption>,
-) -> PatchOption {
-    // use a large context length so that diffy does not do its own hunking
-    let mut diff_options = diffy::DiffOptions::new();
-    diff_options.set_context_len(usize::MAX);
+/// Format how long ago something happened in the largest whole unit, such as "3 days ago".
+pub fn format_age(age: std::time::Duration) -> String {
+    const UNITS: [(&str, u64); 6] = [
+        ("year", 365 * 24 * 3600),
+        ("month", 30 * 24 * 3600),
+        ("day", 24 * 3600),
+        ("hour", 3600),
+        ("minute", 60),
+        ("second", 1),
+    ];
+
+    let secs = age.as_secs();
+    for (unit, len) in UNITS {
+        let n = secs / len;
+        if n > 0 {
+            let plural = if n == 1 { "" } else { "s" };
+            return format!("{n} {unit}{plural} ago");
+        }
+    }
+    "just now".to_string()
+}
 
-    // the real patch
-    let patch = diff_options.create_patch_bytes(original, replaced);
+/// Options for how a patch is shown and answered.
+#[derive(Copy, Clone, Default)]
+pub struct PromptOptions<'a> {
+    /// The answer to take if the prompt isn't answered in time.
+    pub timeout: Option<&'a PromptTimeout>,
+    /// Lines wider than this many columns are shortened when shown.
+    pub max_line_len: Option<usize>,
+    /// The patches of the hunk if they were computed ahead of time with the same `max_line_len`,
+    /// otherwise they're computed when the hunk is shown.
+    pub diffs: Option<&'a HunkDiffs>,
+    /// Unchanged lines that are more than this many lines away from a change are folded when
+    /// shown. The unfold option is only shown if lines were folded.
+    pub fold_context: Option<usize>,
+    /// The command that the patch is piped through to be shown, instead of showing it ourselves.
+    pub diff_viewer: Option<DiffViewer<'a>>,
+    /// The overall progress to show in the prompt header.
+    pub progress: Option<&'a Progress>,
+    /// The ID of the hunk within the run, which is shown after the hunk header.
+    pub hunk_id: Option<u64>,
+    /// Details about the file and hunk, such as the file size, which are shown after the hunk
+    /// header.
+    pub details: Option<&'a str>,
+    /// A summary of the file, such as its encoding and line endings, which is shown with the
+    /// file's header.
+    pub file_summary: Option<&'a str>,
+    /// The capture groups of the hunk's matches and their values. The captures option is only
+    /// shown if this is set.
+    pub captures: Option<&'a str>,
+    /// The replacements that the user can choose between by number, for `--replace-one-of`. The
+    /// first is the hunk's own replacement.
+    pub choices: &'a [Choice],
+    /// Each match that the hunk replaces, where the range is within the original hunk. The
+    /// matches option is only shown if there's more than one.
+    pub matches: &'a [crate::util::MatchReplacement],
+    /// Where to store a note that the user adds to the hunk. The note option is only shown if
+    /// this is set.
+    pub note: Option<&'a std::cell::Cell<Option<String>>>,
+    /// Where to store a search that the user enters, which skips the hunks until the next hunk
+    /// that matches. The search option is only shown if this is set.
+    pub search: Option<&'a std::cell::Cell<Option<grep_regex::RegexMatcher>>>,
+    /// Where to store an answer that also applies to the later hunks, which is one of the options
+    /// for applying or skipping the rest of the file or applying everything remaining. These
+    /// options are only shown if this is set.
+    pub sticky: Option<&'a std::cell::Cell<Option<MenuOption>>>,
+    /// Where to store an answer that also applies to the later hunks that are similar to this
+    /// one, for `--group-similar`. These options are only shown if this is set.
+    pub similar: Option<&'a std::cell::Cell<Option<MenuOption>>>,
+}
 
-    const ESC_STYLE: anstyle::Style = anstyle::Style::new().invert();
-    let esc_styled = style!("ESC", &ESC_STYLE).to_string();
+/// A shell command that shows the patches, for `--diff-viewer`.
+#[derive(Copy, Clone)]
+pub struct DiffViewer<'a> {
+    pub cmd: &'a str,
+    /// The path of the file in the patch header.
+    pub path: &'a Path,
+}
 
-    // a modified patch that is safe to print to the terminal
-    let safe_current = original.replace("\u{001b}", &esc_styled);
-    let safe_replaced = replaced.replace("\u{001b}", &esc_styled);
-    let safe_patch = diff_options.create_patch_bytes(&safe_current, &safe_replaced);
+impl DiffViewer<'_> {
+    /// Show the hunk `patch` (without its '---' and '+++' lines) by piping it to the command. The
+    /// command writes directly to our stdout.
+    fn show(&self, patch: &str) -> anyhow::Result<()> {
+        let diff = format!(
+            "--- a/{}\n+++ b/{}\n{patch}\n",
+            self.path.display(),
+            self.path.display(),
+        );
 
-    label!('patch_prompt: {
-        // take the file path so that it's only ever shown once
-        let src_path = src_path.take();
+        std::io::stdout().flush()?;
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(self.cmd)
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("could not run the diff viewer '{}': {e}", self.cmd))?;
 
-        // show the patch to the user and have them choose how to proceed
-        match menu_prompt(&safe_patch, src_path, progress, line_num, input) {
-            MenuOption::Yes => {
-                // apply the patch
-                let new_hunk = diffy::apply_bytes(original, &patch).unwrap();
-                PatchOption::WriteNew(new_hunk)
-            }
-            MenuOption::No => PatchOption::WriteOriginal,
-            MenuOption::Quit => PatchOption::Quit,
-            MenuOption::Edit => label!('edit_prompt: {
-                const INVALID_PATCH_PROMPT: &str =
-                    r#"Your patch is invalid. Edit again (saying "no" discards!) [y/n]?"#;
-                const DOES_NOT_APPLY_PROMPT: &str =
-                    r#"Your edited hunk does not apply. Edit again (saying "no" discards!) [y/n]?"#;
+        // the command is allowed to exit without reading everything
+        let _ = child.stdin.take().unwrap().write_all(diff.as_bytes());
 
-                let edited = 'edit_hunk: {
-                    let editor_cmd = crate::util::editor_cmd();
+        let status = child.wait()?;
+        if !status.success() {
+            anyhow::bail!("the diff viewer '{}' failed ({status})", self.cmd);
+        }
+        Ok(())
+    }
+}
 
-                    // allow the user to edit the patch
-                    let patch = match user_edit(&patch.to_bytes(), editor_cmd.clone()) {
-                        Ok(Some(x)) => x,
-                        Ok(None) => {
-                            // the editor didn't exit successfully
-                            error!("The editor did not exit successfully.");
-                            continue 'patch_prompt;
-                        }
-                        Err(UserEditError::EditorNotFound) => {
-                            let mut editor_cmd = editor_cmd;
-                            let editor = editor_cmd.next().unwrap().as_ref().to_owned();
-                            error!("The editor {editor:?} was not found.");
-                            continue 'patch_prompt;
-                        }
-                        Err(e) => {
-                            error!("Patch editing failed: {e}.");
-                            continue 'patch_prompt;
-                        }
-                    };
+/// A replacement that the user can choose when prompted.
+pub struct Choice {
+    /// The replacement argument, which is shown next to the choice's number.
+    pub label: String,
+    /// The replaced hunk.
+    pub replaced: Vec<u8>,
+}
 
-                    // if not valid utf-8, then it must not be empty
-                    let is_empty = std::str::from_utf8(&patch)
-                        .map(|x| x.trim().is_empty())
-                        .unwrap_or(false);
+/// An answer to the patch prompt.
+enum Answer {
+    Option(MenuOption),
+    /// The index of one of the [`PromptOptions::choices`].
+    Choice(usize),
+}
 
-                    // this also ignores whitespace since editors may add a newline at the end of
-                    // the file
-                    if is_empty {
-                        // not even the patch header exists anymore
-                        error!("The edited patch file was empty.");
-                        continue 'patch_prompt;
-                    }
+/// Fold each run of unchanged lines in the formatted hunk `patch` into a
+/// "… N unchanged lines …" line, except for the `keep` lines closest to a change. The first line
+/// is the hunk header. Returns `None` if no lines were folded.
+fn fold_unchanged(patch: &str, keep: usize) -> Option<String> {
+    let mut lines = patch.lines();
+    let header = lines.next()?;
+    let lines: Vec<&str> = lines.collect();
+    let is_unchanged = |x: &str| strip_ansi(x).starts_with(' ');
+
+    let mut out = vec![header.to_string()];
+    let mut folded = false;
+    let mut i = 0;
+    while i < lines.len() {
+        let run = lines[i..].iter().take_while(|x| is_unchanged(x)).count();
+        if run == 0 {
+            out.push(lines[i].to_string());
+            i += 1;
+            continue;
+        }
 
-                    let patch = crate::util::rewrite_patch_line_counts(&patch);
+        // a run at the start or end of the hunk only has a change on one side
+        let end = i + run;
+        let keep_before = if i == 0 { 0 } else { keep };
+        let keep_after = if end == lines.len() { 0 } else { keep };
+
+        // a hunk without changes isn't folded, nor is a single line that the marker would replace
+        let hidden = run.saturating_sub(keep_before + keep_after);
+        if run == lines.len() || hidden < 2 {
+            out.extend(lines[i..end].iter().map(|x| x.to_string()));
+        } else {
+            out.extend(lines[i..i + keep_before].iter().map(|x| x.to_string()));
+            out.push(format!(
+                "{}",
+                style!(format!("… {hidden} unchanged lines …"), &FOLD_STYLE),
+            ));
+            out.extend(lines[end - keep_after..end].iter().map(|x| x.to_string()));
+            folded = true;
+        }
+        i = end;
+    }
 
-                    // create and apply the patch
-                    let patch = match diffy::Patch::from_bytes(&patch) {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("{e}");
-                            break 'edit_hunk Err(INVALID_PATCH_PROMPT);
-                        }
-                    };
-                    let new_hunk = match diffy::apply_bytes(original, &patch) {
-                        Ok(x) => x,
-                        Err(e) => {
-                            println!("{e}");
-                            break 'edit_hunk Err(DOES_NOT_APPLY_PROMPT);
-                        }
-                    };
+    folded.then(|| out.join("\n"))
+}
 
-                    Ok(new_hunk)
-                };
+/// Shorten the lines of a hunk that are wider than `max` columns so that they can be shown. If the
+/// original and replaced hunks have the same number of lines, each pair of lines is shortened to
+/// the same window around their first difference so that the change remains visible. Returns
+/// `None` if no lines were shortened.
+fn shorten_long_lines(original: &[u8], replaced: &[u8], max: usize) -> Option<(Vec<u8>, Vec<u8>)> {
+    let original: Vec<_> = original.lines_with_terminator().collect();
+    let replaced: Vec<_> = replaced.lines_with_terminator().collect();
+
+    let is_long =
+        |line: &&[u8]| crate::util::display_width(line.strip_suffix(b"\n").unwrap_or(line)) > max;
+    if !original.iter().chain(&replaced).any(is_long) {
+        return None;
+    }
 
-                match edited {
-                    Ok(edited) => PatchOption::WriteNew(edited),
-                    Err(msg) => {
-                        if yes_no_prompt(msg) {
-                            // answered "yes", so edit again
-                            continue 'edit_prompt;
-                        }
-                        // answered "no", so discard and use original
-                        PatchOption::WriteOriginal
-                    }
-                }
-            }),
+    // the position of the first difference of each line
+    let diffs: Vec<usize> = if original.len() == replaced.len() {
+        original
+            .iter()
+            .zip(&replaced)
+            .map(|(a, b)| a.iter().zip(*b).position(|(a, b)| a != b).unwrap_or(0))
+            .collect()
+    } else {
+        vec![0; original.len().max(replaced.len())]
+    };
+
+    let shorten = |lines: &[&[u8]]| {
+        let mut out = Vec::new();
+        for (line, diff) in lines.iter().zip(&diffs) {
+            let (body, terminator) = match line.strip_suffix(b"\n") {
+                Some(x) => (x, &b"\n"[..]),
+                None => (*line, &b""[..]),
+            };
+
+            let Some((start, end)) = column_window(body, *diff, max) else {
+                out.extend_from_slice(line);
+                continue;
+            };
+
+            if start > 0 {
+                out.extend_from_slice(format!("[{start} bytes] ").as_bytes());
+            }
+            out.extend_from_slice(&body[start..end]);
+            if end < body.len() {
+                out.extend_from_slice(format!(" [{} bytes]", body.len() - end).as_bytes());
+            }
+            out.extend_from_slice(terminator);
         }
-    })
-}
+        out
+    };
 
-pub enum PatchOption {
-    WriteNew(Vec<u8>),
-    WriteOriginal,
-    Quit,
+    Some((shorten(&original), shorten(&replaced)))
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub enum MenuOption {
-    Yes,
-    No,
-    Quit,
-    Edit,
-}
+/// The byte range of the part of `line` that's shown if the line is wider than `max` columns,
+/// which starts a little before the byte `diff` so that some text before the difference is shown.
+/// Returns `None` if the whole line fits.
+fn column_window(line: &[u8], diff: usize, max: usize) -> Option<(usize, usize)> {
+    // the start and width of each char, where wide chars (such as CJK text) take up two columns
+    // and combining marks take up none
+    let chars: Vec<(usize, usize)> = line
+        .char_indices()
+        .map(|(start, _, c)| {
+            (
+                start,
+                unicode_width::UnicodeWidthChar::width(c).unwrap_or(0),
+            )
+        })
+        .collect();
+    let width: usize = chars.iter().map(|x| x.1).sum();
+    if width <= max {
+        return None;
+    }
 
-impl MenuOption {
-    pub const fn list() -> &'static [Self] {
-        &[Self::Yes, Self::No, Self::Quit, Self::Edit]
+    let diff_col: usize = chars.iter().take_while(|x| x.0 < diff).map(|x| x.1).sum();
+    // a window that ends past the end of the line is moved back so that it's still full
+    let start_col = diff_col.saturating_sub(max / 4).min(width - max);
+
+    // the start of the window and its column
+    let mut start = None;
+    let mut col = 0;
+    for &(offset, char_width) in &chars {
+        // the window doesn't start with a combining mark
+        if start.is_none() && col >= start_col && char_width > 0 {
+            start = Some((offset, col));
+        }
+        // a wide char that would only partly fit isn't shown
+        if let Some((start, start_col)) = start {
+            if col + char_width > start_col + max {
+                return Some((start, offset));
+            }
+        }
+        col += char_width;
     }
 
-    pub const fn as_char(&self) -> &'static str {
-        // return a str instead of a char since they're much easier to work with (there is no char
-        // -> str const function)
-        match self {
-            Self::Yes => "y",
-            Self::No => "n",
-            Self::Quit => "q",
-            Self::Edit => "e",
+    let start = start.map_or(line.len(), |x| x.0);
+    Some((start, line.len()))
+}
+
+/// Wait until stdin is readable. Returns false if the timeout expired first.
+fn wait_for_stdin(timeout: std::time::Duration) -> std::io::Result<bool> {
+    let deadline = std::time::Instant::now() + timeout;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        let millis = libc::c_int::try_from(remaining.as_millis()).unwrap_or(libc::c_int::MAX);
+
+        let mut fd = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        match unsafe { libc::poll(&mut fd, 1, millis) } {
+            -1 => {
+                let e = std::io::Error::last_os_error();
+                if e.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            0 if remaining.is_zero() => return Ok(false),
+            0 => {}
+            _ => return Ok(true),
         }
     }
+}
 
-    pub const fn help(&self) -> &'static str {
-        match self {
-            Self::Yes => "replace this hunk",
-            Self::No => "do not replace this hunk",
-            Self::Quit => "quit; do not replace this hunk or any future hunks",
-            Self::Edit => "manually edit the current hunk",
+/// Escape the bytes of a line that aren't printable ASCII characters as "\xNN", and tabs, carriage
+/// returns, and backslashes as "\t", "\r", and "\\".
+fn escape_bytes(line: &[u8]) -> String {
+    let mut escaped = String::new();
+    for b in line {
+        match b {
+            b'\\' => escaped.push_str("\\\\"),
+            b'\t' => escaped.push_str("\\t"),
+            b'\r' => escaped.push_str("\\r"),
+            b' '..=b'~' => escaped.push(char::from(*b)),
+            b => escaped.push_str(&format!("\\x{b:02x}")),
         }
     }
+    escaped
 }
 
-impl std::str::FromStr for MenuOption {
-    type Err = ();
+/// Format the hunk of `patch` with the bytes of each line escaped, so that changes to invisible
+/// characters can be seen.
+fn escaped_patch(patch: &diffy::Patch<[u8]>, line_num: u64) -> String {
+    const REMOVED_STYLE: anstyle::Style = anstyle::AnsiColor::Red.on_default();
+    const ADDED_STYLE: anstyle::Style = anstyle::AnsiColor::Green.on_default();
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        const YES_STR: &str = MenuOption::Yes.as_char();
-        const NO_STR: &str = MenuOption::No.as_char();
-        const QUIT_STR: &str = MenuOption::Quit.as_char();
-        const EDIT_STR: &str = MenuOption::Edit.as_char();
-
-        Ok(match s {
-            YES_STR => Self::Yes,
-            NO_STR => Self::No,
-            QUIT_STR => Self::Quit,
-            EDIT_STR => Self::Edit,
-            _ => return Err(()),
-        })
+    let mut patch_bytes = Vec::new();
+    diffy::PatchFormatter::new()
+        .write_patch_into(patch, &mut patch_bytes)
+        .unwrap();
+    let patch_bytes =
+        crate::util::rewrite_patch_line_start(&patch_bytes, line_num as i128, false).unwrap();
+
+    // skip the '---' and '+++' lines
+    let mut lines = Vec::new();
+    for line in ByteSlice::lines(patch_bytes.as_slice()).skip(2) {
+        let line = match line.split_first() {
+            Some((b'-', rest)) if use_color() => {
+                format!(
+                    "{}",
+                    style!(format!("-{}", escape_bytes(rest)), &REMOVED_STYLE)
+                )
+            }
+            Some((b'+', rest)) if use_color() => {
+                format!(
+                    "{}",
+                    style!(format!("+{}", escape_bytes(rest)), &ADDED_STYLE)
+                )
+            }
+            Some((marker @ (b'-' | b'+' | b' '), rest)) => {
+                format!("{}{}", char::from(*marker), escape_bytes(rest))
+            }
+            // hunk headers and "\ No newline at end of file"
+            _ => line.to_str_lossy().into_owned(),
+        };
+        lines.push(line);
     }
-}
 
-macro_rules! style {
-    ($str:expr, $style:expr) => {{
-        // for type checking
-        let _style: &anstyle::Style = $style;
-        format_args!("{}{}{}", $style, $str, anstyle::Reset)
-    }};
+    let escaped = lines.join("\n");
+    match is_plain() {
+        true => plain_markers(&escaped),
+        false => escaped,
+    }
 }
-pub(crate) use style;
 
-macro_rules! style_print {
-    () => {{
-        print!()
-    }};
+/// Show the changes of a hunk as a word diff, like `git diff --word-diff`, where removed words are
+/// shown as "[-old-]" and added words as "{+new+}". The file's header is shown first if `paths`
+/// is given.
+pub fn print_word_diff(
+    original: &[u8],
+    replaced: &[u8],
+    paths: Option<FilePaths>,
+    line_num: u64,
+    hunk_id: u64,
+) {
+    const HUNK_HEADER_STYLE: anstyle::Style = anstyle::AnsiColor::Cyan.on_default();
+    const REMOVED_STYLE: anstyle::Style = anstyle::AnsiColor::Red.on_default();
+    const ADDED_STYLE: anstyle::Style = anstyle::AnsiColor::Green.on_default();
+
+    let (original, replaced) = (escape_ansi(original), escape_ansi(replaced));
+
+    // the markers are closed at the end of each line so that every line can be read on its own
+    let marked = |words: &[u8], start: &str, end: &str, style: &anstyle::Style| {
+        let lines: Vec<String> = words
+            .split(|x| *x == b'\n')
+            .map(|x| match x.is_empty() {
+                true => String::new(),
+                false => format!("{}", style!(format!("{start}{}{end}", x.as_bstr()), style)),
+            })
+            .collect();
+        lines.join("\n")
+    };
+
+    let mut diff = String::new();
+    for words in crate::util::word_diff(&original, &replaced) {
+        match words {
+            crate::util::WordDiff::Same(x) => diff.push_str(&x.to_str_lossy()),
+            crate::util::WordDiff::Changed { removed, added } => {
+                diff.push_str(&marked(&removed, "[-", "-]", &REMOVED_STYLE));
+                diff.push_str(&marked(&added, "{+", "+}", &ADDED_STYLE));
+            }
+        }
+    }
+
+    if let Some(paths) = paths {
+        print_file_header(paths);
+    }
+    let header = format!(
+        "@@ -{},{} +{},{} @@",
+        line_num + 1,
+        crate::util::count_lines(&original, b'\n'),
+        line_num + 1,
+        crate::util::count_lines(&replaced, b'\n'),
+    );
+    println!("{} #{hunk_id}", style!(header, &HUNK_HEADER_STYLE));
+    println!("{}", diff.trim_end_matches('\n'));
+}
+
+/// Show the file path, and '---' and '+++' lines that can be used with `patch -p1`.
+pub fn print_file_header(paths: FilePaths) {
+    style_println!(
+        &FILENAME_STYLE,
+        "diff --{} {}\n--- a/{}\n+++ b/{}",
+        env!("CARGO_PKG_NAME"),
+        paths.display.display(),
+        paths.patch.display(),
+        paths.patch.display(),
+    );
+}
+
+fn menu_prompt(
+    patch: &diffy::Patch<[u8]>,
+    paths: Option<FilePaths>,
+    progress: (u64, u64),
+    line_num: u64,
+    input: Option<MenuOption>,
+    prompt: PromptOptions,
+    shortened: bool,
+) -> Answer {
+    // the patch is shadowed by its formatted text below
+    let parsed_patch = patch;
+
+    let patch = format_hunk(patch, line_num, &prompt, use_color());
+    let folded = prompt.fold_context.and_then(|x| fold_unchanged(&patch, x));
+    let (patch, folded) = match is_plain() {
+        true => (plain_markers(&patch), folded.as_deref().map(plain_markers)),
+        false => (patch, folded),
+    };
+
+    // the folded lines are shown again once the user has unfolded them
+    let unfolded = std::cell::Cell::new(false);
+
+    // the viewer gets the patch without colors, since it has its own
+    let show_patch = || {
+        if let Some(viewer) = prompt.diff_viewer {
+            match viewer.show(&format_hunk(parsed_patch, line_num, &prompt, false)) {
+                Ok(()) => return,
+                Err(e) => error!("{e:#}"),
+            }
+        }
+        let shown = match (&folded, unfolded.get()) {
+            (Some(folded), false) => folded,
+            _ => &patch,
+        };
+        if !crate::pager::page_hunk(shown) {
+            println!("{shown}");
+        }
+    };
+
+    if let Some(paths) = paths {
+        if let Some(progress) = prompt.progress {
+            style_println!(&STAGE_STYLE, "{}", progress.file_header());
+        }
+        print_file_header(paths);
+        if let Some(summary) = prompt.file_summary {
+            println!("File: {summary}");
+        }
+    }
+    show_patch();
+
+    if let (true, Some(max)) = (shortened, prompt.max_line_len) {
+        style_println!(
+            &HELP_STYLE,
+            "Lines wider than {max} columns were shortened, but the full lines will be patched.",
+        );
+    }
+
+    if folded.is_some() && prompt.diff_viewer.is_none() {
+        style_println!(
+            &HELP_STYLE,
+            "Unchanged lines were folded, but the full hunk will be patched.",
+        );
+    }
+
+    if let Some(input) = input {
+        return Answer::Option(input);
+    }
+
+    for (i, choice) in prompt.choices.iter().enumerate() {
+        println!("{}: {}", i + 1, choice.label);
+    }
+
+    let menu_options: Vec<MenuOption> = MenuOption::list()
+        .iter()
+        .filter(|x| **x != MenuOption::Note || prompt.note.is_some())
+        .filter(|x| **x != MenuOption::Unfold || folded.is_some())
+        .filter(|x| **x != MenuOption::Captures || prompt.captures.is_some())
+        .filter(|x| **x != MenuOption::Matches || prompt.matches.len() > 1)
+        .filter(|x| **x != MenuOption::Search || prompt.search.is_some())
+        .filter(|x| !x.is_sticky() || prompt.sticky.is_some())
+        .filter(|x| !x.is_similar() || prompt.similar.is_some())
+        .copied()
+        .collect();
+
+    let choice_keys = match prompt.choices.len() {
+        0 => None,
+        n => Some(format!("1-{n}")),
+    };
+
+    let options = menu_options
+        .iter()
+        .map(|x| x.keys().join("/"))
+        .chain(choice_keys.clone())
+        .chain(std::iter::once("?".to_string()))
+        .collect::<Vec<String>>()
+        .join(",");
+
+    let help = menu_options
+        .iter()
+        .map(|x| [x.keys().join(", ").as_str(), x.help()].join(" - "))
+        .chain(
+            choice_keys.map(|x| format!("{x} - replace this hunk using the numbered replacement")),
+        )
+        .chain(std::iter::once("? - print help".to_string()))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let header = match prompt.progress {
+        Some(x) => x.header(progress.0, progress.1),
+        None => format!("{}/{}", progress.0 + 1, progress.1),
+    };
+
+    let start = std::time::Instant::now();
+
+    loop {
+        style_print!(&STAGE_STYLE, "({header}) Apply this patch [{options}]? ");
+        std::io::stdout().flush().unwrap();
+
+        if let Some(timeout) = prompt.timeout {
+            if !measure(Phase::Prompt, || wait_for_stdin(timeout.duration)).unwrap() {
+                println!("{} (timed out)", timeout.default.keys()[0]);
+                timeout.expired.set(timeout.expired.get() + 1);
+                return Answer::Option(timeout.default);
+            }
+        }
+
+        // get the command from the user
+        let mut input = String::new();
+        if measure(Phase::Prompt, || crate::prompter::read_line(&mut input)).unwrap() == 0 {
+            // EOF
+            println!();
+            return Answer::Option(MenuOption::Quit);
+        }
+
+        if let Some(i) = input
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|x| (1..=prompt.choices.len()).contains(x))
+        {
+            if let Some(progress) = prompt.progress {
+                progress.record_answer(start.elapsed());
+            }
+            return Answer::Choice(i - 1);
+        }
+
+        match (input.trim().parse(), prompt.note) {
+            (Ok(MenuOption::Note), Some(note)) => {
+                style_print!(&STAGE_STYLE, "Note: ");
+                std::io::stdout().flush().unwrap();
+
+                let mut text = String::new();
+                measure(Phase::Prompt, || crate::prompter::read_line(&mut text)).unwrap();
+
+                // an empty note removes the earlier note
+                let text = text.trim();
+                note.set((!text.is_empty()).then(|| text.to_string()));
+            }
+            (Ok(MenuOption::Bytes), _) => {
+                println!("{}", escaped_patch(parsed_patch, line_num));
+            }
+            (Ok(MenuOption::Search), _) if prompt.search.is_some() => {
+                style_print!(&STAGE_STYLE, "Search for (regex): ");
+                std::io::stdout().flush().unwrap();
+
+                let mut text = String::new();
+                measure(Phase::Prompt, || crate::prompter::read_line(&mut text)).unwrap();
+
+                // an empty search returns to the prompt
+                let text = text.trim_end_matches(['\r', '\n']);
+                if text.is_empty() {
+                    continue;
+                }
+                match grep_regex::RegexMatcher::new(text) {
+                    Ok(x) => {
+                        prompt.search.unwrap().set(Some(x));
+                        if let Some(progress) = prompt.progress {
+                            progress.record_answer(start.elapsed());
+                        }
+                        return Answer::Option(MenuOption::No);
+                    }
+                    Err(e) => error!("Invalid regex: {e}"),
+                }
+            }
+            (Ok(MenuOption::Unfold), _) if folded.is_some() => {
+                unfolded.set(true);
+                println!("{patch}");
+            }
+            (Ok(MenuOption::Captures), _) if prompt.captures.is_some() => {
+                println!("{}", prompt.captures.unwrap());
+            }
+            (Ok(x), _)
+                if !matches!(
+                    x,
+                    MenuOption::Note
+                        | MenuOption::Unfold
+                        | MenuOption::Captures
+                        | MenuOption::Search
+                ) && (!x.is_sticky() || prompt.sticky.is_some())
+                    && (!x.is_similar() || prompt.similar.is_some())
+                    && (x != MenuOption::Matches || prompt.matches.len() > 1) =>
+            {
+                if let Some(progress) = prompt.progress {
+                    progress.record_answer(start.elapsed());
+                }
+                return Answer::Option(x);
+            }
+            _ => {
+                // could not parse the input, so print help text and patch then restart
+                style_println!(&HELP_STYLE, "{help}");
+                show_patch();
+            }
+        }
+    }
+}
+
+/// Format the hunk of `patch`, without its '---' and '+++' lines, starting at the (0-indexed) line
+/// `line_num`. The hunk ID and details are added to the hunk header.
+fn format_hunk(
+    patch: &diffy::Patch<[u8]>,
+    line_num: u64,
+    prompt: &PromptOptions,
+    color: bool,
+) -> String {
+    let mut patch_bytes = Vec::new();
+    let mut formatter = diffy::PatchFormatter::new();
+    if color {
+        formatter = formatter.with_color();
+    }
+    formatter.write_patch_into(patch, &mut patch_bytes).unwrap();
+
+    let patch_bytes =
+        crate::util::rewrite_patch_line_start(&patch_bytes, line_num as i128, color).unwrap();
+
+    let patch = String::from_utf8_lossy(&patch_bytes);
+    let mut patch = patch.trim();
+
+    // remove the first two lines ('---' and '+++')
+    let start = patch.match_indices('\n').nth(1).unwrap().0 + 1;
+    patch = &patch[start..];
+
+    // text after the "@@" header is ignored by patch tools, so the ID and details can go there
+    if prompt.hunk_id.is_some() || prompt.details.is_some() {
+        let (header, rest) = patch.split_once('\n').unwrap_or((patch, ""));
+        let id = prompt.hunk_id.map(|x| format!(" #{x}")).unwrap_or_default();
+        let details = prompt
+            .details
+            .map(|x| format!(" ({x})"))
+            .unwrap_or_default();
+        return format!("{header}{id}{details}\n{rest}")
+            .trim_end()
+            .to_string();
+    }
+
+    patch.to_string()
+}
+
+/// Ask whether to apply all hunks of a file and its `--paired` companion, skip both files, or
+/// review each hunk. Returns the answer for every hunk of both files, or `None` to review them.
+pub fn pair_prompt(path: &Path, companion: &Path) -> Option<MenuOption> {
+    let prompt = format!(
+        "'{}' is paired with '{}'. Apply all hunks of both files, skip both, or review each hunk \
+        [y/n/r]?",
+        path.display(),
+        companion.display(),
+    );
+    match measure(Phase::Prompt, || choice_prompt(&prompt, &['y', 'n', 'r'])) {
+        'y' => Some(MenuOption::Yes),
+        'n' => Some(MenuOption::No),
+        _ => None,
+    }
+}
+
+/// Ask whether to replace each of the hunk's `matches` in turn, where the hunk starts at the
+/// (0-indexed) line `line_num`. Returns the accepted matches, or `None` to go back to the patch
+/// prompt.
+fn match_prompt<'a>(
+    original: &[u8],
+    matches: &'a [crate::util::MatchReplacement],
+    line_num: u64,
+) -> Option<Vec<&'a crate::util::MatchReplacement>> {
+    const REMOVED_STYLE: anstyle::Style = anstyle::AnsiColor::Red.on_default();
+    const ADDED_STYLE: anstyle::Style = anstyle::AnsiColor::Green.on_default();
+
+    let mut accepted = Vec::new();
+    for (i, m) in matches.iter().enumerate() {
+        // the match is shown within its lines
+        let start = original[..m.range.start]
+            .rfind_byte(b'\n')
+            .map_or(0, |x| x + 1);
+        let end = original[m.range.end..]
+            .find_byte(b'\n')
+            .map_or(original.len(), |x| m.range.end + x);
+        let line = line_num + original[..start].find_iter(b"\n").count() as u64;
+
+        println!(
+            "\nMatch {}/{} on line {}:",
+            i + 1,
+            matches.len(),
+            style!(line + 1, &COUNT_STYLE),
+        );
+        println!(
+            "{}{}{}{}",
+            escape_ansi(&original[start..m.range.start]).as_bstr(),
+            style!(
+                format!("[-{}-]", escape_ansi(&original[m.range.clone()]).as_bstr()),
+                &REMOVED_STYLE
+            ),
+            style!(
+                format!("{{+{}+}}", escape_ansi(&m.replacement).as_bstr()),
+                &ADDED_STYLE
+            ),
+            escape_ansi(&original[m.range.end..end]).as_bstr(),
+        );
+
+        const MATCH_PROMPT: &str =
+            "Replace this match, keep it, or go back to the whole hunk [y/n/b]?";
+        match measure(Phase::Prompt, || {
+            choice_prompt(MATCH_PROMPT, &['y', 'n', 'b'])
+        }) {
+            'y' => accepted.push(m),
+            'n' => {}
+            _ => return None,
+        }
+    }
+
+    println!(
+        "Replacing {} of {} matches in this hunk.",
+        style!(accepted.len(), &COUNT_STYLE),
+        style!(matches.len(), &COUNT_STYLE),
+    );
+    Some(accepted)
+}
+
+pub fn yes_no_prompt(prompt: &str) -> bool {
+    choice_prompt(prompt, &['y', 'n']) == 'y'
+}
+
+/// Ask until the answer starts with one of the `choices`, and return it. The `choices` must
+/// include 'n', which is returned if there's no more input.
+fn choice_prompt(prompt: &str, choices: &[char]) -> char {
+    debug_assert!(choices.contains(&'n'));
+    loop {
+        style_print!(&STAGE_STYLE, "{prompt} ");
+        std::io::stdout().flush().unwrap();
+
+        let mut input = String::new();
+        if crate::prompter::read_line(&mut input).unwrap() == 0 {
+            // EOF
+            println!();
+            return 'n';
+        }
+
+        match input.trim().chars().next() {
+            Some(x) if choices.contains(&x) => return x,
+            _ => {}
+        }
+    }
+}
+
+/// Format the diff from the `proposed` lines of a hunk to the lines of the user's `edited` patch,
+/// without its '---' and '+++' lines.
+fn edit_diff(proposed: &[u8], edited: &[u8], color: bool) -> String {
+    let (proposed, edited) = (escape_ansi(proposed), escape_ansi(edited));
+    let patch = diffy::DiffOptions::new()
+        .set_context_len(usize::MAX)
+        .create_patch_bytes(&proposed, &edited);
+
+    let mut patch_bytes = Vec::new();
+    let mut formatter = diffy::PatchFormatter::new();
+    if color {
+        formatter = formatter.with_color();
+    }
+    formatter
+        .write_patch_into(&patch, &mut patch_bytes)
+        .unwrap();
+
+    let patch = String::from_utf8_lossy(&patch_bytes);
+    let patch = patch.trim_end();
+    let start = patch.match_indices('\n').nth(1).map_or(0, |(i, _)| i + 1);
+
+    match is_plain() {
+        true => plain_markers(&patch[start..]),
+        false => patch[start..].to_string(),
+    }
+}
+
+enum EditedPatchError {
+    Invalid(diffy::ParsePatchError),
+    /// The patch doesn't apply, and the lines with conflict markers from [`conflict_markers`].
+    DoesNotApply(diffy::ApplyError, Vec<u8>),
+}
+
+/// Apply the patch that the user edited to the `original` lines. The patch is never decoded as
+/// text, so lines that aren't valid UTF-8 are kept exactly as they were written. The line counts
+/// of the hunk header don't need to be updated by the user.
+fn apply_edited_patch(original: &[u8], edited: &[u8]) -> Result<Vec<u8>, EditedPatchError> {
+    let edited = crate::util::rewrite_patch_line_counts(edited);
+    let patch = diffy::Patch::from_bytes(&edited).map_err(EditedPatchError::Invalid)?;
+    diffy::apply_bytes(original, &patch)
+        .map_err(|e| EditedPatchError::DoesNotApply(e, conflict_markers(original, &patch)))
+}
+
+/// The original lines of a hunk and the lines of an edited patch that doesn't apply to them,
+/// separated by conflict markers so that the user can resolve them later in their editor.
+fn conflict_markers(original: &[u8], patch: &diffy::Patch<[u8]>) -> Vec<u8> {
+    let mut edited = Vec::new();
+    for hunk in patch.hunks() {
+        for line in hunk.lines() {
+            match line {
+                diffy::Line::Context(x) | diffy::Line::Insert(x) => edited.extend_from_slice(x),
+                diffy::Line::Delete(_) => {}
+            }
+        }
+    }
+
+    let mut conflict = Vec::new();
+    for (marker, lines) in [
+        (&b"<<<<<<< original\n"[..], original),
+        (b"=======\n", &edited),
+    ] {
+        conflict.extend_from_slice(marker);
+        conflict.extend_from_slice(lines);
+        if !lines.is_empty() && !lines.ends_with(b"\n") {
+            conflict.push(b'\n');
+        }
+    }
+    conflict.extend_from_slice(b">>>>>>> edited\n");
+
+    conflict
+}
+
+/// Show the main menu and ask the user for a command. Returns `MainMenuOption::Quit` if stdin is
+/// closed.
+pub fn main_menu_prompt() -> MainMenuOption {
+    let help = [
+        "status - show the files and their match counts",
+        "patch  - choose what to do with each hunk of the selected files",
+        "diff   - show the hunks of the selected files",
+        "select - choose which files are selected",
+        "quit   - exit",
+        "Commands can be given by their number or a unique prefix.",
+    ]
+    .join("\n");
+
+    loop {
+        let commands: Vec<_> = MainMenuOption::list()
+            .iter()
+            .enumerate()
+            .map(|(i, x)| format!("{}: {}", i + 1, x.as_str()))
+            .collect();
+        style_println!(&STAGE_STYLE, "*** Commands ***");
+        println!("  {}", commands.join("  "));
+        style_print!(&STAGE_STYLE, "What now? ");
+        std::io::stdout().flush().unwrap();
+
+        let mut input = String::new();
+        if crate::prompter::read_line(&mut input).unwrap() == 0 {
+            // EOF
+            println!();
+            return MainMenuOption::Quit;
+        }
+        let input = input.trim();
+
+        if let Ok(num) = input.parse::<usize>() {
+            if let Some(x) = num
+                .checked_sub(1)
+                .and_then(|x| MainMenuOption::list().get(x))
+            {
+                return *x;
+            }
+        } else if !input.is_empty() {
+            let mut options = MainMenuOption::list()
+                .iter()
+                .filter(|x| x.as_str().starts_with(input));
+            if let (Some(x), None) = (options.next(), options.next()) {
+                return *x;
+            }
+        }
+
+        if !input.is_empty() {
+            style_println!(&HELP_STYLE, "{help}");
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BatchOption {
+    Continue,
+    Stop,
+    ApplyRest,
+}
+
+/// Ask the user whether to continue after a batch of files. Returns `BatchOption::Stop` if stdin is
+/// closed.
+pub fn batch_prompt(done: usize, remaining: usize) -> BatchOption {
+    loop {
+        style_print!(
+            &STAGE_STYLE,
+            "Finished {done} file{}, {remaining} remaining. Continue, stop here, or apply the rest \
+            [c,s,a]? ",
+            if done == 1 { "" } else { "s" },
+        );
+        std::io::stdout().flush().unwrap();
+
+        let mut input = String::new();
+        if crate::prompter::read_line(&mut input).unwrap() == 0 {
+            // EOF
+            println!();
+            return BatchOption::Stop;
+        }
+
+        match input.trim() {
+            "c" => return BatchOption::Continue,
+            "s" => return BatchOption::Stop,
+            "a" => return BatchOption::ApplyRest,
+            _ => style_println!(
+                &HELP_STYLE,
+                "c - continue with the next batch\n\
+                s - stop here and leave the remaining files\n\
+                a - apply all remaining patches without asking",
+            ),
+        }
+    }
+}
+
+/// Show the files and their match counts, and allow the user to toggle which files should be
+/// included, starting with the files in `selected`. Returns which files were selected, or `None`
+/// if the user chose to quit.
+pub fn file_selection_prompt(
+    files: &[(&Path, usize)],
+    dirs: &[(&Path, std::ops::Range<usize>)],
+    mut selected: Vec<bool>,
+) -> Option<Vec<bool>> {
+    let mut help = vec![
+        "<N> - toggle file N",
+        "<N>-<M> - toggle files N through M",
+        "<GLOB> - toggle files matching the glob",
+    ];
+    if !dirs.is_empty() {
+        help.push("d<N> - select all files in directory N, or deselect them if all are selected");
+        help.push("d<N>-<M> - select or deselect directories N through M");
+    }
+    help.extend([
+        "a - select all files",
+        "n - deselect all files",
+        "e - edit the list of selected files in your editor; deleted files are deselected",
+        "q - quit; do not replace any hunks",
+        "? - print help",
+        "<enter> - continue with the selected files",
+    ]);
+    let help = help.join("\n");
+
+    let mut print_files = true;
+
+    loop {
+        if print_files {
+            for (i, ((path, count), is_selected)) in files.iter().zip(&selected).enumerate() {
+                if let Some(dir_idx) = dirs.iter().position(|(_, range)| range.start == i) {
+                    let (dir, range) = &dirs[dir_idx];
+                    let num_selected = selected[range.clone()].iter().filter(|x| **x).count();
+                    let count: usize = files[range.clone()].iter().map(|x| x.1).sum();
+                    style_println!(
+                        &STAGE_STYLE,
+                        "[{}] d{}: {}/ ({} match{} in {} file{})",
+                        match num_selected {
+                            0 => " ",
+                            x if x == range.len() => "x",
+                            _ => "-",
+                        },
+                        dir_idx + 1,
+                        dir.display(),
+                        count,
+                        if count == 1 { "" } else { "es" },
+                        range.len(),
+                        if range.len() == 1 { "" } else { "s" },
+                    );
+                }
+                println!(
+                    "[{}] {}: {} ({} match{})",
+                    if *is_selected { "x" } else { " " },
+                    i + 1,
+                    path.display(),
+                    style!(count, &COUNT_STYLE),
+                    if *count == 1 { "" } else { "es" },
+                );
+            }
+        }
+        print_files = true;
+
+        style_print!(
+            &STAGE_STYLE,
+            "({}/{}) Toggle files [<N>,<N>-<M>,<GLOB>,{}a,n,e,q,?]? ",
+            selected.iter().filter(|x| **x).count(),
+            files.len(),
+            if dirs.is_empty() { "" } else { "d<N>," },
+        );
+        std::io::stdout().flush().unwrap();
+
+        let mut input = String::new();
+        crate::prompter::read_line(&mut input).unwrap();
+
+        match input.trim() {
+            "" => return Some(selected),
+            "a" => selected.iter_mut().for_each(|x| *x = true),
+            "n" => selected.iter_mut().for_each(|x| *x = false),
+            "e" => {
+                let list = file_list(files, &selected);
+                match measure(Phase::Prompt, || {
+                    user_edit(&list, crate::util::editor_cmd())
+                }) {
+                    Ok(Some(edited)) => selected = edited_file_list(files, &selected, &edited),
+                    Ok(None) => error!("The editor did not exit successfully."),
+                    Err(e) => error!("File list editing failed: {e}."),
+                }
+            }
+            "q" => return None,
+            "?" => {
+                style_println!(&HELP_STYLE, "{help}");
+                print_files = false;
+            }
+            input => {
+                let mut toggle = vec![false; files.len()];
+                let mut valid = true;
+
+                for token in input.split([',', ' ']).filter(|x| !x.is_empty()) {
+                    let dir_range = match (token.strip_prefix('d'), dirs.is_empty()) {
+                        (Some(x), false) => crate::parse::index_range(x),
+                        _ => None,
+                    };
+
+                    if let Some(range) = dir_range {
+                        if *range.end() > dirs.len() as u64 {
+                            valid = false;
+                            break;
+                        }
+                        for i in range {
+                            let files = dirs[i as usize - 1].1.clone();
+                            // select the whole directory, unless it's already selected
+                            let all_selected = selected[files.clone()].iter().all(|x| *x);
+                            for i in files {
+                                toggle[i] = selected[i] == all_selected;
+                            }
+                        }
+                    } else if let Some(range) = crate::parse::index_range(token) {
+                        if *range.end() > files.len() as u64 {
+                            valid = false;
+                            break;
+                        }
+                        for i in range {
+                            toggle[i as usize - 1] ^= true;
+                        }
+                    } else if let Ok(glob) = globset::Glob::new(token) {
+                        let glob = glob.compile_matcher();
+                        for (i, (path, _)) in files.iter().enumerate() {
+                            // users will typically write "src/*" rather than "./src/*"
+                            let short_path = path.strip_prefix("./").unwrap_or(path);
+                            if glob.is_match(path) || glob.is_match(short_path) {
+                                toggle[i] ^= true;
+                            }
+                        }
+                    } else {
+                        valid = false;
+                        break;
+                    }
+                }
+
+                if !valid {
+                    // could not parse the input, so print help text then restart
+                    style_println!(&HELP_STYLE, "{help}");
+                    print_files = false;
+                    continue;
+                }
+
+                for (selected, toggle) in selected.iter_mut().zip(toggle) {
+                    *selected ^= toggle;
+                }
+            }
+        }
+    }
+}
+
+/// The paths of the selected files with one per line, to be edited by the user. Paths containing
+/// a newline can't be listed.
+fn file_list(files: &[(&Path, usize)], selected: &[bool]) -> Vec<u8> {
+    let mut list = b"# Delete the lines of the files that shouldn't be patched.\n".to_vec();
+    for ((path, _), _) in files.iter().zip(selected).filter(|(_, x)| **x) {
+        let path = path.as_os_str().as_encoded_bytes();
+        if !path.contains(&b'\n') {
+            list.extend_from_slice(path);
+            list.push(b'\n');
+        }
+    }
+    list
+}
+
+/// Which files are selected after the user edited the [`file_list`] of the files in `selected`.
+/// The files that are still listed stay selected, and the files that can't be listed are
+/// unchanged.
+fn edited_file_list(files: &[(&Path, usize)], selected: &[bool], edited: &[u8]) -> Vec<bool> {
+    let listed: std::collections::HashSet<&[u8]> = edited
+        .lines()
+        .map(|x| x.trim_end())
+        .filter(|x| !x.starts_with(b"#"))
+        .collect();
+
+    files
+        .iter()
+        .zip(selected)
+        .map(|((path, _), selected)| {
+            let path = path.as_os_str().as_encoded_bytes();
+            *selected && (path.contains(&b'\n') || listed.contains(path))
+        })
+        .collect()
+}
+
+/// Show a fuzzy finder over the files and let the user select files. Returns `None` if the user
+/// cancelled, otherwise the selected state of each file. If the user accepts without selecting any
+/// files, the file under the cursor is selected.
+pub fn fuzzy_pick_prompt(files: &[(&Path, usize)]) -> std::io::Result<Option<Vec<bool>>> {
+    let mut selected = vec![false; files.len()];
+    let mut query = String::new();
+    let mut cursor = 0;
+
+    let names: Vec<String> = files
+        .iter()
+        .map(|(path, _)| path.to_string_lossy().into_owned())
+        .collect();
+
+    let _terminal = RawTerminal::new(/* alternate_screen= */ true)?;
+    let mut stdin = std::io::stdin().lock();
+    // the last read filled the buffer, so more input may already be buffered
+    let mut buffered = false;
+
+    loop {
+        // the matching files, with the best matches first
+        let mut shown: Vec<(usize, i64, Vec<usize>)> = names
+            .iter()
+            .enumerate()
+            .filter_map(|(i, name)| {
+                let (score, positions) = crate::fuzzy::score(&query, name)?;
+                Some((i, score, positions))
+            })
+            .collect();
+        shown.sort_by_key(|(i, score, _)| (std::cmp::Reverse(*score), names[*i].len(), *i));
+
+        let (width, height) = terminal_size();
+        shown.truncate(height.saturating_sub(2));
+        cursor = cursor.min(shown.len().saturating_sub(1));
+
+        let mut out = String::from("\x1b[H\x1b[2J");
+        for (row, (i, _, positions)) in shown.iter().enumerate() {
+            let marker = if row == cursor { '>' } else { ' ' };
+            let check = if selected[*i] { '*' } else { ' ' };
+            out.push_str(&format!("{marker}{check} "));
+
+            // the name is cut off at the edge of the terminal, where wide chars take up two columns
+            let mut shown_width = 0;
+            let fits = |c: &char| {
+                shown_width += unicode_width::UnicodeWidthChar::width(*c).unwrap_or(0);
+                shown_width <= width.saturating_sub(3)
+            };
+            for (idx, c) in names[*i].chars().take_while(fits).enumerate() {
+                if positions.contains(&idx) {
+                    out.push_str(&format!("{MATCH_STYLE}{c}{MATCH_STYLE:#}"));
+                } else {
+                    out.push(c);
+                }
+            }
+            out.push_str("\r\n");
+        }
+        out.push_str(&format!(
+            "{STAGE_STYLE}({}/{}) [tab: toggle, ctrl-a: toggle shown, enter: accept, \
+            esc: cancel]{STAGE_STYLE:#}\r\n> {query}",
+            selected.iter().filter(|x| **x).count(),
+            files.len(),
+        ));
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()?;
+
+        // redraw if the terminal is resized while waiting for input (input that stdin has already
+        // buffered doesn't make it readable)
+        if !buffered && !wait_for_input_or_resize((width, height))? {
+            continue;
+        }
+
+        let mut buf = [0; 16];
+        let len = stdin.read(&mut buf)?;
+        buffered = len == buf.len();
+        match &buf[..len] {
+            // EOF, esc, or ctrl-c
+            [] | [0x1b] | [0x03] => return Ok(None),
+            // enter
+            [b'\r'] | [b'\n'] => {
+                if !selected.contains(&true) {
+                    match shown.get(cursor) {
+                        Some((i, _, _)) => selected[*i] = true,
+                        None => continue,
+                    }
+                }
+                return Ok(Some(selected));
+            }
+            // tab
+            [b'\t'] => {
+                if let Some((i, _, _)) = shown.get(cursor) {
+                    selected[*i] ^= true;
+                    cursor += 1;
+                }
+            }
+            // ctrl-a
+            [0x01] => {
+                for (i, _, _) in &shown {
+                    selected[*i] ^= true;
+                }
+            }
+            // up arrow and ctrl-p
+            [0x1b, b'[', b'A'] | [0x10] => cursor = cursor.saturating_sub(1),
+            // down arrow and ctrl-n
+            [0x1b, b'[', b'B'] | [0x0e] => cursor += 1,
+            // backspace
+            [0x7f] | [0x08] => {
+                query.pop();
+                cursor = 0;
+            }
+            // ctrl-u
+            [0x15] => {
+                query.clear();
+                cursor = 0;
+            }
+            input => {
+                // ignore other control chars and escape sequences
+                if let Ok(x) = std::str::from_utf8(input) {
+                    if !x.chars().any(char::is_control) {
+                        query.push_str(x);
+                        cursor = 0;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Wait until stdin is readable or the terminal is no longer the size `size`. Returns false if the
+/// terminal was resized first.
+fn wait_for_input_or_resize(size: (usize, usize)) -> std::io::Result<bool> {
+    const RESIZE_CHECK: std::time::Duration = std::time::Duration::from_millis(100);
+
+    loop {
+        if wait_for_stdin(RESIZE_CHECK)? {
+            return Ok(true);
+        }
+        if terminal_size() != size {
+            return Ok(false);
+        }
+    }
+}
+
+/// Puts the terminal in raw mode, optionally with an alternate screen, and restores it when
+/// dropped.
+pub(crate) struct RawTerminal {
+    original: libc::termios,
+    alternate_screen: bool,
+}
+
+impl RawTerminal {
+    pub fn new(alternate_screen: bool) -> std::io::Result<Self> {
+        let mut original = std::mem::MaybeUninit::uninit();
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, original.as_mut_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let original = unsafe { original.assume_init() };
+
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        if alternate_screen {
+            print!("\x1b[?1049h");
+        }
+
+        Ok(Self {
+            original,
+            alternate_screen,
+        })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if self.alternate_screen {
+            print!("\x1b[?1049l");
+        }
+        let _ = std::io::stdout().flush();
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &self.original) };
+    }
+}
+
+/// The width and height of the terminal, or 80x24 if unknown. The terminal can be resized at any
+/// time, so this is queried again before each redraw rather than being cached.
+pub fn terminal_size() -> (usize, usize) {
+    if is_test_mode() {
+        return (80, 24);
+    }
+    let mut size = std::mem::MaybeUninit::<libc::winsize>::uninit();
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, size.as_mut_ptr()) } != 0 {
+        return (80, 24);
+    }
+    let size = unsafe { size.assume_init() };
+    match (size.ws_col, size.ws_row) {
+        (0, _) | (_, 0) => (80, 24),
+        (cols, rows) => (cols.into(), rows.into()),
+    }
+}
+
+/// The patches of a hunk, which are computed before the hunk is shown.
+pub struct HunkDiffs {
+    /// The real patch.
+    patch: Vec<u8>,
+    /// A modified patch that is safe to print to the terminal.
+    safe_patch: Vec<u8>,
+    /// Whether very long lines were shortened in the safe patch.
+    shortened: bool,
+}
+
+impl HunkDiffs {
+    pub fn new(original: &[u8], replaced: &[u8], max_line_len: Option<usize>) -> Self {
+        // use a large context length so that diffy does not do its own hunking
+        let mut diff_options = diffy::DiffOptions::new();
+        diff_options.set_context_len(usize::MAX);
+
+        let patch = measure(Phase::Diff, || {
+            diff_options
+                .create_patch_bytes(original, replaced)
+                .to_bytes()
+        });
+
+        // very long lines are shortened so that they can be shown
+        let shortened = max_line_len.and_then(|max| shorten_long_lines(original, replaced, max));
+        let (shown_original, shown_replaced) = match &shortened {
+            Some((original, replaced)) => (original.as_slice(), replaced.as_slice()),
+            None => (original, replaced),
+        };
+
+        let safe_current = escape_ansi(shown_original);
+        let safe_replaced = escape_ansi(shown_replaced);
+        let safe_patch = measure(Phase::Diff, || {
+            diff_options
+                .create_patch_bytes(&safe_current, &safe_replaced)
+                .to_bytes()
+        });
+
+        Self {
+            patch,
+            safe_patch,
+            shortened: shortened.is_some(),
+        }
+    }
+}
+
+pub fn patch_prompt(
+    original: &[u8],
+    replaced: &[u8],
+    mut src_paths: Option<FilePaths>,
+    progress: (u64, u64),
+    line_num: u64,
+    input: Option<MenuOption>,
+    options: PromptOptions,
+) -> PatchOption {
+    let computed;
+    let diffs = match options.diffs {
+        Some(x) => x,
+        None => {
+            computed = HunkDiffs::new(original, replaced, options.max_line_len);
+            &computed
+        }
+    };
+    let patch = diffy::Patch::from_bytes(&diffs.patch).unwrap();
+    let safe_patch = diffy::Patch::from_bytes(&diffs.safe_patch).unwrap();
+
+    label!('patch_prompt: {
+        // take the file path so that it's only ever shown once
+        let src_paths = src_paths.take();
+
+        // show the patch to the user and have them choose how to proceed
+        match menu_prompt(
+            &safe_patch,
+            src_paths,
+            progress,
+            line_num,
+            input,
+            options,
+            diffs.shortened,
+        ) {
+            Answer::Choice(i) => PatchOption::WriteNew(options.choices[i].replaced.clone()),
+            Answer::Option(
+                x @ (MenuOption::Yes
+                | MenuOption::ApplyFile
+                | MenuOption::ApplyAll
+                | MenuOption::ApplySimilar),
+            ) => {
+                if let (true, Some(sticky)) = (x.is_sticky(), options.sticky) {
+                    sticky.set(Some(x));
+                }
+                if let (true, Some(similar)) = (x.is_similar(), options.similar) {
+                    similar.set(Some(x));
+                }
+                // apply the patch
+                let new_hunk = diffy::apply_bytes(original, &patch).unwrap();
+                PatchOption::WriteNew(new_hunk)
+            }
+            Answer::Option(
+                x @ (MenuOption::No | MenuOption::SkipFile | MenuOption::SkipSimilar),
+            ) => {
+                if let (true, Some(sticky)) = (x.is_sticky(), options.sticky) {
+                    sticky.set(Some(x));
+                }
+                if let (true, Some(similar)) = (x.is_similar(), options.similar) {
+                    similar.set(Some(x));
+                }
+                PatchOption::WriteOriginal
+            }
+            Answer::Option(MenuOption::Quit) => PatchOption::Quit,
+            Answer::Option(MenuOption::Matches) => {
+                let Some(accepted) = match_prompt(original, options.matches, line_num) else {
+                    continue 'patch_prompt;
+                };
+                if accepted.len() == options.matches.len() {
+                    PatchOption::WriteNew(diffy::apply_bytes(original, &patch).unwrap())
+                } else if accepted.is_empty() {
+                    PatchOption::WriteOriginal
+                } else {
+                    PatchOption::WriteNew(crate::util::apply_match_replacements(
+                        original, &accepted,
+                    ))
+                }
+            }
+            Answer::Option(
+                MenuOption::Note
+                | MenuOption::Bytes
+                | MenuOption::Unfold
+                | MenuOption::Captures
+                | MenuOption::Search,
+            ) => {
+                unreachable!(
+                    "notes, bytes, unfolding, and searches are handled within the menu prompt"
+                )
+            }
+            Answer::Option(MenuOption::Edit) => label!('edit_prompt: {
+                const INVALID_PATCH_PROMPT: &str =
+                    r#"Your patch is invalid. Edit again (saying "no" discards!) [y/n]?"#;
+                const DOES_NOT_APPLY_PROMPT: &str = "Your edited hunk does not apply. Edit again, \
+                    write it with conflict markers, or discard it [y/c/n]?";
+
+                let edited = {
+                    let editor_cmd = crate::util::editor_cmd();
+
+                    // allow the user to edit the patch
+                    let edited = measure(Phase::Prompt, || {
+                        user_edit(&patch.to_bytes(), editor_cmd.clone())
+                    });
+                    let patch = match edited {
+                        Ok(Some(x)) => x,
+                        Ok(None) => {
+                            // the editor didn't exit successfully
+                            error!("The editor did not exit successfully.");
+                            continue 'patch_prompt;
+                        }
+                        Err(UserEditError::EditorNotFound) => {
+                            let mut editor_cmd = editor_cmd;
+                            let editor = editor_cmd.next().unwrap().as_ref().to_owned();
+                            error!("The editor {editor:?} was not found.");
+                            continue 'patch_prompt;
+                        }
+                        Err(e) => {
+                            error!("Patch editing failed: {e}.");
+                            continue 'patch_prompt;
+                        }
+                    };
+
+                    // this also ignores whitespace since editors may add a newline at the end of
+                    // the file
+                    if patch.trim().is_empty() {
+                        // not even the patch header exists anymore
+                        error!("The edited patch file was empty.");
+                        continue 'patch_prompt;
+                    }
+
+                    match apply_edited_patch(original, &patch) {
+                        Ok(x) => Ok(x),
+                        Err(EditedPatchError::Invalid(e)) => {
+                            error!("{e}");
+                            Err((INVALID_PATCH_PROMPT, None))
+                        }
+                        Err(EditedPatchError::DoesNotApply(e, conflict)) => {
+                            println!("{e}");
+                            Err((DOES_NOT_APPLY_PROMPT, Some(conflict)))
+                        }
+                    }
+                };
+
+                match edited {
+                    Ok(edited) => {
+                        let proposed = diffy::apply_bytes(original, &patch).unwrap();
+                        if edited == proposed {
+                            break 'edit_prompt PatchOption::WriteNew(edited);
+                        }
+
+                        // show how the edits differ from the proposed hunk before applying them
+                        println!("Your edits changed the proposed hunk:");
+                        println!("{}", edit_diff(&proposed, &edited, use_color()));
+                        const CONFIRM_EDIT_PROMPT: &str = "Apply your edited hunk, edit again, or \
+                            go back to the proposed hunk [y/e/n]?";
+                        match measure(Phase::Prompt, || {
+                            choice_prompt(CONFIRM_EDIT_PROMPT, &['y', 'e', 'n'])
+                        }) {
+                            'y' => PatchOption::WriteNew(edited),
+                            'e' => continue 'edit_prompt,
+                            _ => continue 'patch_prompt,
+                        }
+                    }
+                    Err((msg, None)) => {
+                        if yes_no_prompt(msg) {
+                            // answered "yes", so edit again
+                            continue 'edit_prompt;
+                        }
+                        // answered "no", so discard and use original
+                        PatchOption::WriteOriginal
+                    }
+                    Err((msg, Some(conflict))) => match choice_prompt(msg, &['y', 'c', 'n']) {
+                        'y' => continue 'edit_prompt,
+                        'c' => PatchOption::WriteNew(conflict),
+                        _ => PatchOption::WriteOriginal,
+                    },
+                }
+            }),
+        }
+    })
+}
+
+/// Show all of a file's changes as one diff and ask whether to apply them, for
+/// `--interactive=once`. `diff` is the diff from `original` to the file with every hunk replaced.
+/// The diff can be edited, in which case the edited file is returned.
+pub fn file_prompt(original: &[u8], diff: &[u8]) -> PatchOption {
+    let patch = diffy::Patch::from_bytes(diff).unwrap();
+
+    let mut formatted = Vec::new();
+    let mut formatter = diffy::PatchFormatter::new();
+    if use_color() {
+        formatter = formatter.with_color();
+    }
+    formatter.write_patch_into(&patch, &mut formatted).unwrap();
+    let mut formatted = String::from_utf8_lossy(&formatted).trim_end().to_string();
+    if is_plain() {
+        formatted = plain_markers(&formatted);
+    }
+
+    let options = [
+        (MenuOption::Yes, "apply all changes to this file"),
+        (MenuOption::No, "do not change this file"),
+        (
+            MenuOption::Quit,
+            "quit; do not change this file or any later files",
+        ),
+        (MenuOption::Edit, "manually edit the changes to this file"),
+    ];
+    let keys = options
+        .iter()
+        .map(|(x, _)| x.keys().join("/"))
+        .chain(std::iter::once("?".to_string()))
+        .collect::<Vec<String>>()
+        .join(",");
+    let help = options
+        .iter()
+        .map(|(x, help)| format!("{} - {help}", x.keys().join(", ")))
+        .chain(std::iter::once("? - print help".to_string()))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    println!("{formatted}");
+
+    loop {
+        style_print!(&STAGE_STYLE, "Apply all changes to this file [{keys}]? ");
+        std::io::stdout().flush().unwrap();
+
+        let mut input = String::new();
+        if measure(Phase::Prompt, || crate::prompter::read_line(&mut input)).unwrap() == 0 {
+            // EOF
+            println!();
+            return PatchOption::Quit;
+        }
+
+        let option = input
+            .trim()
+            .parse::<MenuOption>()
+            .ok()
+            .filter(|x| options.iter().any(|(o, _)| o == x));
+
+        match option {
+            Some(MenuOption::Yes) => {
+                return PatchOption::WriteNew(diffy::apply_bytes(original, &patch).unwrap());
+            }
+            Some(MenuOption::No) => return PatchOption::WriteOriginal,
+            Some(MenuOption::Quit) => return PatchOption::Quit,
+            Some(_) => {
+                let edited = measure(Phase::Prompt, || user_edit(diff, crate::util::editor_cmd()));
+                let edited = match edited {
+                    Ok(Some(x)) => x,
+                    Ok(None) => {
+                        error!("The editor did not exit successfully.");
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Patch editing failed: {e}.");
+                        continue;
+                    }
+                };
+
+                // an empty patch doesn't change anything
+                if edited.trim().is_empty() {
+                    return PatchOption::WriteOriginal;
+                }
+
+                match apply_edited_patch(original, &edited) {
+                    Ok(x) => return PatchOption::WriteNew(x),
+                    Err(EditedPatchError::DoesNotApply(e, _)) => {
+                        error!("Your edited patch does not apply: {e}")
+                    }
+                    Err(EditedPatchError::Invalid(e)) => error!("Your patch is invalid: {e}"),
+                }
+            }
+            None => {
+                style_println!(&HELP_STYLE, "{help}");
+                println!("{formatted}");
+            }
+        }
+    }
+}
+
+pub enum PatchOption {
+    WriteNew(Vec<u8>),
+    WriteOriginal,
+    Quit,
+}
+
+/// The paths of a file that are shown to the user.
+#[derive(Copy, Clone, Debug)]
+pub struct FilePaths<'a> {
+    /// The path as chosen by `--path-display`.
+    pub display: &'a Path,
+    /// The path relative to the root, for use in patch headers.
+    pub patch: &'a Path,
+    /// The path that the file was found at.
+    pub real: &'a Path,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MainMenuOption {
+    Status,
+    Patch,
+    Diff,
+    Select,
+    Quit,
+}
+
+impl MainMenuOption {
+    pub const fn list() -> &'static [Self] {
+        &[
+            Self::Status,
+            Self::Patch,
+            Self::Diff,
+            Self::Select,
+            Self::Quit,
+        ]
+    }
+
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Status => "status",
+            Self::Patch => "patch",
+            Self::Diff => "diff",
+            Self::Select => "select",
+            Self::Quit => "quit",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MenuOption {
+    Yes,
+    No,
+    Quit,
+    Edit,
+    Note,
+    Bytes,
+    Unfold,
+    Captures,
+    Matches,
+    Search,
+    ApplyFile,
+    SkipFile,
+    ApplyAll,
+    ApplySimilar,
+    SkipSimilar,
+}
+
+impl MenuOption {
+    pub const fn list() -> &'static [Self] {
+        &[
+            Self::Yes,
+            Self::No,
+            Self::ApplyFile,
+            Self::SkipFile,
+            Self::ApplyAll,
+            Self::ApplySimilar,
+            Self::SkipSimilar,
+            Self::Quit,
+            Self::Edit,
+            Self::Note,
+            Self::Bytes,
+            Self::Unfold,
+            Self::Captures,
+            Self::Matches,
+            Self::Search,
+        ]
+    }
+
+    pub const fn as_char(&self) -> &'static str {
+        // return a str instead of a char since they're much easier to work with (there is no char
+        // -> str const function)
+        match self {
+            Self::Yes => "y",
+            Self::No => "n",
+            Self::Quit => "q",
+            Self::Edit => "e",
+            Self::Note => "N",
+            Self::Bytes => "b",
+            Self::Unfold => "u",
+            Self::Captures => "c",
+            Self::Matches => "m",
+            Self::Search => "/",
+            Self::ApplyFile => "a",
+            Self::SkipFile => "d",
+            Self::ApplyAll => "A",
+            Self::ApplySimilar => "g",
+            Self::SkipSimilar => "G",
+        }
+    }
+
+    /// Whether the answer also applies to later hunks.
+    pub const fn is_sticky(&self) -> bool {
+        matches!(self, Self::ApplyFile | Self::SkipFile | Self::ApplyAll)
+    }
+
+    /// Whether the answer also applies to later hunks that are similar to this one.
+    pub const fn is_similar(&self) -> bool {
+        matches!(self, Self::ApplySimilar | Self::SkipSimilar)
+    }
+
+    /// The keys that choose this answer, which may have been customized in the config file.
+    pub fn keys(&self) -> Vec<&'static str> {
+        match KEYS.get() {
+            Some(keys) => keys.get(*self),
+            None => vec![self.as_char()],
+        }
+    }
+
+    pub const fn help(&self) -> &'static str {
+        match self {
+            Self::Yes => "replace this hunk",
+            Self::No => "do not replace this hunk",
+            Self::Quit => "quit; do not replace this hunk or any future hunks",
+            Self::Edit => "manually edit the current hunk",
+            Self::Note => "add a note to this hunk, which is saved with the exported decisions",
+            Self::Bytes => "show the hunk with escaped bytes, to reveal invisible characters",
+            Self::Unfold => "show the unchanged lines that were folded",
+            Self::Captures => "show the capture groups of the matches in this hunk",
+            Self::Matches => "choose which of the matches in this hunk to replace, one at a time",
+            Self::Search => {
+                "search for a regex and skip to the next hunk that matches; do not replace this \
+                hunk or the skipped hunks"
+            }
+            Self::ApplyFile => "replace this hunk and all later hunks in the file",
+            Self::SkipFile => "do not replace this hunk or any of the later hunks in the file",
+            Self::ApplyAll => "replace this hunk and all later hunks in all files",
+            Self::ApplySimilar => "replace this hunk and all later hunks that are similar to it",
+            Self::SkipSimilar => {
+                "do not replace this hunk or any of the later hunks that are similar to it"
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for MenuOption {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::list()
+            .iter()
+            .find(|x| x.keys().contains(&s))
+            .copied()
+            .ok_or(())
+    }
+}
+
+macro_rules! style {
+    ($str:expr, $style:expr) => {{
+        // for type checking
+        let _style: &anstyle::Style = $style;
+        format_args!(
+            "{}{}{:#}",
+            crate::ui::active_style($style),
+            $str,
+            crate::ui::active_style($style),
+        )
+    }};
+}
+pub(crate) use style;
+
+macro_rules! style_print {
+    () => {{
+        print!()
+    }};
     ($style:expr) => {{
         // for type checking
         let _style: &anstyle::Style = $style;
         print!()
     }};
     ($style:expr, $fmt:literal $($arg:tt)*) => {{
-        let style: &anstyle::Style = $style;
+        let style: &anstyle::Style = crate::ui::active_style($style);
         print!("{style}{}{style:#}", format_args!($fmt $($arg)*))
     }};
 }
@@ -458,7 +2300,7 @@ macro_rules! style_println {
         println!()
     }};
     ($style:expr, $fmt:literal $($arg:tt)*) => {{
-        let style: &anstyle::Style = $style;
+        let style: &anstyle::Style = crate::ui::active_style($style);
         println!("{style}{}{style:#}", format_args!($fmt $($arg)*))
     }};
 }
@@ -469,15 +2311,227 @@ macro_rules! error {
         error!("")
     }};
     ($fmt:literal $($arg:tt)*) => {{
-        println!("{} {}", style!("ERROR:", &crate::ui::ERROR_STYLE), format_args!($fmt $($arg)*))
+        crate::ui::report(crate::ui::Level::Error, format_args!($fmt $($arg)*))
     }};
 }
 pub(crate) use error;
 
+macro_rules! warning {
+    ($fmt:literal $($arg:tt)*) => {{
+        crate::ui::report(crate::ui::Level::Warning, format_args!($fmt $($arg)*))
+    }};
+}
+pub(crate) use warning;
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(
+            strip_ansi("found \u{001b}[1m1\u{001b}[0m error"),
+            "found 1 error"
+        );
+        assert_eq!(strip_ansi("a\u{001b}[38;5;1mb"), "ab");
+        assert_eq!(strip_ansi("no styles"), "no styles");
+    }
+
+    #[test]
+    fn test_plain_markers() {
+        assert_eq!(
+            plain_markers("@@ -1,3 +1,3 @@\n a\n-b\n+c\n d"),
+            "@@ -1,3 +1,3 @@\n a\nREMOVED: b\nADDED: c\n d",
+        );
+    }
+
+    #[test]
+    fn test_fold_unchanged() {
+        let lines: Vec<String> = (1..=9).map(|x| format!(" {x}")).collect();
+        let patch = format!(
+            "@@ -1,9 +1,9 @@\n{}\n-a\n+b\n{}",
+            lines.join("\n"),
+            lines.join("\n")
+        );
+        assert_eq!(
+            fold_unchanged(&patch, 2).map(|x| strip_ansi(&x)),
+            Some(
+                "@@ -1,9 +1,9 @@\n… 7 unchanged lines …\n 8\n 9\n-a\n+b\n 1\n 2\n\
+                … 7 unchanged lines …"
+                    .to_string()
+            ),
+        );
+
+        // folding a single line wouldn't hide anything
+        assert_eq!(fold_unchanged("@@ -1,2 +1,2 @@\n 1\n-a\n+b\n 2", 0), None);
+        assert_eq!(fold_unchanged(&patch, 8), None);
+    }
+
+    #[test]
+    fn test_progress_header() {
+        let progress = Progress::new(vec![2, 3, 4]);
+        assert_eq!(
+            progress.header(0, 2),
+            "file 1/3, hunk 1/2, 8 hunks remaining"
+        );
+        progress.set_file(1);
+        progress.record_answer(std::time::Duration::from_secs(10));
+        assert_eq!(
+            progress.header(1, 3),
+            "file 2/3, hunk 2/3, 5 hunks remaining, about 1m 0s left",
+        );
+        progress.set_file(2);
+        assert_eq!(
+            progress.header(3, 4),
+            "file 3/3, hunk 4/4, 0 hunks remaining"
+        );
+        assert_eq!(progress.file_header(), "File 3 of 3");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        use std::time::Duration;
+        assert_eq!(format_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(format_duration(Duration::from_secs(150)), "2m 30s");
+        assert_eq!(format_duration(Duration::from_secs(3900)), "1h 5m");
+    }
+
+    #[test]
+    fn test_escape_bytes() {
+        assert_eq!(escape_bytes(b"a b~"), "a b~");
+        assert_eq!(escape_bytes(b"\ta\r\\"), "\\ta\\r\\\\");
+        assert_eq!(
+            escape_bytes("a\u{200b}b\x1b".as_bytes()),
+            "a\\xe2\\x80\\x8bb\\x1b"
+        );
+    }
+
+    #[test]
+    fn test_format_size_and_age() {
+        use std::time::Duration;
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024), "3.0 MiB");
+
+        assert_eq!(format_age(Duration::from_millis(500)), "just now");
+        assert_eq!(format_age(Duration::from_secs(60)), "1 minute ago");
+        assert_eq!(
+            format_age(Duration::from_secs(3 * 24 * 3600 + 5)),
+            "3 days ago"
+        );
+    }
+
+    #[test]
+    fn test_shorten_long_lines() {
+        assert_eq!(shorten_long_lines(b"abc\n", b"abd\n", 3), None);
+
+        // the lines are shortened around the difference
+        let original = b"0123456789abcdefghij\nxy\n";
+        let replaced = b"0123456789ABCDEFGHIJ\nxy\n";
+        let (a, b) = shorten_long_lines(original, replaced, 8).unwrap();
+        assert_eq!(a.as_bstr(), "[8 bytes] 89abcdef [4 bytes]\nxy\n");
+        assert_eq!(b.as_bstr(), "[8 bytes] 89ABCDEF [4 bytes]\nxy\n");
+
+        // different line counts are shortened from the start
+        let (a, b) = shorten_long_lines(b"0123456789", b"01\n23456789", 4).unwrap();
+        assert_eq!(a.as_bstr(), "0123 [6 bytes]");
+        assert_eq!(b.as_bstr(), "01\n2345 [4 bytes]");
+
+        // lines are shortened by their width, and chars aren't split
+        assert_eq!(shorten_long_lines("aaé".as_bytes(), b"", 3), None);
+        let (a, _) = shorten_long_lines("日本語のテキスト".as_bytes(), b"", 5).unwrap();
+        assert_eq!(a.as_bstr(), "日本 [18 bytes]");
+        let (a, _) = shorten_long_lines("ée\u{301}🦀🦀".as_bytes(), b"", 4).unwrap();
+        assert_eq!(a.as_bstr(), "ée\u{301}🦀 [4 bytes]");
+    }
+
+    #[test]
+    fn test_edit_diff() {
+        assert_eq!(
+            edit_diff(b"a\nfoo\nc\n", b"a\nbar\nc\n", false),
+            "@@ -1,3 +1,3 @@\n a\n-foo\n+bar\n c",
+        );
+    }
+
+    #[test]
+    fn test_conflict_markers() {
+        let patch =
+            diffy::Patch::from_bytes(b"--- a\n+++ b\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n").unwrap();
+        assert_eq!(
+            conflict_markers(b"x\ny", &patch).as_bstr(),
+            "<<<<<<< original\nx\ny\n=======\na\nc\n>>>>>>> edited\n",
+        );
+    }
+
+    #[test]
+    fn test_apply_edited_patch() {
+        // lines that aren't valid utf-8, with a "\r\n" line ending
+        let original = b"a\xff\r\nb\xc3\n";
+        let patch = diffy::create_patch_bytes(original, b"a\xfe\r\nb\xc3\n").to_bytes();
+
+        // the editor adds a line without updating the line counts
+        let cmd = ["sh", "-c", r"printf '+\376\377\n' >> $1", "rust-test"];
+        let edited = user_edit(&patch, cmd).unwrap().unwrap();
+        assert_eq!(edited[..patch.len()], patch);
+
+        assert_eq!(
+            apply_edited_patch(original, &edited)
+                .ok()
+                .unwrap()
+                .as_bstr(),
+            b"a\xfe\r\nb\xc3\n\xfe\xff\n".as_bstr(),
+        );
+
+        // an unchanged patch applies the proposed hunk
+        assert_eq!(
+            apply_edited_patch(original, &patch).ok().unwrap().as_bstr(),
+            b"a\xfe\r\nb\xc3\n".as_bstr(),
+        );
+
+        let Err(EditedPatchError::DoesNotApply(_, conflict)) =
+            apply_edited_patch(b"a\xff\nc\n", &patch)
+        else {
+            panic!("the patch applied");
+        };
+        assert!(conflict.starts_with(b"<<<<<<< original\na\xff\nc\n"));
+        assert!(matches!(
+            apply_edited_patch(original, b"--- a\n+++ b\n@@ -\xff +1 @@\n"),
+            Err(EditedPatchError::Invalid(_)),
+        ));
+    }
+
+    #[test]
+    fn test_edited_file_list() {
+        let files = [
+            (Path::new("a.txt"), 1),
+            (Path::new("b.txt"), 2),
+            (Path::new("c\nd.txt"), 1),
+            (Path::new("e.txt"), 1),
+        ];
+        let selected = [true, true, true, false];
+
+        let list = file_list(&files, &selected);
+        assert_eq!(
+            list,
+            b"# Delete the lines of the files that shouldn't be patched.\na.txt\nb.txt\n",
+        );
+        assert_eq!(
+            edited_file_list(&files, &selected, &list),
+            [true, true, true, false],
+        );
+
+        // unselected files can't be added back, and unknown paths are ignored
+        let edited = b"b.txt  \ne.txt\nf.txt\n";
+        assert_eq!(
+            edited_file_list(&files, &selected, edited),
+            [false, true, true, false],
+        );
+        assert_eq!(
+            edited_file_list(&files, &selected, b""),
+            [false, false, true, false],
+        );
+    }
+
     #[test]
     fn test_parse_patch_options() {
         for (option, as_str) in MenuOption::list().iter().map(|x| (*x, x.as_char())) {
@@ -509,7 +2563,7 @@ mod tests {
     fn test_user_edit_linux() {
         let cmd = ["sh", "-c", "printf foobar > $1", "rust-test"];
         assert_eq!(
-            user_edit_linux(b"hello world", cmd).ok(),
+            user_edit_linux(b"hello world", cmd, false).ok(),
             Some(Some(b"foobar".to_vec()))
         );
     }
@@ -0,0 +1,279 @@
+//! The original contents of the files modified by recent runs, so that a run can be undone with
+//! `repatch undo [ID]`.
+//!
+//! Each run that modifies files has its own directory within the undo directory, named with the
+//! ID of the run, which is the time of the run in seconds since the unix epoch. The directory of a
+//! run contains a "manifest" file where each line has the format "HASH PATH", where "HASH" is a
+//! hash of the file contents after the run. The original contents of the file on line `N`
+//! (starting from 0) are in a file named `N`. Only the most recent runs are kept.
+
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::Context;
+
+use crate::util::fnv1a;
+
+/// The number of runs that can be undone.
+const MAX_RUNS: usize = 20;
+
+fn dir() -> anyhow::Result<PathBuf> {
+    let dir = crate::history::state_dir()
+        .ok_or_else(|| anyhow::anyhow!("could not find the state directory"))?;
+    Ok(dir.join("undo"))
+}
+
+/// A run that can be undone.
+pub struct Run {
+    pub id: String,
+    /// Seconds since the unix epoch.
+    pub time: u64,
+    /// The number of files that the run modified.
+    pub files: usize,
+}
+
+/// The time of a run from its ID, which is the time with an optional "-N" suffix for runs that
+/// were in the same second.
+fn parse_id(id: &str) -> Option<(u64, u64)> {
+    let (time, n) = match id.split_once('-') {
+        Some((time, n)) => (time, n.parse().ok()?),
+        None => (id, 0),
+    };
+    Some((time.parse().ok()?, n))
+}
+
+/// The IDs of the runs that can be undone, from oldest to newest.
+fn run_ids(dir: &Path) -> anyhow::Result<Vec<String>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("could not read '{}'", dir.display())),
+    };
+
+    let mut ids = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let Some(id) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if let (Some(key), true) = (parse_id(&id), entry.file_type()?.is_dir()) {
+            ids.push((key, id));
+        }
+    }
+    ids.sort();
+
+    Ok(ids.into_iter().map(|(_, id)| id).collect())
+}
+
+/// The runs that can be undone, from oldest to newest.
+pub fn list() -> anyhow::Result<Vec<Run>> {
+    let dir = dir()?;
+
+    let mut runs = Vec::new();
+    for id in run_ids(&dir)? {
+        let manifest = std::fs::read(dir.join(&id).join("manifest")).unwrap_or_default();
+        runs.push(Run {
+            time: parse_id(&id).unwrap().0,
+            files: parse_manifest(&manifest).map(|x| x.len()).unwrap_or(0),
+            id,
+        });
+    }
+
+    Ok(runs)
+}
+
+/// The original contents of the files modified during a run.
+#[derive(Default)]
+pub struct Journal {
+    files: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl Journal {
+    /// Record the original contents of a file that was modified. If the file was already recorded,
+    /// the earlier contents are kept.
+    pub fn record(&mut self, path: &Path, original: Vec<u8>) -> anyhow::Result<()> {
+        let path = std::fs::canonicalize(path)
+            .with_context(|| format!("could not resolve the path '{}'", path.display()))?;
+
+        // the manifest can't contain paths with newlines, so these files can't be undone
+        if path.as_os_str().as_bytes().contains(&b'\n') {
+            return Ok(());
+        }
+
+        if !self.files.iter().any(|(x, _)| *x == path) {
+            self.files.push((path, original));
+        }
+
+        Ok(())
+    }
+
+    /// The absolute paths of the modified files, in the order that they were modified.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|(path, _)| path.as_path())
+    }
+
+    /// Save the journal, replacing the journal of an earlier run. Nothing is saved if no files
+    /// were modified.
+    pub fn save(self) -> anyhow::Result<()> {
+        if self.files.is_empty() {
+            return Ok(());
+        }
+
+        let dir = dir()?;
+        // an undo directory from before each run had its own directory
+        if dir.join("manifest").exists() {
+            std::fs::remove_dir_all(&dir)
+                .with_context(|| format!("could not remove '{}'", dir.display()))?;
+        }
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("could not create '{}'", dir.display()))?;
+
+        // write to a new directory first so that only complete journals are ever found
+        let new_dir = tempfile::Builder::new()
+            .prefix(".new-")
+            .tempdir_in(&dir)
+            .with_context(|| format!("could not create a directory in '{}'", dir.display()))?;
+
+        let mut manifest = Vec::new();
+        for (i, (path, original)) in self.files.iter().enumerate() {
+            // the current contents, which may differ from what we wrote if a `--post-file-cmd`
+            // command modified the file
+            let hash = fnv1a(&std::fs::read(path).unwrap_or_default());
+            write!(manifest, "{hash:016x} ")?;
+            manifest.extend_from_slice(path.as_os_str().as_bytes());
+            manifest.push(b'\n');
+
+            std::fs::write(new_dir.path().join(i.to_string()), original)?;
+        }
+        std::fs::write(new_dir.path().join("manifest"), manifest)?;
+
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut id = time.to_string();
+        for n in 1.. {
+            if !dir.join(&id).exists() {
+                break;
+            }
+            id = format!("{time}-{n}");
+        }
+        let run_dir = dir.join(&id);
+        std::fs::rename(new_dir.into_path(), &run_dir)
+            .with_context(|| format!("could not create '{}'", run_dir.display()))?;
+
+        // only the most recent runs are kept
+        let ids = run_ids(&dir)?;
+        for id in &ids[..ids.len().saturating_sub(MAX_RUNS)] {
+            let old = dir.join(id);
+            std::fs::remove_dir_all(&old)
+                .with_context(|| format!("could not remove '{}'", old.display()))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Restore the files modified by the run `id` (or by the last run if `None`), and return their
+/// paths. Unless `force` is true, nothing is restored if any of the files were modified after the
+/// run.
+pub fn undo(id: Option<&str>, force: bool) -> anyhow::Result<Vec<PathBuf>> {
+    let dir = dir()?;
+
+    let ids = run_ids(&dir)?;
+    let id = match id {
+        Some(id) if ids.iter().any(|x| x == id) => id,
+        Some(id) => anyhow::bail!("there is no run '{id}' to undo (see 'repatch undo --list')"),
+        None => match ids.last() {
+            Some(id) => id,
+            None => anyhow::bail!("there is no run to undo"),
+        },
+    };
+    let dir = dir.join(id);
+
+    let manifest =
+        std::fs::read(dir.join("manifest")).context("could not read the undo manifest")?;
+    let entries = parse_manifest(&manifest).context("invalid undo manifest")?;
+
+    if !force {
+        for (hash, path) in &entries {
+            let current = std::fs::read(path)
+                .with_context(|| format!("could not read '{}'", path.display()))?;
+            if fnv1a(&current) != *hash {
+                anyhow::bail!(
+                    "the file '{}' was modified after the run\n\
+                    Use '--force' to restore it anyways.",
+                    path.display(),
+                );
+            }
+        }
+    }
+
+    let mut restored = Vec::new();
+    for (i, (_, path)) in entries.into_iter().enumerate() {
+        let original = std::fs::read(dir.join(i.to_string())).with_context(|| {
+            format!(
+                "could not read the original contents of '{}'",
+                path.display()
+            )
+        })?;
+        crate::util::replace_contents(&path, &original)
+            .with_context(|| format!("could not restore the file '{}'", path.display()))?;
+        restored.push(path);
+    }
+
+    // a run can only be undone once
+    std::fs::remove_dir_all(&dir)
+        .with_context(|| format!("could not remove '{}'", dir.display()))?;
+
+    Ok(restored)
+}
+
+fn parse_manifest(s: &[u8]) -> anyhow::Result<Vec<(u64, PathBuf)>> {
+    let mut entries = Vec::new();
+
+    for (line_num, line) in s.split(|x| *x == b'\n').enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+
+        let hash = line
+            .split(|x| *x == b' ')
+            .next()
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u64::from_str_radix(x, 16).ok());
+        let path = line.get(17..);
+
+        let (Some(hash), Some(path)) = (hash, path) else {
+            anyhow::bail!("line {}: expected \"<HASH> <PATH>\"", line_num + 1);
+        };
+        entries.push((hash, PathBuf::from(std::ffi::OsStr::from_bytes(path))));
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(parse_id("1700000000"), Some((1700000000, 0)));
+        assert_eq!(parse_id("1700000000-2"), Some((1700000000, 2)));
+        assert_eq!(parse_id(".new-abc"), None);
+        assert_eq!(parse_id("manifest"), None);
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        assert_eq!(
+            parse_manifest(b"00000000000000ff /a b/c\n0000000000000001 /d\n").unwrap(),
+            [(0xff, PathBuf::from("/a b/c")), (1, PathBuf::from("/d"))],
+        );
+        assert!(parse_manifest(b"xyz /a\n").is_err());
+        assert!(parse_manifest(b"00000000000000ff").is_err());
+    }
+}
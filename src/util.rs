@@ -1,6 +1,8 @@
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::io::Write;
+use std::ops::RangeInclusive;
+use std::os::fd::{AsFd, AsRawFd};
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
@@ -8,9 +10,10 @@ use std::process::Command;
 use std::sync::OnceLock;
 use std::time::SystemTime;
 
+use crate::cli::NormalizationForm;
+use crate::engine::PatternMatcher;
 use bstr::ByteSlice;
 use grep_matcher::{Captures, Matcher};
-use grep_regex::RegexMatcher;
 
 pub fn ranges(sorted_list: &[u64], padding: u64) -> Vec<std::ops::RangeInclusive<u64>> {
     let mut ranges = Vec::new();
@@ -42,20 +45,162 @@ pub fn ranges(sorted_list: &[u64], padding: u64) -> Vec<std::ops::RangeInclusive
     ranges
 }
 
+/// The lines of a hunk, and the lines that the hunk can change.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HunkRange {
+    /// The lines that are shown, including the context lines.
+    pub shown: RangeInclusive<u64>,
+    /// The lines that are replaced when the hunk is applied. The other shown lines may be shown
+    /// by other hunks too.
+    pub changed: RangeInclusive<u64>,
+}
+
+/// The hunks of the (sorted) matching lines with `padding` lines of context, where nearby hunks
+/// are combined according to `merge`. The changed lines of the hunks never overlap.
+pub fn hunk_ranges(
+    sorted_list: &[u64],
+    padding: u64,
+    merge: crate::cli::HunkMerge,
+) -> Vec<HunkRange> {
+    use crate::cli::HunkMerge;
+
+    let mut merged = match merge {
+        HunkMerge::Aggressive | HunkMerge::Exact => ranges(sorted_list, padding),
+        HunkMerge::Never => {
+            let mut list = sorted_list.to_vec();
+            list.dedup();
+            return list
+                .into_iter()
+                .map(|x| HunkRange {
+                    shown: x.saturating_sub(padding)..=x.saturating_add(padding),
+                    changed: x..=x,
+                })
+                .collect();
+        }
+    };
+
+    if merge == HunkMerge::Aggressive {
+        // also combine the hunks that are next to each other
+        merged.dedup_by(|next, prev| {
+            if prev.end().saturating_add(1) < *next.start() {
+                return false;
+            }
+            *prev = *prev.start()..=*next.end();
+            true
+        });
+    }
+
+    merged
+        .into_iter()
+        .map(|x| HunkRange {
+            shown: x.clone(),
+            changed: x,
+        })
+        .collect()
+}
+
+/// Combine the hunks where the last changed line of one hunk and the first changed line of the next
+/// are adjacent matching lines, so that a match spanning several lines is always within one hunk.
+pub fn join_adjacent_matches(mut hunks: Vec<HunkRange>, sorted_list: &[u64]) -> Vec<HunkRange> {
+    let is_match = |x: &u64| sorted_list.binary_search(x).is_ok();
+
+    hunks.dedup_by(|next, prev| {
+        let adjacent = prev.changed.end().checked_add(1) == Some(*next.changed.start());
+        if !adjacent || !is_match(prev.changed.end()) || !is_match(next.changed.start()) {
+            return false;
+        }
+        prev.shown =
+            *prev.shown.start().min(next.shown.start())..=*prev.shown.end().max(next.shown.end());
+        prev.changed = *prev.changed.start()..=*next.changed.end();
+        true
+    });
+
+    hunks
+}
+
+/// The directory for new files while they're written, for `--temp-dir`.
+static TEMP_DIR: OnceLock<std::path::PathBuf> = OnceLock::new();
+
+/// Write new files in `dir` instead of next to the files that they replace. If `dir` is on a
+/// different filesystem, the new files are copied into place.
+pub fn set_temp_dir(dir: std::path::PathBuf) {
+    let _ = TEMP_DIR.set(dir);
+}
+
+/// A change to the permission bits of new files that replace existing files, for `--chmod`.
+static MODE_CHANGE: OnceLock<ModeChange> = OnceLock::new();
+
+/// Change the permissions of each new file that replaces a file with `change`, instead of using
+/// the permissions of the file that it replaces.
+pub fn set_mode_change(change: ModeChange) {
+    let _ = MODE_CHANGE.set(change);
+}
+
+/// A change to the user, group, and other read/write/execute bits of a file's mode, like the mode
+/// argument of `chmod`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModeChange(pub Vec<ModeClause>);
+
+/// One comma-separated part of a [`ModeChange`], such as "go-w".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModeClause {
+    /// The bits of the users that the clause applies to.
+    pub who: u32,
+    /// "+", "-", or "=".
+    pub op: u8,
+    /// The bits of the permissions, for all users.
+    pub perms: u32,
+}
+
+impl ModeChange {
+    /// The mode after applying the change to `mode`.
+    pub fn apply(&self, mut mode: u32) -> u32 {
+        for clause in &self.0 {
+            let bits = clause.who & clause.perms;
+            mode = match clause.op {
+                b'+' => mode | bits,
+                b'-' => mode & !bits,
+                _ => (mode & !clause.who) | bits,
+            };
+        }
+        mode
+    }
+}
+
+/// Replace the file at `path` with a new file written by `f`. The file is only replaced if `f`
+/// returns true. If `fsync` is true, the new file and the directory are synced to disk before and
+/// after the new file replaces the original.
 pub fn replace_file<T>(
     path: impl AsRef<Path>,
     modified_at: Option<SystemTime>,
+    fsync: bool,
     f: impl FnOnce(&File, &File) -> (bool, T),
 ) -> Result<T, ReplaceFileError> {
+    // the time spent outside of `f` is counted as writing
+    let start = std::time::Instant::now();
+    let mut inner = std::time::Duration::ZERO;
+    let f = |original: &File, new: &File| {
+        let start = std::time::Instant::now();
+        let rv = f(original, new);
+        inner = start.elapsed();
+        rv
+    };
+
+    // an unnamed file can only be linked into a directory on the same filesystem
     #[cfg(target_os = "linux")]
-    {
-        replace_file_linux(path, modified_at, /* allow_fallback= */ true, f)
-    }
+    let rv = match TEMP_DIR.get() {
+        None => replace_file_linux(path, modified_at, /* allow_fallback= */ true, fsync, f),
+        Some(_) => replace_file_compat(path, modified_at, fsync, f),
+    };
 
     #[cfg(not(target_os = "linux"))]
-    {
-        replace_file_compat(path, modified_at, f)
-    }
+    let rv = replace_file_compat(path, modified_at, fsync, f);
+
+    crate::profile::add(
+        crate::profile::Phase::Write,
+        start.elapsed().saturating_sub(inner),
+    );
+    rv
 }
 
 /// A linux-specific variant of [`replace_file`].
@@ -64,6 +209,7 @@ fn replace_file_linux<T>(
     path: impl AsRef<Path>,
     modified_at: Option<SystemTime>,
     allow_fallback: bool,
+    fsync: bool,
     f: impl FnOnce(&File, &File) -> (bool, T),
 ) -> Result<T, ReplaceFileError> {
     use std::ffi::CString;
@@ -94,6 +240,11 @@ fn replace_file_linux<T>(
         parent_path = Path::new("./");
     }
 
+    // the unnamed file is linked into place through /proc, which may not be mounted
+    if allow_fallback && !Path::new("/proc/self/fd").is_dir() {
+        return replace_file_compat(path, modified_at, fsync, f);
+    }
+
     // create an unnamed file on the mount for the path
     let new = match OpenOptions::new()
         .write(true)
@@ -102,21 +253,21 @@ fn replace_file_linux<T>(
         .open(parent_path)
     {
         Ok(x) => x,
-        // O_TMPFILE is only supported on a few filesystems
-        Err(e) if allow_fallback && e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
-            return replace_file_compat(path, modified_at, f);
+        // O_TMPFILE is only supported on a few filesystems, and kernels from before it was added
+        // treat it as `O_DIRECTORY`
+        Err(e)
+            if allow_fallback
+                && matches!(e.raw_os_error(), Some(libc::EOPNOTSUPP | libc::EISDIR)) =>
+        {
+            return replace_file_compat(path, modified_at, fsync, f);
         }
         Err(e) => return Err(e.into()),
     };
 
     let original = File::open(path)?;
 
-    // copy only the user/group/other read/write/execute permission bits
-    #[allow(clippy::useless_conversion)]
-    let mask = u32::from(libc::S_IRWXU | libc::S_IRWXG | libc::S_IRWXO);
-
     // set the permissions after creating the file so that it's not affected by the umask
-    new.set_permissions(read_permissions(&original, mask)?)?;
+    new.set_permissions(new_permissions(&original)?)?;
 
     // the path to the new file in the /proc mount
     let mut procfd_c_path = Vec::new();
@@ -134,14 +285,10 @@ fn replace_file_linux<T>(
         return Ok(rv);
     };
 
-    if let Some(modified_at) = modified_at {
-        // the current "modified" time for the file
-        let latest_modified = std::fs::metadata(path)?.modified()?;
+    check_modified_time(path, modified_at)?;
 
-        // return an error if the file's "modified" timestamps differ
-        if latest_modified != modified_at {
-            return Err(ReplaceFileError::ModifiedTimeChanged);
-        }
+    if fsync {
+        new.sync_all()?;
     }
 
     // give the new file a temporary name
@@ -159,8 +306,27 @@ fn replace_file_linux<T>(
         return Err(std::io::Error::last_os_error().into());
     }
 
+    if fsync {
+        // make sure the temporary name is on disk
+        if let Err(e) = sync_dir(parent_path) {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e.into());
+        }
+    }
+
     // replace the original file at `path` with the new file
-    std::fs::rename(&tmp_path, path)?;
+    if let Err(e) = std::fs::rename(&tmp_path, path) {
+        let rv = match is_cross_device(&e) {
+            true => copy_replace(&tmp_path, path),
+            false => Err(e),
+        };
+        let _ = std::fs::remove_file(&tmp_path);
+        rv?;
+    }
+
+    if fsync {
+        sync_dir(parent_path)?;
+    }
 
     Ok(rv)
 }
@@ -169,6 +335,7 @@ fn replace_file_linux<T>(
 fn replace_file_compat<T>(
     path: impl AsRef<Path>,
     modified_at: Option<SystemTime>,
+    fsync: bool,
     f: impl FnOnce(&File, &File) -> (bool, T),
 ) -> Result<T, ReplaceFileError> {
     let path = path.as_ref();
@@ -177,12 +344,8 @@ fn replace_file_compat<T>(
         return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file").into());
     }
 
-    // copy only the user/group/other read/write/execute permission bits
-    #[allow(clippy::useless_conversion)]
-    let mask = u32::from(libc::S_IRWXU | libc::S_IRWXG | libc::S_IRWXO);
-
     let original = File::open(path)?;
-    let original_permissions = read_permissions(&original, mask)?;
+    let original_permissions = new_permissions(&original)?;
 
     let mut prefix = OsString::new();
     prefix.push(".");
@@ -196,8 +359,12 @@ fn replace_file_compat<T>(
         // even though we set the permissions below, we should also set them here to avoid
         // temporarily creating a file that's more permissive than the original
         .permissions(original_permissions.clone())
-        // create it in the same directory since you can't rename a file across filesystems
-        .tempfile_in(path.parent().unwrap())?;
+        // create it in the same directory since you can't rename a file across filesystems, unless
+        // '--temp-dir' was given, in which case it's copied into place
+        .tempfile_in(match TEMP_DIR.get() {
+            Some(dir) => dir.as_path(),
+            None => path.parent().unwrap(),
+        })?;
 
     // set the permissions after creating the file so that it's not affected by the umask
     new.as_file().set_permissions(original_permissions)?;
@@ -212,6 +379,77 @@ fn replace_file_compat<T>(
         return Ok(rv);
     };
 
+    check_modified_time(path, modified_at)?;
+
+    let parent_path = match path.parent().unwrap() {
+        x if x == Path::new("") => Path::new("./"),
+        x => x,
+    };
+
+    if fsync {
+        new.as_file().sync_all()?;
+        sync_dir(parent_path)?;
+    }
+
+    // replace the original file at `path` with the new file
+    if let Err(e) = new.persist(path) {
+        if !is_cross_device(&e.error) {
+            return Err(e.error.into());
+        }
+        // the temporary file is removed when dropped
+        copy_replace(e.file.path(), path)?;
+    }
+
+    if fsync {
+        sync_dir(parent_path)?;
+    }
+
+    Ok(rv)
+}
+
+/// Like [`replace_file`], but for files that the current user can't replace. The new file is
+/// written to an anonymous temporary file, which is passed to `write` to replace the file some
+/// other way.
+pub fn replace_file_via<T>(
+    path: impl AsRef<Path>,
+    modified_at: Option<SystemTime>,
+    f: impl FnOnce(&File, &File) -> (bool, T),
+    write: impl FnOnce(&File) -> std::io::Result<()>,
+) -> Result<T, ReplaceFileError> {
+    use std::io::{Seek, SeekFrom};
+
+    let path = path.as_ref();
+
+    if !path.is_file() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file").into());
+    }
+
+    let original = File::open(path)?;
+    let new = match TEMP_DIR.get() {
+        Some(dir) => tempfile::tempfile_in(dir)?,
+        None => crate::workspace::tempfile()?,
+    };
+
+    let (do_replace_file, rv) = f(&original, &new);
+
+    // the user-provided closure asked us to stop
+    if !do_replace_file {
+        return Ok(rv);
+    };
+
+    check_modified_time(path, modified_at)?;
+
+    (&new).seek(SeekFrom::Start(0))?;
+    write(&new)?;
+
+    Ok(rv)
+}
+
+/// Returns an error if the file's "modified" time isn't `modified_at`.
+fn check_modified_time(
+    path: &Path,
+    modified_at: Option<SystemTime>,
+) -> Result<(), ReplaceFileError> {
     if let Some(modified_at) = modified_at {
         // the current "modified" time for the file
         let latest_modified = std::fs::metadata(path)?.modified()?;
@@ -222,10 +460,87 @@ fn replace_file_compat<T>(
         }
     }
 
-    // replace the original file at `path` with the new file
-    new.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
 
-    Ok(rv)
+/// Copy the file at `src` to `dest`, sharing the data between the files if the filesystem supports
+/// it (a "reflink").
+pub fn copy_file(src: &Path, dest: &Path) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let from = File::open(src)?;
+        let to = File::create(dest)?;
+        if unsafe { libc::ioctl(to.as_raw_fd(), libc::FICLONE, from.as_raw_fd()) } == 0 {
+            return to.set_permissions(from.metadata()?.permissions());
+        }
+    }
+
+    std::fs::copy(src, dest).map(|_| ())
+}
+
+/// Whether the current user can replace the file at `path`. Files that aren't writable would
+/// otherwise be replaced by a file with a different owner, if their directory is writable.
+pub fn is_writable(path: &Path) -> bool {
+    use std::ffi::CString;
+
+    let parent = match path.parent() {
+        Some(x) if x != Path::new("") => x,
+        _ => Path::new("./"),
+    };
+
+    let access = |path: &Path| match CString::new(path.as_os_str().as_bytes()) {
+        Ok(x) => unsafe { libc::access(x.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    };
+
+    access(path) && access(parent)
+}
+
+/// Sync a directory to disk, so that changes to its entries are durable.
+fn sync_dir(path: &Path) -> std::io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+/// Returns true if a rename failed because the source and destination are on different filesystems,
+/// or because the destination is a mount point (for example a bind-mounted file).
+fn is_cross_device(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EXDEV | libc::EBUSY))
+}
+
+/// Replace `to` with a copy of `from`, for when a rename fails because they're on different
+/// filesystems (for example with bind mounts or overlayfs). The copy is written to a new temporary
+/// file next to `to` and renamed. If that rename also fails (for example if `to` is itself a mount
+/// point), `to` is overwritten in place.
+fn copy_replace(from: &Path, to: &Path) -> std::io::Result<()> {
+    let mut parent = to.parent().unwrap();
+    if parent == Path::new("") {
+        parent = Path::new("./");
+    }
+
+    let permissions = std::fs::metadata(from)?.permissions();
+
+    let mut tmp = tempfile::Builder::new()
+        .prefix(".")
+        .suffix(".tmp")
+        .permissions(permissions.clone())
+        .tempfile_in(parent)?;
+    std::io::copy(&mut File::open(from)?, tmp.as_file_mut())?;
+    // set the permissions after creating the file so that it's not affected by the umask
+    tmp.as_file().set_permissions(permissions)?;
+    tmp.as_file().sync_all()?;
+
+    match tmp.persist(to) {
+        Ok(_) => Ok(()),
+        Err(e) if is_cross_device(&e.error) => {
+            let mut dest = std::fs::OpenOptions::new()
+                .write(true)
+                .truncate(true)
+                .open(to)?;
+            std::io::copy(&mut File::open(from)?, &mut dest)?;
+            dest.sync_all()
+        }
+        Err(e) => Err(e.error),
+    }
 }
 
 #[derive(Debug)]
@@ -253,6 +568,19 @@ impl std::fmt::Display for ReplaceFileError {
 
 impl std::error::Error for ReplaceFileError {}
 
+/// The permissions of a new file that replaces `original`, which are the user/group/other
+/// read/write/execute bits of `original` with the `--chmod` change applied.
+fn new_permissions(original: &File) -> std::io::Result<std::fs::Permissions> {
+    #[allow(clippy::useless_conversion)]
+    let mask = u32::from(libc::S_IRWXU | libc::S_IRWXG | libc::S_IRWXO);
+
+    let permissions = read_permissions(original, mask)?;
+    Ok(match MODE_CHANGE.get() {
+        Some(change) => std::fs::Permissions::from_mode(change.apply(permissions.mode())),
+        None => permissions,
+    })
+}
+
 /// Returns the file permissions without any file type bits. Also applies an additional bitmask to
 /// the returned mode.
 fn read_permissions(file: &File, mask: u32) -> std::io::Result<std::fs::Permissions> {
@@ -330,131 +658,1213 @@ pub fn editor_cmd() -> impl Iterator<Item = impl AsRef<OsStr>> + Clone {
     cmd.iter()
 }
 
-pub fn replace_regex(
-    matcher: &RegexMatcher,
-    replacement: &[u8],
-    haystack: &[u8],
-    dest: &mut Vec<u8>,
-) -> Result<(), <RegexMatcher as Matcher>::Error> {
-    let mut captures = matcher.new_captures().unwrap();
-    matcher.replace_with_captures(haystack, &mut captures, dest, |caps, dest| {
-        caps.interpolate(
-            |name| matcher.capture_index(name),
-            haystack,
-            replacement,
-            dest,
-        );
-        true
-    })
-}
+/// Run the shell command `cmd`, where each "{}" is replaced with `path`. If `cmd` doesn't contain
+/// "{}", `path` is added to the end of the command.
+pub fn run_path_cmd(cmd: &str, path: &Path) -> std::io::Result<std::process::ExitStatus> {
+    // pass the path as a positional parameter so that it doesn't need to be quoted
+    let script = if cmd.contains("{}") {
+        cmd.replace("{}", "\"$1\"")
+    } else {
+        format!("{cmd} \"$1\"")
+    };
 
-pub fn rewrite_patch_line_counts(bytes: &[u8]) -> std::borrow::Cow<[u8]> {
-    let result = (|| {
-        let mut lines = crate::parse::lines_with_pos(bytes);
+    Command::new("sh")
+        .arg("-c")
+        .arg(script)
+        .arg("sh")
+        .arg(path)
+        .status()
+}
 
-        let (header, header_start) = lines.nth(2)?;
+/// How the replacement text for each match is computed.
+#[derive(Copy, Clone)]
+pub enum Replacement<'a> {
+    /// A replacement with capture group indices and names, and the `${FILE}`, `${LINE}`, and
+    /// `${MATCH}` variables.
+    Template(&'a [u8]),
+    /// A replacement that is used as it is, for `--fixed-strings`.
+    Literal(&'a [u8]),
+    /// A script that returns the replacement, with the `<REPLACE>` argument available to the
+    /// script as `replace`.
+    Script(&'a crate::script::Script, &'a str),
+    /// The rules of `--rules`, which are applied in order to the whole haystack rather than to
+    /// each match. The matcher must match wherever any of the rules match.
+    Rules(&'a [crate::rules::Rule]),
+}
 
-        let (range_1, range_2) = crate::parse::patch_block_header(header)?;
+impl<'a> Replacement<'a> {
+    /// The replacement for a match on the (0-indexed) line `line_num` of the file `path`, with the
+    /// `${FILE}`, `${LINE}`, and `${MATCH}` variables expanded. `storage` is used to hold the
+    /// expanded template.
+    pub fn for_line<'b>(
+        self,
+        storage: &'b mut Option<Vec<u8>>,
+        path: &Path,
+        line_num: u64,
+    ) -> Replacement<'b>
+    where
+        'a: 'b,
+    {
+        let Self::Template(template) = self else {
+            return self;
+        };
 
-        let mut content_start = None;
-        let mut line_counts = (0, 0);
+        *storage = expand_template_vars(template, path, line_num + 1);
+        match storage {
+            Some(x) => Replacement::Template(x),
+            None => self,
+        }
+    }
 
-        // count the number of + and - lines
-        for (line, pos) in lines {
-            if content_start.is_none() {
-                content_start = Some(pos);
+    /// Append the replacement for the match `caps` to `dest`. If a script fails, an error is
+    /// printed and the match is left unchanged.
+    fn append(
+        &self,
+        matcher: &PatternMatcher,
+        haystack: &[u8],
+        caps: &<PatternMatcher as Matcher>::Captures,
+        dest: &mut Vec<u8>,
+    ) {
+        let script = match self {
+            Self::Template(replacement) => {
+                caps.interpolate(
+                    |name| matcher.capture_index(name),
+                    haystack,
+                    replacement,
+                    dest,
+                );
+                return;
             }
-
-            match line.first() {
-                Some(b' ') | None => {
-                    line_counts.0 += 1;
-                    line_counts.1 += 1;
-                }
-                Some(b'-') => line_counts.0 += 1,
-                Some(b'+') => line_counts.1 += 1,
-                _ => return None,
+            Self::Literal(replacement) => {
+                dest.extend_from_slice(replacement);
+                return;
             }
-        }
+            Self::Rules(_) => unreachable!("rules are applied to the whole haystack"),
+            Self::Script(script, replace) => {
+                let caps: Vec<String> = (0..caps.len())
+                    .map(|i| match caps.get(i) {
+                        Some(m) => haystack[m].to_str_lossy().into_owned(),
+                        None => String::new(),
+                    })
+                    .collect();
+                script.run(replace, &caps, &|name| matcher.capture_index(name))
+            }
+        };
 
-        if (range_1.1, range_2.1) == line_counts {
-            // no need to change the patch
-            return None;
+        let m = &haystack[caps.get(0).unwrap()];
+        match script {
+            Ok(crate::script::Value::Unit) => {
+                print_script_error(m, "the script didn't return a value");
+                dest.extend_from_slice(m);
+            }
+            Ok(x) => dest.extend_from_slice(x.to_string().as_bytes()),
+            Err(e) => {
+                print_script_error(m, e);
+                dest.extend_from_slice(m);
+            }
         }
+    }
+}
 
-        let content_start = content_start?;
+fn print_script_error(m: &[u8], e: impl std::fmt::Display) {
+    crate::ui::error!("replacement script failed for '{}': {e}", m.as_bstr());
+}
 
-        // build the new patch
-        let mut new_patch = Vec::new();
+/// Replace the file at `path` with a new file containing `contents`.
+pub fn replace_contents(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let rv = replace_file(path, None, /* fsync= */ false, |_, mut new| {
+        let rv = new.write_all(contents);
+        (rv.is_ok(), rv)
+    });
 
-        // add the header
-        new_patch.extend_from_slice(&bytes[..header_start]);
+    match rv {
+        Ok(rv) => rv,
+        Err(ReplaceFileError::Io(e)) => Err(e),
+        Err(ReplaceFileError::ModifiedTimeChanged) => unreachable!(),
+    }
+}
 
-        // write the new line numbers
-        writeln!(
-            &mut new_patch,
-            "@@ -{},{} +{},{} @@",
-            range_1.0, line_counts.0, range_2.0, line_counts.1,
-        )
-        .ok()?;
+/// A hash that is stable across platforms and program versions (64-bit FNV-1a).
+pub fn fnv1a(data: &[u8]) -> u64 {
+    fnv1a_chunks([data])
+}
 
-        // add the patch contents
-        new_patch.extend_from_slice(&bytes[content_start..]);
+/// The [`fnv1a`] hash of the concatenated chunks, without concatenating them.
+pub fn fnv1a_chunks<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in chunks.into_iter().flatten() {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
 
-        Some(new_patch)
-    })();
+/// Replace stdin with the controlling terminal, so that the user can be prompted after stdin was
+/// used for some other input.
+pub fn reopen_stdin_tty() -> std::io::Result<()> {
+    let tty = File::open("/dev/tty")?;
+    if unsafe { libc::dup2(tty.as_raw_fd(), libc::STDIN_FILENO) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
 
-    match result {
-        Some(x) => std::borrow::Cow::Owned(x),
-        None => std::borrow::Cow::Borrowed(bytes),
+/// Point stdout at `to`, so that the output meant for the user doesn't mix with some other output.
+/// Returns a file for the original stdout.
+pub fn divert_stdout(to: &impl AsRawFd) -> std::io::Result<File> {
+    std::io::stdout().flush()?;
+    let original = File::from(std::io::stdout().as_fd().try_clone_to_owned()?);
+    if unsafe { libc::dup2(to.as_raw_fd(), libc::STDOUT_FILENO) } == -1 {
+        return Err(std::io::Error::last_os_error());
     }
+    Ok(original)
 }
 
-pub fn rewrite_patch_line_start(bytes: &[u8], offset: i128, ansi: bool) -> Option<Vec<u8>> {
-    let mut lines = crate::parse::lines_with_pos(bytes);
-    let (mut header, header_start) = lines.nth(2)?;
-    let (_, content_start) = lines.next()?;
+/// Returns true if a match starting at the (0-indexed) byte `start` is within the (1-indexed)
+/// `columns`. All matches are within the columns if `columns` is `None`.
+pub fn in_columns(columns: Option<&RangeInclusive<u64>>, start: usize) -> bool {
+    columns.is_none_or(|x| x.contains(&(start as u64 + 1)))
+}
 
-    const ANSI_RESET: &[u8] = b"\x1b[0m";
-    const ANSI_HEADER_COLOR: &[u8] = b"\x1b[36m";
+/// Which matches within a line can be replaced, and how their replacements are written.
+#[derive(Clone, Debug)]
+pub struct MatchFilter {
+    /// Only matches starting within these (1-indexed) columns.
+    pub columns: Option<RangeInclusive<u64>>,
+    /// Only matches that aren't part of a longer identifier, for `--identifier`.
+    pub identifier: Option<crate::identifier::IdentifierChars>,
+    /// Replacements are written in the normalization form of the matched text, or of the rest of
+    /// its line if the match doesn't show one, and otherwise in this form, for `--normalize`.
+    pub normalize: Option<NormalizationForm>,
+    /// The line terminator, which ends each line of a haystack with several lines.
+    pub line_terminator: u8,
+}
 
-    if ansi {
-        header = header.strip_prefix(ANSI_RESET)?;
-        header = header.strip_prefix(ANSI_HEADER_COLOR)?;
-        header = header.strip_suffix(ANSI_RESET)?;
+impl Default for MatchFilter {
+    fn default() -> Self {
+        Self::columns(None)
     }
+}
 
-    let (mut pair_1, mut pair_2) = crate::parse::patch_block_header(header)?;
+impl MatchFilter {
+    pub fn columns(columns: Option<RangeInclusive<u64>>) -> Self {
+        Self {
+            columns,
+            identifier: None,
+            normalize: None,
+            line_terminator: b'\n',
+        }
+    }
 
-    let (offset, positive_offset) = if offset >= 0 {
-        (u64::try_from(offset).ok()?, true)
-    } else {
-        (u64::try_from(-offset).ok()?, false)
-    };
+    /// Write the replacement `dest[start..]` of the match `m` in the normalization form of the
+    /// text that it replaces.
+    fn normalize(&self, haystack: &[u8], m: grep_matcher::Match, dest: &mut Vec<u8>, start: usize) {
+        let Some(default) = self.normalize else {
+            return;
+        };
+        let Ok(replacement) = std::str::from_utf8(&dest[start..]) else {
+            return;
+        };
 
-    if positive_offset {
-        pair_1.0 = pair_1.0.checked_add(offset)?;
-        pair_2.0 = pair_2.0.checked_add(offset)?;
-    } else {
-        pair_1.0 = pair_1.0.checked_sub(offset)?;
-        pair_2.0 = pair_2.0.checked_sub(offset)?;
+        let form_of = |x: &[u8]| std::str::from_utf8(x).ok().and_then(crate::normalize::form);
+        let terminator = self.line_terminator;
+        let line_start = haystack[..m.start()]
+            .rfind_byte(terminator)
+            .map_or(0, |x| x + 1);
+        let line_end = haystack[m.end()..]
+            .find_byte(terminator)
+            .map_or(haystack.len(), |x| m.end() + x);
+        let form = form_of(&haystack[m])
+            .or_else(|| form_of(&haystack[line_start..line_end]))
+            .unwrap_or(default);
+
+        let normalized = match form {
+            NormalizationForm::Nfc => crate::normalize::nfc(replacement),
+            NormalizationForm::Nfd => crate::normalize::nfd(replacement),
+        };
+        dest.truncate(start);
+        dest.extend_from_slice(normalized.as_bytes());
     }
 
-    // build the new patch
-    let mut new_patch = Vec::new();
+    fn keeps(&self, haystack: &[u8], m: grep_matcher::Match) -> bool {
+        in_columns(self.columns.as_ref(), m.start())
+            && self
+                .identifier
+                .is_none_or(|x| x.is_complete(haystack, m.start()..m.end()))
+    }
+}
 
-    // add the header
-    new_patch.extend_from_slice(&bytes[..header_start]);
+/// Expand the `${FILE}`, `${LINE}`, and `${MATCH}` variables in a replacement template, where
+/// `line` is 1-indexed. `${MATCH}` is expanded to `${0}` so that it's interpolated with the capture
+/// groups. Returns `None` if the template doesn't contain any of the variables.
+pub fn expand_template_vars(template: &[u8], path: &Path, line: u64) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut found = false;
+    let mut rest = template;
+
+    while let Some(idx) = rest.find_byte(b'$') {
+        out.extend_from_slice(&rest[..idx]);
+        rest = &rest[idx..];
+
+        let (value, len) = if rest.starts_with(b"$$") {
+            // an escaped "$"
+            (b"$$".to_vec(), 2)
+        } else if rest.starts_with(b"${FILE}") {
+            // the path is interpolated later, so any "$" must be escaped
+            let path = path.as_os_str().as_bytes().replace(b"$", b"$$");
+            (path, 7)
+        } else if rest.starts_with(b"${LINE}") {
+            (line.to_string().into_bytes(), 7)
+        } else if rest.starts_with(b"${MATCH}") {
+            (b"${0}".to_vec(), 8)
+        } else {
+            (b"$".to_vec(), 1)
+        };
 
-    if ansi {
-        new_patch.extend_from_slice(ANSI_RESET);
-        new_patch.extend_from_slice(ANSI_HEADER_COLOR);
+        found |= len > 2;
+        out.extend_from_slice(&value);
+        rest = &rest[len..];
     }
+    out.extend_from_slice(rest);
 
-    // write the new line numbers
-    write!(
-        &mut new_patch,
-        "@@ -{},{} +{},{} @@",
-        pair_1.0, pair_1.1, pair_2.0, pair_2.1,
+    found.then_some(out)
+}
+
+/// A part of a replacement template, as it's parsed when interpolated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TemplatePart<'a> {
+    /// Text that's used as it is, where "$$" is a "$".
+    Literal(&'a [u8]),
+    /// A reference to a capture group by its index or name.
+    Group(GroupRef<'a>),
+    /// The `${FILE}`, `${LINE}`, or `${MATCH}` variable.
+    Variable(&'a str),
+}
+
+/// How a capture group is referenced in a replacement template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GroupRef<'a> {
+    Index(usize),
+    Name(&'a str),
+}
+
+/// Split a replacement template into its parts, each with the text of the template that it was
+/// parsed from. Capture group references follow the rules of [`Captures::interpolate`]: "$name"
+/// continues for as long as there are letters, digits, and underscores, and is a group index if
+/// it's a number. A "$" that doesn't start a reference is literal.
+pub fn template_parts(template: &[u8]) -> Vec<(&[u8], TemplatePart<'_>)> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+
+    while !rest.is_empty() {
+        let dollar = rest.find_byte(b'$').unwrap_or(rest.len());
+        if dollar > 0 {
+            parts.push((&rest[..dollar], TemplatePart::Literal(&rest[..dollar])));
+            rest = &rest[dollar..];
+            continue;
+        }
+
+        if rest.starts_with(b"$$") {
+            parts.push((&rest[..2], TemplatePart::Literal(b"$")));
+            rest = &rest[2..];
+            continue;
+        }
+
+        // the reference, which is literal if it's empty or its brace isn't closed
+        let braced = rest.get(1) == Some(&b'{');
+        let name_start = if braced { 2 } else { 1 };
+        let name_len = rest[name_start..]
+            .iter()
+            .take_while(|x| x.is_ascii_alphanumeric() || **x == b'_')
+            .count();
+        let name_end = name_start + name_len;
+        let closed = !braced || rest.get(name_end) == Some(&b'}');
+        if name_len == 0 || !closed {
+            parts.push((&rest[..1], TemplatePart::Literal(b"$")));
+            rest = &rest[1..];
+            continue;
+        }
+
+        // the name only has ascii characters
+        let name = std::str::from_utf8(&rest[name_start..name_end]).unwrap();
+        let len = if braced { name_end + 1 } else { name_end };
+        let part = match name.parse::<u32>() {
+            _ if braced && matches!(name, "FILE" | "LINE" | "MATCH") => {
+                TemplatePart::Variable(name)
+            }
+            Ok(index) => TemplatePart::Group(GroupRef::Index(index as usize)),
+            Err(_) => TemplatePart::Group(GroupRef::Name(name)),
+        };
+        parts.push((&rest[..len], part));
+        rest = &rest[len..];
+    }
+
+    parts
+}
+
+/// Returns the (0-indexed) start of each match in the haystack that `filter` keeps.
+pub fn match_starts(matcher: &PatternMatcher, filter: &MatchFilter, haystack: &[u8]) -> Vec<usize> {
+    let mut starts = Vec::new();
+    matcher
+        .find_iter(haystack, |m| {
+            if filter.keeps(haystack, m) {
+                starts.push(m.start());
+            }
+            true
+        })
+        .unwrap();
+    starts
+}
+
+/// Replace each match in the haystack that `filter` keeps. Returns the replaced matches, where the
+/// range of each match's replacement is relative to the start of `dest`.
+pub fn replace_regex(
+    matcher: &PatternMatcher,
+    replacement: Replacement,
+    filter: &MatchFilter,
+    haystack: &[u8],
+    dest: &mut Vec<u8>,
+) -> Result<Vec<ReplacedMatch>, <PatternMatcher as Matcher>::Error> {
+    if let Replacement::Rules(rules) = replacement {
+        let keep = |haystack: &[u8], m| filter.keeps(haystack, m);
+        return Ok(crate::rules::replace(rules, haystack, keep, dest));
+    }
+
+    let mut replaced = Vec::new();
+    let mut captures = matcher.new_captures().unwrap();
+    matcher.replace_with_captures(haystack, &mut captures, dest, |caps, dest| {
+        let m = caps.get(0).unwrap();
+        if filter.keeps(haystack, m) {
+            let start = dest.len();
+            replacement.append(matcher, haystack, caps, dest);
+            filter.normalize(haystack, m, dest, start);
+            replaced.push(ReplacedMatch {
+                range: m.start()..m.end(),
+                replacement: start..dest.len(),
+            });
+        } else {
+            dest.extend_from_slice(&haystack[m]);
+        }
+        true
+    })?;
+    Ok(replaced)
+}
+
+/// A match that [`replace_regex`] replaced.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplacedMatch {
+    /// The range of the match in the haystack.
+    pub range: std::ops::Range<usize>,
+    /// The range of the match's replacement in the destination.
+    pub replacement: std::ops::Range<usize>,
+}
+
+/// A match within a haystack, and the text that would replace it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MatchReplacement {
+    pub range: std::ops::Range<usize>,
+    pub replacement: Vec<u8>,
+}
+
+/// Returns each match in the haystack that `filter` keeps, along with the text that would replace
+/// it.
+pub fn match_replacements(
+    matcher: &PatternMatcher,
+    replacement: Replacement,
+    filter: &MatchFilter,
+    haystack: &[u8],
+) -> Result<Vec<MatchReplacement>, <PatternMatcher as Matcher>::Error> {
+    if let Replacement::Rules(_) = replacement {
+        let mut dest = Vec::new();
+        let replaced = replace_regex(matcher, replacement, filter, haystack, &mut dest)?;
+        let matches = replaced.into_iter().map(|x| MatchReplacement {
+            range: x.range,
+            replacement: dest[x.replacement].to_vec(),
+        });
+        return Ok(matches.collect());
+    }
+
+    let mut rv = Vec::new();
+    let mut captures = matcher.new_captures().unwrap();
+    matcher.captures_iter(haystack, &mut captures, |caps| {
+        let m = caps.get(0).unwrap();
+        if !filter.keeps(haystack, m) {
+            return true;
+        }
+        let mut dest = Vec::new();
+        replacement.append(matcher, haystack, caps, &mut dest);
+        filter.normalize(haystack, m, &mut dest, 0);
+        rv.push(MatchReplacement {
+            range: m.start()..m.end(),
+            replacement: dest,
+        });
+        true
+    })?;
+    Ok(rv)
+}
+
+/// The number of terminal columns that the text takes up when shown, where wide chars (such as CJK
+/// text and most emoji) take up two columns and combining marks take up none. Invalid UTF-8 is
+/// counted as replacement chars.
+pub fn display_width(text: &[u8]) -> usize {
+    unicode_width::UnicodeWidthStr::width(&*text.to_str_lossy())
+}
+
+/// Returns the haystack with only the given matches replaced, where the matches are sorted and
+/// don't overlap.
+pub fn apply_match_replacements(haystack: &[u8], matches: &[&MatchReplacement]) -> Vec<u8> {
+    let mut rv = Vec::with_capacity(haystack.len());
+    let mut pos = 0;
+    for m in matches {
+        rv.extend_from_slice(&haystack[pos..m.range.start]);
+        rv.extend_from_slice(&m.replacement);
+        pos = m.range.end;
+    }
+    rv.extend_from_slice(&haystack[pos..]);
+    rv
+}
+
+/// A key that is the same for hunks that are similar, for `--group-similar`. Hunks are similar if
+/// they replace the same matched texts with the same replacements, and their lines are the same
+/// apart from whitespace and numbers (so that hunks that differ only by indentation or by a line
+/// or version number are similar). If the hunk can't be split into its matches, its replaced lines
+/// are compared instead.
+pub fn similarity_key(original: &[u8], replaced: &[u8], matches: &[MatchReplacement]) -> u64 {
+    let mut key = Vec::new();
+    for m in matches {
+        key.extend_from_slice(&original[m.range.clone()]);
+        key.push(0);
+        key.extend_from_slice(&m.replacement);
+        key.push(0);
+    }
+
+    let lines = [original, replaced];
+    let lines = match matches.is_empty() {
+        true => &lines[..],
+        false => &lines[..1],
+    };
+    for bytes in lines {
+        let mut prev_digit = false;
+        for byte in bytes.iter().filter(|x| !x.is_ascii_whitespace()) {
+            match (byte.is_ascii_digit(), prev_digit) {
+                (true, true) => {}
+                (true, false) => key.push(b'0'),
+                (false, _) => key.push(*byte),
+            }
+            prev_digit = byte.is_ascii_digit();
+        }
+        key.push(0);
+    }
+
+    fnv1a(&key)
+}
+
+/// The number of matches that are replaced in `new`, if `new` is the haystack with only some of
+/// the (sorted and non-overlapping) matches replaced, like [`apply_match_replacements`]. If it
+/// could be several subsets of the matches, the largest is counted. Returns `None` if `new` was
+/// changed in some other way.
+pub fn count_applied_matches(
+    haystack: &[u8],
+    matches: &[MatchReplacement],
+    new: &[u8],
+) -> Option<u64> {
+    // each offset into `new` that the text before the next match can end at, with the most
+    // matches replaced before it
+    let mut offsets = std::collections::BTreeMap::from([(0, 0)]);
+    let mut pos = 0;
+    for m in matches {
+        let mut next = std::collections::BTreeMap::new();
+        for (offset, n) in offsets {
+            let Some(rest) = new[offset..].strip_prefix(&haystack[pos..m.range.start]) else {
+                continue;
+            };
+            let offset = new.len() - rest.len();
+            // the match was either replaced or kept
+            for (text, count) in [
+                (m.replacement.as_slice(), n + 1),
+                (&haystack[m.range.clone()], n),
+            ] {
+                if rest.starts_with(text) {
+                    let x: &mut u64 = next.entry(offset + text.len()).or_default();
+                    *x = (*x).max(count);
+                }
+            }
+        }
+        offsets = next;
+        pos = m.range.end;
+    }
+
+    offsets
+        .into_iter()
+        .filter(|(offset, _)| new[*offset..] == haystack[pos..])
+        .map(|(_, n)| n)
+        .max()
+}
+
+/// Returns the range of each capture group (including group 0, the whole match) of each match in
+/// the haystack that `filter` keeps. Groups that didn't participate in the match are `None`.
+pub fn match_captures(
+    matcher: &PatternMatcher,
+    filter: &MatchFilter,
+    haystack: &[u8],
+) -> Result<Vec<MatchCaptures>, <PatternMatcher as Matcher>::Error> {
+    let mut rv = Vec::new();
+    let mut captures = matcher.new_captures().unwrap();
+    matcher.captures_iter(haystack, &mut captures, |caps| {
+        if filter.keeps(haystack, caps.get(0).unwrap()) {
+            let groups = (0..caps.len()).map(|i| caps.get(i).map(|m| m.start()..m.end()));
+            rv.push(groups.collect());
+        }
+        true
+    })?;
+    Ok(rv)
+}
+
+/// The range of each capture group of a match, by group index.
+pub type MatchCaptures = Vec<Option<std::ops::Range<usize>>>;
+
+/// The name of each capture group of the regex `pattern`, by group index. Unnamed groups (and
+/// group 0) are `None`.
+pub fn capture_names(pattern: &str) -> Vec<Option<String>> {
+    fn visit(hir: &regex_syntax::hir::Hir, names: &mut Vec<Option<String>>) {
+        use regex_syntax::hir::HirKind;
+        match hir.kind() {
+            HirKind::Capture(capture) => {
+                let index = capture.index as usize;
+                if names.len() <= index {
+                    names.resize(index + 1, None);
+                }
+                names[index] = capture.name.as_deref().map(str::to_string);
+                visit(&capture.sub, names);
+            }
+            HirKind::Repetition(repetition) => visit(&repetition.sub, names),
+            HirKind::Concat(hirs) | HirKind::Alternation(hirs) => {
+                for hir in hirs {
+                    visit(hir, names);
+                }
+            }
+            HirKind::Empty | HirKind::Literal(_) | HirKind::Class(_) | HirKind::Look(_) => {}
+        }
+    }
+
+    let mut names = vec![None];
+    if let Ok(hir) = regex_syntax::Parser::new().parse(pattern) {
+        visit(&hir, &mut names);
+    }
+    names
+}
+
+/// Whether the regex `pattern` can match an empty string without any anchors or other
+/// assertions, so that it would match between every pair of characters.
+pub fn matches_empty_anywhere(pattern: &str) -> bool {
+    let Ok(hir) = regex_syntax::Parser::new().parse(pattern) else {
+        return false;
+    };
+    hir.properties().minimum_len() == Some(0) && hir.properties().look_set().is_empty()
+}
+
+/// The anchors ('^' and '$') in the multi-line regex `pattern` that can never match since '^'
+/// follows something other than a line break, or '$' is followed by something other than a line
+/// break.
+pub fn unmatchable_anchors(pattern: &str) -> Vec<char> {
+    use regex_syntax::hir::{Class, Hir, HirKind, Look};
+
+    fn first(hir: &Hir) -> &Hir {
+        match hir.kind() {
+            HirKind::Capture(x) => first(&x.sub),
+            HirKind::Concat(x) => first(&x[0]),
+            _ => hir,
+        }
+    }
+    fn last(hir: &Hir) -> &Hir {
+        match hir.kind() {
+            HirKind::Capture(x) => last(&x.sub),
+            HirKind::Concat(x) => last(x.last().unwrap()),
+            _ => hir,
+        }
+    }
+    // whether the byte of `hir` next to the anchor can't be a line break
+    fn not_newline(hir: &Hir, byte: impl Fn(&[u8]) -> u8) -> bool {
+        match hir.kind() {
+            HirKind::Literal(x) => byte(&x.0) != b'\n',
+            HirKind::Class(Class::Unicode(x)) => {
+                !x.iter().any(|x| x.start() <= '\n' && '\n' <= x.end())
+            }
+            HirKind::Class(Class::Bytes(x)) => {
+                !x.iter().any(|x| x.start() <= b'\n' && b'\n' <= x.end())
+            }
+            _ => false,
+        }
+    }
+    fn visit(hir: &Hir, anchors: &mut Vec<char>) {
+        match hir.kind() {
+            HirKind::Capture(x) => visit(&x.sub, anchors),
+            HirKind::Repetition(x) => visit(&x.sub, anchors),
+            HirKind::Alternation(hirs) => hirs.iter().for_each(|x| visit(x, anchors)),
+            HirKind::Concat(hirs) => {
+                for pair in hirs.windows(2) {
+                    let (before, after) = (last(&pair[0]), first(&pair[1]));
+                    if *after.kind() == HirKind::Look(Look::StartLF)
+                        && not_newline(before, |x| *x.last().unwrap())
+                    {
+                        anchors.push('^');
+                    }
+                    if *before.kind() == HirKind::Look(Look::EndLF) && not_newline(after, |x| x[0])
+                    {
+                        anchors.push('$');
+                    }
+                }
+                hirs.iter().for_each(|x| visit(x, anchors));
+            }
+            HirKind::Empty | HirKind::Literal(_) | HirKind::Class(_) | HirKind::Look(_) => {}
+        }
+    }
+
+    let mut anchors = Vec::new();
+    let hir = regex_syntax::ParserBuilder::new()
+        .multi_line(true)
+        .build()
+        .parse(pattern);
+    if let Ok(hir) = hir {
+        visit(&hir, &mut anchors);
+    }
+    anchors.sort();
+    anchors.dedup();
+    anchors
+}
+
+/// Add the text of each match in the haystack to `counts`.
+pub fn count_matched_texts(
+    matcher: &PatternMatcher,
+    haystack: &[u8],
+    counts: &mut std::collections::BTreeMap<Vec<u8>, u64>,
+) -> Result<(), <PatternMatcher as Matcher>::Error> {
+    matcher.find_iter(haystack, |m| {
+        *counts.entry(haystack[m].to_vec()).or_default() += 1;
+        true
+    })
+}
+
+/// Returns the number of lines in `bytes`, including a final line without a line terminator.
+pub fn count_lines(bytes: &[u8], line_terminator: u8) -> u64 {
+    let count = bytes.iter().filter(|x| **x == line_terminator).count();
+    let count = match bytes.last() {
+        Some(x) if *x != line_terminator => count + 1,
+        _ => count,
+    };
+    count.try_into().unwrap()
+}
+
+/// Whether the start of a file has a marker that's commonly used for generated code, such as
+/// "@generated" or "Code generated by ... DO NOT EDIT.". Only the first few lines are checked so
+/// that files which merely mention the markers aren't skipped.
+pub fn has_generated_marker(head: &[u8]) -> bool {
+    const MARKERS: &[&[u8]] = &[b"@generated", b"DO NOT EDIT"];
+    const LINES: usize = 10;
+
+    head.split(|x| *x == b'\n')
+        .take(LINES)
+        .any(|line| MARKERS.iter().any(|marker| line.contains_str(marker)))
+}
+
+/// The (0-indexed) lines of a file that `--ensure-header` replaces with `header`, which are the
+/// lines at the start of the file that match `matcher`. If the first line doesn't match, only the
+/// first line is returned and the header is inserted before it. A "#!" line always stays first.
+/// Returns `None` if the file already starts with the header or has no other lines.
+pub fn header_lines(matcher: &PatternMatcher, bytes: &[u8], header: &[u8]) -> Option<Vec<u64>> {
+    let mut lines = bytes.split_inclusive(|x| *x == b'\n').peekable();
+
+    let mut start = 0;
+    let mut rest = bytes;
+    if let Some(shebang) = lines.next_if(|x| x.starts_with(b"#!")) {
+        start = 1;
+        rest = &bytes[shebang.len()..];
+    }
+
+    if rest.starts_with(header) || rest.is_empty() {
+        return None;
+    }
+
+    let matching: Vec<u64> = (start..)
+        .zip(lines)
+        .take_while(|(_, line)| matcher.is_match(line).unwrap())
+        .map(|(line_num, _)| line_num)
+        .collect();
+
+    match matching.is_empty() {
+        true => Some(vec![start]),
+        false => Some(matching),
+    }
+}
+
+/// Returns `path` relative to the directory `base`, using ".." components if needed. Both paths
+/// must be absolute.
+pub fn relative_path(path: &Path, base: &Path) -> std::path::PathBuf {
+    let mut path_components = path.components().peekable();
+    let mut base_components = base.components().peekable();
+
+    // skip the common prefix
+    while let (Some(a), Some(b)) = (path_components.peek(), base_components.peek()) {
+        if a != b {
+            break;
+        }
+        path_components.next();
+        base_components.next();
+    }
+
+    let mut rv: std::path::PathBuf = base_components
+        .map(|_| std::path::Component::ParentDir)
+        .collect();
+    rv.extend(path_components);
+
+    if rv.as_os_str().is_empty() {
+        rv.push(".");
+    }
+
+    rv
+}
+
+/// Swap all occurrences of the bytes `a` and `b`.
+/// The columns of each line that are within the byte `ranges` of the file, as 1-indexed inclusive
+/// ranges. Lines outside of the ranges aren't included. Returns the (0-indexed) line number as an
+/// error if two separate ranges are on the same line.
+pub fn line_windows(
+    bytes: &[u8],
+    ranges: &[std::ops::Range<u64>],
+    line_terminator: u8,
+) -> Result<std::collections::BTreeMap<u64, RangeInclusive<u64>>, u64> {
+    // merge the overlapping and adjacent ranges
+    let mut ranges = ranges.to_vec();
+    ranges.sort_by_key(|x| x.start);
+    let mut merged: Vec<std::ops::Range<u64>> = Vec::new();
+    for range in ranges.into_iter().filter(|x| !x.is_empty()) {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    let mut windows = std::collections::BTreeMap::new();
+    let mut line_start = 0;
+
+    for (line_num, line) in (0..).zip(bytes.split_inclusive(|x| *x == line_terminator)) {
+        let line_end = line_start + line.len() as u64;
+
+        for range in &merged {
+            if range.end <= line_start || range.start >= line_end {
+                continue;
+            }
+
+            let start = range.start.max(line_start) - line_start + 1;
+            let end = range.end.min(line_end) - line_start;
+            if windows.insert(line_num, start..=end).is_some() {
+                return Err(line_num);
+            }
+        }
+
+        line_start = line_end;
+    }
+
+    Ok(windows)
+}
+
+/// The (0-indexed) lines strictly between each line containing `begin` and the next line containing
+/// `end`. A block without an end line isn't included.
+pub fn marked_blocks(
+    bytes: &[u8],
+    begin: &[u8],
+    end: &[u8],
+    line_terminator: u8,
+) -> Vec<std::ops::Range<u64>> {
+    let mut blocks = Vec::new();
+    let mut block_start = None;
+
+    for (line_num, line) in (0..).zip(bytes.split_inclusive(|x| *x == line_terminator)) {
+        match block_start {
+            None if line.find(begin).is_some() => block_start = Some(line_num + 1),
+            Some(start) if line.find(end).is_some() => {
+                blocks.push(start..line_num);
+                block_start = None;
+            }
+            _ => {}
+        }
+    }
+
+    blocks
+}
+
+/// Sort the paths so that the files in each directory are next to each other, with the files in a
+/// directory before the files in its subdirectories.
+pub fn sort_by_dir(paths: &mut [impl AsRef<Path>], natural: bool) {
+    let cmp = |a: &Path, b: &Path| match natural {
+        true => natural_cmp(a, b),
+        false => a.cmp(b),
+    };
+    paths.sort_by(|a, b| {
+        let (a, b) = (a.as_ref(), b.as_ref());
+        match (a.parent(), b.parent()) {
+            (Some(x), Some(y)) => cmp(x, y).then_with(|| cmp(a, b)),
+            (x, y) => x.cmp(&y).then_with(|| cmp(a, b)),
+        }
+    });
+}
+
+/// Compare two paths in natural order, component by component. Runs of digits are compared by
+/// their value (so "file2" comes before "file10") and letters are compared case-insensitively.
+/// Paths that are only different by case or leading zeros are compared normally.
+pub fn natural_cmp(a: &Path, b: &Path) -> std::cmp::Ordering {
+    let mut xs = a.iter();
+    let mut ys = b.iter();
+
+    loop {
+        let ord = match (xs.next(), ys.next()) {
+            (None, None) => return a.cmp(b),
+            (Some(x), Some(y)) => natural_cmp_bytes(x.as_encoded_bytes(), y.as_encoded_bytes()),
+            (x, y) => x.is_some().cmp(&y.is_some()),
+        };
+        if ord.is_ne() {
+            return ord;
+        }
+    }
+}
+
+fn natural_cmp_bytes(mut a: &[u8], mut b: &[u8]) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+
+    // split off the leading run of digits
+    fn digits(x: &[u8]) -> (&[u8], &[u8]) {
+        let len = x.iter().take_while(|x| x.is_ascii_digit()).count();
+        x.split_at(len)
+    }
+
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, a_rest) = digits(a);
+                let (y, b_rest) = digits(b);
+                let x = &x[x.iter().take_while(|x| **x == b'0').count()..];
+                let y = &y[y.iter().take_while(|x| **x == b'0').count()..];
+                let ord = x.len().cmp(&y.len()).then_with(|| x.cmp(y));
+                if ord.is_ne() {
+                    return ord;
+                }
+                (a, b) = (a_rest, b_rest);
+            }
+            (Some(x), Some(y)) => {
+                let ord = x.to_ascii_lowercase().cmp(&y.to_ascii_lowercase());
+                if ord.is_ne() {
+                    return ord;
+                }
+                (a, b) = (&a[1..], &b[1..]);
+            }
+        }
+    }
+}
+
+/// Group consecutive paths that have the same parent directory. Returns the directory and the
+/// range of indices of each group.
+pub fn dir_groups<'a>(paths: &[&'a Path]) -> Vec<(&'a Path, std::ops::Range<usize>)> {
+    let mut groups: Vec<(&Path, std::ops::Range<usize>)> = Vec::new();
+
+    for (i, path) in paths.iter().enumerate() {
+        let dir = match path.parent() {
+            Some(x) if x != Path::new("") => x,
+            _ => Path::new("."),
+        };
+
+        match groups.last_mut() {
+            Some((last, range)) if *last == dir => range.end = i + 1,
+            _ => groups.push((dir, i..i + 1)),
+        }
+    }
+
+    groups
+}
+
+pub fn swap_bytes(bytes: &[u8], a: u8, b: u8) -> std::borrow::Cow<'_, [u8]> {
+    if a == b {
+        return std::borrow::Cow::Borrowed(bytes);
+    }
+
+    let swapped = bytes
+        .iter()
+        .map(|x| match *x {
+            x if x == a => b,
+            x if x == b => a,
+            x => x,
+        })
+        .collect();
+
+    std::borrow::Cow::Owned(swapped)
+}
+
+/// The encoding of `bytes` from its byte-order mark, or otherwise whether it's valid UTF-8.
+pub fn describe_encoding(bytes: &[u8]) -> &'static str {
+    if bytes.starts_with(b"\xef\xbb\xbf") {
+        "UTF-8 with BOM"
+    } else if bytes.starts_with(b"\xff\xfe") {
+        "UTF-16LE"
+    } else if bytes.starts_with(b"\xfe\xff") {
+        "UTF-16BE"
+    } else if std::str::from_utf8(bytes).is_ok() {
+        "UTF-8"
+    } else {
+        "not UTF-8"
+    }
+}
+
+/// Whether the lines of `bytes` end with "\n", "\r\n", or a mix of both.
+pub fn describe_line_endings(bytes: &[u8]) -> String {
+    let lf = bytes.iter().filter(|x| **x == b'\n').count();
+    let crlf = bytes.windows(2).filter(|x| x == b"\r\n").count();
+
+    match (crlf, lf - crlf) {
+        (0, 0) => "no line endings".to_string(),
+        (0, _) => "LF line endings".to_string(),
+        (_, 0) => "CRLF line endings".to_string(),
+        (crlf, lf) => format!("mixed line endings ({crlf} CRLF, {lf} LF)"),
+    }
+}
+
+/// Convert the line endings of `bytes` to `eol`.
+pub fn convert_eol(bytes: &[u8], eol: crate::git::Eol) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(bytes.len());
+
+    for line in bytes.split_inclusive(|x| *x == b'\n') {
+        let Some(line) = line.strip_suffix(b"\n") else {
+            // the last line has no line ending
+            converted.extend_from_slice(line);
+            break;
+        };
+        converted.extend_from_slice(line.strip_suffix(b"\r").unwrap_or(line));
+        converted.extend_from_slice(match eol {
+            crate::git::Eol::Lf => b"\n",
+            crate::git::Eol::Crlf => b"\r\n",
+        });
+    }
+
+    converted
+}
+
+/// Remove the trailing spaces and tabs of each line of `bytes`, keeping the line terminators. A
+/// "\r" before a "\n" terminator is treated as part of the line ending.
+pub fn trim_trailing_whitespace(bytes: &[u8], line_terminator: u8) -> Vec<u8> {
+    let mut trimmed = Vec::with_capacity(bytes.len());
+
+    for line in bytes.split_inclusive(|x| *x == line_terminator) {
+        let mut content = line.strip_suffix(&[line_terminator]).unwrap_or(line);
+        if line_terminator == b'\n' && content.len() < line.len() {
+            content = content.strip_suffix(b"\r").unwrap_or(content);
+        }
+        let ending = &line[content.len()..];
+        let end = content
+            .iter()
+            .rposition(|x| !matches!(x, b' ' | b'\t'))
+            .map_or(0, |x| x + 1);
+        trimmed.extend_from_slice(&content[..end]);
+        trimmed.extend_from_slice(ending);
+    }
+
+    trimmed
+}
+
+/// A run of words in a [`word_diff`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum WordDiff {
+    /// Words that weren't changed.
+    Same(Vec<u8>),
+    /// Words that were removed and the words that were added in their place. Either can be empty.
+    Changed { removed: Vec<u8>, added: Vec<u8> },
+}
+
+/// Split `bytes` into words for a [`word_diff`]. Runs of word characters and runs of whitespace
+/// are words, and each newline and punctuation character is its own word.
+fn words(bytes: &[u8]) -> Vec<&[u8]> {
+    let class = |b: u8| match b {
+        b'\n' => 0,
+        b if b.is_ascii_whitespace() => 1,
+        // non-ascii bytes are treated as word characters so that utf-8 characters aren't split
+        b if b.is_ascii_alphanumeric() || b == b'_' || !b.is_ascii() => 2,
+        _ => 3,
+    };
+
+    let mut words = Vec::new();
+    let mut start = 0;
+    for i in 1..=bytes.len() {
+        let end = i == bytes.len();
+        if end || matches!(class(bytes[i]), 0 | 3) || class(bytes[i]) != class(bytes[i - 1]) {
+            words.push(&bytes[start..i]);
+            start = i;
+        }
+    }
+    words
+}
+
+/// The word-by-word changes from `original` to `replaced`, like `git diff --word-diff`.
+pub fn word_diff(original: &[u8], replaced: &[u8]) -> Vec<WordDiff> {
+    // diffy compares lines, so each word is written on its own line, and a newline is written as
+    // an empty line (no other word is empty)
+    let as_lines = |bytes| {
+        let mut lines = Vec::new();
+        for word in words(bytes) {
+            if word != b"\n" {
+                lines.extend_from_slice(word);
+            }
+            lines.push(b'\n');
+        }
+        lines
+    };
+    let (original_lines, replaced_lines) = (as_lines(original), as_lines(replaced));
+    let patch = diffy::DiffOptions::new()
+        .set_context_len(usize::MAX)
+        .create_patch_bytes(&original_lines, &replaced_lines);
+
+    let word = |line: &[u8]| match line.strip_suffix(b"\n").unwrap_or(line) {
+        b"" => b"\n".to_vec(),
+        x => x.to_vec(),
+    };
+
+    let mut diff = Vec::new();
+    let lines = patch.hunks().iter().flat_map(|x| x.lines());
+    for line in lines {
+        match (line, diff.last_mut()) {
+            (diffy::Line::Context(x), Some(WordDiff::Same(same))) => same.extend(word(x)),
+            (diffy::Line::Context(x), _) => diff.push(WordDiff::Same(word(x))),
+            (diffy::Line::Delete(x), Some(WordDiff::Changed { removed, .. })) => {
+                removed.extend(word(x))
+            }
+            (diffy::Line::Insert(x), Some(WordDiff::Changed { added, .. })) => {
+                added.extend(word(x))
+            }
+            (diffy::Line::Delete(x), _) => diff.push(WordDiff::Changed {
+                removed: word(x),
+                added: Vec::new(),
+            }),
+            (diffy::Line::Insert(x), _) => diff.push(WordDiff::Changed {
+                removed: Vec::new(),
+                added: word(x),
+            }),
+        }
+    }
+
+    // there are no hunks if nothing changed
+    if diff.is_empty() && !original.is_empty() {
+        diff.push(WordDiff::Same(original.to_vec()));
+    }
+
+    diff
+}
+
+pub fn rewrite_patch_line_counts(bytes: &[u8]) -> std::borrow::Cow<'_, [u8]> {
+    let result = (|| {
+        let mut lines = crate::parse::lines_with_pos(bytes);
+
+        let (header, header_start) = lines.nth(2)?;
+
+        let (range_1, range_2) = crate::parse::patch_block_header(header)?;
+
+        let mut content_start = None;
+        let mut line_counts = (0, 0);
+
+        // count the number of + and - lines
+        for (line, pos) in lines {
+            if content_start.is_none() {
+                content_start = Some(pos);
+            }
+
+            match line.first() {
+                Some(b' ') | None => {
+                    line_counts.0 += 1;
+                    line_counts.1 += 1;
+                }
+                Some(b'-') => line_counts.0 += 1,
+                Some(b'+') => line_counts.1 += 1,
+                _ => return None,
+            }
+        }
+
+        if (range_1.1, range_2.1) == line_counts {
+            // no need to change the patch
+            return None;
+        }
+
+        let content_start = content_start?;
+
+        // build the new patch
+        let mut new_patch = Vec::new();
+
+        // add the header
+        new_patch.extend_from_slice(&bytes[..header_start]);
+
+        // write the new line numbers
+        writeln!(
+            &mut new_patch,
+            "@@ -{},{} +{},{} @@",
+            range_1.0, line_counts.0, range_2.0, line_counts.1,
+        )
+        .ok()?;
+
+        // add the patch contents
+        new_patch.extend_from_slice(&bytes[content_start..]);
+
+        Some(new_patch)
+    })();
+
+    match result {
+        Some(x) => std::borrow::Cow::Owned(x),
+        None => std::borrow::Cow::Borrowed(bytes),
+    }
+}
+
+pub fn rewrite_patch_line_start(bytes: &[u8], offset: i128, ansi: bool) -> Option<Vec<u8>> {
+    let mut lines = crate::parse::lines_with_pos(bytes);
+    let (mut header, header_start) = lines.nth(2)?;
+    let (_, content_start) = lines.next()?;
+
+    const ANSI_RESET: &[u8] = b"\x1b[0m";
+    const ANSI_HEADER_COLOR: &[u8] = b"\x1b[36m";
+
+    if ansi {
+        header = header.strip_prefix(ANSI_RESET)?;
+        header = header.strip_prefix(ANSI_HEADER_COLOR)?;
+        header = header.strip_suffix(ANSI_RESET)?;
+    }
+
+    let (mut pair_1, mut pair_2) = crate::parse::patch_block_header(header)?;
+
+    let (offset, positive_offset) = if offset >= 0 {
+        (u64::try_from(offset).ok()?, true)
+    } else {
+        (u64::try_from(-offset).ok()?, false)
+    };
+
+    if positive_offset {
+        pair_1.0 = pair_1.0.checked_add(offset)?;
+        pair_2.0 = pair_2.0.checked_add(offset)?;
+    } else {
+        pair_1.0 = pair_1.0.checked_sub(offset)?;
+        pair_2.0 = pair_2.0.checked_sub(offset)?;
+    }
+
+    // build the new patch
+    let mut new_patch = Vec::new();
+
+    // add the header
+    new_patch.extend_from_slice(&bytes[..header_start]);
+
+    if ansi {
+        new_patch.extend_from_slice(ANSI_RESET);
+        new_patch.extend_from_slice(ANSI_HEADER_COLOR);
+    }
+
+    // write the new line numbers
+    write!(
+        &mut new_patch,
+        "@@ -{},{} +{},{} @@",
+        pair_1.0, pair_1.1, pair_2.0, pair_2.1,
     )
     .ok()?;
 
@@ -472,7 +1882,7 @@ pub fn rewrite_patch_line_start(bytes: &[u8], offset: i128, ansi: bool) -> Optio
 
 /// A label you can jump to using `continue`.
 ///
-/// ```
+/// ```ignore
 /// let x: u32 = label!('start {
 ///     let input = todo!();
 ///     match input {
@@ -504,6 +1914,112 @@ mod tests {
 
     use std::io::Write;
 
+    #[test]
+    fn test_line_windows() {
+        let bytes = b"foo\nbar\nbaz\n";
+        assert_eq!(
+            line_windows(bytes, &[1..4, 4..6], b'\n')
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [(0, 2..=4), (1, 1..=2)],
+        );
+        assert_eq!(
+            line_windows(bytes, &[8..12, 9..10, 4..5], b'\n')
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [(1, 1..=1), (2, 1..=4)],
+        );
+        assert_eq!(line_windows(bytes, &[0..1, 2..3], b'\n'), Err(0));
+        assert_eq!(line_windows(bytes, &[0..1, 1..3], b'\n').unwrap().len(), 1);
+        assert!(line_windows(bytes, &[5..5, 100..200], b'\n')
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_marked_blocks() {
+        let bytes = b"a\n# BEGIN\nb\nc\n# END\nd\n# BEGIN\n# END\n# BEGIN\ne\n";
+        assert_eq!(marked_blocks(bytes, b"BEGIN", b"END", b'\n'), [2..4, 7..7],);
+        assert_eq!(marked_blocks(b"a\nb\n", b"BEGIN", b"END", b'\n'), []);
+        // the end marker is only checked after the begin line
+        assert_eq!(
+            marked_blocks(b"<x>\ny\n</x>\n<x>\n</x>\n", b"x", b"/x", b'\n'),
+            [1..2, 4..4],
+        );
+    }
+
+    #[test]
+    fn test_sort_by_dir() {
+        let mut paths = [
+            "./z.rs",
+            "./src/sub/b.rs",
+            "./src/a.rs",
+            "./a.rs",
+            "./src/z.rs",
+        ];
+        sort_by_dir(&mut paths, false);
+        assert_eq!(
+            paths,
+            [
+                "./a.rs",
+                "./z.rs",
+                "./src/a.rs",
+                "./src/z.rs",
+                "./src/sub/b.rs"
+            ],
+        );
+
+        let paths: Vec<&Path> = paths.iter().map(Path::new).collect();
+        assert_eq!(
+            dir_groups(&paths),
+            [
+                (Path::new("."), 0..2),
+                (Path::new("./src"), 2..4),
+                (Path::new("./src/sub"), 4..5),
+            ],
+        );
+
+        assert_eq!(dir_groups(&[Path::new("a.rs")]), [(Path::new("."), 0..1)]);
+        assert_eq!(dir_groups(&[]), []);
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut paths = [
+            "file10.txt",
+            "File2.txt",
+            "file2.txt",
+            "file1.txt",
+            "a/file02.txt",
+            "a/b/x",
+            "a10/x",
+            "a9/x",
+        ];
+        paths.sort_by(|a, b| natural_cmp(Path::new(a), Path::new(b)));
+        assert_eq!(
+            paths,
+            [
+                "a/b/x",
+                "a/file02.txt",
+                "a9/x",
+                "a10/x",
+                "file1.txt",
+                "File2.txt",
+                "file2.txt",
+                "file10.txt",
+            ],
+        );
+
+        let mut paths = ["./src/b10.rs", "./SRC/c.rs", "./src/b9.rs", "./a.rs"];
+        sort_by_dir(&mut paths, true);
+        assert_eq!(
+            paths,
+            ["./a.rs", "./SRC/c.rs", "./src/b9.rs", "./src/b10.rs"],
+        );
+    }
+
     #[test]
     fn test_ranges() {
         let list = [1, 2, 10, 12, 35, 38, 55, u64::MAX];
@@ -533,13 +2049,627 @@ mod tests {
         assert_eq!(ranges(&list, padding), [0..=3, 4..=8, 99..=101]);
     }
 
+    #[test]
+    fn test_hunk_ranges() {
+        use crate::cli::HunkMerge;
+
+        let shown = |merge| -> Vec<_> {
+            hunk_ranges(&[1, 4, 10, 10], 1, merge)
+                .into_iter()
+                .map(|x| x.shown)
+                .collect()
+        };
+        assert_eq!(shown(HunkMerge::Exact), [0..=2, 3..=5, 9..=11]);
+        assert_eq!(shown(HunkMerge::Aggressive), [0..=5, 9..=11]);
+        assert_eq!(shown(HunkMerge::Never), [0..=2, 3..=5, 9..=11]);
+
+        // overlapping contexts are shown twice, but the changed lines don't overlap
+        assert_eq!(
+            hunk_ranges(&[0, 2, 3], 2, HunkMerge::Never),
+            [
+                HunkRange {
+                    shown: 0..=2,
+                    changed: 0..=0,
+                },
+                HunkRange {
+                    shown: 0..=4,
+                    changed: 2..=2,
+                },
+                HunkRange {
+                    shown: 1..=5,
+                    changed: 3..=3,
+                },
+            ],
+        );
+        assert_eq!(
+            hunk_ranges(&[0, 2, 3], 2, HunkMerge::Exact),
+            [HunkRange {
+                shown: 0..=5,
+                changed: 0..=5,
+            }],
+        );
+        assert_eq!(
+            hunk_ranges(&[5], u64::MAX, HunkMerge::Aggressive),
+            [HunkRange {
+                shown: 0..=u64::MAX,
+                changed: 0..=u64::MAX,
+            }],
+        );
+    }
+
+    #[test]
+    fn test_join_adjacent_matches() {
+        use crate::cli::HunkMerge;
+
+        let lines = [2, 3, 4, 8];
+        let hunks = hunk_ranges(&lines, 0, HunkMerge::Exact);
+        assert_eq!(hunks.len(), 4);
+        assert_eq!(
+            join_adjacent_matches(hunks, &lines),
+            [
+                HunkRange {
+                    shown: 2..=4,
+                    changed: 2..=4,
+                },
+                HunkRange {
+                    shown: 8..=8,
+                    changed: 8..=8,
+                },
+            ],
+        );
+
+        // only the changed lines are joined, even if the context lines overlap
+        let lines = [2, 3];
+        assert_eq!(
+            join_adjacent_matches(hunk_ranges(&lines, 1, HunkMerge::Never), &lines),
+            [HunkRange {
+                shown: 1..=4,
+                changed: 2..=3,
+            }],
+        );
+    }
+
+    #[test]
+    fn test_copy_replace() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("from");
+        let to = dir.path().join("to");
+        std::fs::write(&from, "new").unwrap();
+        std::fs::write(&to, "old").unwrap();
+        std::fs::set_permissions(&from, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        copy_replace(&from, &to).unwrap();
+        assert_eq!(std::fs::read(&to).unwrap(), b"new");
+        let mode = std::fs::metadata(&to).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        // only the source and destination remain
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_expand_template_vars() {
+        let path = Path::new("src/$a.rs");
+        assert_eq!(expand_template_vars(b"x $1 $$ ${y}", path, 3), None);
+        assert_eq!(
+            expand_template_vars(b"${FILE}:${LINE} ${MATCH}", path, 3).unwrap(),
+            b"src/$$a.rs:3 ${0}",
+        );
+        assert_eq!(
+            expand_template_vars(b"$${LINE}${LINE}$", path, 3).unwrap(),
+            b"$${LINE}3$",
+        );
+    }
+
+    #[test]
+    fn test_template_parts() {
+        use GroupRef::*;
+        use TemplatePart::*;
+
+        assert_eq!(
+            template_parts(b"a$1b ${2}_${name}$$"),
+            [
+                (&b"a"[..], Literal(b"a")),
+                (b"$1b", Group(Name("1b"))),
+                (b" ", Literal(b" ")),
+                (b"${2}", Group(Index(2))),
+                (b"_", Literal(b"_")),
+                (b"${name}", Group(Name("name"))),
+                (b"$$", Literal(b"$")),
+            ],
+        );
+        assert_eq!(
+            template_parts(b"$0${LINE}$LINE"),
+            [
+                (&b"$0"[..], Group(Index(0))),
+                (b"${LINE}", Variable("LINE")),
+                (b"$LINE", Group(Name("LINE"))),
+            ],
+        );
+        // a "$" that doesn't start a reference is literal
+        assert_eq!(
+            template_parts(b"$-${x"),
+            [
+                (&b"$"[..], Literal(b"$")),
+                (b"-", Literal(b"-")),
+                (b"$", Literal(b"$")),
+                (b"{x", Literal(b"{x")),
+            ],
+        );
+        assert_eq!(template_parts(b""), []);
+    }
+
+    #[test]
+    fn test_matches_empty_anywhere() {
+        assert!(matches_empty_anywhere(""));
+        assert!(matches_empty_anywhere("x*"));
+        assert!(matches_empty_anywhere("(foo)?"));
+        assert!(!matches_empty_anywhere("x+"));
+        assert!(!matches_empty_anywhere("^"));
+        assert!(!matches_empty_anywhere(r"\bx*"));
+        // patterns that can't be parsed aren't checked
+        assert!(!matches_empty_anywhere("("));
+    }
+
+    #[test]
+    fn test_unmatchable_anchors() {
+        assert_eq!(unmatchable_anchors("foo$"), []);
+        assert_eq!(unmatchable_anchors(r"foo$\n^bar"), []);
+        assert_eq!(unmatchable_anchors(r"foo$[\s]"), []);
+        assert_eq!(unmatchable_anchors("foo$bar"), ['$']);
+        assert_eq!(unmatchable_anchors("(foo$)bar"), ['$']);
+        assert_eq!(unmatchable_anchors("foo^bar"), ['^']);
+        assert_eq!(unmatchable_anchors("a|x[a-z]^(b$c)"), ['$', '^']);
+        assert_eq!(unmatchable_anchors("("), []);
+    }
+
+    #[test]
+    fn test_match_captures() {
+        let matcher = PatternMatcher::new(r"(\w)=(?<val>\d)?;").unwrap();
+        assert_eq!(
+            match_captures(&matcher, &MatchFilter::default(), b"a=1; b=;").unwrap(),
+            [
+                vec![Some(0..4), Some(0..1), Some(2..3)],
+                vec![Some(5..8), Some(5..6), None],
+            ],
+        );
+        assert_eq!(
+            match_captures(&matcher, &MatchFilter::columns(Some(2..=6)), b"a=1; b=;").unwrap(),
+            [vec![Some(5..8), Some(5..6), None]],
+        );
+
+        assert_eq!(
+            capture_names(r"(a)(?:b)(?<x>c(d))|(?P<y>e)*"),
+            [
+                None,
+                None,
+                Some("x".to_string()),
+                None,
+                Some("y".to_string())
+            ],
+        );
+        assert_eq!(capture_names("("), [None]);
+    }
+
+    #[test]
+    fn test_normalize_replacements() {
+        let matcher = PatternMatcher::new(r"x|caf(e\x{301}|\x{e9})").unwrap();
+        let filter = MatchFilter {
+            normalize: Some(NormalizationForm::Nfc),
+            ..Default::default()
+        };
+        let replace = |replacement: &str, haystack: &str| {
+            let replacement = Replacement::Template(replacement.as_bytes());
+            match_replacements(&matcher, replacement, &filter, haystack.as_bytes())
+                .unwrap()
+                .into_iter()
+                .map(|x| String::from_utf8(x.replacement).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // the form of the match
+        assert_eq!(
+            replace("$0 \u{e9}", "cafe\u{301}"),
+            ["cafe\u{301} e\u{301}"]
+        );
+        assert_eq!(replace("$0 e\u{301}", "caf\u{e9}"), ["caf\u{e9} \u{e9}"]);
+        // the form of the rest of the line
+        assert_eq!(
+            replace("\u{e9}", "x e\u{301}\nx \u{e9}"),
+            ["e\u{301}", "\u{e9}"]
+        );
+        // or the default form
+        assert_eq!(replace("e\u{301}", "x"), ["\u{e9}"]);
+
+        // the lines end with the line terminator
+        let filter = MatchFilter {
+            line_terminator: b'\0',
+            ..filter
+        };
+        let replaced = match_replacements(
+            &matcher,
+            Replacement::Template("\u{e9}".as_bytes()),
+            &filter,
+            "x e\u{301}\0x \u{e9}\n".as_bytes(),
+        )
+        .unwrap();
+        let replaced: Vec<_> = replaced.iter().map(|x| x.replacement.as_slice()).collect();
+        assert_eq!(replaced, ["e\u{301}".as_bytes(), "\u{e9}".as_bytes()]);
+    }
+
+    #[test]
+    fn test_match_replacements() {
+        let matcher = PatternMatcher::new(r"(\w)=(?<val>\d)").unwrap();
+        assert_eq!(
+            match_replacements(
+                &matcher,
+                Replacement::Template(b"${val}:$1"),
+                &MatchFilter::default(),
+                b"a=1, b=2, c=x"
+            )
+            .unwrap(),
+            [
+                MatchReplacement {
+                    range: 0..3,
+                    replacement: b"1:a".to_vec(),
+                },
+                MatchReplacement {
+                    range: 5..8,
+                    replacement: b"2:b".to_vec(),
+                },
+            ],
+        );
+        assert_eq!(
+            match_replacements(
+                &matcher,
+                Replacement::Template(b""),
+                &MatchFilter::default(),
+                b""
+            )
+            .unwrap(),
+            []
+        );
+        assert_eq!(
+            match_replacements(
+                &matcher,
+                Replacement::Literal(b"${val}:$1"),
+                &MatchFilter::default(),
+                b"a=1"
+            )
+            .unwrap(),
+            [MatchReplacement {
+                range: 0..3,
+                replacement: b"${val}:$1".to_vec(),
+            }],
+        );
+        assert_eq!(
+            match_replacements(
+                &matcher,
+                Replacement::Template(b""),
+                &MatchFilter::columns(Some(2..=6)),
+                b"a=1, b=2"
+            )
+            .unwrap(),
+            [MatchReplacement {
+                range: 5..8,
+                replacement: b"".to_vec(),
+            }],
+        );
+    }
+
+    #[test]
+    fn test_similarity_key() {
+        let m = |range, replacement: &[u8]| MatchReplacement {
+            range,
+            replacement: replacement.to_vec(),
+        };
+        let key = similarity_key(b"  x = foo(1);\n", b"", &[m(6..9, b"bar")]);
+
+        // whitespace and numbers don't matter
+        assert_eq!(
+            key,
+            similarity_key(b"x=foo(23);\n", b"", &[m(2..5, b"bar")])
+        );
+        assert_eq!(
+            key,
+            similarity_key(b"\tx = foo(4);", b"", &[m(5..8, b"bar")])
+        );
+
+        // but the matched text, the replacement, and the rest of the line do
+        assert_ne!(
+            key,
+            similarity_key(b"  x = fob(1);\n", b"", &[m(6..9, b"bar")])
+        );
+        assert_ne!(
+            key,
+            similarity_key(b"  x = foo(1);\n", b"", &[m(6..9, b"baz")])
+        );
+        assert_ne!(
+            key,
+            similarity_key(b"  y = foo(1);\n", b"", &[m(6..9, b"bar")])
+        );
+        assert_ne!(key, similarity_key(b"  x = foo(1);\n", b"", &[]));
+
+        // without matches, the replaced lines are compared
+        let key = similarity_key(b"a 1\n", b"b 1\n", &[]);
+        assert_eq!(key, similarity_key(b"a 2\n", b"b 3\n", &[]));
+        assert_ne!(key, similarity_key(b"a 1\n", b"c 1\n", &[]));
+    }
+
+    #[test]
+    fn test_count_applied_matches() {
+        let m = |range, replacement: &[u8]| MatchReplacement {
+            range,
+            replacement: replacement.to_vec(),
+        };
+        let haystack = b"a=1, a=2, a=3";
+        let matches = [m(0..1, b"bb"), m(5..6, b"bb"), m(10..11, b"bb")];
+
+        let count = |new: &[u8]| count_applied_matches(haystack, &matches, new);
+        assert_eq!(count(b"a=1, a=2, a=3"), Some(0));
+        assert_eq!(count(b"bb=1, a=2, bb=3"), Some(2));
+        assert_eq!(count(b"bb=1, bb=2, bb=3"), Some(3));
+        for subset in [vec![], vec![&matches[1]], vec![&matches[0], &matches[2]]] {
+            let new = apply_match_replacements(haystack, &subset);
+            assert_eq!(count(&new), Some(subset.len() as u64));
+        }
+
+        // other changes
+        assert_eq!(count(b"bb=1, a=2, bb=4"), None);
+        assert_eq!(count(b"b=1, a=2, a=3"), None);
+        assert_eq!(count(b""), None);
+
+        // many matches whose replacements could be confused with the kept matches
+        let haystack = [b'a'; 64];
+        let matches: Vec<_> = (0..64).map(|i| m(i..i + 1, b"aa")).collect();
+        let new = [b'a'; 100];
+        assert_eq!(count_applied_matches(&haystack, &matches, &new), Some(36));
+        let new = [&[b'a'; 100][..], b"b"].concat();
+        assert_eq!(count_applied_matches(&haystack, &matches, &new), None);
+    }
+
+    #[test]
+    fn test_replace_regex() {
+        let matcher = PatternMatcher::new(r"(\w)=(\d)").unwrap();
+        let mut dest = b"> ".to_vec();
+        let replaced = replace_regex(
+            &matcher,
+            Replacement::Template(b"$1 == $2"),
+            &MatchFilter::columns(Some(1..=6)),
+            b"a=1, b=2, c=3",
+            &mut dest,
+        )
+        .unwrap();
+        assert_eq!(dest, b"> a == 1, b == 2, c=3");
+        assert_eq!(
+            replaced,
+            [
+                ReplacedMatch {
+                    range: 0..3,
+                    replacement: 2..8,
+                },
+                ReplacedMatch {
+                    range: 5..8,
+                    replacement: 10..16,
+                },
+            ],
+        );
+
+        let matches = [
+            MatchReplacement {
+                range: 0..3,
+                replacement: b"x".to_vec(),
+            },
+            MatchReplacement {
+                range: 5..8,
+                replacement: b"y".to_vec(),
+            },
+        ];
+        assert_eq!(
+            apply_match_replacements(b"a=1, b=2, c=3", &[&matches[1]]),
+            b"a=1, y, c=3"
+        );
+        assert_eq!(
+            apply_match_replacements(b"a=1, b=2, c=3", &[&matches[0], &matches[1]]),
+            b"x, y, c=3"
+        );
+        assert_eq!(apply_match_replacements(b"a=1", &[]), b"a=1");
+    }
+
+    #[test]
+    fn test_count_matched_texts() {
+        let matcher = PatternMatcher::new(r"(?i)colou?r").unwrap();
+        let mut counts = std::collections::BTreeMap::new();
+        count_matched_texts(&matcher, b"Color COLOR color", &mut counts).unwrap();
+        count_matched_texts(&matcher, b"Colour Color x", &mut counts).unwrap();
+        assert_eq!(
+            counts.into_iter().collect::<Vec<_>>(),
+            [
+                (b"COLOR".to_vec(), 1),
+                (b"Color".to_vec(), 2),
+                (b"Colour".to_vec(), 1),
+                (b"color".to_vec(), 1),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_relative_path() {
+        let rel = |path, base| relative_path(Path::new(path), Path::new(base));
+        assert_eq!(rel("/a/b/c.rs", "/a"), Path::new("b/c.rs"));
+        assert_eq!(rel("/a/b/c.rs", "/a/d/e"), Path::new("../../b/c.rs"));
+        assert_eq!(rel("/a/b", "/a/b"), Path::new("."));
+        assert_eq!(rel("/a", "/a/b"), Path::new(".."));
+        assert_eq!(rel("/ab/c", "/a"), Path::new("../ab/c"));
+    }
+
+    #[test]
+    fn test_run_path_cmd() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("it's a file");
+
+        assert!(run_path_cmd("touch", &path).unwrap().success());
+        assert!(path.exists());
+        assert!(run_path_cmd("test -f {} && rm -- {}", &path)
+            .unwrap()
+            .success());
+        assert!(!path.exists());
+        assert!(!run_path_cmd("test -f", &path).unwrap().success());
+    }
+
+    #[test]
+    fn test_count_lines() {
+        assert_eq!(count_lines(b"", b'\n'), 0);
+        assert_eq!(count_lines(b"a", b'\n'), 1);
+        assert_eq!(count_lines(b"a\n", b'\n'), 1);
+        assert_eq!(count_lines(b"a\nb", b'\n'), 2);
+        assert_eq!(count_lines(b"\n\n", b'\n'), 2);
+        assert_eq!(count_lines(b"a;b;", b';'), 2);
+    }
+
+    #[test]
+    fn test_swap_bytes() {
+        assert_eq!(swap_bytes(b"a;b\nc;", b';', b'\n').as_ref(), b"a\nb;c\n");
+        assert_eq!(swap_bytes(b"a;b\nc;", b'\n', b'\n').as_ref(), b"a;b\nc;");
+        assert_eq!(swap_bytes(b"", b';', b'\n').as_ref(), b"");
+    }
+
+    #[test]
+    fn test_describe_file() {
+        assert_eq!(describe_encoding(b"abc"), "UTF-8");
+        assert_eq!(describe_encoding(b"\xef\xbb\xbfabc"), "UTF-8 with BOM");
+        assert_eq!(describe_encoding(b"\xff\xfea\0"), "UTF-16LE");
+        assert_eq!(describe_encoding(b"caf\xe9"), "not UTF-8");
+
+        assert_eq!(describe_line_endings(b"a\nb\n"), "LF line endings");
+        assert_eq!(describe_line_endings(b"a\r\nb"), "CRLF line endings");
+        assert_eq!(
+            describe_line_endings(b"a\r\nb\nc\n"),
+            "mixed line endings (1 CRLF, 2 LF)"
+        );
+        assert_eq!(describe_line_endings(b"a"), "no line endings");
+    }
+
+    #[test]
+    fn test_convert_eol() {
+        use crate::git::Eol;
+
+        assert_eq!(convert_eol(b"a\nb\r\nc", Eol::Crlf), b"a\r\nb\r\nc");
+        assert_eq!(convert_eol(b"a\nb\r\nc\n", Eol::Lf), b"a\nb\nc\n");
+        assert_eq!(convert_eol(b"a\r\r\n", Eol::Lf), b"a\r\n");
+        assert_eq!(convert_eol(b"", Eol::Crlf), b"");
+    }
+
+    #[test]
+    fn test_trim_trailing_whitespace() {
+        assert_eq!(
+            trim_trailing_whitespace(b"a \nb\t\t\r\nc  ", b'\n'),
+            b"a\nb\r\nc"
+        );
+        assert_eq!(trim_trailing_whitespace(b" a b \n \n", b'\n'), b" a b\n\n");
+        assert_eq!(trim_trailing_whitespace(b"a \0b\n \0", b'\0'), b"a\0b\n\0");
+        assert_eq!(trim_trailing_whitespace(b"", b'\n'), b"");
+    }
+
+    #[test]
+    fn test_word_diff() {
+        let same = |x: &str| WordDiff::Same(x.as_bytes().to_vec());
+        let changed = |a: &str, b: &str| WordDiff::Changed {
+            removed: a.as_bytes().to_vec(),
+            added: b.as_bytes().to_vec(),
+        };
+
+        assert_eq!(
+            words(b"foo_1(a, b)\n\n"),
+            [
+                &b"foo_1"[..],
+                b"(",
+                b"a",
+                b",",
+                b" ",
+                b"b",
+                b")",
+                b"\n",
+                b"\n"
+            ]
+        );
+        assert_eq!(
+            word_diff(b"let foo = foo(1);\n", b"let bar = bar(1);\n"),
+            [
+                same("let "),
+                changed("foo", "bar"),
+                same(" = "),
+                changed("foo", "bar"),
+                same("(1);\n"),
+            ],
+        );
+        assert_eq!(
+            word_diff(b"a\nb\n", b"a b\n"),
+            [same("a"), changed("\n", " "), same("b\n")],
+        );
+        assert_eq!(word_diff(b"a b\n", b"a b\n"), [same("a b\n")]);
+        assert_eq!(word_diff(b"", b"a"), [changed("", "a")]);
+    }
+
+    #[test]
+    fn test_has_generated_marker() {
+        assert!(has_generated_marker(
+            b"// Code generated by protoc-gen-go. DO NOT EDIT.\npackage x\n"
+        ));
+        assert!(has_generated_marker(
+            b"/*\n * @generated by build.rs\n */\n"
+        ));
+        assert!(!has_generated_marker(b"fn main() {}\n"));
+        // only the first lines are checked
+        let late = "\n".repeat(10) + "// @generated";
+        assert!(!has_generated_marker(late.as_bytes()));
+    }
+
+    #[test]
+    fn test_header_lines() {
+        let matcher = PatternMatcher::new("^// ").unwrap();
+        let header = b"// Copyright 2024\n";
+
+        assert_eq!(
+            header_lines(&matcher, b"fn main() {}\n", header),
+            Some(vec![0])
+        );
+        assert_eq!(
+            header_lines(
+                &matcher,
+                b"// Copyright 2023\n// MIT\nfn x() {}\n// y\n",
+                header
+            ),
+            Some(vec![0, 1]),
+        );
+        assert_eq!(
+            header_lines(&matcher, b"// Copyright 2024\nfn x() {}\n", header),
+            None
+        );
+        assert_eq!(header_lines(&matcher, b"", header), None);
+
+        // the shebang line stays first
+        assert_eq!(
+            header_lines(&matcher, b"#!/bin/sh\n// old\necho\n", header),
+            Some(vec![1]),
+        );
+        assert_eq!(
+            header_lines(&matcher, b"#!/bin/sh\necho\n", header),
+            Some(vec![1])
+        );
+        assert_eq!(
+            header_lines(&matcher, b"#!/bin/sh\n// Copyright 2024\necho\n", header),
+            None,
+        );
+        assert_eq!(header_lines(&matcher, b"#!/bin/sh\n", header), None);
+    }
+
     // it would be nice to make this helper a generic fn, but it's not possible without HRTBs
     macro_rules! replace_file_tester {
-        ($f: ident) => {{
+        ($f: ident, $fsync: expr) => {{
             let mut file = tempfile::Builder::new().tempfile().unwrap();
             file.write_all(b"hello world\n").unwrap();
 
-            $f(file.path(), None, |mut original, mut new| {
+            $f(file.path(), None, $fsync, |mut original, mut new| {
                 new.write_all(b"foo ").unwrap();
                 std::io::copy(&mut original, &mut new).unwrap();
                 (true, ())
@@ -558,7 +2688,7 @@ mod tests {
             let mut file = tempfile::Builder::new().tempfile().unwrap();
             file.write_all(b"hello world\n").unwrap();
 
-            $f(file.path(), None, |mut original, mut new| {
+            $f(file.path(), None, $fsync, |mut original, mut new| {
                 new.write_all(b"foo ").unwrap();
                 std::io::copy(&mut original, &mut new).unwrap();
                 (false, ())
@@ -587,7 +2717,7 @@ mod tests {
                 target_permissions,
             );
 
-            $f(file.path(), None, |mut original, mut new| {
+            $f(file.path(), None, $fsync, |mut original, mut new| {
                 new.write_all(b"foo ").unwrap();
                 std::io::copy(&mut original, &mut new).unwrap();
                 (true, ())
@@ -611,12 +2741,14 @@ mod tests {
 
     #[test]
     fn test_replace_file() {
-        replace_file_tester!(replace_file);
+        replace_file_tester!(replace_file, false);
+        replace_file_tester!(replace_file, true);
     }
 
     #[test]
     fn test_replace_file_compat() {
-        replace_file_tester!(replace_file_compat);
+        replace_file_tester!(replace_file_compat, false);
+        replace_file_tester!(replace_file_compat, true);
     }
 
     // 'replace_file_linux' only works on certain filesystems, so ignore by default
@@ -629,11 +2761,19 @@ mod tests {
         pub fn helper<T>(
             path: impl AsRef<Path>,
             modified_at: Option<SystemTime>,
+            fsync: bool,
             f: impl FnOnce(&File, &File) -> (bool, T),
         ) -> Result<T, ReplaceFileError> {
-            replace_file_linux(path, modified_at, /* allow_fallback= */ false, f)
+            replace_file_linux(
+                path,
+                modified_at,
+                /* allow_fallback= */ false,
+                fsync,
+                f,
+            )
         }
 
-        replace_file_tester!(helper);
+        replace_file_tester!(helper, false);
+        replace_file_tester!(helper, true);
     }
 }
@@ -0,0 +1,104 @@
+//! A directory for the temporary files of a run, such as the files of hunks that are edited or
+//! filtered. Each run has its own directory "$XDG_RUNTIME_DIR/repatch/<PID>" (or
+//! "<TMPDIR>/repatch-<UID>/<PID>" if `XDG_RUNTIME_DIR` isn't set). The directory is removed when
+//! the run ends, and the directories of processes that are no longer running (such as a run that
+//! crashed or was killed) are removed by the next run.
+//!
+//! Files that must be on the same filesystem as the files that they replace, and files that are
+//! kept after the run (such as the undo journal), aren't written here.
+
+use std::fs::File;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::Context;
+
+/// The directory of this run, if it was created.
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// The directory of this run, which is removed when dropped.
+pub struct Workspace {
+    path: PathBuf,
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// The directory containing the directory of each run.
+fn parent_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(x) if !x.is_empty() => PathBuf::from(x).join("repatch"),
+        _ => {
+            let uid = unsafe { libc::getuid() };
+            std::env::temp_dir().join(format!("repatch-{uid}"))
+        }
+    }
+}
+
+/// Create the directory of this run, after removing the directories of earlier runs that are no
+/// longer running. The temporary files of this run are written there until the returned
+/// [`Workspace`] is dropped.
+pub fn create() -> anyhow::Result<Workspace> {
+    let parent = parent_dir();
+    // the directories may have the contents of private files, so only we can read them
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&parent)
+        .with_context(|| format!("could not create '{}'", parent.display()))?;
+
+    let entries = std::fs::read_dir(&parent)
+        .with_context(|| format!("could not read '{}'", parent.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let Some(pid) = path
+            .file_name()
+            .and_then(|x| x.to_str()?.parse::<i32>().ok())
+        else {
+            continue;
+        };
+        if pid != std::process::id() as i32 && !crate::lock::is_running(pid) {
+            let _ = std::fs::remove_dir_all(&path);
+        }
+    }
+
+    let path = parent.join(std::process::id().to_string());
+    // a process with the same pid may have left its directory behind
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&path)
+        .with_context(|| format!("could not create '{}'", path.display()))?;
+
+    let _ = DIR.set(path.clone());
+    Ok(Workspace { path })
+}
+
+/// The directory of this run, if it was created.
+pub fn dir() -> Option<&'static Path> {
+    DIR.get().map(PathBuf::as_path)
+}
+
+/// A new unnamed temporary file in the directory of this run, or in the system's temporary
+/// directory if there isn't one.
+pub fn tempfile() -> std::io::Result<File> {
+    match dir() {
+        Some(dir) => tempfile::tempfile_in(dir),
+        None => tempfile::tempfile(),
+    }
+}
+
+/// A new named temporary file in the directory of this run, or in the system's temporary directory
+/// if there isn't one. The file is removed when dropped.
+pub fn named_tempfile(prefix: &str, suffix: &str) -> std::io::Result<tempfile::NamedTempFile> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(prefix).suffix(suffix);
+    match dir() {
+        Some(dir) => builder.tempfile_in(dir),
+        None => builder.tempfile(),
+    }
+}
@@ -0,0 +1,233 @@
+//! Tests that run the repatch binary in a temporary directory.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Run repatch with `args` in `dir`, writing `input` to its stdin. Returns its exit status and
+/// its stdout and stderr together. The state, config, and runtime directories are within `dir`
+/// so that the user's files aren't used.
+fn repatch(dir: &Path, args: &[&str], input: &str) -> (bool, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_repatch"))
+        .args(args)
+        .arg("--test-mode")
+        .current_dir(dir)
+        .env("HOME", dir)
+        .env("XDG_STATE_HOME", dir.join(".state"))
+        .env("XDG_CONFIG_HOME", dir.join(".config"))
+        .env("XDG_RUNTIME_DIR", dir.join(".runtime"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // the process may exit without reading all of its input
+    let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+
+    let output = child.wait_with_output().unwrap();
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    (output.status.success(), text)
+}
+
+#[test]
+fn test_cache_fixed_strings() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.txt"), "foo\nfoo\n").unwrap();
+
+    // "$0" is the whole match in a template, so nothing would be replaced
+    let (_, output) = repatch(dir.path(), &["foo", "$0", "--cache", "--show", "a.txt"], "");
+    assert!(output.contains("Found 0 matches"), "{output}");
+
+    // but it's literal text with `--fixed-strings`, so the cached results can't be used
+    let args = ["foo", "$0", "-F", "--cache", "--show", "a.txt"];
+    let (_, output) = repatch(dir.path(), &args, "");
+    assert!(output.contains("Found 2 matches"), "{output}");
+}
+
+#[test]
+fn test_cache_identifier() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.txt"), "foo\nfoo_bar\n").unwrap();
+
+    let (_, output) = repatch(dir.path(), &["foo", "x", "--cache", "--show", "a.txt"], "");
+    assert!(output.contains("Found 2 matches"), "{output}");
+
+    // "foo_bar" is a different identifier
+    let args = ["foo", "x", "--identifier", "--cache", "--show", "a.txt"];
+    let (_, output) = repatch(dir.path(), &args, "");
+    assert!(output.contains("Found 1 match "), "{output}");
+
+    // the identifier characters from the config file also change the matches
+    std::fs::write(dir.path().join("b.txt"), "foo\nfoo-bar\n").unwrap();
+    let args = ["foo", "x", "--identifier", "--cache", "--show", "b.txt"];
+    let (_, output) = repatch(dir.path(), &args, "");
+    assert!(output.contains("Found 2 matches"), "{output}");
+
+    std::fs::create_dir_all(dir.path().join(".config/repatch")).unwrap();
+    std::fs::write(
+        dir.path().join(".config/repatch/config.toml"),
+        "[identifiers]\ntxt = \"-\"\n",
+    )
+    .unwrap();
+    let (_, output) = repatch(dir.path(), &args, "");
+    assert!(output.contains("Found 1 match "), "{output}");
+}
+
+#[test]
+fn test_summary_some_matches() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.txt"), "foo foo\n").unwrap();
+
+    // choose the matches of the hunk one at a time, replacing only the first
+    let (success, output) = repatch(dir.path(), &["foo", "bar", "a.txt"], "m\ny\nn\n");
+    assert!(success, "{output}");
+    assert!(
+        output.contains("Applied 1 hunk (0 edited) and skipped 0, replacing 1 match."),
+        "{output}"
+    );
+    let text = std::fs::read_to_string(dir.path().join("a.txt")).unwrap();
+    assert_eq!(text, "bar foo\n");
+}
+
+#[test]
+fn test_apply_blank_hunk() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.txt"), "foo\n").unwrap();
+
+    // there's nothing to read, so this would never finish if the user was asked to confirm
+    let (success, output) = repatch(dir.path(), &["foo", " ", "--apply", "a.txt"], "");
+    assert!(success, "{output}");
+    let text = std::fs::read_to_string(dir.path().join("a.txt")).unwrap();
+    assert_eq!(text, " \n");
+}
+
+#[test]
+fn test_prompt_eof() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.txt"), "foo\n").unwrap();
+
+    // the input ends at the hunk's prompt
+    let (success, output) = repatch(dir.path(), &["foo", "bar", "a.txt"], "");
+    assert!(success, "{output}");
+
+    // and at the confirmation that the hunk should be removed
+    let (success, output) = repatch(dir.path(), &["foo", " ", "a.txt"], "y\n");
+    assert!(success, "{output}");
+    assert!(output.contains("removes all lines of the hunk"), "{output}");
+
+    let text = std::fs::read_to_string(dir.path().join("a.txt")).unwrap();
+    assert_eq!(text, "foo\n");
+}
+
+#[test]
+fn test_vanished_file() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.txt"), "foo\n").unwrap();
+    std::fs::write(dir.path().join("b.txt"), "foo\n").unwrap();
+
+    // "b.txt" is deleted after it's searched but before it's patched
+    let args = ["foo", "bar", "--apply", "--post-file-cmd", "rm b.txt; true"];
+    let (success, output) = repatch(dir.path(), &[&args[..], &["a.txt", "b.txt"]].concat(), "");
+    assert!(success, "{output}");
+    assert!(
+        output.contains("skipping 'b.txt' since it was deleted or renamed"),
+        "{output}"
+    );
+    let text = std::fs::read_to_string(dir.path().join("a.txt")).unwrap();
+    assert_eq!(text, "bar\n");
+}
+
+#[test]
+fn test_apply_protected_file() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // root can read and write the file anyways
+    if unsafe { libc::geteuid() } == 0 {
+        return;
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.txt"), "foo\n").unwrap();
+    std::fs::write(dir.path().join("b.txt"), "foo\n").unwrap();
+
+    // "b.txt" can't be read after it's searched
+    let args = [
+        "foo",
+        "bar",
+        "--apply",
+        "--post-file-cmd",
+        "chmod 000 b.txt; true",
+    ];
+    let (success, output) = repatch(dir.path(), &[&args[..], &["a.txt", "b.txt"]].concat(), "");
+    assert!(!success, "{output}");
+    assert!(
+        output.contains("skipping 'b.txt' since it can't be read"),
+        "{output}"
+    );
+    assert!(output.contains("1 file could not be patched"), "{output}");
+    let text = std::fs::read_to_string(dir.path().join("a.txt")).unwrap();
+    assert_eq!(text, "bar\n");
+
+    // and "b.txt" can't be replaced since its directory is read-only
+    let perms = |mode| std::fs::Permissions::from_mode(mode);
+    std::fs::set_permissions(dir.path().join("b.txt"), perms(0o644)).unwrap();
+    std::fs::create_dir(dir.path().join("ro")).unwrap();
+    std::fs::write(dir.path().join("ro/c.txt"), "foo\n").unwrap();
+    std::fs::set_permissions(dir.path().join("ro"), perms(0o555)).unwrap();
+    let (success, output) = repatch(dir.path(), &["foo", "bar", "--apply", "ro/c.txt"], "");
+    std::fs::set_permissions(dir.path().join("ro"), perms(0o755)).unwrap();
+    assert!(!success, "{output}");
+    assert!(output.contains("since it can't be written"), "{output}");
+}
+
+#[test]
+fn test_normalize_decomposed_file() {
+    let dir = tempfile::tempdir().unwrap();
+    // "café" and "résumé" in the decomposed form
+    std::fs::write(
+        dir.path().join("a.txt"),
+        "cafe\u{301}\nre\u{301}sume\u{301}: cafe\u{301}\n",
+    )
+    .unwrap();
+
+    // the replacement is typed in the composed form
+    let args = [
+        "caf\u{e9}",
+        "th\u{e9}",
+        "--normalize",
+        "nfc",
+        "--apply",
+        "a.txt",
+    ];
+    let (success, output) = repatch(dir.path(), &args, "");
+    assert!(success, "{output}");
+    let text = std::fs::read_to_string(dir.path().join("a.txt")).unwrap();
+    assert_eq!(text, "the\u{301}\nre\u{301}sume\u{301}: the\u{301}\n");
+}
+
+#[test]
+fn test_hunk_filter_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    let text = "foo\na\nb\nc\nd\ne\nf\ng\nh\nfoo\n";
+    std::fs::write(dir.path().join("a.txt"), text).unwrap();
+
+    // the filter accepts the first hunk and fails for the second
+    let filter = r#"test "$REPATCH_LINE" = 1 && cat || exit 2"#;
+    let args = [
+        "foo",
+        "bar",
+        "--apply",
+        "--context",
+        "0",
+        "--hunk-filter",
+        filter,
+        "a.txt",
+    ];
+    let (success, output) = repatch(dir.path(), &args, "");
+    assert!(!success, "{output}");
+    assert!(output.contains("the hunk filter"), "{output}");
+    let new = std::fs::read_to_string(dir.path().join("a.txt")).unwrap();
+    assert_eq!(new, text);
+}